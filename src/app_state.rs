@@ -15,6 +15,9 @@ pub enum AppState {
     Scene3,
     Scene4,
     Scene5,
+    Solitaire,
+    Blackjack,
+    CardTable, // The online table, with the chat
     PauseMenu,
 }
 
@@ -25,7 +28,10 @@ impl AppState {
             AppState::Scene2 => AppState::Scene3,
             AppState::Scene3 => AppState::Scene4,
             AppState::Scene4 => AppState::Scene5,
-            AppState::Scene5 => AppState::Scene1,
+            AppState::Scene5 => AppState::Solitaire,
+            AppState::Solitaire => AppState::Blackjack,
+            AppState::Blackjack => AppState::CardTable,
+            AppState::CardTable => AppState::Scene1,
             AppState::PauseMenu => AppState::Scene1,
         }
     }
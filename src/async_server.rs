@@ -0,0 +1,273 @@
+// Server asincrono: un task tokio per client invece di un thread.
+//
+// Same ServerState, messages and simulation thread as the thread-per-client
+// server, only the connections are served differently. Every client has a
+// reader task and a writer task draining its own bounded queue, so writing to
+// a slow socket never holds the state lock nor delays anybody else.
+
+use native_tls::TlsAcceptor;
+use std::net::TcpListener as StdTcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+
+use crate::protocol::{encode_json, FrameDecoder, ServerMessage};
+use crate::server::{
+    check_login, handle_payload, lock_state, server_log, LogLevel, ServerState, ACCEPT_POLL_INTERVAL, CLIENT_QUEUE_SIZE,
+    TLS_HANDSHAKE_TIMEOUT,
+};
+
+
+// How often a connection checks it wasn't removed from the state (kick, lagging...)
+const CLIENT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// Time left to a removed client to receive what was queued before it
+const CLIENT_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+// ====== METHODS ======
+
+/// Serves the listener on a tokio runtime, returns once the server shut down
+/// and every connection is closed
+pub(crate) fn run(listener: StdTcpListener, state: Arc<Mutex<ServerState>>, tls: Option<TlsAcceptor>) {
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            server_log!(LogLevel::Error, "Could not start the async runtime: {}", e);
+            return;
+        }
+    };
+    runtime.block_on(accept_connections(listener, state, tls));
+}
+
+async fn accept_connections(listener: StdTcpListener, state: Arc<Mutex<ServerState>>, tls: Option<TlsAcceptor>) {
+    let listener = match listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            server_log!(LogLevel::Error, "Could not use the listener: {}", e);
+            return;
+        }
+    };
+    let tls = tls.map(tokio_native_tls::TlsAcceptor::from);
+    let mut connections = JoinSet::new();
+
+    while !lock_state(&state).shutting_down {
+        // Wake up every now and then to notice the shutdown
+        let Ok(accepted) = timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await else {
+            continue;
+        };
+        while connections.try_join_next().is_some() {}
+
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                server_log!(LogLevel::Warn, "Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        let tls = tls.clone();
+        connections.spawn(async move {
+            match tls {
+                Some(acceptor) => match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => handle_client(stream, state).await,
+                    Ok(Err(e)) => server_log!(LogLevel::Warn, "TLS handshake failed: {}", e),
+                    Err(_) => server_log!(LogLevel::Warn, "TLS handshake timed out"),
+                },
+                None => handle_client(stream, state).await,
+            }
+        });
+    }
+
+    while connections.join_next().await.is_some() {}
+}
+
+async fn handle_client<S>(stream: S, state: Arc<Mutex<ServerState>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (sender, mut outgoing) = mpsc::channel(CLIENT_QUEUE_SIZE);
+    let registered = lock_state(&state).register_client(sender);
+    let client_id = match registered {
+        Ok(client_id) => client_id,
+        Err(reason) => {
+            server_log!(LogLevel::Info, "Refused a connection: {}", reason);
+            if let Ok(frame) = encode_json(&ServerMessage::Disconnected { reason: reason.to_string() }) {
+                let _ = writer.write_all(&frame).await;
+            }
+            let _ = writer.shutdown().await;
+            return;
+        }
+    };
+    server_log!(LogLevel::Info, "Client connected: {}", client_id);
+
+    // Ends once the client is removed from the state and everything queued before was sent
+    let mut writer_task = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let Some(frame) = message.frame() else {
+                server_log!(LogLevel::Warn, "Could not encode message for client {}", client_id);
+                continue;
+            };
+            if let Err(e) = writer.write_all(frame).await {
+                server_log!(LogLevel::Warn, "Failed to send message to client {}: {}", client_id, e);
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0u8; 4096];
+    let mut writer_done = false;
+    'connection: loop {
+        tokio::select! {
+            read = reader.read(&mut buffer) => match read {
+                Ok(0) => {
+                    server_log!(LogLevel::Info, "Client disconnected: {}", client_id);
+                    break;
+                }
+                Ok(bytes_read) => decoder.extend(&buffer[..bytes_read]),
+                Err(e) => {
+                    server_log!(LogLevel::Warn, "Dropping client {}: {}", client_id, e);
+                    break;
+                }
+            },
+            _ = &mut writer_task => {
+                writer_done = true;
+                break;
+            }
+            // A writer stuck on a full socket never ends by itself
+            _ = sleep(CLIENT_CHECK_INTERVAL) => {
+                if !lock_state(&state).has_client(client_id) {
+                    break;
+                }
+            }
+        }
+
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(payload)) => {
+                    let login = handle_payload(client_id, &payload, &mut lock_state(&state));
+                    // The password hash would stall a runtime worker, it gets a blocking thread
+                    if let Some(login) = login {
+                        let state = Arc::clone(&state);
+                        let _ = tokio::task::spawn_blocking(move || check_login(&state, login)).await;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    server_log!(LogLevel::Warn, "Dropping client {}: {}", client_id, e);
+                    break 'connection;
+                }
+            }
+        }
+    }
+
+    lock_state(&state).remove_client(client_id);
+    if !writer_done && timeout(CLIENT_FLUSH_TIMEOUT, &mut writer_task).await.is_err() {
+        writer_task.abort();
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use crate::auth::test_credentials;
+    use crate::protocol::{read_frame, read_json, write_json, ClientMessage};
+    use crate::server::{start_server, AdminCommand, ServerConfig, ServerMode};
+
+    const CONNECTIONS: usize = 300;
+
+    fn async_config(max_clients: usize) -> ServerConfig {
+        ServerConfig {
+            port: 0,
+            max_clients,
+            log_level: LogLevel::Error,
+            mode: ServerMode::Async,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn login(stream: &mut TcpStream) {
+        let login = ClientMessage::Login { username: "player1".to_string(), password: "securepassword".to_string() };
+        write_json(stream, &login).unwrap();
+    }
+
+    // Skips the snapshots the simulation keeps sending, without decoding them:
+    // with hundreds of players they are big and the test would fall behind
+    fn read_non_snapshot(stream: &mut TcpStream) -> ServerMessage {
+        loop {
+            let payload = read_frame(stream).unwrap();
+            if !payload.starts_with(br#"{"type":"state_snapshot""#) {
+                return serde_json::from_slice(&payload).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_ping_and_shutdown() {
+        let handle = start_server(&async_config(8), test_credentials(), None).unwrap();
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        login(&mut stream);
+        assert!(matches!(read_non_snapshot(&mut stream), ServerMessage::LoginAccepted { .. }));
+
+        write_json(&mut stream, &ClientMessage::Ping { nonce: 5 }).unwrap();
+        assert_eq!(read_non_snapshot(&mut stream), ServerMessage::Pong { nonce: 5 });
+
+        handle.shutdown();
+        assert!(matches!(read_non_snapshot(&mut stream), ServerMessage::Disconnected { .. }));
+        handle.wait();
+    }
+
+    #[test]
+    fn test_server_full() {
+        let handle = start_server(&async_config(1), test_credentials(), None).unwrap();
+        let _first = TcpStream::connect(handle.local_addr()).unwrap();
+        std::thread::sleep(Duration::from_millis(100)); // Let the first one register
+
+        let mut second = TcpStream::connect(handle.local_addr()).unwrap();
+        let response: ServerMessage = read_json(&mut second).unwrap();
+        assert_eq!(response, ServerMessage::Disconnected { reason: "Server is full".to_string() });
+
+        handle.shutdown();
+        handle.wait();
+    }
+
+    // Hundreds of clients logged in at once, every one of them gets the broadcast
+    #[test]
+    fn test_load_many_connections() {
+        let handle = start_server(&async_config(CONNECTIONS), test_credentials(), None).unwrap();
+        let addr = handle.local_addr();
+
+        let mut clients: Vec<TcpStream> = (0..CONNECTIONS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for stream in &mut clients {
+            login(stream);
+        }
+        for stream in &mut clients {
+            assert!(matches!(read_non_snapshot(stream), ServerMessage::LoginAccepted { .. }));
+        }
+
+        let output = handle.execute(&AdminCommand::List);
+        assert!(output.starts_with(&format!("{} client(s)", CONNECTIONS)), "{}", output);
+
+        handle.execute(&AdminCommand::Broadcast("load test".to_string()));
+        let expected = ServerMessage::Status { message: "load test".to_string() };
+        for stream in &mut clients {
+            assert_eq!(read_non_snapshot(stream), expected);
+        }
+
+        handle.shutdown();
+        handle.wait();
+    }
+}
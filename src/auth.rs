@@ -0,0 +1,223 @@
+// Credenziali dei giocatori e token di sessione per il server.
+//
+// Passwords are never stored: every user has a random salt and the
+// PBKDF2-HMAC-SHA256 hash of salt + password. The server only talks to the
+// `CredentialStore` trait, so the users file can be swapped for something else.
+
+use pbkdf2::pbkdf2_hmac;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+// ====== CONSTANTS ======
+
+pub const DEFAULT_USERS_FILE: &str = "users.json";
+pub const DEFAULT_HASH_ROUNDS: u32 = 100_000;
+const SALT_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+const SESSION_TOKEN_SIZE: usize = 32;
+
+
+// ====== STRUCTS ======
+
+/// Anything able to look up the users allowed to log in
+pub trait CredentialStore: Send {
+    /// A copy of the stored record, so the slow hash check can run away from the store
+    fn user(&self, username: &str) -> Option<UserRecord>;
+
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self.user(username).is_some_and(|user| user.verify(password))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub salt: String, // hex
+    pub password_hash: String, // hex
+    pub rounds: u32,
+}
+
+impl UserRecord {
+    pub fn new(username: &str, password: &str) -> Self {
+        Self::with_rounds(username, password, DEFAULT_HASH_ROUNDS)
+    }
+
+    pub fn with_rounds(username: &str, password: &str, rounds: u32) -> Self {
+        let salt: [u8; SALT_SIZE] = rand::thread_rng().gen();
+        UserRecord {
+            username: username.to_string(),
+            salt: to_hex(&salt),
+            password_hash: to_hex(&hash_password(password, &salt, rounds)),
+            rounds,
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let (Some(salt), Some(expected)) = (from_hex(&self.salt), from_hex(&self.password_hash)) else {
+            return false;
+        };
+        let hash = hash_password(password, &salt, self.rounds);
+
+        // Compare every byte so the timing doesn't leak how much of the hash matched
+        hash.len() == expected.len()
+            && hash.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UsersFile {
+    users: Vec<UserRecord>,
+}
+
+/// Users kept in a local JSON file: `{"users": [{"username": ..., "salt": ..., ...}]}`
+#[derive(Debug)]
+pub struct JsonCredentialStore {
+    path: PathBuf,
+    users: HashMap<String, UserRecord>,
+}
+
+impl JsonCredentialStore {
+    /// An empty store that will be written to `path` on `save`
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        JsonCredentialStore {
+            path: path.as_ref().to_path_buf(),
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read_to_string(path.as_ref())?;
+        let file: UsersFile = serde_json::from_str(&contents)?;
+
+        let mut store = JsonCredentialStore::new(path);
+        for user in file.users {
+            store.users.insert(user.username.clone(), user);
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut users: Vec<UserRecord> = self.users.values().cloned().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        let json = serde_json::to_string_pretty(&UsersFile { users })?;
+        fs::write(&self.path, json)
+    }
+
+    /// Adds or replaces a user (call `save` to persist it)
+    pub fn add_user(&mut self, record: UserRecord) {
+        self.users.insert(record.username.clone(), record);
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+}
+
+impl CredentialStore for JsonCredentialStore {
+    fn user(&self, username: &str) -> Option<UserRecord> {
+        self.users.get(username).cloned()
+    }
+}
+
+
+// ====== METHODS ======
+
+pub fn hash_password(password: &str, salt: &[u8], rounds: u32) -> [u8; HASH_SIZE] {
+    let mut hash = [0u8; HASH_SIZE];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+/// Random token handed to a client after logging in, used to resume the session
+pub fn generate_session_token() -> String {
+    let bytes: [u8; SESSION_TOKEN_SIZE] = rand::thread_rng().gen();
+    to_hex(&bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Store shared by the tests: player1 / securepassword, one round so it stays fast
+#[cfg(test)]
+pub(crate) fn test_credentials() -> Box<dyn CredentialStore> {
+    let mut store = JsonCredentialStore::new("unused_users.json");
+    store.add_user(UserRecord::with_rounds("player1", "securepassword", 1));
+    Box::new(store)
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_password() {
+        let user = UserRecord::with_rounds("player1", "securepassword", 10);
+        assert!(user.verify("securepassword"));
+        assert!(!user.verify("wrongpassword"));
+        assert!(!user.password_hash.contains("securepassword"));
+    }
+
+    #[test]
+    fn test_same_password_different_salt() {
+        let a = UserRecord::with_rounds("a", "password", 10);
+        let b = UserRecord::with_rounds("b", "password", 10);
+        assert_ne!(a.salt, b.salt);
+        assert_ne!(a.password_hash, b.password_hash);
+    }
+
+    #[test]
+    fn test_save_and_load_users_file() {
+        let path = std::env::temp_dir().join(format!("ivan_game_users_{}.json", generate_session_token()));
+
+        let mut store = JsonCredentialStore::new(&path);
+        store.add_user(UserRecord::with_rounds("player1", "securepassword", 10));
+        store.save().unwrap();
+
+        let loaded = JsonCredentialStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded.verify("player1", "securepassword"));
+        assert!(!loaded.verify("player1", "nope"));
+        assert!(!loaded.verify("player2", "securepassword"));
+    }
+
+    #[test]
+    fn test_session_tokens_are_unique() {
+        let a = generate_session_token();
+        let b = generate_session_token();
+        assert_eq!(a.len(), SESSION_TOKEN_SIZE * 2);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        let bytes = [0u8, 1, 171, 255];
+        assert_eq!(to_hex(&bytes), "0001abff");
+        assert_eq!(from_hex("0001abff").unwrap(), bytes);
+        assert!(from_hex("abc").is_none());
+        assert!(from_hex("zz").is_none());
+    }
+}
@@ -3,13 +3,164 @@
 
 use std::net::TcpListener;
 use std::thread;
-use std::io::{self, Write};
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
 
 // mod server; // Assuming your server logic is in server.rs
-use ivan_game::server;
+use ivan_game::server::{self, AdminCommand, ServerConfig};
+use ivan_game::auth::{JsonCredentialStore, UserRecord, DEFAULT_USERS_FILE};
+use ivan_game::recording::{load_recording, Replay};
+
+// If both files exist the server only accepts TLS connections
+const TLS_CERT_FILE: &str = "cert.pem";
+const TLS_KEY_FILE: &str = "key.pem";
+
+const USAGE: &str = "Usage:
+  server [--address <ip>] [--port <port>] [--max-clients <n>] [--log-level <error|warn|info|debug>]
+         [--mode <threads|async>] [--idle-timeout <seconds>] [--record <file>]
+         [--udp-port <port>] [--session-timeout <seconds>]
+  server adduser <username> <password>
+  server replay <file>";
 
 fn main() {
-    let address = "127.0.0.1:8080";
-    println!("Starting server at {}", address);
-    server::start_server(address);
+    let args: Vec<String> = std::env::args().collect();
+
+    // server adduser <username> <password>
+    if args.len() == 4 && args[1] == "adduser" {
+        add_user(&args[2], &args[3]);
+        return;
+    }
+
+    // server replay <file>
+    if args.len() == 3 && args[1] == "replay" {
+        replay(&args[2]);
+        return;
+    }
+
+    let config = match parse_args(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let credentials = match JsonCredentialStore::load(DEFAULT_USERS_FILE) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Could not load {}: {}", DEFAULT_USERS_FILE, e);
+            eprintln!("Nobody can log in, add a user with: server adduser <username> <password>");
+            JsonCredentialStore::new(DEFAULT_USERS_FILE)
+        }
+    };
+
+    let tls = if std::path::Path::new(TLS_CERT_FILE).exists() && std::path::Path::new(TLS_KEY_FILE).exists() {
+        match server::load_tls_acceptor(TLS_CERT_FILE, TLS_KEY_FILE) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                eprintln!("Could not load {} / {}: {}", TLS_CERT_FILE, TLS_KEY_FILE, e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let handle = match server::start_server(&config, Box::new(credentials), tls) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Could not start the server on {}: {}", config.bind_address(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // Admin console, one command per line
+    println!("Type help for the admin commands");
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse::<AdminCommand>() {
+            Ok(command) => {
+                println!("{}", handle.execute(&command));
+                if command == AdminCommand::Shutdown {
+                    break;
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    // Without a console (stdin closed) the server keeps running until killed
+    handle.wait();
+}
+
+fn parse_args(args: &[String]) -> Result<ServerConfig, String> {
+    let mut config = ServerConfig::default();
+    let mut args = args.iter();
+
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        match flag.as_str() {
+            "--address" => config.address = value.clone(),
+            "--port" => config.port = value.parse().map_err(|_| format!("Invalid port '{}'", value))?,
+            "--max-clients" => {
+                config.max_clients = value.parse().map_err(|_| format!("Invalid max clients '{}'", value))?
+            }
+            "--log-level" => config.log_level = value.parse()?,
+            "--mode" => config.mode = value.parse()?,
+            "--idle-timeout" => {
+                let seconds: u64 = value.parse().map_err(|_| format!("Invalid idle timeout '{}'", value))?;
+                config.idle_timeout = Duration::from_secs(seconds);
+            }
+            "--session-timeout" => {
+                let seconds: u64 = value.parse().map_err(|_| format!("Invalid session timeout '{}'", value))?;
+                config.session_timeout = Duration::from_secs(seconds);
+            }
+            "--record" => config.record_path = Some(value.into()),
+            "--udp-port" => {
+                config.udp_port = Some(value.parse().map_err(|_| format!("Invalid UDP port '{}'", value))?)
+            }
+            _ => return Err(format!("Unknown argument '{}'", flag)),
+        }
+    }
+    Ok(config)
+}
+
+fn add_user(username: &str, password: &str) {
+    let mut store = JsonCredentialStore::load(DEFAULT_USERS_FILE)
+        .unwrap_or_else(|_| JsonCredentialStore::new(DEFAULT_USERS_FILE));
+    store.add_user(UserRecord::new(username, password));
+    match store.save() {
+        Ok(()) => println!("Saved user {} to {}", username, DEFAULT_USERS_FILE),
+        Err(e) => eprintln!("Could not save {}: {}", DEFAULT_USERS_FILE, e),
+    }
+}
+
+// Feeds a recorded session to a fresh server and reports where it answered differently
+fn replay(path: &str) {
+    let result = load_recording(path)
+        .map_err(|e| e.to_string())
+        .and_then(|entries| Replay::new(entries).and_then(|mut replay| replay.run()).map_err(|e| e.to_string()));
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Could not replay {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    for mismatch in &report.mismatches {
+        println!("{}", mismatch);
+    }
+    println!(
+        "Replayed {} events, {} client(s) still connected, {} mismatch(es)",
+        report.events, report.clients, report.mismatches.len()
+    );
+    if !report.mismatches.is_empty() {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,672 @@
+// Regole del blackjack contro il banco, senza Bevy: la scena in blackjack_scene.rs le disegna.
+//
+// One player against the dealer, dealt from a shoe of several decks that is
+// reshuffled once the cut card comes out. The player can hit, stand, double
+// on two cards and split pairs up to four hands (split aces get one card
+// each); the dealer draws to 17 and, depending on the config, stands or hits
+// on a soft 17. A natural blackjack pays 3 to 2. The chips live in a
+// `ChipBank`, a small JSON file, so they last between sessions.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::cards::{Card, Deck, Rank};
+
+
+// ====== CONSTANTS ======
+
+pub const DEFAULT_CHIPS_FILE: &str = "chips.json";
+pub const STARTING_CHIPS: u64 = 1000;
+pub const MAX_HANDS: usize = 4;
+const BLACKJACK: u8 = 21;
+const DEALER_STANDS: u8 = 17;
+
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlackjackConfig {
+    pub decks: usize,
+    pub dealer_stands_on_soft_17: bool,
+    /// How much of the shoe is dealt before the cut card
+    pub penetration: f32,
+}
+
+impl Default for BlackjackConfig {
+    fn default() -> Self {
+        BlackjackConfig { decks: 6, dealer_stands_on_soft_17: true, penetration: 0.75 }
+    }
+}
+
+/// The decks the dealer deals from
+#[derive(Debug, Clone)]
+pub struct Shoe {
+    deck: Deck<Card>,
+    decks: usize,
+    cut: usize, // Reshuffle before a round once only this many cards are left
+    rng: StdRng,
+}
+
+impl Shoe {
+    pub fn new(decks: usize, penetration: f32, seed: u64) -> Self {
+        let mut shoe = Shoe { deck: Deck::from_cards(Vec::new()), decks: decks.max(1), cut: 0, rng: StdRng::seed_from_u64(seed) };
+        shoe.cut = ((shoe.decks * 52) as f32 * (1. - penetration.clamp(0., 1.))) as usize;
+        shoe.shuffle();
+        shoe
+    }
+
+    /// A single deck dealing `cards` in this order first, for tests and replays
+    pub fn stacked(cards: Vec<Card>, seed: u64) -> Self {
+        let mut cards = cards;
+        cards.reverse();
+        Shoe { deck: Deck::from_cards(cards), decks: 1, cut: 0, rng: StdRng::seed_from_u64(seed) }
+    }
+
+    /// Puts every card back and shuffles
+    pub fn shuffle(&mut self) {
+        let cards = (0..self.decks).flat_map(|_| Card::all()).collect();
+        self.deck = Deck::from_cards(cards);
+        self.deck.shuffle(&mut self.rng);
+    }
+
+    pub fn needs_shuffle(&self) -> bool {
+        self.deck.len() <= self.cut
+    }
+
+    pub fn draw(&mut self) -> Card {
+        if self.deck.is_empty() {
+            self.shuffle();
+        }
+        self.deck.draw().expect("A shuffled shoe is never empty")
+    }
+
+    pub fn len(&self) -> usize {
+        self.deck.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deck.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerHand {
+    pub cards: Vec<Card>,
+    pub bet: u64,
+    pub doubled: bool,
+    /// Made by a split, so 21 on two cards isn't a blackjack
+    pub split: bool,
+    pub done: bool,
+}
+
+impl PlayerHand {
+    fn new(bet: u64) -> Self {
+        PlayerHand { cards: Vec::new(), bet, doubled: false, split: false, done: false }
+    }
+
+    pub fn total(&self) -> u8 {
+        hand_total(&self.cards).0
+    }
+
+    pub fn is_blackjack(&self) -> bool {
+        !self.split && is_blackjack(&self.cards)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Betting,
+    Playing,
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Blackjack,
+    Win,
+    Push,
+    Lose,
+}
+
+impl Outcome {
+    /// The chips given back for a hand that bet `bet`, the bet included
+    pub fn returned(&self, bet: u64) -> u64 {
+        match self {
+            Outcome::Blackjack => bet + bet * 3 / 2,
+            Outcome::Win => bet * 2,
+            Outcome::Push => bet,
+            Outcome::Lose => 0,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Outcome::Blackjack => "Blackjack!",
+            Outcome::Win => "Win",
+            Outcome::Push => "Push",
+            Outcome::Lose => "Lose",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlackjackError {
+    RoundInProgress,
+    NoRound,
+    InvalidBet,
+    NotEnoughChips,
+    CannotDouble,
+    CannotSplit,
+}
+
+impl fmt::Display for BlackjackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            BlackjackError::RoundInProgress => "Finish this round first",
+            BlackjackError::NoRound => "Deal a round first",
+            BlackjackError::InvalidBet => "The bet must be at least one chip",
+            BlackjackError::NotEnoughChips => "Not enough chips",
+            BlackjackError::CannotDouble => "Only two card hands can be doubled",
+            BlackjackError::CannotSplit => "Only pairs can be split, up to four hands",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for BlackjackError {}
+
+#[derive(Debug, Clone)]
+pub struct Blackjack {
+    config: BlackjackConfig,
+    shoe: Shoe,
+    chips: u64,
+    dealer: Vec<Card>,
+    hands: Vec<PlayerHand>,
+    active: usize,
+    phase: Phase,
+    outcomes: Vec<Outcome>,
+}
+
+impl Blackjack {
+    pub fn new(config: BlackjackConfig, chips: u64, seed: u64) -> Self {
+        let shoe = Shoe::new(config.decks, config.penetration, seed);
+        Self::with_shoe(config, chips, shoe)
+    }
+
+    pub fn with_shoe(config: BlackjackConfig, chips: u64, shoe: Shoe) -> Self {
+        Blackjack {
+            config,
+            shoe,
+            chips,
+            dealer: Vec::new(),
+            hands: Vec::new(),
+            active: 0,
+            phase: Phase::Betting,
+            outcomes: Vec::new(),
+        }
+    }
+
+    /// Takes the bet and deals two cards each, the dealer's second one face down
+    pub fn deal(&mut self, bet: u64) -> Result<(), BlackjackError> {
+        if self.phase == Phase::Playing {
+            return Err(BlackjackError::RoundInProgress);
+        }
+        if bet == 0 {
+            return Err(BlackjackError::InvalidBet);
+        }
+        if bet > self.chips {
+            return Err(BlackjackError::NotEnoughChips);
+        }
+        if self.shoe.needs_shuffle() {
+            self.shoe.shuffle();
+        }
+
+        self.chips -= bet;
+        self.hands = vec![PlayerHand::new(bet)];
+        self.dealer.clear();
+        self.outcomes.clear();
+        self.active = 0;
+        self.phase = Phase::Playing;
+        for _ in 0..2 {
+            let card = self.shoe.draw();
+            self.hands[0].cards.push(card);
+            let card = self.shoe.draw();
+            self.dealer.push(card);
+        }
+
+        // A natural on either side ends the round at once
+        if self.hands[0].is_blackjack() || is_blackjack(&self.dealer) {
+            self.settle();
+        }
+        Ok(())
+    }
+
+    pub fn hit(&mut self) -> Result<(), BlackjackError> {
+        self.active_hand_mut()?; // No card leaves the shoe without a hand to take it
+        let card = self.shoe.draw();
+        let hand = &mut self.hands[self.active];
+        hand.cards.push(card);
+        if hand.total() >= BLACKJACK {
+            hand.done = true;
+            self.next_hand();
+        }
+        Ok(())
+    }
+
+    pub fn stand(&mut self) -> Result<(), BlackjackError> {
+        self.active_hand_mut()?.done = true;
+        self.next_hand();
+        Ok(())
+    }
+
+    /// Doubles the bet for exactly one more card
+    pub fn double(&mut self) -> Result<(), BlackjackError> {
+        let chips = self.chips;
+        let hand = self.active_hand_mut()?;
+        if hand.cards.len() != 2 {
+            return Err(BlackjackError::CannotDouble);
+        }
+        if hand.bet > chips {
+            return Err(BlackjackError::NotEnoughChips);
+        }
+        let bet = hand.bet;
+        hand.bet *= 2;
+        hand.doubled = true;
+        self.chips -= bet;
+
+        let card = self.shoe.draw();
+        let hand = &mut self.hands[self.active];
+        hand.cards.push(card);
+        hand.done = true;
+        self.next_hand();
+        Ok(())
+    }
+
+    /// Splits a pair into two hands with the same bet, each getting a second card
+    pub fn split(&mut self) -> Result<(), BlackjackError> {
+        let chips = self.chips;
+        let hands = self.hands.len();
+        let hand = self.active_hand_mut()?;
+        let pair = hand.cards.len() == 2 && card_value(hand.cards[0].rank) == card_value(hand.cards[1].rank);
+        if !pair || hands >= MAX_HANDS {
+            return Err(BlackjackError::CannotSplit);
+        }
+        if hand.bet > chips {
+            return Err(BlackjackError::NotEnoughChips);
+        }
+        let aces = hand.cards[0].rank == Rank::Ace;
+        let mut other = PlayerHand::new(hand.bet);
+        other.cards.push(hand.cards.pop().expect("A pair"));
+        other.split = true;
+        hand.split = true;
+        self.chips -= other.bet;
+        self.hands.insert(self.active + 1, other);
+
+        for index in [self.active, self.active + 1] {
+            let card = self.shoe.draw();
+            let hand = &mut self.hands[index];
+            hand.cards.push(card);
+            // Split aces get one card only
+            hand.done = aces || hand.total() == BLACKJACK;
+        }
+        if self.hands[self.active].done {
+            self.next_hand();
+        }
+        Ok(())
+    }
+
+    pub fn can_double(&self) -> bool {
+        self.active_hand().is_some_and(|hand| hand.cards.len() == 2 && hand.bet <= self.chips)
+    }
+
+    pub fn can_split(&self) -> bool {
+        self.active_hand().is_some_and(|hand| {
+            hand.cards.len() == 2
+                && card_value(hand.cards[0].rank) == card_value(hand.cards[1].rank)
+                && self.hands.len() < MAX_HANDS
+                && hand.bet <= self.chips
+        })
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn chips(&self) -> u64 {
+        self.chips
+    }
+
+    /// More chips for the player, e.g. after losing everything
+    pub fn add_chips(&mut self, chips: u64) {
+        self.chips += chips;
+    }
+
+    pub fn config(&self) -> &BlackjackConfig {
+        &self.config
+    }
+
+    pub fn hands(&self) -> &[PlayerHand] {
+        &self.hands
+    }
+
+    /// The index of the hand being played
+    pub fn active(&self) -> Option<usize> {
+        (self.phase == Phase::Playing).then_some(self.active)
+    }
+
+    /// All of the dealer's cards, the hole card included
+    pub fn dealer(&self) -> &[Card] {
+        &self.dealer
+    }
+
+    /// What the player sees of the dealer: only the first card until the round is over
+    pub fn dealer_visible(&self) -> &[Card] {
+        match self.phase {
+            Phase::Playing => &self.dealer[..1],
+            _ => &self.dealer,
+        }
+    }
+
+    /// One per hand, once the round is over
+    pub fn outcomes(&self) -> &[Outcome] {
+        &self.outcomes
+    }
+
+    pub fn shoe(&self) -> &Shoe {
+        &self.shoe
+    }
+
+    fn active_hand(&self) -> Option<&PlayerHand> {
+        self.active().and_then(|index| self.hands.get(index))
+    }
+
+    fn active_hand_mut(&mut self) -> Result<&mut PlayerHand, BlackjackError> {
+        let index = self.active().ok_or(BlackjackError::NoRound)?;
+        self.hands.get_mut(index).ok_or(BlackjackError::NoRound)
+    }
+
+    // Moves to the next hand still to play, or lets the dealer play once there are none
+    fn next_hand(&mut self) {
+        match self.hands.iter().position(|hand| !hand.done) {
+            Some(index) => self.active = index,
+            None => {
+                self.play_dealer();
+                self.settle();
+            }
+        }
+    }
+
+    fn play_dealer(&mut self) {
+        if self.hands.iter().all(|hand| hand.total() > BLACKJACK) {
+            return; // Everything busted, nothing to beat
+        }
+        loop {
+            let (total, soft) = hand_total(&self.dealer);
+            let hits = total < DEALER_STANDS || total == DEALER_STANDS && soft && !self.config.dealer_stands_on_soft_17;
+            if !hits {
+                break;
+            }
+            let card = self.shoe.draw();
+            self.dealer.push(card);
+        }
+    }
+
+    fn settle(&mut self) {
+        let dealer = hand_total(&self.dealer).0;
+        let dealer_blackjack = is_blackjack(&self.dealer);
+        self.outcomes = self
+            .hands
+            .iter()
+            .map(|hand| {
+                let total = hand.total();
+                match () {
+                    _ if total > BLACKJACK => Outcome::Lose,
+                    _ if hand.is_blackjack() && dealer_blackjack => Outcome::Push,
+                    _ if hand.is_blackjack() => Outcome::Blackjack,
+                    _ if dealer_blackjack || total < dealer && dealer <= BLACKJACK => Outcome::Lose,
+                    _ if dealer > BLACKJACK || total > dealer => Outcome::Win,
+                    _ => Outcome::Push,
+                }
+            })
+            .collect();
+        self.chips += self.hands.iter().zip(&self.outcomes).map(|(hand, outcome)| outcome.returned(hand.bet)).sum::<u64>();
+        self.phase = Phase::Finished;
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChipsFile {
+    chips: u64,
+}
+
+/// The player's chips, kept in a local JSON file: `{"chips": 1000}`
+#[derive(Debug)]
+pub struct ChipBank {
+    path: PathBuf,
+    pub chips: u64,
+}
+
+impl ChipBank {
+    /// A bank with `STARTING_CHIPS`, saved to `path`
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        ChipBank { path: path.as_ref().to_path_buf(), chips: STARTING_CHIPS }
+    }
+
+    /// The chips saved at `path`, or a fresh `STARTING_CHIPS` if there is no such file yet
+    pub fn load_or_new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut bank = Self::new(path);
+        match fs::read_to_string(&bank.path) {
+            Ok(contents) => bank.chips = serde_json::from_str::<ChipsFile>(&contents)?.chips,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        Ok(bank)
+    }
+
+    /// Like `load_or_new`, but a file that is not valid chips is renamed to
+    /// `<path>.bad` rather than left for the next save to overwrite; returns
+    /// where it went
+    pub fn load_or_set_aside(path: impl AsRef<Path>) -> io::Result<(Self, Option<PathBuf>)> {
+        match Self::load_or_new(&path) {
+            Ok(bank) => Ok((bank, None)),
+            // serde_json says UnexpectedEof for a truncated or empty file
+            Err(error) if matches!(error.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => {
+                let bank = Self::new(path);
+                let mut aside = bank.path.clone().into_os_string();
+                aside.push(".bad");
+                let aside = PathBuf::from(aside);
+                fs::rename(&bank.path, &aside)?;
+                Ok((bank, Some(aside)))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&ChipsFile { chips: self.chips })?;
+        fs::write(&self.path, json)
+    }
+}
+
+
+// ====== METHODS ======
+
+/// Aces count 11 here, `hand_total` makes them 1 when needed
+pub fn card_value(rank: Rank) -> u8 {
+    match rank {
+        Rank::Ace => 11,
+        Rank::Jack | Rank::Queen | Rank::King => 10,
+        rank => rank.value(),
+    }
+}
+
+/// The best total of a hand and whether it is soft (an ace still counting 11)
+pub fn hand_total(cards: &[Card]) -> (u8, bool) {
+    let mut total: u8 = cards.iter().map(|card| card_value(card.rank)).sum();
+    let mut soft_aces = cards.iter().filter(|card| card.rank == Rank::Ace).count();
+    while total > BLACKJACK && soft_aces > 0 {
+        total -= 10;
+        soft_aces -= 1;
+    }
+    (total, soft_aces > 0)
+}
+
+pub fn is_blackjack(cards: &[Card]) -> bool {
+    cards.len() == 2 && hand_total(cards).0 == BLACKJACK
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cards(names: &str) -> Vec<Card> {
+        names.split_whitespace().map(|name| name.parse().unwrap()).collect()
+    }
+
+    // Dealt in this order: player, dealer, player, dealer, then the draws
+    fn stacked_game(names: &str, config: BlackjackConfig) -> Blackjack {
+        Blackjack::with_shoe(config, STARTING_CHIPS, Shoe::stacked(cards(names), 1))
+    }
+
+    #[test]
+    fn test_hand_totals() {
+        assert_eq!(hand_total(&cards("ace_of_clubs 6_of_hearts")), (17, true));
+        assert_eq!(hand_total(&cards("ace_of_clubs 6_of_hearts king_of_spades")), (17, false));
+        assert_eq!(hand_total(&cards("ace_of_clubs ace_of_hearts 9_of_spades")), (21, true));
+        assert!(is_blackjack(&cards("ace_of_clubs queen_of_hearts")));
+        assert!(!is_blackjack(&cards("7_of_clubs 7_of_hearts 7_of_spades")));
+    }
+
+    #[test]
+    fn test_soft_17_rule() {
+        let deal = "10_of_clubs ace_of_clubs 7_of_clubs 6_of_clubs 5_of_diamonds 10_of_diamonds";
+
+        let mut stands = stacked_game(deal, BlackjackConfig::default());
+        stands.deal(100).unwrap();
+        assert_eq!(stands.dealer_visible().len(), 1);
+        stands.stand().unwrap();
+        assert_eq!(stands.dealer().len(), 2);
+        assert_eq!(stands.outcomes(), &[Outcome::Push]);
+        assert_eq!(stands.chips(), STARTING_CHIPS);
+
+        // Hitting the soft 17 makes a hard 12, then 22
+        let config = BlackjackConfig { dealer_stands_on_soft_17: false, ..Default::default() };
+        let mut hits = stacked_game(deal, config);
+        hits.deal(100).unwrap();
+        hits.stand().unwrap();
+        assert_eq!(hits.dealer().len(), 4);
+        assert_eq!(hits.outcomes(), &[Outcome::Win]);
+        assert_eq!(hits.chips(), STARTING_CHIPS + 100);
+        assert_eq!(hits.phase(), Phase::Finished);
+    }
+
+    #[test]
+    fn test_blackjack_pays_3_to_2() {
+        let mut game = stacked_game("ace_of_clubs 9_of_clubs king_of_clubs 7_of_clubs", BlackjackConfig::default());
+        game.deal(100).unwrap();
+        assert_eq!(game.phase(), Phase::Finished); // Nothing to play
+        assert_eq!(game.outcomes(), &[Outcome::Blackjack]);
+        assert_eq!(game.chips(), STARTING_CHIPS + 150);
+    }
+
+    #[test]
+    fn test_double_and_split() {
+        let mut game = stacked_game("5_of_clubs 10_of_clubs 6_of_clubs 7_of_clubs 10_of_hearts", BlackjackConfig::default());
+        game.deal(100).unwrap();
+        assert!(game.can_double() && !game.can_split());
+        assert_eq!(game.split(), Err(BlackjackError::CannotSplit));
+        game.double().unwrap();
+        assert_eq!(game.hands()[0].total(), 21);
+        assert_eq!(game.outcomes(), &[Outcome::Win]);
+        assert_eq!(game.chips(), STARTING_CHIPS + 200);
+
+        let mut game = stacked_game(
+            "8_of_clubs 10_of_clubs 8_of_diamonds 6_of_clubs 3_of_clubs 10_of_hearts 9_of_hearts 10_of_spades",
+            BlackjackConfig::default(),
+        );
+        game.deal(100).unwrap();
+        assert_eq!(game.deal(100), Err(BlackjackError::RoundInProgress));
+        game.split().unwrap();
+        assert_eq!(game.chips(), STARTING_CHIPS - 200);
+        assert_eq!(game.hands().iter().map(PlayerHand::total).collect::<Vec<_>>(), vec![11, 18]);
+        game.hit().unwrap();
+        assert_eq!(game.double(), Err(BlackjackError::CannotDouble)); // Three cards now
+        game.stand().unwrap();
+        assert_eq!(game.active(), Some(1));
+        game.stand().unwrap();
+
+        // The dealer's 16 draws a ten and busts
+        assert_eq!(game.dealer().len(), 3);
+        assert_eq!(game.outcomes(), &[Outcome::Win, Outcome::Win]);
+        assert_eq!(game.chips(), STARTING_CHIPS + 200);
+        let left = game.shoe().len();
+        assert_eq!(game.hit(), Err(BlackjackError::NoRound));
+        assert_eq!(game.shoe().len(), left); // Nothing drawn for nobody
+        assert_eq!(game.deal(STARTING_CHIPS * 2), Err(BlackjackError::NotEnoughChips));
+    }
+
+    #[test]
+    fn test_shoe_reshuffles_at_the_cut_card() {
+        let mut shoe = Shoe::new(2, 0.5, 3);
+        assert_eq!(shoe.len(), 104);
+        while !shoe.needs_shuffle() {
+            shoe.draw();
+        }
+        assert_eq!(shoe.len(), 52);
+
+        let mut game = Blackjack::new(BlackjackConfig { decks: 2, penetration: 0.5, ..Default::default() }, 100, 3);
+        game.shoe = shoe;
+        game.deal(10).unwrap();
+        assert!(game.shoe().len() > 52);
+    }
+
+    #[test]
+    fn test_chip_bank_save_and_load() {
+        let path = std::env::temp_dir().join(format!("ivan_game_chips_{}.json", std::process::id()));
+        let mut bank = ChipBank::load_or_new(&path).unwrap();
+        assert_eq!(bank.chips, STARTING_CHIPS);
+        bank.chips = 1234;
+        bank.save().unwrap();
+
+        let loaded = ChipBank::load_or_new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.chips, 1234);
+    }
+
+    #[test]
+    fn test_corrupt_chip_bank_is_set_aside() {
+        let path = std::env::temp_dir().join(format!("ivan_game_corrupt_chips_{}.json", std::process::id()));
+        fs::write(&path, "{\"chips\": 12").unwrap();
+        assert!(ChipBank::load_or_new(&path).is_err());
+
+        let (bank, aside) = ChipBank::load_or_set_aside(&path).unwrap();
+        let aside = aside.unwrap();
+        assert_eq!(bank.chips, STARTING_CHIPS);
+        assert!(!path.exists());
+        bank.save().unwrap();
+
+        // The next save doesn't touch what was there
+        let kept = fs::read_to_string(&aside).unwrap();
+        fs::remove_file(&aside).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(kept, "{\"chips\": 12");
+
+        let (bank, aside) = ChipBank::load_or_set_aside(&path).unwrap();
+        assert_eq!((bank.chips, aside), (STARTING_CHIPS, None)); // No file at all is not corrupt
+    }
+}
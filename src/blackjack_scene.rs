@@ -0,0 +1,415 @@
+// Scena del blackjack: disegna la partita di blackjack.rs e la gioca con tastiera e pulsanti.
+//
+// Cards are dealt from the shoe in the top right with a `CardTween`, the
+// dealer's hole card is turned over with a `FlipCard` when the round ends.
+// Keys: Space or Enter deal, H hit, S stand, D double, X split, Up and Down
+// change the bet; the buttons at the bottom do the same. The chips are loaded
+// from `DEFAULT_CHIPS_FILE` when the scene starts and saved after every round
+// and when leaving; a player who lost everything starts again from
+// `STARTING_CHIPS`. A chips file that can't be read is renamed, never
+// overwritten.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use rand::Rng;
+
+use crate::app_state::AppState;
+use crate::blackjack::{hand_total, Blackjack, BlackjackConfig, ChipBank, Phase, DEFAULT_CHIPS_FILE, STARTING_CHIPS};
+use crate::buttons::{labeled_button_system, spawn_button};
+use crate::card_animation::{CardFaces, CardTween, FlipCard};
+use crate::cards::{Card, CardHandles};
+use crate::game_rng::{GameRng, RngStream};
+
+
+// ====== CONSTANTS ======
+
+const CARD_SCALE: f32 = 0.45;
+const SHOE_POSITION: Vec3 = Vec3::new(480., 260., 1.);
+const DEALER_Y: f32 = 170.;
+const PLAYER_Y: f32 = -80.;
+const CARD_SPACING: f32 = 32.; // Between two cards of a hand
+const HAND_SPACING: f32 = 260.; // Between two split hands
+const CARD_Z: f32 = 1.;
+const DEAL_STAGGER: Duration = Duration::from_millis(150);
+const MOVE_DURATION: Duration = Duration::from_millis(250);
+const BET_STEP: u64 = 10;
+const PLAYING_TEXT_COLOR: Color = Color::srgb(1., 0.85, 0.2); // The status while a round is on
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Component)]
+pub struct BlackjackEntity;
+
+/// Where a card sprite is on the table: the dealer's hand or one of the player's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component)]
+struct TableSlot {
+    hand: Option<usize>, // None for the dealer
+    index: usize,
+}
+
+#[derive(Debug, Component)]
+struct StatusText;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component)]
+enum BlackjackButton {
+    Deal,
+    Hit,
+    Stand,
+    Double,
+    Split,
+    BetDown,
+    BetUp,
+}
+
+/// The rules the scene deals with
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct BlackjackRules(pub BlackjackConfig);
+
+#[derive(Debug, Resource)]
+pub struct BlackjackTable {
+    pub game: Blackjack,
+    pub bet: u64,
+    bank: Option<ChipBank>, // None when the chips file could be neither read nor moved: it is left alone
+    message: String,
+}
+
+impl BlackjackTable {
+    // The bets still on the table go back to the player when leaving mid round
+    fn save_chips(&mut self) {
+        let on_table: u64 = match self.game.phase() {
+            Phase::Playing => self.game.hands().iter().map(|hand| hand.bet).sum(),
+            _ => 0,
+        };
+        let Some(bank) = &mut self.bank else {
+            return;
+        };
+        bank.chips = self.game.chips() + on_table;
+        if let Err(error) = bank.save() {
+            warn!("Could not save the chips: {}", error);
+        }
+    }
+
+    // A player who lost everything starts again, and nobody bets more than they have left
+    fn refill(&mut self) -> bool {
+        let broke = self.game.chips() == 0;
+        if broke {
+            self.game.add_chips(STARTING_CHIPS);
+        }
+        self.bet = self.bet.min(self.game.chips());
+        broke
+    }
+}
+
+#[derive(Default)]
+pub struct BlackjackPlugin {
+    pub config: BlackjackConfig,
+}
+
+impl BlackjackPlugin {
+    pub fn new(config: BlackjackConfig) -> Self {
+        BlackjackPlugin { config }
+    }
+}
+
+impl Plugin for BlackjackPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BlackjackRules(self.config))
+            .add_systems(OnEnter(AppState::Blackjack), setup_blackjack)
+            .add_systems(OnExit(AppState::Blackjack), cleanup_blackjack)
+            .add_systems(
+                Update,
+                (labeled_button_system, blackjack_input, lay_out_cards, update_status_text)
+                    .chain()
+                    .run_if(in_state(AppState::Blackjack)),
+            );
+    }
+}
+
+
+// ====== METHODS ======
+
+fn setup_blackjack(mut commands: Commands, rules: Res<BlackjackRules>, mut rng: ResMut<GameRng>) {
+    let bank = match ChipBank::load_or_set_aside(DEFAULT_CHIPS_FILE) {
+        Ok((bank, Some(aside))) => {
+            warn!("The chips file was not valid, moved to {}", aside.display());
+            Some(bank)
+        }
+        Ok((bank, None)) => Some(bank),
+        Err(error) => {
+            warn!("Could not load the chips, playing with {} that won't be saved: {}", STARTING_CHIPS, error);
+            None
+        }
+    };
+    let chips = bank.as_ref().map_or(STARTING_CHIPS, |bank| bank.chips);
+    let game = Blackjack::new(rules.0, chips, rng.stream(RngStream::Blackjack).gen());
+    let mut table = BlackjackTable { game, bet: BET_STEP, bank, message: "Space to deal".to_string() };
+    if table.refill() {
+        table.message = format!("Out of chips, here are {} more. Space to deal", STARTING_CHIPS);
+    }
+    commands.insert_resource(table);
+
+    commands.spawn((
+        TextBundle::from_section("", TextStyle { font_size: 28., ..default() })
+            .with_style(Style { position_type: PositionType::Absolute, top: Val::Px(12.), left: Val::Px(12.), ..default() }),
+        StatusText,
+        BlackjackEntity,
+    ));
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.),
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(20.),
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            BlackjackEntity,
+        ))
+        .with_children(|parent| {
+            for (label, button) in [
+                ("Deal", BlackjackButton::Deal),
+                ("Hit", BlackjackButton::Hit),
+                ("Stand", BlackjackButton::Stand),
+                ("Double", BlackjackButton::Double),
+                ("Split", BlackjackButton::Split),
+                ("Bet -", BlackjackButton::BetDown),
+                ("Bet +", BlackjackButton::BetUp),
+            ] {
+                spawn_button(parent, label, button);
+            }
+        });
+}
+
+fn cleanup_blackjack(mut commands: Commands, query: Query<Entity, With<BlackjackEntity>>, table: Option<ResMut<BlackjackTable>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    if let Some(mut table) = table {
+        table.save_chips();
+    }
+    commands.remove_resource::<BlackjackTable>();
+}
+
+fn blackjack_input(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    buttons: Query<(&Interaction, &BlackjackButton), Changed<Interaction>>,
+    mut table: ResMut<BlackjackTable>,
+    cards: Query<Entity, With<TableSlot>>,
+) {
+    let pressed = buttons.iter().filter(|(interaction, _)| **interaction == Interaction::Pressed).map(|(_, button)| *button);
+    let typed = [
+        (KeyCode::Space, BlackjackButton::Deal),
+        (KeyCode::Enter, BlackjackButton::Deal),
+        (KeyCode::KeyH, BlackjackButton::Hit),
+        (KeyCode::KeyS, BlackjackButton::Stand),
+        (KeyCode::KeyD, BlackjackButton::Double),
+        (KeyCode::KeyX, BlackjackButton::Split),
+        (KeyCode::ArrowDown, BlackjackButton::BetDown),
+        (KeyCode::ArrowUp, BlackjackButton::BetUp),
+    ]
+    .into_iter()
+    .filter(|(key, _)| keys.just_pressed(*key))
+    .map(|(_, button)| button);
+    let actions: Vec<BlackjackButton> = pressed.chain(typed).collect();
+
+    for action in actions {
+        let table = &mut *table;
+        let result = match action {
+            BlackjackButton::Deal => {
+                let dealt = table.game.deal(table.bet);
+                if dealt.is_ok() {
+                    for entity in &cards {
+                        commands.entity(entity).despawn_recursive(); // Clear the last round
+                    }
+                }
+                dealt
+            }
+            BlackjackButton::Hit => table.game.hit(),
+            BlackjackButton::Stand => table.game.stand(),
+            BlackjackButton::Double => table.game.double(),
+            BlackjackButton::Split => table.game.split(),
+            BlackjackButton::BetDown => {
+                table.bet = table.bet.saturating_sub(BET_STEP).max(BET_STEP);
+                Ok(())
+            }
+            BlackjackButton::BetUp => {
+                table.bet = (table.bet + BET_STEP).min(table.game.chips().max(BET_STEP));
+                Ok(())
+            }
+        };
+        table.message = match result {
+            Err(error) => error.to_string(),
+            Ok(()) if table.game.phase() == Phase::Finished => {
+                let outcomes: Vec<String> = table.game.outcomes().iter().map(ToString::to_string).collect();
+                let refilled = table.refill();
+                table.save_chips();
+                if refilled {
+                    format!("{}. Out of chips, here are {} more. Space to deal again", outcomes.join(", "), STARTING_CHIPS)
+                } else {
+                    format!("{}. Space to deal again", outcomes.join(", "))
+                }
+            }
+            Ok(()) => String::new(),
+        };
+    }
+}
+
+/// Where the card at `slot` goes: the dealer's hand on top, the player's hands side by side below
+fn slot_transform(hands: usize, slot: TableSlot) -> Transform {
+    let (x, y) = match slot.hand {
+        None => (0., DEALER_Y),
+        Some(hand) => ((hand as f32 - (hands as f32 - 1.) / 2.) * HAND_SPACING, PLAYER_Y),
+    };
+    let position = Vec3::new(x + slot.index as f32 * CARD_SPACING, y, CARD_Z + slot.index as f32 * 0.01);
+    Transform::from_translation(position).with_scale(Vec3::splat(CARD_SCALE))
+}
+
+// Keeps a sprite for every card on the table: new cards are dealt from the
+// shoe, cards moved by a split slide over and the hole card is turned over
+fn lay_out_cards(
+    mut commands: Commands,
+    table: Res<BlackjackTable>,
+    card_handles: Res<CardHandles>,
+    mut sprites: Query<(Entity, &Card, &mut TableSlot, &CardFaces)>,
+) {
+    if !table.is_changed() {
+        return;
+    }
+    let game = &table.game;
+    let dealer = game.dealer().iter().enumerate().map(|(index, card)| {
+        (TableSlot { hand: None, index }, *card, index < game.dealer_visible().len())
+    });
+    let hands = game.hands().iter().enumerate().flat_map(|(hand, cards)| {
+        cards.cards.iter().enumerate().map(move |(index, card)| (TableSlot { hand: Some(hand), index }, *card, true))
+    });
+    let expected: Vec<(TableSlot, Card, bool)> = dealer.chain(hands).collect();
+
+    let mut unused: Vec<Entity> = sprites.iter().map(|(entity, ..)| entity).collect();
+    let mut dealt = 0;
+    for (slot, card, face_up) in expected {
+        let target = slot_transform(game.hands().len(), slot);
+        let existing = unused.iter().position(|entity| sprites.get(*entity).is_ok_and(|(_, shown, ..)| *shown == card));
+        if let Some(position) = existing {
+            let entity = unused.swap_remove(position);
+            let (_, _, mut current, faces) = sprites.get_mut(entity).expect("Still there");
+            if *current != slot {
+                *current = slot;
+                commands.entity(entity).insert(CardTween::new(target, MOVE_DURATION));
+            }
+            if faces.face_up != face_up {
+                commands.entity(entity).insert(FlipCard::default());
+            }
+            continue;
+        }
+
+        let (Ok(front), Ok(back)) = (card_handles.get(&card), card_handles.back()) else {
+            error!("No image for the card {}", card);
+            continue;
+        };
+        let faces = CardFaces { front, back, face_up };
+        commands.spawn((
+            SpriteBundle {
+                texture: faces.shown(),
+                transform: Transform::from_translation(SHOE_POSITION).with_scale(Vec3::splat(CARD_SCALE)),
+                ..default()
+            },
+            CardTween::deal(target).with_delay(DEAL_STAGGER * dealt),
+            faces,
+            card,
+            slot,
+            BlackjackEntity,
+        ));
+        dealt += 1;
+    }
+    for entity in unused {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn update_status_text(table: Res<BlackjackTable>, rules: Res<BlackjackRules>, mut text: Query<&mut Text, With<StatusText>>) {
+    if !table.is_changed() {
+        return;
+    }
+    let game = &table.game;
+    let mut lines = vec![format!(
+        "Chips: {}   Bet: {}   Dealer {} on soft 17",
+        game.chips(),
+        table.bet,
+        if rules.0.dealer_stands_on_soft_17 { "stands" } else { "hits" }
+    )];
+    if !game.dealer().is_empty() {
+        lines.push(format!("Dealer: {}", hand_total(game.dealer_visible()).0));
+    }
+    for (index, hand) in game.hands().iter().enumerate() {
+        let marker = if game.active() == Some(index) { "> " } else { "" };
+        let outcome = game.outcomes().get(index).map(|outcome| format!(" - {}", outcome)).unwrap_or_default();
+        lines.push(format!("{}Hand {}: {} (bet {}){}", marker, index + 1, hand.total(), hand.bet, outcome));
+    }
+    if game.phase() == Phase::Playing {
+        let mut moves = vec!["H hit", "S stand"];
+        if game.can_double() {
+            moves.push("D double");
+        }
+        if game.can_split() {
+            moves.push("X split");
+        }
+        lines.push(moves.join(", "));
+    }
+    if !table.message.is_empty() {
+        lines.push(table.message.clone());
+    }
+
+    for mut text in &mut text {
+        text.sections[0].value = lines.join("\n");
+        text.sections[0].style.color = if game.phase() == Phase::Playing { PLAYING_TEXT_COLOR } else { Color::WHITE };
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blackjack::Shoe;
+
+    #[test]
+    fn test_split_hands_sit_side_by_side() {
+        let dealer = slot_transform(1, TableSlot { hand: None, index: 1 });
+        assert_eq!(dealer.translation.truncate(), Vec2::new(CARD_SPACING, DEALER_Y));
+
+        let left = slot_transform(2, TableSlot { hand: Some(0), index: 0 });
+        let right = slot_transform(2, TableSlot { hand: Some(1), index: 0 });
+        assert_eq!(left.translation.x, -right.translation.x);
+        assert_eq!(right.translation.x - left.translation.x, HAND_SPACING);
+        assert!(slot_transform(1, TableSlot { hand: Some(0), index: 2 }).translation.z > left.translation.z);
+    }
+
+    #[test]
+    fn test_refill_and_bet_after_a_loss() {
+        // Two rounds of 17 against the dealer's 19
+        let cards = "10_of_clubs 10_of_hearts 7_of_clubs 9_of_hearts 10_of_spades 10_of_diamonds 7_of_spades 9_of_diamonds";
+        let shoe = Shoe::stacked(cards.split_whitespace().map(|name| name.parse().unwrap()).collect(), 1);
+        let game = Blackjack::with_shoe(BlackjackConfig::default(), 100, shoe);
+        let mut table = BlackjackTable { game, bet: 60, bank: None, message: String::new() };
+
+        table.game.deal(table.bet).unwrap();
+        table.game.stand().unwrap();
+        assert!(!table.refill());
+        assert_eq!(table.bet, 40); // Only what is left
+
+        table.game.deal(table.bet).unwrap();
+        table.game.stand().unwrap();
+        assert!(table.refill());
+        assert_eq!(table.game.chips(), STARTING_CHIPS);
+        assert_eq!(table.bet, 40);
+    }
+}
@@ -3,13 +3,17 @@ use bevy::prelude::*;
 use bevy::color::palettes::basic::*;
 use bevy::transform::commands;
 
-const NORMAL_BUTTON: Color = Color::srgb(0.15, 0.15, 0.15);
-const HOVERED_BUTTON: Color = Color::srgb(0.25, 0.25, 0.25);
-const PRESSED_BUTTON: Color = Color::srgb(0.35, 0.75, 0.35);
+pub const NORMAL_BUTTON: Color = Color::srgb(0.15, 0.15, 0.15);
+pub const HOVERED_BUTTON: Color = Color::srgb(0.25, 0.25, 0.25);
+pub const PRESSED_BUTTON: Color = Color::srgb(0.35, 0.75, 0.35);
 
 #[derive(Debug, Component)]
 pub struct Scene3Bundle;
 
+/// A button made by `spawn_button`, its label stays the same when pressed
+#[derive(Debug, Component)]
+pub struct LabeledButton;
+
 
 pub fn button_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
     commands.spawn(
@@ -56,6 +60,54 @@ pub fn button_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
         });
 }
 
+/// A button looking like the one of `button_setup`, with `label` on it and `marker` on the button
+pub fn spawn_button(parent: &mut ChildBuilder, label: &str, marker: impl Bundle) {
+    parent
+        .spawn((ButtonBundle {
+            style: Style {
+                width: Val::Px(150.0),
+                height: Val::Px(65.0),
+                border: UiRect::all(Val::Px(5.0)),
+                margin: UiRect::horizontal(Val::Px(5.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            border_color: BorderColor(Color::BLACK),
+            border_radius: BorderRadius::MAX,
+            background_color: NORMAL_BUTTON.into(),
+            ..default()
+        }, LabeledButton, marker))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                label,
+                TextStyle {
+                    font: Default::default(),
+                    font_size: 30.0,
+                    color: Color::srgb(0.9, 0.9, 0.9),
+                },
+            ));
+        });
+}
+
+// What a button shows of its state
+type ButtonColors<'a> = (&'a Interaction, &'a mut BackgroundColor, &'a mut BorderColor);
+
+/// The colors of `button_system`, for the buttons of `spawn_button`
+pub fn labeled_button_system(
+    mut interaction_query: Query<ButtonColors, (Changed<Interaction>, With<LabeledButton>)>,
+) {
+    for (interaction, mut color, mut border_color) in &mut interaction_query {
+        let (background, border) = match *interaction {
+            Interaction::Pressed => (PRESSED_BUTTON, RED.into()),
+            Interaction::Hovered => (HOVERED_BUTTON, Color::WHITE),
+            Interaction::None => (NORMAL_BUTTON, Color::BLACK),
+        };
+        *color = background.into();
+        border_color.0 = border;
+    }
+}
+
 pub fn button_system(
     mut interaction_query: Query<
         (
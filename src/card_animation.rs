@@ -0,0 +1,295 @@
+// Animazioni delle carte: girarle, distribuirle, aprirle a ventaglio e impilarle.
+//
+// Everything is a component with its own timer, so any scene can animate its
+// card sprites by inserting one: `FlipCard` squashes the sprite to nothing on
+// the x axis, swaps face and back and grows it again, `CardTween` moves it to
+// a target along a curve (dealing is a tween from the deck). `fan_layout` and
+// `stack_layout` only compute where the cards of a hand or a pile go, to be
+// used as tween targets. The component is removed when its animation ends and
+// a `CardAnimationFinished` event is sent.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+
+// ====== CONSTANTS ======
+
+pub const FLIP_DURATION: Duration = Duration::from_millis(300);
+pub const DEAL_DURATION: Duration = Duration::from_millis(450);
+pub const DEAL_ARC: f32 = 80.; // How far the dealt cards curve from the straight line
+pub const STACK_OFFSET: Vec2 = Vec2::new(0.6, 1.2); // Between two cards of a pile, enough to see its thickness
+const TWEEN_Z_LIFT: f32 = 100.; // Moving cards fly over the resting ones
+
+
+// ====== STRUCTS ======
+
+/// The two images of a card sprite, the one shown depends on `face_up`
+#[derive(Debug, Clone, Component)]
+pub struct CardFaces {
+    pub front: Handle<Image>,
+    pub back: Handle<Image>,
+    pub face_up: bool,
+}
+
+impl CardFaces {
+    pub fn shown(&self) -> Handle<Image> {
+        if self.face_up { self.front.clone() } else { self.back.clone() }
+    }
+}
+
+/// Turns the card over, needs `CardFaces` on the same entity
+#[derive(Debug, Clone, Component)]
+pub struct FlipCard {
+    timer: Timer,
+    scale_x: Option<f32>, // The width before the flip, taken on the first frame
+    swapped: bool,
+}
+
+impl FlipCard {
+    pub fn new(duration: Duration) -> Self {
+        FlipCard { timer: Timer::new(duration, TimerMode::Once), scale_x: None, swapped: false }
+    }
+}
+
+impl Default for FlipCard {
+    fn default() -> Self {
+        Self::new(FLIP_DURATION)
+    }
+}
+
+/// Moves the card from where it is to `target`, bending the path by `arc`
+/// (positive to the left of the direction of travel)
+#[derive(Debug, Clone, Component)]
+pub struct CardTween {
+    pub target: Transform,
+    pub arc: f32,
+    start: Option<Transform>,
+    delay: Duration,
+    timer: Timer,
+}
+
+impl CardTween {
+    pub fn new(target: Transform, duration: Duration) -> Self {
+        CardTween { target, arc: 0., start: None, delay: Duration::ZERO, timer: Timer::new(duration, TimerMode::Once) }
+    }
+
+    /// As a dealer does: quick and curved
+    pub fn deal(target: Transform) -> Self {
+        Self::new(target, DEAL_DURATION).with_arc(DEAL_ARC)
+    }
+
+    pub fn with_arc(mut self, arc: f32) -> Self {
+        self.arc = arc;
+        self
+    }
+
+    /// Waits before moving, e.g. to deal a hand one card after the other
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.timer.set_duration(self.timer.duration() + delay);
+        self.delay = delay;
+        self
+    }
+
+    /// From 0 while waiting to 1 when arrived
+    fn progress(&self) -> f32 {
+        let moving = self.timer.duration().saturating_sub(self.delay);
+        if moving.is_zero() {
+            return 1.;
+        }
+        let elapsed = self.timer.elapsed().saturating_sub(self.delay);
+        (elapsed.as_secs_f32() / moving.as_secs_f32()).min(1.)
+    }
+
+    /// Where the card is at `progress` of the way from `start`
+    pub fn transform_at(&self, start: &Transform, progress: f32) -> Transform {
+        let t = ease_in_out(progress);
+        let from = start.translation.truncate();
+        let to = self.target.translation.truncate();
+        let normal = (to - from).perp().normalize_or_zero();
+        let control = from.lerp(to, 0.5) + normal * self.arc * 2.; // The curve gets halfway to the control point
+        let position = from.lerp(control, t).lerp(control.lerp(to, t), t);
+
+        let z = match progress {
+            p if p <= 0. => start.translation.z,
+            p if p >= 1. => self.target.translation.z,
+            _ => self.target.translation.z.max(start.translation.z) + TWEEN_Z_LIFT,
+        };
+        Transform {
+            translation: position.extend(z),
+            rotation: start.rotation.slerp(self.target.rotation, t),
+            scale: start.scale.lerp(self.target.scale, t),
+        }
+    }
+}
+
+/// Sent when a `FlipCard` or a `CardTween` is done and removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Event)]
+pub struct CardAnimationFinished(pub Entity);
+
+pub struct CardAnimationPlugin;
+
+impl Plugin for CardAnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<CardAnimationFinished>()
+            .add_systems(Update, (animate_card_tweens, animate_card_flips).chain());
+    }
+}
+
+
+// ====== METHODS ======
+
+fn ease_in_out(t: f32) -> f32 {
+    let t = t.clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+// The width of a flipping card: down to 0 halfway, then back
+fn flip_scale(progress: f32) -> f32 {
+    (1. - 2. * progress.clamp(0., 1.)).abs()
+}
+
+/// Where the `count` cards of a hand go when fanned around `center`: on an arc
+/// of `radius` spread over `spread` radians, each one tilted along it and a
+/// little above the one before
+pub fn fan_layout(center: Vec3, count: usize, radius: f32, spread: f32) -> Vec<Transform> {
+    let step = if count > 1 { spread / (count - 1) as f32 } else { 0. };
+    (0..count)
+        .map(|index| {
+            let angle = step * (index as f32 - (count - 1) as f32 / 2.); // From the left, 0 is straight up
+            let pivot = center - Vec3::new(0., radius, 0.);
+            let position = pivot + Vec3::new(radius * angle.sin(), radius * angle.cos(), index as f32 * 0.01);
+            Transform::from_translation(position).with_rotation(Quat::from_rotation_z(-angle))
+        })
+        .collect()
+}
+
+/// Where the `count` cards of a pile go: each one `offset` from the one below
+pub fn stack_layout(base: Vec3, count: usize, offset: Vec2) -> Vec<Transform> {
+    (0..count)
+        .map(|index| Transform::from_translation(base + (offset * index as f32).extend(index as f32 * 0.01)))
+        .collect()
+}
+
+/// A fan as wide as the usual hand: about 60 degrees
+pub fn default_fan_spread(count: usize) -> f32 {
+    (count as f32 * PI / 36.).min(PI / 3.)
+}
+
+fn animate_card_tweens(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut finished: EventWriter<CardAnimationFinished>,
+    mut query: Query<(Entity, &mut CardTween, &mut Transform)>,
+) {
+    for (entity, mut tween, mut transform) in &mut query {
+        let start = *tween.start.get_or_insert(*transform);
+        tween.timer.tick(time.delta());
+        *transform = tween.transform_at(&start, tween.progress());
+        if tween.timer.finished() {
+            commands.entity(entity).remove::<CardTween>();
+            finished.send(CardAnimationFinished(entity));
+        }
+    }
+}
+
+fn animate_card_flips(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut finished: EventWriter<CardAnimationFinished>,
+    mut query: Query<(Entity, &mut FlipCard, &mut CardFaces, &mut Transform, &mut Handle<Image>)>,
+) {
+    for (entity, mut flip, mut faces, mut transform, mut texture) in &mut query {
+        let scale_x = *flip.scale_x.get_or_insert(transform.scale.x);
+        flip.timer.tick(time.delta());
+        let progress = flip.timer.fraction();
+
+        // Edge on, the other side shows up
+        if progress >= 0.5 && !flip.swapped {
+            flip.swapped = true;
+            faces.face_up = !faces.face_up;
+            *texture = faces.shown();
+        }
+        transform.scale.x = scale_x * flip_scale(progress);
+
+        if flip.timer.finished() {
+            transform.scale.x = scale_x;
+            commands.entity(entity).remove::<FlipCard>();
+            finished.send(CardAnimationFinished(entity));
+        }
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::time::TimeUpdateStrategy;
+
+    #[test]
+    fn test_tween_path() {
+        let start = Transform::from_xyz(0., 0., 1.);
+        let tween = CardTween::deal(Transform::from_xyz(100., 0., 2.));
+        assert_eq!(tween.transform_at(&start, 0.), start);
+        assert_eq!(tween.transform_at(&start, 1.).translation, Vec3::new(100., 0., 2.));
+
+        // Halfway it is off the straight line by the arc, and above everything
+        let halfway = tween.transform_at(&start, 0.5).translation;
+        assert!((halfway.x - 50.).abs() < 0.001);
+        assert!((halfway.y - DEAL_ARC).abs() < 0.001);
+        assert!(halfway.z > 100.);
+
+        let waiting = CardTween::new(Transform::IDENTITY, Duration::from_secs(1)).with_delay(Duration::from_secs(1));
+        assert_eq!(waiting.progress(), 0.);
+    }
+
+    #[test]
+    fn test_layouts() {
+        let fan = fan_layout(Vec3::ZERO, 5, 300., 1.);
+        assert_eq!(fan.len(), 5);
+        assert!(fan[2].translation.truncate().distance(Vec2::ZERO) < 0.001); // The middle card is on the center
+        assert!((fan[0].translation.x + fan[4].translation.x).abs() < 0.001);
+        assert!(fan[0].translation.y < 0. && fan[0].rotation.to_euler(EulerRot::XYZ).2 > 0.); // Lower and tilted left
+        assert!(fan.windows(2).all(|pair| pair[0].translation.z < pair[1].translation.z));
+        assert_eq!(fan_layout(Vec3::ONE, 1, 300., 1.)[0].translation, Vec3::ONE);
+
+        let stack = stack_layout(Vec3::new(10., 10., 0.), 3, STACK_OFFSET);
+        assert_eq!(stack[2].translation.truncate(), Vec2::new(10., 10.) + STACK_OFFSET * 2.);
+    }
+
+    #[test]
+    fn test_flip_swaps_the_face() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugins(CardAnimationPlugin)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(50)));
+
+        let front = Handle::weak_from_u128(1);
+        let back = Handle::weak_from_u128(2);
+        let faces = CardFaces { front: front.clone(), back: back.clone(), face_up: false };
+        let card = app.world_mut().spawn((faces, FlipCard::default(), Transform::from_scale(Vec3::splat(0.5)), back.clone())).id();
+
+        app.update(); // The first frame has no time passing
+        app.update();
+        app.update();
+        let scale = app.world().get::<Transform>(card).unwrap().scale;
+        assert!(scale.x < 0.5 && scale.y == 0.5);
+
+        for _ in 0..10 {
+            if app.world().get::<FlipCard>(card).is_none() {
+                break;
+            }
+            app.update();
+        }
+        assert!(app.world().get::<FlipCard>(card).is_none());
+        assert!(app.world().get::<CardFaces>(card).unwrap().face_up);
+        assert_eq!(app.world().get::<Handle<Image>>(card), Some(&front));
+        assert_eq!(app.world().get::<Transform>(card).unwrap().scale.x, 0.5);
+        assert_eq!(app.world().resource::<Events<CardAnimationFinished>>().len(), 1);
+    }
+}
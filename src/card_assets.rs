@@ -0,0 +1,200 @@
+// Manifesto delle immagini delle carte: quali file ci sono e dove, senza leggere le cartelle.
+//
+// The card pack under `assets/playing-cards-assets-master` is described here
+// once, so `CardHandles` is built from the typed cards (`Deck::with_jokers`
+// and the back) the same way on every platform, wasm included, where the
+// asset folder can't be listed. Each face can be drawn in one of the `CardArt`
+// styles (the svg ones drawn by card_svg.rs); when the pack has no such file for a face (only the back has `@2x`
+// art, only jacks, queens and kings have a `simple` svg) the next best one is
+// used. Faces that fail to load are logged and left out of `CardHandles`, so
+// asking for them gives a `MissingCardFace` error.
+
+use std::fmt;
+
+use bevy::asset::AssetLoadFailedEvent;
+use bevy::prelude::*;
+
+use crate::card_svg::{CardRasterizer, CardSvgPlugin, SvgSource};
+use crate::cards::{CardFace, CardHandles, Deck, CARD_BACK};
+
+
+// ====== CONSTANTS ======
+
+/// The pack in the assets folder
+pub const PLAYING_CARDS: CardManifest = CardManifest {
+    folder: "playing-cards-assets-master",
+    png: "png",
+    svg: "svg-cards",
+    svg_simple: "svg-cards/simple",
+    hidpi: &[CARD_BACK],
+    simple: &[
+        "jack_of_clubs", "jack_of_diamonds", "jack_of_hearts", "jack_of_spades",
+        "queen_of_clubs", "queen_of_diamonds", "queen_of_hearts", "queen_of_spades",
+        "king_of_clubs", "king_of_diamonds", "king_of_hearts", "king_of_spades",
+    ],
+    svg_back: false,
+};
+
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Reflect)]
+pub enum CardArt {
+    #[default]
+    Png,
+    /// Double resolution png, the plain one where there is none
+    Png2x,
+    Svg,
+    /// Less detailed court cards, the full svg for the others
+    SvgSimple,
+}
+
+impl fmt::Display for CardArt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CardArt::Png => "png",
+            CardArt::Png2x => "@2x png",
+            CardArt::Svg => "svg",
+            CardArt::SvgSimple => "simple svg",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Where a card pack keeps its images (relative to the assets folder) and
+/// which faces have the optional variants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardManifest {
+    pub folder: &'static str,
+    pub png: &'static str,
+    pub svg: &'static str,
+    pub svg_simple: &'static str,
+    pub hidpi: &'static [&'static str],
+    pub simple: &'static [&'static str],
+    pub svg_back: bool,
+}
+
+impl CardManifest {
+    /// The names of every face in the pack: the 54 cards of `Deck::with_jokers` and the back
+    pub fn faces(&self) -> Vec<String> {
+        let cards = Deck::with_jokers().cards().iter().map(CardFace::asset_name).collect::<Vec<_>>();
+        cards.into_iter().chain([CARD_BACK.to_string()]).collect()
+    }
+
+    /// The asset path of a face in the given art, or of its closest variant
+    pub fn path(&self, face: &str, art: CardArt) -> String {
+        match art {
+            CardArt::Png => format!("{}/{}/{}.png", self.folder, self.png, face),
+            CardArt::Png2x if self.hidpi.contains(&face) => format!("{}/{}/{}@2x.png", self.folder, self.png, face),
+            CardArt::Png2x => self.path(face, CardArt::Png),
+            CardArt::Svg | CardArt::SvgSimple if face == CARD_BACK && !self.svg_back => self.path(face, CardArt::Png),
+            CardArt::SvgSimple if self.simple.contains(&face) => format!("{}/{}/{}.svg", self.folder, self.svg_simple, face),
+            CardArt::Svg | CardArt::SvgSimple => format!("{}/{}/{}.svg", self.folder, self.svg, face),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingCardFace {
+    pub face: String,
+    pub art: CardArt,
+}
+
+impl fmt::Display for MissingCardFace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No {} image for the card face `{}`", self.art, self.face)
+    }
+}
+
+impl std::error::Error for MissingCardFace {}
+
+pub struct CardAssetsPlugin;
+
+impl Plugin for CardAssetsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(CardSvgPlugin)
+            .add_systems(Startup, load_card_faces)
+            .add_systems(Update, report_missing_card_faces);
+    }
+}
+
+
+// ====== METHODS ======
+
+/// Starts loading every face of the pack in the art chosen in `CardHandles`;
+/// the svg ones get an empty image the `CardRasterizer` draws into
+pub fn load_card_faces(
+    asset_server: Res<AssetServer>,
+    images: Res<Assets<Image>>,
+    mut card_handles: ResMut<CardHandles>,
+    mut rasterizer: ResMut<CardRasterizer>,
+) {
+    let art = card_handles.art;
+    for face in PLAYING_CARDS.faces() {
+        let path = PLAYING_CARDS.path(&face, art);
+        let handle: Handle<Image> = if path.ends_with(".svg") {
+            let image = images.reserve_handle();
+            rasterizer.faces.insert(image.id(), (face.clone(), asset_server.load(path)));
+            image
+        } else {
+            asset_server.load(path)
+        };
+        card_handles.cards_map.insert(face, handle);
+    }
+}
+
+// A face that doesn't load is dropped, so asking for it is an error instead of an invisible sprite
+fn report_missing_card_faces(
+    mut failed_images: EventReader<AssetLoadFailedEvent<Image>>,
+    mut failed_svgs: EventReader<AssetLoadFailedEvent<SvgSource>>,
+    mut card_handles: ResMut<CardHandles>,
+    mut rasterizer: ResMut<CardRasterizer>,
+) {
+    let failed_images = failed_images.read().map(|event| (event.id, event.path.clone(), event.error.to_string()));
+    let failed_svgs = failed_svgs.read().filter_map(|event| {
+        let image = rasterizer.faces.iter().find(|(_, (_, source))| source.id() == event.id).map(|(image, _)| *image)?;
+        Some((image, event.path.clone(), event.error.to_string()))
+    });
+    let failed: Vec<_> = failed_images.chain(failed_svgs).collect();
+
+    for (image, path, error) in failed {
+        rasterizer.faces.remove(&image);
+        let Some(face) = card_handles.cards_map.iter().find(|(_, handle)| handle.id() == image).map(|(face, _)| face.clone()) else {
+            continue;
+        };
+        card_handles.cards_map.remove(&face);
+        error!("Card face `{}` could not be loaded from {}: {}", face, path, error);
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_every_path_exists() {
+        assert_eq!(PLAYING_CARDS.faces().len(), 55);
+        for art in [CardArt::Png, CardArt::Png2x, CardArt::Svg, CardArt::SvgSimple] {
+            for face in PLAYING_CARDS.faces() {
+                let path = format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), PLAYING_CARDS.path(&face, art));
+                assert!(Path::new(&path).is_file(), "{} is missing", path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_variants_fall_back() {
+        assert_eq!(PLAYING_CARDS.path("back", CardArt::Png2x), "playing-cards-assets-master/png/back@2x.png");
+        assert_eq!(PLAYING_CARDS.path("7_of_clubs", CardArt::Png2x), "playing-cards-assets-master/png/7_of_clubs.png");
+        assert_eq!(PLAYING_CARDS.path("king_of_hearts", CardArt::SvgSimple), "playing-cards-assets-master/svg-cards/simple/king_of_hearts.svg");
+        assert_eq!(PLAYING_CARDS.path("7_of_clubs", CardArt::SvgSimple), "playing-cards-assets-master/svg-cards/7_of_clubs.svg");
+        assert_eq!(PLAYING_CARDS.path("back", CardArt::Svg), "playing-cards-assets-master/png/back.png");
+    }
+}
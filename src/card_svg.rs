@@ -0,0 +1,395 @@
+// Rasterizzazione delle carte svg a runtime, alla risoluzione giusta per lo schermo e lo zoom.
+//
+// The svg files are loaded as `SvgSource` assets (border stripped, as the old
+// Node script did) and drawn into images with resvg. The resolution is the
+// window scale factor divided by the camera zoom, rounded up to a power of two
+// so zooming doesn't redraw every frame; the faces are drawn on the async
+// compute pool and cached for the current resolution only. The image handles
+// in `CardHandles` never change, only what they contain, so sprites already on
+// screen get sharper by themselves as each face is done, and their size is
+// pinned to the card size so a bigger image doesn't mean a bigger card.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::tasks::{block_on, poll_once, AsyncComputeTaskPool, Task};
+use bevy::window::PrimaryWindow;
+use resvg::tiny_skia::{Pixmap, Transform as SvgTransform};
+use resvg::usvg::{fontdb, Options, Tree};
+
+
+// ====== CONSTANTS ======
+
+const MIN_RASTER_SCALE: f32 = 0.5;
+const MAX_RASTER_SCALE: f32 = 4.; // A 4x card is already 900 pixels wide
+const BORDER_ID: &str = "id=\"path5\""; // The outline the png cards were made without
+
+
+// ====== STRUCTS ======
+
+/// The file of an svg card, ready to be drawn at any size
+#[derive(Debug, Asset, TypePath)]
+pub struct SvgSource {
+    data: Arc<[u8]>, // Shared with the drawing tasks
+}
+
+#[derive(Debug)]
+pub enum SvgError {
+    Io(std::io::Error),
+    Parse(resvg::usvg::Error),
+    TooBig { width: u32, height: u32 },
+}
+
+impl fmt::Display for SvgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SvgError::Io(error) => write!(f, "Could not read the svg: {}", error),
+            SvgError::Parse(error) => write!(f, "Not a valid svg: {}", error),
+            SvgError::TooBig { width, height } => write!(f, "Can't draw a {}x{} image", width, height),
+        }
+    }
+}
+
+impl std::error::Error for SvgError {}
+
+impl From<std::io::Error> for SvgError {
+    fn from(error: std::io::Error) -> Self {
+        SvgError::Io(error)
+    }
+}
+
+#[derive(Debug, Default)]
+struct SvgSourceLoader;
+
+impl AssetLoader for SvgSourceLoader {
+    type Asset = SvgSource;
+    type Settings = ();
+    type Error = SvgError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<SvgSource, SvgError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        let data = strip_border(&data);
+        Tree::from_data(&data, &Options::default()).map_err(SvgError::Parse)?; // Fail here rather than when drawing
+        Ok(SvgSource { data: data.into() })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["svg"]
+    }
+}
+
+// A face being drawn on the async compute pool
+struct Drawing {
+    scale: f32,
+    task: Task<Result<Image, SvgError>>,
+}
+
+/// The svg faces and what they were last drawn at
+#[derive(Resource)]
+pub struct CardRasterizer {
+    /// Image drawn into -> (face name, its svg)
+    pub faces: HashMap<AssetId<Image>, (String, Handle<SvgSource>)>,
+    /// The size every card sprite gets, whatever the resolution
+    pub sizes: HashMap<AssetId<Image>, Vec2>,
+    scale: f32,
+    drawn: HashMap<AssetId<Image>, f32>,
+    redrawn: bool,
+    drawing: HashMap<AssetId<Image>, Drawing>, // At most one task per face
+    cache: HashMap<AssetId<SvgSource>, Image>, // At the current scale, cleared when it changes
+    fonts: Arc<fontdb::Database>,
+}
+
+impl Default for CardRasterizer {
+    fn default() -> Self {
+        // The ranks are text; there are no system fonts to find on the web
+        let mut fonts = fontdb::Database::new();
+        #[cfg(not(target_arch = "wasm32"))]
+        fonts.load_system_fonts();
+        CardRasterizer {
+            faces: HashMap::new(),
+            sizes: HashMap::new(),
+            scale: 1.,
+            drawn: HashMap::new(),
+            redrawn: false,
+            drawing: HashMap::new(),
+            cache: HashMap::new(),
+            fonts: Arc::new(fonts),
+        }
+    }
+}
+
+impl CardRasterizer {
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+}
+
+pub struct CardSvgPlugin;
+
+impl Plugin for CardSvgPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<SvgSource>()
+            .register_asset_loader(SvgSourceLoader)
+            .init_resource::<CardRasterizer>()
+            .add_systems(Update, (update_raster_scale, rasterize_card_faces, size_card_sprites).chain());
+    }
+}
+
+
+// ====== METHODS ======
+
+/// Draws an svg `scale` times its own size
+pub fn rasterize(data: &[u8], scale: f32, fonts: Arc<fontdb::Database>) -> Result<Image, SvgError> {
+    let mut options = Options::default();
+    options.fontdb = fonts;
+    let tree = Tree::from_data(data, &options).map_err(SvgError::Parse)?;
+
+    let width = (tree.size().width() * scale).ceil() as u32;
+    let height = (tree.size().height() * scale).ceil() as u32;
+    let mut pixmap = Pixmap::new(width, height).ok_or(SvgError::TooBig { width, height })?;
+    resvg::render(&tree, SvgTransform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skia keeps the colors premultiplied by the alpha, the textures don't
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    Ok(Image::new(
+        Extent3d { width, height, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        pixels,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+    ))
+}
+
+/// The svg without the card outline
+fn strip_border(data: &[u8]) -> Vec<u8> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return data.to_vec();
+    };
+    let Some(id) = text.find(BORDER_ID) else {
+        return data.to_vec();
+    };
+    match (text[..id].rfind("<path"), text[id..].find("/>")) {
+        (Some(start), Some(end)) => [&text[..start], &text[id + end + 2..]].concat().into_bytes(),
+        _ => data.to_vec(),
+    }
+}
+
+/// Pixels per card pixel needed for `scale_factor` and a camera `zoom`
+/// (`OrthographicProjection::scale`, smaller is closer), as a power of two
+fn raster_scale(scale_factor: f32, zoom: f32) -> f32 {
+    let needed = scale_factor / zoom.max(f32::EPSILON);
+    2f32.powf(needed.log2().ceil()).clamp(MIN_RASTER_SCALE, MAX_RASTER_SCALE)
+}
+
+fn update_raster_scale(
+    windows: Query<&Window, With<PrimaryWindow>>,
+    projections: Query<&OrthographicProjection, With<Camera2d>>,
+    mut rasterizer: ResMut<CardRasterizer>,
+) {
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let zoom = projections.iter().map(|projection| projection.scale).reduce(f32::min).unwrap_or(1.); // The closest camera
+    let scale = raster_scale(window.scale_factor(), zoom);
+    if rasterizer.scale != scale {
+        rasterizer.scale = scale;
+        rasterizer.cache.clear();
+    }
+}
+
+// Starts drawing each loaded face not yet drawn at the current scale, and
+// swaps in the faces whose drawing finished
+fn rasterize_card_faces(
+    mut rasterizer: ResMut<CardRasterizer>,
+    sources: Res<Assets<SvgSource>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let rasterizer = &mut *rasterizer;
+    let scale = rasterizer.scale;
+    let pool = AsyncComputeTaskPool::get();
+    for (image, (face, source)) in &rasterizer.faces {
+        // A face still drawing at an old scale is started again once it is done
+        if rasterizer.drawn.get(image) == Some(&scale) || rasterizer.drawing.contains_key(image) {
+            continue;
+        }
+        let Some(svg) = sources.get(source) else {
+            continue; // Still loading
+        };
+        if let Some(drawn) = rasterizer.cache.get(&source.id()) {
+            rasterizer.drawn.insert(*image, scale);
+            rasterizer.sizes.insert(*image, drawn.size_f32() / scale);
+            rasterizer.redrawn = true;
+            images.insert(*image, drawn.clone());
+            continue;
+        }
+        let (data, fonts) = (svg.data.clone(), rasterizer.fonts.clone());
+        let task = pool.spawn(async move { rasterize(&data, scale, fonts) });
+        rasterizer.drawing.insert(*image, Drawing { scale, task });
+    }
+
+    let mut finished = Vec::new();
+    for (image, drawing) in rasterizer.drawing.iter_mut() {
+        if let Some(result) = block_on(poll_once(&mut drawing.task)) {
+            finished.push((*image, drawing.scale, result));
+        }
+    }
+    for (image, drawn_at, result) in finished {
+        rasterizer.drawing.remove(&image);
+        let Some((face, source)) = rasterizer.faces.get(&image) else {
+            continue; // Its svg failed to load meanwhile
+        };
+        // Even at an old scale it is better than what was there, it is just not cached
+        rasterizer.drawn.insert(image, drawn_at);
+        let drawn = match result {
+            Ok(drawn) => drawn,
+            Err(error) => {
+                error!("Card face `{}` could not be drawn: {}", face, error);
+                continue;
+            }
+        };
+        if drawn_at == scale {
+            rasterizer.cache.insert(source.id(), drawn.clone());
+        }
+        rasterizer.sizes.insert(image, drawn.size_f32() / drawn_at);
+        rasterizer.redrawn = true;
+        images.insert(image, drawn);
+    }
+}
+
+// Sprites spawned before their face was drawn are sized once it is; a size
+// the sprite was given on purpose is kept, only the resolution changes
+fn size_card_sprites(mut rasterizer: ResMut<CardRasterizer>, mut sprites: Query<(Ref<Handle<Image>>, &mut Sprite)>) {
+    let redrawn = std::mem::take(&mut rasterizer.redrawn);
+    for (texture, mut sprite) in &mut sprites {
+        if sprite.custom_size.is_some() || !redrawn && !texture.is_changed() {
+            continue;
+        }
+        if let Some(size) = rasterizer.sizes.get(&texture.id()) {
+            sprite.custom_size = Some(*size);
+        }
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::card_assets::{CardArt, PLAYING_CARDS};
+
+    fn card_svg(face: &str) -> Vec<u8> {
+        let path = format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), PLAYING_CARDS.path(face, CardArt::Svg));
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn test_border_is_stripped() {
+        let svg = b"<svg><g><path style=\"fill:#FFFFFF\" d=\"M0,0\" id=\"path5\" /><path id=\"path7\" /></g></svg>";
+        assert_eq!(strip_border(svg), b"<svg><g><path id=\"path7\" /></g></svg>");
+        assert_eq!(strip_border(b"<svg/>"), b"<svg/>");
+
+        let stripped = strip_border(&card_svg("7_of_clubs"));
+        assert!(!String::from_utf8(stripped).unwrap().contains(BORDER_ID));
+    }
+
+    #[test]
+    fn test_raster_scale() {
+        assert_eq!(raster_scale(1., 1.), 1.);
+        assert_eq!(raster_scale(1.25, 1.), 2.);
+        assert_eq!(raster_scale(2., 0.5), 4.); // Retina and zoomed in
+        assert_eq!(raster_scale(1., 3.), 0.5);
+        assert_eq!(raster_scale(2., 0.01), MAX_RASTER_SCALE);
+    }
+
+    // The svg is drawn as big as the png made from it, twice as big at 2x
+    #[test]
+    fn test_rasterize_card() {
+        let fonts = Arc::new(fontdb::Database::new());
+        let data = strip_border(&card_svg("queen_of_hearts"));
+        let normal = rasterize(&data, 1., fonts.clone()).unwrap();
+        assert_eq!((normal.width(), normal.height()), (223, 324));
+        let double = rasterize(&data, 2., fonts).unwrap();
+        assert_eq!((double.width(), double.height()), (446, 648));
+        assert_eq!(double.texture_descriptor.format, TextureFormat::Rgba8UnormSrgb);
+    }
+
+    // An app drawing the ace of spades at 2x into the returned image
+    fn rasterizer_app() -> (App, Handle<Image>) {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<Image>()
+            .init_asset::<SvgSource>()
+            .insert_resource(CardRasterizer { fonts: Arc::new(fontdb::Database::new()), ..default() })
+            .add_systems(Update, (rasterize_card_faces, size_card_sprites).chain());
+
+        let data = strip_border(&card_svg("ace_of_spades"));
+        let source = app.world_mut().resource_mut::<Assets<SvgSource>>().add(SvgSource { data: data.into() });
+        let image = app.world_mut().resource_mut::<Assets<Image>>().add(Image::default());
+        let mut rasterizer = app.world_mut().resource_mut::<CardRasterizer>();
+        rasterizer.faces.insert(image.id(), ("ace_of_spades".to_string(), source.clone()));
+        rasterizer.scale = 2.;
+        (app, image)
+    }
+
+    fn update_until_drawn(app: &mut App, image: &Handle<Image>) -> Option<u32> {
+        let drawn = |app: &App| app.world().resource::<Assets<Image>>().get(image).map(Image::width);
+        for _ in 0..500 {
+            app.update();
+            if drawn(app) == Some(446) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        drawn(app)
+    }
+
+    #[test]
+    fn test_faces_are_drawn_in_the_background() {
+        let (mut app, image) = rasterizer_app();
+        assert_eq!(update_until_drawn(&mut app, &image), Some(446));
+        let rasterizer = app.world().resource::<CardRasterizer>();
+        assert!(rasterizer.drawing.is_empty());
+        assert_eq!(rasterizer.sizes[&image.id()], Vec2::new(223., 324.));
+        assert_eq!(rasterizer.cache.len(), 1);
+    }
+
+    #[test]
+    fn test_preset_sprite_size_is_kept() {
+        let (mut app, image) = rasterizer_app();
+        let preset = Vec2::new(100., 145.);
+        let sized = app.world_mut().spawn(SpriteBundle {
+            sprite: Sprite { custom_size: Some(preset), ..default() },
+            texture: image.clone(),
+            ..default()
+        }).id();
+        let natural = app.world_mut().spawn(SpriteBundle { texture: image.clone(), ..default() }).id();
+        assert_eq!(update_until_drawn(&mut app, &image), Some(446));
+        app.update(); // The sprites are sized the frame the face is swapped in, to be sure
+
+        let size = |entity: Entity| app.world().get::<Sprite>(entity).unwrap().custom_size;
+        assert_eq!(size(sized), Some(preset));
+        assert_eq!(size(natural), Some(Vec2::new(223., 324.)));
+    }
+}
@@ -0,0 +1,227 @@
+// Tavolo da carte lato client: disegna solo quello che il server ci fa vedere.
+//
+// The server sends our own hand (`Hand`) and the public view of the table
+// (`TableUpdate`), the other players' cards are drawn face down with back.png.
+// Keys: D draws, 1-9 play the n-th card of the hand, shift + 1-9 discard it.
+// The messages are followed from every scene, the table is drawn only in its own.
+
+use bevy::prelude::*;
+
+use crate::app_state::AppState;
+use crate::cards::{card_image, Card, CardHandles};
+use crate::networking::{ClientMessageEvent, ServerMessageEvent};
+use crate::protocol::{ClientMessage, ServerMessage, TableAction, TableInfo};
+
+
+// ====== CONSTANTS ======
+
+const CARD_SCALE: f32 = 0.5;
+const CARD_SPACING: f32 = 60.; // Horizontal distance between cards of the same row
+const OWN_HAND_Y: f32 = -260.;
+const OPPONENTS_Y: f32 = 260.;
+const OPPONENT_SPACING: f32 = 360.; // Between the hands of two opponents
+const DECK_POSITION: Vec2 = Vec2::new(-260., 0.);
+const DISCARD_POSITION: Vec2 = Vec2::new(260., 0.);
+const TABLE_Z: f32 = 10.; // Above the scene, each card a bit higher than the previous
+
+const HAND_KEYS: [KeyCode; 9] = [
+    KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3,
+    KeyCode::Digit4, KeyCode::Digit5, KeyCode::Digit6,
+    KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9,
+];
+
+
+// ====== STRUCTS ======
+
+/// What this client knows of the table it is sitting at
+#[derive(Resource, Debug, Default)]
+pub struct CardTableState {
+    pub own_id: Option<usize>,
+    pub hand: Vec<Card>,
+    pub table: Option<TableInfo>, // None until a match starts in our room
+}
+
+impl CardTableState {
+    /// Applies a message from the server, returns true if something visible changed
+    pub fn apply(&mut self, message: &ServerMessage) -> bool {
+        match message {
+            ServerMessage::LoginAccepted { client_id, .. } => self.own_id = Some(*client_id),
+            ServerMessage::Hand { cards } => self.hand = cards.clone(),
+            ServerMessage::TableUpdate(info) => self.table = Some(info.clone()),
+            ServerMessage::RoomLeft { .. } | ServerMessage::MatchEnded { .. } => {
+                self.hand.clear();
+                self.table = None;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The cards of every other player, face down, in seating order
+    pub fn opponents(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let hands = self.table.iter().flat_map(|table| table.hands.iter());
+        hands.filter(|hand| Some(hand.player) != self.own_id).map(|hand| (hand.player, hand.cards))
+    }
+}
+
+#[derive(Component)]
+struct TableCard;
+
+pub struct CardTablePlugin;
+
+impl Plugin for CardTablePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CardTableState>()
+            .add_systems(OnEnter(AppState::CardTable), redraw_table)
+            .add_systems(OnExit(AppState::CardTable), cleanup_card_table)
+            .add_systems(
+                Update,
+                (
+                    receive_table_messages,
+                    (table_keyboard_input, draw_table).run_if(in_state(AppState::CardTable)),
+                )
+                    .chain(),
+            );
+    }
+}
+
+
+// ====== METHODS ======
+
+fn receive_table_messages(mut inbound: EventReader<ServerMessageEvent>, mut state: ResMut<CardTableState>) {
+    for ServerMessageEvent(message) in inbound.read() {
+        // Snapshots arrive every tick, draw_table must only redraw for the table messages
+        if state.bypass_change_detection().apply(message) {
+            state.set_changed();
+        }
+    }
+}
+
+fn table_keyboard_input(
+    keys: Res<ButtonInput<KeyCode>>,
+    state: Res<CardTableState>,
+    mut outbound: EventWriter<ClientMessageEvent>,
+) {
+    if state.table.is_none() {
+        return;
+    }
+    if keys.just_pressed(KeyCode::KeyD) {
+        outbound.send(ClientMessageEvent(ClientMessage::TableAction(TableAction::Draw)));
+    }
+
+    let discard = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    for (index, key) in HAND_KEYS.iter().enumerate() {
+        let Some(card) = state.hand.get(index).filter(|_| keys.just_pressed(*key)) else {
+            continue;
+        };
+        let card = *card;
+        let action = if discard { TableAction::Discard { card } } else { TableAction::Play { card } };
+        outbound.send(ClientMessageEvent(ClientMessage::TableAction(action)));
+    }
+}
+
+// The cards were thrown away when leaving the scene
+fn redraw_table(mut state: ResMut<CardTableState>) {
+    state.set_changed();
+}
+
+fn cleanup_card_table(mut commands: Commands, cards: Query<Entity, With<TableCard>>) {
+    for entity in &cards {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+// Throws everything away and lays the table out again, it only happens when the server sends something
+fn draw_table(
+    mut commands: Commands,
+    state: Res<CardTableState>,
+    card_handles: Res<CardHandles>,
+    asset_server: Res<AssetServer>,
+    cards: Query<Entity, With<TableCard>>,
+) {
+    if !state.is_changed() {
+        return;
+    }
+    for entity in &cards {
+        commands.entity(entity).despawn_recursive();
+    }
+    let Some(table) = &state.table else {
+        return;
+    };
+
+    let mut z = TABLE_Z;
+    let mut spawn = |name: &str, position: Vec2| {
+        z += 0.1;
+        commands.spawn((
+            SpriteBundle {
+                texture: card_image(name, &card_handles, &asset_server),
+                transform: Transform::from_translation(position.extend(z)).with_scale(Vec3::splat(CARD_SCALE)),
+                ..default()
+            },
+            TableCard,
+        ));
+    };
+
+    for (index, card) in state.hand.iter().enumerate() {
+        spawn(&card.to_string(), Vec2::new(row_offset(index, state.hand.len()), OWN_HAND_Y));
+    }
+
+    let opponents: Vec<(usize, usize)> = state.opponents().collect();
+    for (seat, (_, cards)) in opponents.iter().enumerate() {
+        let center = (seat as f32 - (opponents.len() as f32 - 1.) / 2.) * OPPONENT_SPACING;
+        for index in 0..*cards {
+            spawn("back", Vec2::new(center + row_offset(index, *cards) / 3., OPPONENTS_Y));
+        }
+    }
+
+    if table.deck_size > 0 {
+        spawn("back", DECK_POSITION);
+    }
+    if let Some(card) = &table.discard_top {
+        spawn(&card.to_string(), DISCARD_POSITION);
+    }
+    for (index, played) in table.played.iter().enumerate() {
+        spawn(&played.card.to_string(), Vec2::new(row_offset(index, table.played.len()) / 2., 0.));
+    }
+}
+
+// X of the `index`-th card of a row of `count` cards centered on 0
+fn row_offset(index: usize, count: usize) -> f32 {
+    (index as f32 - (count as f32 - 1.) / 2.) * CARD_SPACING
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::HandInfo;
+
+    #[test]
+    fn test_only_opponents_are_face_down() {
+        let mut state = CardTableState::default();
+        state.apply(&ServerMessage::LoginAccepted { client_id: 2, session_token: String::new() });
+        state.apply(&ServerMessage::Hand { cards: vec!["ace_of_spades".parse().unwrap()] });
+        state.apply(&ServerMessage::TableUpdate(TableInfo {
+            hands: vec![HandInfo { player: 1, cards: 3 }, HandInfo { player: 2, cards: 1 }],
+            ..TableInfo::default()
+        }));
+
+        assert_eq!(state.opponents().collect::<Vec<_>>(), vec![(1, 3)]);
+        assert!(!state.apply(&ServerMessage::Pong { nonce: 0 }));
+
+        state.apply(&ServerMessage::RoomLeft { room_id: 0 });
+        assert!(state.table.is_none() && state.hand.is_empty());
+        assert_eq!(state.opponents().count(), 0);
+    }
+
+    #[test]
+    fn test_row_offset_is_centered() {
+        assert_eq!(row_offset(0, 1), 0.);
+        assert_eq!(row_offset(0, 3), -CARD_SPACING);
+        assert_eq!(row_offset(2, 3), CARD_SPACING);
+    }
+}
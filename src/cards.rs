@@ -1,8 +1,13 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fmt, str::FromStr};
 
 use bevy::prelude::*;
-use rand::{seq::SliceRandom, thread_rng, Rng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::card_animation::{CardFaces, FlipCard};
+use crate::card_assets::{CardArt, MissingCardFace, PLAYING_CARDS};
+use crate::game_rng::{GameRng, RngStream};
+use crate::picking::{Draggable, Pickable};
 use crate::Scene1Entity;
 
 #[macro_export]
@@ -103,13 +108,18 @@ pub const CARD_NAMES_ARRAY: [&str; 56] = ["10_of_clubs",
 
 // ====== STRUCTS ======
 
-#[derive(Resource)]
+/// The card images by asset name (see `CardFace`), filled by `load_card_faces`
+#[derive(Debug, Default, Resource)]
 pub struct CardHandles {
+    pub art: CardArt,
     pub cards_map: HashMap<String, Handle<Image>>,
 }
 
-#[derive(Debug, Component)]
-enum Suit {
+/// Name of the image shown for a face down card
+pub const CARD_BACK: &str = "back";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Component)]
+pub enum Suit {
     Clubs = 0,
     Hearts = 1,
     Spades = 2, 
@@ -117,6 +127,8 @@ enum Suit {
 }
 
 impl Suit {
+    pub const ALL: [Suit; 4] = [Suit::Clubs, Suit::Hearts, Suit::Spades, Suit::Diamonds];
+
     fn into_string(&self) -> String {
         let ans = match self {
             Suit::Clubs => "Clubs",
@@ -127,51 +139,374 @@ impl Suit {
         ans.to_string()
     }
 
-    fn from_string(raw_string: &str) -> Self {
-        // let raw_string = raw_string.to_lowercase();
+    pub fn is_red(&self) -> bool {
+        matches!(self, Suit::Hearts | Suit::Diamonds)
+    }
+}
+
+/// As in the asset names: `clubs`, `hearts`...
+impl fmt::Display for Suit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.into_string().to_lowercase())
+    }
+}
+
+impl FromStr for Suit {
+    type Err = CardParseError;
+
+    fn from_str(raw_string: &str) -> Result<Self, Self::Err> {
+        match raw_string {
+            "clubs" => Ok(Suit::Clubs),
+            "hearts" => Ok(Suit::Hearts),
+            "spades" => Ok(Suit::Spades),
+            "diamonds" => Ok(Suit::Diamonds),
+            _ => Err(CardParseError::UnknownSuit(raw_string.to_string())),
+        }
+    }
+}
+
+/// Ordered from the lowest, the ace is high (games where it is low handle it themselves)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rank {
+    Two = 2,
+    Three = 3,
+    Four = 4,
+    Five = 5,
+    Six = 6,
+    Seven = 7,
+    Eight = 8,
+    Nine = 9,
+    Ten = 10,
+    Jack = 11,
+    Queen = 12,
+    King = 13,
+    Ace = 14,
+}
+
+impl Rank {
+    pub const ALL: [Rank; 13] = [
+        Rank::Two, Rank::Three, Rank::Four, Rank::Five, Rank::Six, Rank::Seven, Rank::Eight,
+        Rank::Nine, Rank::Ten, Rank::Jack, Rank::Queen, Rank::King, Rank::Ace,
+    ];
+
+    /// 2 to 10 for the numbers, then 11 jack, 12 queen, 13 king, 14 ace
+    pub fn value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// As in the asset names: `2` ... `10`, `jack`, `queen`, `king`, `ace`
+impl fmt::Display for Rank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rank::Jack => f.write_str("jack"),
+            Rank::Queen => f.write_str("queen"),
+            Rank::King => f.write_str("king"),
+            Rank::Ace => f.write_str("ace"),
+            rank => write!(f, "{}", rank.value()),
+        }
+    }
+}
+
+impl FromStr for Rank {
+    type Err = CardParseError;
+
+    fn from_str(raw_string: &str) -> Result<Self, Self::Err> {
+        Rank::ALL
+            .into_iter()
+            .find(|rank| rank.to_string() == raw_string)
+            .ok_or_else(|| CardParseError::UnknownRank(raw_string.to_string()))
+    }
+}
+
+/// One of the 52 cards of a standard deck, written as its image: `7_of_clubs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Component)]
+pub struct Card {
+    pub rank: Rank,
+    pub suit: Suit,
+}
+
+impl Card {
+    pub const fn new(rank: Rank, suit: Suit) -> Self {
+        Card { rank, suit }
+    }
+
+    /// The 52 cards, suit by suit
+    pub fn all() -> impl Iterator<Item = Card> {
+        Suit::ALL.into_iter().flat_map(|suit| Rank::ALL.into_iter().map(move |rank| Card::new(rank, suit)))
+    }
+}
+
+impl fmt::Display for Card {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_of_{}", self.rank, self.suit)
+    }
+}
+
+impl FromStr for Card {
+    type Err = CardParseError;
+
+    fn from_str(raw_string: &str) -> Result<Self, Self::Err> {
+        let (rank, suit) = raw_string
+            .split_once("_of_")
+            .ok_or_else(|| CardParseError::Malformed(raw_string.to_string()))?;
+        Ok(Card::new(rank.parse()?, suit.parse()?))
+    }
+}
+
+// On the network a card travels by its name, as in the images
+impl Serialize for Card {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Card {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw_string = String::deserialize(deserializer)?;
+        raw_string.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Joker {
+    Black,
+    Red,
+}
+
+impl fmt::Display for Joker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Joker::Black => f.write_str("black_joker"),
+            Joker::Red => f.write_str("red_joker"),
+        }
+    }
+}
+
+/// A card of a deck played with the jokers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeckCard {
+    Card(Card),
+    Joker(Joker),
+}
+
+impl From<Card> for DeckCard {
+    fn from(card: Card) -> Self {
+        DeckCard::Card(card)
+    }
+}
+
+impl fmt::Display for DeckCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeckCard::Card(card) => card.fmt(f),
+            DeckCard::Joker(joker) => joker.fmt(f),
+        }
+    }
+}
+
+impl FromStr for DeckCard {
+    type Err = CardParseError;
+
+    fn from_str(raw_string: &str) -> Result<Self, Self::Err> {
         match raw_string {
-            "clubs" => Suit::Clubs,
-            "hearts" => Suit::Hearts,
-            "spades" => Self::Spades,
-            "diamonds" => Self::Diamonds,
-            _ => panic!("String could not be converted into any Suit variant")
+            "black_joker" => Ok(DeckCard::Joker(Joker::Black)),
+            "red_joker" => Ok(DeckCard::Joker(Joker::Red)),
+            _ => raw_string.parse().map(DeckCard::Card),
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CardParseError {
+    Malformed(String), // Not `<rank>_of_<suit>`
+    UnknownRank(String),
+    UnknownSuit(String),
+}
+
+impl fmt::Display for CardParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CardParseError::Malformed(s) => write!(f, "'{}' is not a card, expected e.g. 7_of_clubs", s),
+            CardParseError::UnknownRank(s) => write!(f, "Unknown rank '{}'", s),
+            CardParseError::UnknownSuit(s) => write!(f, "Unknown suit '{}'", s),
+        }
+    }
+}
+
+impl std::error::Error for CardParseError {}
+
+/// Anything with a face among the card images
+pub trait CardFace {
+    /// Name of the image, without extension: the key in `CardHandles`
+    fn asset_name(&self) -> String;
+}
+
+impl CardFace for Card {
+    fn asset_name(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl CardFace for Joker {
+    fn asset_name(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl CardFace for DeckCard {
+    fn asset_name(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeckError {
+    NotEnoughCards { needed: usize, left: usize },
+}
+
+impl fmt::Display for DeckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeckError::NotEnoughCards { needed, left } => {
+                write!(f, "Not enough cards: {} needed, {} left in the deck", needed, left)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeckError {}
+
+/// A pile of cards drawn from the top, `Deck<Card>` for the standard 52 and
+/// `Deck<DeckCard>` when playing with the jokers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deck<C = Card> {
+    cards: Vec<C>, // The top of the deck is the end of the Vec
+    burned: Vec<C>,
+}
+
+impl Deck<Card> {
+    /// The 52 cards, not shuffled
+    pub fn standard() -> Self {
+        Deck::from_cards(Card::all().collect())
+    }
+}
+
+impl Deck<DeckCard> {
+    /// The 52 cards and the two jokers, not shuffled
+    pub fn with_jokers() -> Self {
+        let jokers = [Joker::Black, Joker::Red].map(DeckCard::Joker);
+        Deck::from_cards(Card::all().map(DeckCard::Card).chain(jokers).collect())
+    }
+}
+
+impl<C> Deck<C> {
+    /// The last card is the top one
+    pub fn from_cards(cards: Vec<C>) -> Self {
+        Deck { cards, burned: Vec::new() }
+    }
+
+    pub fn shuffle(&mut self, rng: &mut impl Rng) {
+        self.cards.shuffle(rng);
+    }
+
+    /// Always the same order for the same seed, e.g. to replay a game
+    pub fn shuffle_with_seed(&mut self, seed: u64) {
+        self.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+
+    pub fn draw(&mut self) -> Option<C> {
+        self.cards.pop()
+    }
+
+    /// Puts the top card aside face down, as dealers do before the flop
+    pub fn burn(&mut self) -> Option<&C> {
+        let card = self.cards.pop()?;
+        self.burned.push(card);
+        self.burned.last()
+    }
+
+    /// `cards_each` cards to each of `hands` hands, one at a time around the table
+    pub fn deal(&mut self, hands: usize, cards_each: usize) -> Result<Vec<Vec<C>>, DeckError> {
+        let needed = hands * cards_each;
+        if needed > self.cards.len() {
+            return Err(DeckError::NotEnoughCards { needed, left: self.cards.len() });
+        }
+        let mut dealt: Vec<Vec<C>> = (0..hands).map(|_| Vec::with_capacity(cards_each)).collect();
+        for _ in 0..cards_each {
+            for hand in dealt.iter_mut() {
+                hand.extend(self.cards.pop());
+            }
+        }
+        Ok(dealt)
+    }
+
+    /// Puts a card back under the deck
+    pub fn put_under(&mut self, card: C) {
+        self.cards.insert(0, card);
+    }
+
+    pub fn peek(&self) -> Option<&C> {
+        self.cards.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cards.is_empty()
+    }
+
+    /// From the bottom to the top
+    pub fn cards(&self) -> &[C] {
+        &self.cards
+    }
+
+    pub fn burned(&self) -> &[C] {
+        &self.burned
+    }
+}
+
 #[derive(Debug, Bundle)]
 struct CardBundle {
-    suit: Suit,
+    card: Card,
     front: SpriteBundle,
+    pickable: Pickable,
+    draggable: Draggable,
 }
 
+impl CardHandles {
+    pub fn new(art: CardArt) -> Self {
+        CardHandles { art, cards_map: HashMap::new() }
+    }
 
-// ====== METHODS ======
+    /// The loaded image of a card
+    pub fn get(&self, card: &impl CardFace) -> Result<Handle<Image>, MissingCardFace> {
+        self.face(&card.asset_name())
+    }
 
+    pub fn back(&self) -> Result<Handle<Image>, MissingCardFace> {
+        self.face(CARD_BACK)
+    }
 
-pub fn load_cards_pngs(
-    asset_server: Res<AssetServer>,
-    mut handles_map: ResMut<CardHandles>    
-) {
-    let cards_path = fs::read_dir("assets/playing-cards-assets-master/png").unwrap();
+    fn face(&self, name: &str) -> Result<Handle<Image>, MissingCardFace> {
+        self.cards_map
+            .get(name)
+            .cloned()
+            .ok_or_else(|| MissingCardFace { face: name.to_string(), art: self.art })
+    }
+}
 
-    for path in cards_path {
-        // println!("Name: {}", path.unwrap().path().display());
-        let path = path.unwrap().path().into_os_string().into_string().expect("Failed to convert PathBuf to String");
-        let path = path.replace("assets/", "");
-        // println!("{:?}", path);
-        // example: "playing-cards-assets-master/png\\queen_of_hearts.png"
 
-        // Save the handle to the image
-        let handle: Handle<Image> = asset_server.load(path.clone());
+// ====== METHODS ======
 
-        // Construct the key for the hashmap
-        let map_key = path.replace(".png", "");
-        let mut map_key: Vec<_> = map_key.split("\\").collect();
-        let map_key = map_key[1];
-        // dbg!(map_key);
 
-        handles_map.cards_map.insert(map_key.to_owned(), handle);
+/// Image of a card by name (e.g. `7_of_clubs`), loaded on the spot if it wasn't preloaded
+pub fn card_image(card_name: &str, card_handles: &CardHandles, asset_server: &AssetServer) -> Handle<Image> {
+    match card_handles.cards_map.get(card_name) {
+        Some(handle) => handle.clone(),
+        None => asset_server.load(PLAYING_CARDS.path(card_name, CardArt::Png)), // No svg loader for images
     }
 }
 
@@ -193,60 +528,146 @@ pub fn int_to_string(n: u8) -> String {
     ans.to_string()
 }
 
+/// Spawns the card face down and turns it over
 pub fn spawn_card(
-    card_name: String, 
+    card: Card, 
     mut commands: Commands, 
     card_handles: Res<CardHandles>
-) {
-    // Card name example: 7_of_clubs
-    let suit_part: Vec<_> = card_name.split("_").collect();
-    let card_suit = suit_part.last().expect("Maybe vec was empty?");
-    dbg!(card_suit);
-    dbg!(&card_name);
-
-    commands.spawn((
+) -> Result<Entity, MissingCardFace> {
+    let faces = CardFaces {
+        front: card_handles.get(&card)?,
+        back: card_handles.back()?,
+        face_up: false,
+    };
+    let entity = commands.spawn((
         CardBundle {
-            suit: Suit::from_string(card_suit),
+            card,
             front: SpriteBundle {
-                texture: card_handles.cards_map.get(&card_name).expect("No handles found for this key").clone(),
+                texture: faces.shown(),
                 transform: Transform {
                     translation: Vec3 { x: 0., y: 400., z: 1. },
                     ..Default::default()
                 },
                 ..default()
             },
+            pickable: Pickable::default(),
+            draggable: Draggable,
         },
+        faces,
+        FlipCard::default(),
         Scene1Entity
     ));
-
-    commands.spawn((
-        SpriteBundle {
-            texture: card_handles.cards_map.get("back").expect("No handles found for this key").clone(),
-            transform: Transform {
-                translation: Vec3 { x: 0., y: 400., z: 0. },
-                ..Default::default()
-            },
-            ..default()
-        },
-        Scene1Entity
-    ));
+    Ok(entity.id())
 }
 
 pub fn spawn_random_card(
     mut commands: Commands,
     card_handles: Res<CardHandles>,
-    keyboard_input: Res<ButtonInput<KeyCode>>
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut rng: ResMut<GameRng>,
 ) {
     if !keyboard_input.just_released(KeyCode::KeyC) { return; }
 
-    let invalid_cards = ["back", "back@2x", "black_joker", "red_joker"];
+    if let Some(card) = Deck::standard().cards().choose(rng.stream(RngStream::Cards)) {
+        if let Err(missing) = spawn_card(*card, commands, card_handles) {
+            error!("Can't spawn the card: {}", missing);
+        }
+    }
+}
+
+
 
-    let mut rng = rand::thread_rng();
+// ================== TEST DOWN HERE ==================
 
-    while let Some(card_name) = CARD_NAMES_ARRAY.choose(&mut rng){
-        if !invalid_cards.contains(card_name) {
-            spawn_card(card_name.to_owned().to_owned(), commands, card_handles);
-            return;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_standard_deck_has_52_different_cards() {
+        let deck = Deck::standard();
+        assert_eq!(deck.len(), 52);
+        assert_eq!(deck.cards().iter().collect::<HashSet<_>>().len(), 52);
+        for suit in Suit::ALL {
+            assert_eq!(deck.cards().iter().filter(|card| card.suit == suit).count(), 13);
+        }
+        for rank in Rank::ALL {
+            assert_eq!(deck.cards().iter().filter(|card| card.rank == rank).count(), 4);
+        }
+    }
+
+    #[test]
+    fn test_deck_with_jokers_has_54() {
+        let deck = Deck::with_jokers();
+        assert_eq!(deck.len(), 54);
+        assert_eq!(deck.cards().iter().collect::<HashSet<_>>().len(), 54);
+        let jokers: Vec<_> = deck.cards().iter().filter(|card| matches!(card, DeckCard::Joker(_))).collect();
+        assert_eq!(jokers, [&DeckCard::Joker(Joker::Black), &DeckCard::Joker(Joker::Red)]);
+    }
+
+    // Every card has its image, and every image but the backs is a card
+    #[test]
+    fn test_names_match_the_assets() {
+        let names: HashSet<String> = Deck::with_jokers().cards().iter().map(CardFace::asset_name).collect();
+        let assets: HashSet<String> = CARD_NAMES_ARRAY
+            .iter()
+            .filter(|name| !name.starts_with(CARD_BACK))
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(names, assets);
+
+        for name in &assets {
+            assert_eq!(&name.parse::<DeckCard>().unwrap().to_string(), name);
         }
-    };    
+        assert_eq!("10_of_hearts".parse(), Ok(Card::new(Rank::Ten, Suit::Hearts)));
+        assert_eq!(Card::new(Rank::Queen, Suit::Spades).to_string(), "queen_of_spades");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!("back".parse::<Card>(), Err(CardParseError::Malformed("back".to_string())));
+        assert_eq!("1_of_clubs".parse::<Card>(), Err(CardParseError::UnknownRank("1".to_string())));
+        assert_eq!("ace_of_cups".parse::<Card>(), Err(CardParseError::UnknownSuit("cups".to_string())));
+        assert!("red_joker".parse::<Card>().is_err());
+        assert!("Clubs".parse::<Suit>().is_err());
+    }
+
+    #[test]
+    fn test_seeded_shuffle() {
+        let mut first = Deck::standard();
+        let mut second = Deck::standard();
+        first.shuffle_with_seed(42);
+        second.shuffle_with_seed(42);
+        assert_eq!(first, second);
+        assert_ne!(first, Deck::standard());
+
+        second.shuffle_with_seed(43);
+        assert_ne!(first, second);
+        assert_eq!(first.cards().iter().collect::<HashSet<_>>().len(), 52); // Still the same cards
+    }
+
+    #[test]
+    fn test_draw_burn_deal() {
+        let mut deck = Deck::standard();
+        deck.shuffle_with_seed(1);
+        let top = *deck.peek().unwrap();
+        assert_eq!(deck.draw(), Some(top));
+        let burned = *deck.burn().unwrap();
+        assert_eq!(deck.burned(), [burned]);
+
+        let hands = deck.deal(4, 5).unwrap();
+        assert_eq!(hands.len(), 4);
+        assert!(hands.iter().all(|hand| hand.len() == 5));
+        assert_eq!(deck.len(), 52 - 2 - 20);
+
+        // Nothing lost nor duplicated
+        let mut seen: HashSet<Card> = deck.cards().iter().chain(deck.burned()).chain(hands.iter().flatten()).copied().collect();
+        seen.insert(top);
+        assert_eq!(seen.len(), 52);
+
+        assert_eq!(deck.deal(10, 4), Err(DeckError::NotEnoughCards { needed: 40, left: 30 }));
+        assert_eq!(deck.len(), 30); // A deal that can't be completed doesn't start
+    }
 }
@@ -0,0 +1,430 @@
+// Chat in gioco: casella di testo, log dei messaggi e sussurri con /w.
+//
+// The server relays every message (see `server.rs`): chat stays inside the
+// sender's room, or among the players in the lobby, and whispers go to a
+// single player by name. This module holds what both sides share, the limits,
+// the rate limiter and the parsing of what the player types, plus the Bevy UI,
+// shown in the card table scene only (the log keeps filling in the others).
+
+use bevy::input::keyboard::{Key, KeyboardInput};
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Instant;
+
+use crate::app_state::AppState;
+use crate::networking::{ClientMessageEvent, ServerMessageEvent};
+use crate::protocol::{ClientMessage, ServerMessage};
+
+
+// ====== CONSTANTS ======
+
+pub const MAX_CHAT_LENGTH: usize = 200; // In characters, not bytes
+pub const CHAT_BURST: f32 = 5.; // Messages that can be sent back to back
+pub const CHAT_RATE: f32 = 1.; // Messages per second regained after a burst
+
+const CHAT_LOG_SIZE: usize = 100;
+const CHAT_VISIBLE_LINES: usize = 8;
+const CHAT_FONT_SIZE: f32 = 18.;
+
+
+// ====== STRUCTS ======
+
+/// Token bucket: `capacity` messages at once, then `refill_per_second`
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    capacity: f32,
+    refill_per_second: f32,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: f32, refill_per_second: f32, now: Instant) -> Self {
+        RateLimiter { capacity, refill_per_second, tokens: capacity, last_refill: now }
+    }
+
+    /// The limits the server applies to every client's chat
+    pub fn chat(now: Instant) -> Self {
+        Self::new(CHAT_BURST, CHAT_RATE, now)
+    }
+
+    /// Takes a token if there is one left
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
+
+/// Why a line typed in the chat box can't be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatInputError {
+    Empty,
+    TooLong,
+    UnknownCommand(String),
+    WhisperUsage,
+}
+
+impl fmt::Display for ChatInputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatInputError::Empty => write!(f, "Empty message"),
+            ChatInputError::TooLong => write!(f, "Message too long (max {} characters)", MAX_CHAT_LENGTH),
+            ChatInputError::UnknownCommand(command) => write!(f, "Unknown command {}", command),
+            ChatInputError::WhisperUsage => write!(f, "Usage: /w <name> <message>"),
+        }
+    }
+}
+
+impl std::error::Error for ChatInputError {}
+
+/// Checks a chat text the same way on the client and on the server, returns it trimmed
+pub fn validate_chat_text(text: &str) -> Result<&str, ChatInputError> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(ChatInputError::Empty);
+    }
+    if text.chars().count() > MAX_CHAT_LENGTH {
+        return Err(ChatInputError::TooLong);
+    }
+    Ok(text)
+}
+
+/// Turns a line typed by the player into the message for the server:
+/// plain text is a chat message, `/w <name> <message>` a whisper
+pub fn parse_chat_input(input: &str) -> Result<ClientMessage, ChatInputError> {
+    let input = input.trim();
+    let Some(command) = input.strip_prefix('/') else {
+        let text = validate_chat_text(input)?;
+        return Ok(ClientMessage::Chat { text: text.to_string() });
+    };
+
+    let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+    match name {
+        "w" | "whisper" => {
+            let (to, text) = rest.trim_start().split_once(char::is_whitespace).ok_or(ChatInputError::WhisperUsage)?;
+            let text = validate_chat_text(text).map_err(|e| match e {
+                ChatInputError::Empty => ChatInputError::WhisperUsage,
+                e => e,
+            })?;
+            Ok(ClientMessage::Whisper { to: to.to_string(), text: text.to_string() })
+        }
+        _ => Err(ChatInputError::UnknownCommand(format!("/{}", name))),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatLineKind {
+    Room,
+    Lobby,
+    Whisper,
+    System, // Errors and connection notices
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatLine {
+    pub kind: ChatLineKind,
+    pub text: String,
+}
+
+impl ChatLine {
+    pub fn system(text: impl Into<String>) -> Self {
+        ChatLine { kind: ChatLineKind::System, text: text.into() }
+    }
+
+    /// The line shown for a server message, `None` for what isn't chat
+    pub fn from_server(message: &ServerMessage) -> Option<Self> {
+        let (kind, text) = match message {
+            ServerMessage::Chat { name, text, room: Some(room_id), .. } => {
+                (ChatLineKind::Room, format!("[room {}] {}: {}", room_id, name, text))
+            }
+            ServerMessage::Chat { name, text, room: None, .. } => {
+                (ChatLineKind::Lobby, format!("[lobby] {}: {}", name, text))
+            }
+            ServerMessage::Whisper { name, to, text, .. } => {
+                (ChatLineKind::Whisper, format!("[{} -> {}] {}", name, to, text))
+            }
+            ServerMessage::Error { message } => (ChatLineKind::System, message.clone()),
+            ServerMessage::Disconnected { reason } => (ChatLineKind::System, format!("Disconnected: {}", reason)),
+            _ => return None,
+        };
+        Some(ChatLine { kind, text })
+    }
+
+    fn color(&self) -> Color {
+        match self.kind {
+            ChatLineKind::Room => Color::WHITE,
+            ChatLineKind::Lobby => Color::srgb(0.8, 0.8, 0.8),
+            ChatLineKind::Whisper => Color::srgb(0.9, 0.5, 0.9),
+            ChatLineKind::System => Color::srgb(1., 0.6, 0.3),
+        }
+    }
+}
+
+/// Last chat lines received, oldest first, and how far the player scrolled back
+#[derive(Resource, Debug, Default)]
+pub struct ChatLog {
+    lines: VecDeque<ChatLine>,
+    scroll: usize, // Lines hidden at the bottom, 0 follows the newest
+}
+
+impl ChatLog {
+    pub fn push(&mut self, line: ChatLine) {
+        if self.lines.len() == CHAT_LOG_SIZE {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        // Someone reading the history doesn't want it to move under their eyes
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.max_scroll());
+        }
+    }
+
+    /// Positive values go back in the history
+    pub fn scroll_by(&mut self, lines: isize) {
+        self.scroll = self.scroll.saturating_add_signed(lines).min(self.max_scroll());
+    }
+
+    pub fn visible_lines(&self) -> impl Iterator<Item = &ChatLine> {
+        let end = self.lines.len() - self.scroll;
+        let start = end.saturating_sub(CHAT_VISIBLE_LINES);
+        self.lines.range(start..end)
+    }
+
+    fn max_scroll(&self) -> usize {
+        self.lines.len().saturating_sub(CHAT_VISIBLE_LINES)
+    }
+}
+
+/// What the player is typing; while focused the keyboard only goes to the chat
+#[derive(Resource, Debug, Default)]
+pub struct ChatInput {
+    pub text: String,
+    pub focused: bool,
+}
+
+#[derive(Component)]
+struct ChatRoot;
+
+#[derive(Component)]
+struct ChatLogText;
+
+#[derive(Component)]
+struct ChatInputText;
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatLog>()
+            .init_resource::<ChatInput>()
+            .add_systems(OnEnter(AppState::CardTable), setup_chat_ui)
+            .add_systems(OnExit(AppState::CardTable), cleanup_chat_ui)
+            // Right after the input is read, so the game systems don't see the keys typed in the chat
+            .add_systems(PreUpdate, chat_keyboard_input.after(InputSystem).run_if(in_state(AppState::CardTable)))
+            .add_systems(
+                Update,
+                (receive_chat_messages, update_chat_ui.run_if(in_state(AppState::CardTable))).chain(),
+            );
+    }
+}
+
+
+// ====== METHODS ======
+
+fn setup_chat_ui(mut commands: Commands, mut log: ResMut<ChatLog>, mut input: ResMut<ChatInput>) {
+    // The new texts are empty, update_chat_ui fills them in
+    log.set_changed();
+    input.set_changed();
+    commands
+        .spawn((NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(40.),
+                left: Val::Px(12.),
+                width: Val::Px(420.),
+                flex_direction: FlexDirection::Column,
+                padding: UiRect::all(Val::Px(6.)),
+                row_gap: Val::Px(4.),
+                ..default()
+            },
+            background_color: Color::srgba(0., 0., 0., 0.4).into(),
+            ..default()
+        }, ChatRoot))
+        .with_children(|parent| {
+            parent.spawn((TextBundle::default(), ChatLogText));
+            parent.spawn((
+                TextBundle::from_section("", TextStyle { font_size: CHAT_FONT_SIZE, ..default() }),
+                ChatInputText,
+            ));
+        });
+}
+
+fn cleanup_chat_ui(mut commands: Commands, roots: Query<Entity, With<ChatRoot>>, mut input: ResMut<ChatInput>) {
+    for entity in &roots {
+        commands.entity(entity).despawn_recursive();
+    }
+    input.focused = false;
+}
+
+fn chat_keyboard_input(
+    mut events: EventReader<KeyboardInput>,
+    mut keys: ResMut<ButtonInput<KeyCode>>,
+    mut input: ResMut<ChatInput>,
+    mut log: ResMut<ChatLog>,
+    mut outbound: EventWriter<ClientMessageEvent>,
+) {
+    for event in events.read() {
+        if !event.state.is_pressed() {
+            continue;
+        }
+        match &event.logical_key {
+            Key::PageUp => log.scroll_by(CHAT_VISIBLE_LINES as isize),
+            Key::PageDown => log.scroll_by(-(CHAT_VISIBLE_LINES as isize)),
+            // Enter opens the box, sends what was typed and closes it again
+            Key::Enter => {
+                if !input.focused {
+                    input.focused = true;
+                    continue;
+                }
+                input.focused = false;
+                let text = std::mem::take(&mut input.text);
+                match parse_chat_input(&text) {
+                    Ok(message) => {
+                        outbound.send(ClientMessageEvent(message));
+                    }
+                    Err(ChatInputError::Empty) => {}
+                    Err(e) => log.push(ChatLine::system(e.to_string())),
+                }
+            }
+            _ if !input.focused => {}
+            Key::Backspace => {
+                input.text.pop();
+            }
+            Key::Space => push_chat_text(&mut input.text, " "),
+            Key::Character(characters) => push_chat_text(&mut input.text, characters),
+            _ => {}
+        }
+    }
+
+    if input.focused {
+        keys.reset_all();
+    }
+}
+
+// Typing stops at the limit, the server would refuse a longer message anyway
+fn push_chat_text(text: &mut String, characters: &str) {
+    for character in characters.chars().filter(|c| !c.is_control()) {
+        if text.chars().count() >= MAX_CHAT_LENGTH {
+            return;
+        }
+        text.push(character);
+    }
+}
+
+fn receive_chat_messages(mut inbound: EventReader<ServerMessageEvent>, mut log: ResMut<ChatLog>) {
+    for ServerMessageEvent(message) in inbound.read() {
+        if let Some(line) = ChatLine::from_server(message) {
+            log.push(line);
+        }
+    }
+}
+
+fn update_chat_ui(
+    log: Res<ChatLog>,
+    input: Res<ChatInput>,
+    mut log_text: Query<&mut Text, (With<ChatLogText>, Without<ChatInputText>)>,
+    mut input_text: Query<&mut Text, (With<ChatInputText>, Without<ChatLogText>)>,
+) {
+    if log.is_changed() {
+        for mut text in &mut log_text {
+            text.sections = log
+                .visible_lines()
+                .map(|line| {
+                    let style = TextStyle { font_size: CHAT_FONT_SIZE, color: line.color(), ..default() };
+                    TextSection::new(format!("{}\n", line.text), style)
+                })
+                .collect();
+        }
+    }
+
+    if input.is_changed() {
+        for mut text in &mut input_text {
+            text.sections[0].value = if input.focused {
+                format!("> {}_", input.text)
+            } else {
+                "Press Enter to chat".to_string()
+            };
+        }
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_chat_input() {
+        assert_eq!(parse_chat_input("  ciao  "), Ok(ClientMessage::Chat { text: "ciao".to_string() }));
+        assert_eq!(
+            parse_chat_input("/w ivan  see you later"),
+            Ok(ClientMessage::Whisper { to: "ivan".to_string(), text: "see you later".to_string() })
+        );
+        assert_eq!(parse_chat_input("/w ivan"), Err(ChatInputError::WhisperUsage));
+        assert_eq!(parse_chat_input("/w ivan   "), Err(ChatInputError::WhisperUsage));
+        assert_eq!(parse_chat_input("/kick ivan"), Err(ChatInputError::UnknownCommand("/kick".to_string())));
+        assert_eq!(parse_chat_input("   "), Err(ChatInputError::Empty));
+        assert_eq!(parse_chat_input(&"a".repeat(MAX_CHAT_LENGTH + 1)), Err(ChatInputError::TooLong));
+        // The limit counts characters, not bytes
+        assert!(parse_chat_input(&"è".repeat(MAX_CHAT_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2., 1., start);
+
+        assert!(limiter.try_acquire(start));
+        assert!(limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start + Duration::from_millis(500)));
+        assert!(limiter.try_acquire(start + Duration::from_millis(1000)));
+        // A long pause never gives more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.try_acquire(later) && limiter.try_acquire(later));
+        assert!(!limiter.try_acquire(later));
+    }
+
+    #[test]
+    fn test_chat_log_scroll() {
+        let mut log = ChatLog::default();
+        for i in 0..CHAT_VISIBLE_LINES + 4 {
+            log.push(ChatLine::system(i.to_string()));
+        }
+        let last = |log: &ChatLog| log.visible_lines().last().unwrap().text.clone();
+        assert_eq!(log.visible_lines().count(), CHAT_VISIBLE_LINES);
+        assert_eq!(last(&log), (CHAT_VISIBLE_LINES + 3).to_string());
+
+        log.scroll_by(100); // Stops at the oldest line
+        assert_eq!(log.visible_lines().next().unwrap().text, "0");
+        log.push(ChatLine::system("new"));
+        assert_eq!(log.visible_lines().next().unwrap().text, "0");
+
+        log.scroll_by(-100);
+        assert_eq!(last(&log), "new");
+    }
+}
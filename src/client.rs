@@ -6,19 +6,143 @@ use std::net::{TcpStream, TcpListener};
 use std::io::{self, Write, Read};
 use std::thread;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq, Eq)]
+use rand::Rng;
+
+use crate::protocol::{read_frame, read_json, write_frame, write_json, ClientMessage, ServerMessage, Transport};
+
+// How often the client pings the server
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// A server silent for this long is considered gone, even if the socket looks open
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+// Weight of a new sample in the smoothed round-trip time
+const RTT_SMOOTHING: f64 = 0.125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ClientState {
     Connected,
     Disconnected,
     Connecting,
 }
 
+/// How long to wait between reconnection attempts and when to give up
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f32, // Delay growth after every failed attempt
+    pub jitter: f32, // Fraction of the delay randomly added or removed (0.0 - 1.0)
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 8,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before retrying after `attempt` failures, without jitter
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let factor = (self.multiplier as f64).powi(attempt as i32);
+        let delay = self.initial_delay.as_secs_f64() * factor;
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// Delay before retrying after `attempt` failures, jittered so many
+    /// clients dropped together don't all come back at the same instant
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay(attempt).as_secs_f64();
+        let jitter = base * self.jitter.clamp(0., 1.) as f64;
+        if jitter <= 0. {
+            return Duration::from_secs_f64(base);
+        }
+        Duration::from_secs_f64(rand::thread_rng().gen_range(base - jitter..=base + jitter))
+    }
+}
+
+/// Ping bookkeeping: measures the round-trip time to the server and notices
+/// when the server stopped talking
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat {
+    next_nonce: u64,
+    pending: Option<(u64, Instant)>, // Last ping sent, until its Pong arrives
+    rtt: Option<Duration>, // Smoothed, None until the first Pong
+    last_received: Option<Instant>, // Any message counts, not only the Pongs
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat::default()
+    }
+
+    /// Starts over on a new connection
+    pub fn reset(&mut self, now: Instant) {
+        self.pending = None;
+        self.rtt = None;
+        self.last_received = Some(now);
+    }
+
+    /// The next ping to send, its Pong is matched by nonce
+    pub fn ping(&mut self, now: Instant) -> ClientMessage {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.pending = Some((nonce, now));
+        ClientMessage::Ping { nonce }
+    }
+
+    pub fn on_message(&mut self, message: &ServerMessage, now: Instant) {
+        self.last_received = Some(now);
+        let ServerMessage::Pong { nonce } = message else {
+            return;
+        };
+        // The Pong of an older ping can arrive after a newer ping was sent, skip it
+        let Some((_, sent_at)) = self.pending.filter(|(pending, _)| pending == nonce) else {
+            return;
+        };
+        self.pending = None;
+
+        let sample = now.saturating_duration_since(sent_at);
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f64(1. - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+            None => sample,
+        });
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// True if nothing arrived from the server for longer than `timeout`
+    pub fn is_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        self.last_received.is_some_and(|last| now.saturating_duration_since(last) > timeout)
+    }
+}
+
+/// What a client needs to open (and reopen) a TLS connection
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    pub domain: String, // Must match the name in the server certificate
+    pub connector: TlsConnector,
+}
+
 #[derive(Debug)]
 pub struct Client {
-    pub stream: Option<TcpStream>, // Make it optional to handle disconnected state
+    pub stream: Option<Transport>, // Make it optional to handle disconnected state
     pub state: ClientState,
     pub last_message: String,
+    pub address: Option<String>, // Last server address, used to reconnect
+    pub reconnect_policy: ReconnectPolicy,
+    pub session_token: Option<String>, // Given by the server after the login
+    pub tls: Option<ClientTls>, // None for plain TCP
+    pub heartbeat: Heartbeat,
 }
 
 impl Client {
@@ -28,13 +152,34 @@ impl Client {
             stream: None,
             state: ClientState::Disconnected,
             last_message: String::new(),
+            address: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            session_token: None,
+            tls: None,
+            heartbeat: Heartbeat::new(),
         }
     }
 
-    // Set the stream for the client
-    pub fn set_stream(&mut self, stream: TcpStream) {
-        self.stream = Some(stream);
+    // Create a disconnected client that already knows where the server is
+    pub fn with_address(address: &str) -> Self {
+        Client {
+            address: Some(address.to_string()),
+            ..Client::new()
+        }
+    }
+
+    // Set the stream (plain or TLS) for the client
+    pub fn set_stream(&mut self, stream: impl Into<Transport>) {
+        self.stream = Some(stream.into());
+        self.state = ClientState::Connected;
+        self.heartbeat.reset(Instant::now());
+    }
+
+    /// Connected over a socket the Client doesn't own, e.g. the UDP one in udp.rs
+    pub fn set_connected(&mut self) {
+        self.stream = None;
         self.state = ClientState::Connected;
+        self.heartbeat.reset(Instant::now());
     }
 
     // Connect to the server
@@ -42,17 +187,31 @@ impl Client {
         let stream = TcpStream::connect(address)?;
         println!("Connected to the server at {}", address);
         Ok(Client {
-            stream: Some(stream),
+            stream: Some(stream.into()),
             state: ClientState::Connected,
-            last_message: String::new(),
+            ..Client::with_address(address)
         })
     }
 
-    pub fn connect_with_tls(address: &str) -> io::Result<TlsStream<TcpStream>> {
-        let connector = TlsConnector::new().unwrap();
-        let stream = TcpStream::connect(address)?;
-        let tls_stream = connector.connect("server_name", stream).expect("TLS connection failed");
-        Ok(tls_stream)
+    // Connect over TLS, trusting the system root certificates
+    pub fn connect_with_tls(address: &str, domain: &str) -> io::Result<Self> {
+        let connector = TlsConnector::new().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Self::connect_with_tls_connector(address, domain, connector)
+    }
+
+    // Connect over TLS with a custom connector (e.g. one trusting a self-signed certificate)
+    pub fn connect_with_tls_connector(address: &str, domain: &str, connector: TlsConnector) -> io::Result<Self> {
+        let mut client = Client::with_address(address);
+        client.tls = Some(ClientTls { domain: domain.to_string(), connector });
+        let transport = client.open_transport(address)?;
+        client.set_stream(transport);
+        println!("Connected to the server at {} (TLS)", address);
+        Ok(client)
+    }
+
+    // Opens a new connection, wrapped in TLS when the client is configured for it
+    fn open_transport(&self, address: &str) -> io::Result<Transport> {
+        open_transport(address, self.tls.as_ref())
     }
 
     pub fn is_connected(&self) -> bool {
@@ -61,22 +220,55 @@ impl Client {
 
     // Disconnect from the server
     pub fn disconnect(&mut self) {
-        if let Some(stream) = self.stream.take() {
+        if let Some(mut stream) = self.stream.take() {
             // Close the stream explicitly
-            let _ = stream.shutdown(std::net::Shutdown::Both);
+            let _ = stream.shutdown();
         }
         self.state = ClientState::Disconnected;
         println!("Disconnected from the server");
     }
 
+    // Try to connect again to the last address, waiting longer after every failure.
+    // Blocks until connected or out of attempts, returns the address on success
     pub fn reconnect(&mut self) -> io::Result<String> {
-        Err(io::Error::new(io::ErrorKind::Unsupported, "Unable to reconnect. Function not implemented."))
+        let address = self.address.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "No server address to reconnect to")
+        })?;
+        if self.stream.is_some() {
+            self.disconnect();
+        }
+
+        let max_attempts = self.reconnect_policy.max_attempts;
+        let mut last_error = None;
+        for attempt in 0..max_attempts {
+            if attempt > 0 {
+                thread::sleep(self.reconnect_policy.delay(attempt - 1));
+            }
+
+            self.state = ClientState::Connecting;
+            match self.open_transport(&address) {
+                Ok(stream) => {
+                    self.set_stream(stream);
+                    println!("Reconnected to the server at {}", address);
+                    return Ok(address);
+                }
+                Err(e) => {
+                    eprintln!("Reconnect attempt {} of {} failed: {}", attempt + 1, max_attempts, e);
+                    self.state = ClientState::Disconnected;
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::TimedOut, "Reconnecting is disabled (max_attempts is 0)")
+        }))
     }
 
-    // Send a message to the server
+    // Send a message to the server as a single frame
     pub fn send_message(&mut self, message: &str) -> io::Result<()> {
         if let Some(ref mut stream) = self.stream {
-            stream.write_all(message.as_bytes())?;
+            write_frame(stream, message.as_bytes())?;
             self.last_message = message.to_string(); // Store the last message
             Ok(())
         } else {
@@ -84,18 +276,49 @@ impl Client {
         }
     }
 
-    // Receive a message from the server
+    // Receive a whole frame from the server
     pub fn receive_message(&mut self) -> io::Result<String> {
         if let Some(ref mut stream) = self.stream {
-            let mut buffer = [0; 512];
-            let bytes_read = stream.read(&mut buffer)?;
-            let response = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
+            let payload = read_frame(stream)?;
+            let response = String::from_utf8_lossy(&payload).to_string();
             Ok(response)
         } else {
             Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server"))
         }
     }
 
+    // Send a typed message to the server
+    pub fn send(&mut self, message: &ClientMessage) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            write_json(stream, message)?;
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server"))
+        }
+    }
+
+    // Receive a typed message from the server
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        if let Some(ref mut stream) = self.stream {
+            let message = read_json(stream)?;
+            self.heartbeat.on_message(&message, Instant::now());
+            Ok(message)
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server"))
+        }
+    }
+
+    // Send a heartbeat ping, the round-trip time is updated when `receive` gets the Pong
+    pub fn ping(&mut self) -> io::Result<()> {
+        let ping = self.heartbeat.ping(Instant::now());
+        self.send(&ping)
+    }
+
+    // Smoothed round-trip time to the server, None until a Pong came back
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.rtt()
+    }
+
     // Run the client loop
     //  keep the client in a loop as long as it's connected. 
     // You can add additional logic to handle reconnections, timeouts, etc.
@@ -104,20 +327,79 @@ impl Client {
             // Example of handling messages
             let mut input = String::new();
             io::stdin().read_line(&mut input)?;
-            self.send_message(&input.trim())?;
+            let message = match input.trim() {
+                "status" => ClientMessage::Status,
+                "leave" => ClientMessage::Leave,
+                "logout" => ClientMessage::Logout,
+                text => ClientMessage::Chat { text: text.to_string() },
+            };
+            self.send(&message)?;
+            if message == ClientMessage::Leave {
+                self.disconnect();
+                break;
+            }
 
-            let response = self.receive_message()?;
-            println!("Received from server: {}", response);
+            let response = self.receive()?;
+            println!("Received from server: {:?}", response);
         }
         Ok(())
     }
 
-    pub fn authenticate(&self) -> u8 {
-        0
+    // Login handshake, must be the first message after connecting.
+    // Returns the session token, also kept in `session_token` for `resume_session`
+    pub fn authenticate(&mut self, username: &str, password: &str) -> io::Result<String> {
+        self.send(&ClientMessage::Login {
+            username: username.to_string(),
+            password: password.to_string(),
+        })?;
+        self.wait_for_login()
+    }
+
+    // Log in again with the token of the previous session (e.g. after `reconnect`)
+    pub fn resume_session(&mut self) -> io::Result<String> {
+        let session_token = self.session_token.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "No session to resume")
+        })?;
+        self.send(&ClientMessage::Resume { session_token })?;
+        self.wait_for_login()
+    }
+
+    fn wait_for_login(&mut self) -> io::Result<String> {
+        match self.receive()? {
+            ServerMessage::LoginAccepted { session_token, .. } => {
+                self.session_token = Some(session_token.clone());
+                Ok(session_token)
+            }
+            ServerMessage::LoginRejected { reason } => {
+                self.session_token = None;
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+            }
+            ServerMessage::Disconnected { reason } => {
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
+            }
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected answer to login: {:?}", other),
+            )),
+        }
     }
 }
 
 
+/// Connects to the server, over TLS when `tls` is given
+pub fn open_transport(address: &str, tls: Option<&ClientTls>) -> io::Result<Transport> {
+    let stream = TcpStream::connect(address)?;
+    match tls {
+        Some(tls) => {
+            let tls_stream = tls.connector.connect(&tls.domain, stream).map_err(|e| {
+                io::Error::new(io::ErrorKind::ConnectionRefused, format!("TLS handshake failed: {}", e))
+            })?;
+            Ok(Transport::Tls(tls_stream))
+        }
+        None => Ok(Transport::Plain(stream)),
+    }
+}
+
 
 // ================== TEST DOWN HERE ==================
 
@@ -192,6 +474,110 @@ mod tests {
         assert_eq!(response, message);
     }
 
+    fn fast_policy(max_attempts: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(100),
+            max_attempts,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    // An address nobody is listening on (for now)
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_reconnect_policy_backoff() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: 5,
+        };
+
+        assert_eq!(policy.base_delay(0), Duration::from_millis(100));
+        assert_eq!(policy.base_delay(1), Duration::from_millis(200));
+        assert_eq!(policy.base_delay(3), Duration::from_millis(800));
+        assert_eq!(policy.base_delay(4), Duration::from_secs(1)); // Capped
+        assert_eq!(policy.base_delay(100), Duration::from_secs(1));
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(99) && delay <= Duration::from_millis(301));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_without_address() {
+        let mut client = Client::new();
+        let error = client.reconnect().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_reconnect_gives_up() {
+        let mut client = Client::with_address(&free_address());
+        client.reconnect_policy = fast_policy(3);
+
+        assert!(client.reconnect().is_err());
+        assert_eq!(client.state, ClientState::Disconnected);
+    }
+
+    #[test]
+    fn test_reconnect_when_server_comes_back() {
+        let address = free_address();
+        let mut client = Client::with_address(&address);
+        client.reconnect_policy = fast_policy(20);
+
+        // The server only comes up after the first attempts failed
+        let server_address = address.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let listener = TcpListener::bind(server_address).unwrap();
+            let _ = listener.accept();
+        });
+
+        assert_eq!(client.reconnect().unwrap(), address);
+        assert_eq!(client.state, ClientState::Connected);
+    }
+
+    #[test]
+    fn test_heartbeat_measures_rtt() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.reset(start);
+        assert_eq!(heartbeat.rtt(), None);
+
+        let ClientMessage::Ping { nonce } = heartbeat.ping(start) else { unreachable!() };
+        heartbeat.on_message(&ServerMessage::Pong { nonce }, start + Duration::from_millis(80));
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(80)));
+
+        // Later samples are smoothed, a stale Pong is ignored
+        let sent = start + Duration::from_secs(1);
+        let ClientMessage::Ping { nonce } = heartbeat.ping(sent) else { unreachable!() };
+        heartbeat.on_message(&ServerMessage::Pong { nonce: nonce - 1 }, sent);
+        heartbeat.on_message(&ServerMessage::Pong { nonce }, sent + Duration::from_millis(160));
+        let rtt = heartbeat.rtt().unwrap().as_secs_f64();
+        assert!((rtt - 0.090).abs() < 1e-6, "{}", rtt);
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new();
+        assert!(!heartbeat.is_timed_out(start + HEARTBEAT_TIMEOUT * 2, HEARTBEAT_TIMEOUT)); // Never connected
+
+        heartbeat.reset(start);
+        let status = ServerMessage::Status { message: "alive".to_string() };
+        heartbeat.on_message(&status, start + HEARTBEAT_TIMEOUT);
+        assert!(!heartbeat.is_timed_out(start + HEARTBEAT_TIMEOUT * 2, HEARTBEAT_TIMEOUT));
+        assert!(heartbeat.is_timed_out(start + HEARTBEAT_TIMEOUT * 3, HEARTBEAT_TIMEOUT));
+    }
+
     #[test]
     fn test_client_receive_empty_message() {
         let address = "127.0.0.1:8082"; // Different port for the mock server
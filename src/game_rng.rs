@@ -0,0 +1,120 @@
+// Generatore casuale del gioco: un solo seme, un flusso separato per ogni sottosistema.
+//
+// Every system that needs randomness takes `ResMut<GameRng>` and draws from
+// its own `RngStream`, so the same seed gives the same card deals, ball
+// spawns and particle bursts, and one system drawing more or less doesn't
+// change what the others get. The seed is random unless SEED_ENV is set, e.g.
+// `IVAN_GAME_SEED=42` to replay a session or reproduce a bug.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+
+// ====== CONSTANTS ======
+
+pub const SEED_ENV: &str = "IVAN_GAME_SEED";
+
+
+// ====== STRUCTS ======
+
+/// The systems drawing random numbers, each with its own stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RngStream {
+    Cards = 1,
+    Balls = 2,
+    Particles = 3,
+    Solitaire = 4,
+    Blackjack = 5,
+}
+
+#[derive(Debug, Clone, Resource)]
+pub struct GameRng {
+    seed: u64,
+    streams: HashMap<RngStream, StdRng>, // Made on first use
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        GameRng { seed, streams: HashMap::new() }
+    }
+
+    /// Seeded from SEED_ENV when it is set to a number, at random otherwise
+    pub fn from_env() -> Self {
+        let seed = match std::env::var(SEED_ENV).map(|seed| seed.parse::<u64>()) {
+            Ok(Ok(seed)) => seed,
+            Ok(Err(e)) => {
+                eprintln!("{} is not a number, using a random seed: {}", SEED_ENV, e);
+                rand::random()
+            }
+            Err(_) => rand::random(),
+        };
+        println!("Game seed: {}", seed);
+        Self::new(seed)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Starts every stream over from a new seed
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.streams.clear();
+    }
+
+    pub fn stream(&mut self, stream: RngStream) -> &mut StdRng {
+        let seed = stream_seed(self.seed, stream);
+        self.streams.entry(stream).or_insert_with(|| StdRng::seed_from_u64(seed))
+    }
+}
+
+
+// ====== METHODS ======
+
+// Spreads the game seed and the stream apart (splitmix64), so nearby seeds don't give related streams
+fn stream_seed(seed: u64, stream: RngStream) -> u64 {
+    let mut z = seed ^ (stream as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn draws(rng: &mut GameRng, stream: RngStream) -> Vec<u32> {
+        (0..8).map(|_| rng.stream(stream).gen()).collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_numbers() {
+        let mut first = GameRng::new(42);
+        let mut second = GameRng::new(42);
+        assert_eq!(draws(&mut first, RngStream::Cards), draws(&mut second, RngStream::Cards));
+        assert_ne!(draws(&mut GameRng::new(43), RngStream::Cards), draws(&mut GameRng::new(42), RngStream::Cards));
+
+        first.reseed(42);
+        assert_eq!(draws(&mut first, RngStream::Cards), draws(&mut GameRng::new(42), RngStream::Cards));
+    }
+
+    #[test]
+    fn test_streams_are_independent() {
+        let mut busy = GameRng::new(7);
+        for _ in 0..100 {
+            busy.stream(RngStream::Particles).gen::<f32>();
+        }
+        let mut quiet = GameRng::new(7);
+        assert_eq!(draws(&mut busy, RngStream::Balls), draws(&mut quiet, RngStream::Balls));
+        assert_ne!(draws(&mut quiet, RngStream::Balls), draws(&mut quiet, RngStream::Cards));
+    }
+}
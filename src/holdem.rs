@@ -0,0 +1,603 @@
+// Regole del Texas Hold'em: una mano dai bui allo showdown, senza Bevy né rete.
+//
+// `Holdem` plays a single hand: it posts the blinds, deals the hole cards,
+// checks every action of the player whose turn it is, deals the board street
+// by street and at the end pays each pot (side pots included) to the best
+// hands among the players who put chips in it. The server drives it with the
+// actions it receives and sends everyone only what they may see.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cards::{Card, Deck};
+use crate::poker::{evaluate, split_pot, winners, HandRank};
+
+
+// ====== CONSTANTS ======
+
+pub const MIN_PLAYERS: usize = 2;
+pub const MAX_PLAYERS: usize = 10;
+pub const HOLE_CARDS: usize = 2;
+pub const BOARD_CARDS: usize = 5;
+const BURNED_CARDS: usize = 3; // One before each street
+
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HoldemConfig {
+    pub small_blind: u64,
+    pub big_blind: u64,
+}
+
+impl Default for HoldemConfig {
+    fn default() -> Self {
+        HoldemConfig { small_blind: 5, big_blind: 10 }
+    }
+}
+
+/// `Showdown` once the hand is over, even when everybody else folded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Street {
+    PreFlop,
+    Flop,
+    Turn,
+    River,
+    Showdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Fold,
+    Check,
+    Call,
+    /// Raise the bet on this street to this total
+    Raise(u64),
+    /// Every chip left: a call or a raise, whichever it turns out to be
+    AllIn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldemError {
+    NotEnoughPlayers,
+    TooManyPlayers,
+    BadDeck { needed: usize },
+    UnknownPlayer,
+    HandOver,
+    NotYourTurn,
+    CannotCheck,
+    CannotRaise,
+    RaiseTooSmall { min: u64 },
+    NotEnoughChips,
+}
+
+impl fmt::Display for HoldemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HoldemError::NotEnoughPlayers => write!(f, "At least {} players with chips are needed", MIN_PLAYERS),
+            HoldemError::TooManyPlayers => write!(f, "At most {} players fit at the table", MAX_PLAYERS),
+            HoldemError::BadDeck { needed } => write!(f, "The deck must have at least {} different cards", needed),
+            HoldemError::UnknownPlayer => write!(f, "That player is not in the hand"),
+            HoldemError::HandOver => write!(f, "The hand is over"),
+            HoldemError::NotYourTurn => write!(f, "It's not your turn"),
+            HoldemError::CannotCheck => write!(f, "There is a bet to call"),
+            HoldemError::CannotRaise => write!(f, "The betting was not reopened, call or fold"),
+            HoldemError::RaiseTooSmall { min } => write!(f, "The raise must be to at least {}", min),
+            HoldemError::NotEnoughChips => write!(f, "Not enough chips"),
+        }
+    }
+}
+
+impl std::error::Error for HoldemError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seat {
+    pub player: usize,
+    pub chips: u64,
+    /// Put in on the current street
+    pub bet: u64,
+    /// Put in during the whole hand
+    pub committed: u64,
+    pub folded: bool,
+    hole: Vec<Card>,
+    needs_to_act: bool,
+    may_raise: bool, // False after a short all in: who already acted can only call it
+}
+
+impl Seat {
+    pub fn is_all_in(&self) -> bool {
+        !self.folded && self.chips == 0
+    }
+
+    fn can_bet(&self) -> bool {
+        !self.folded && self.chips > 0
+    }
+
+    fn put(&mut self, amount: u64) {
+        let amount = amount.min(self.chips);
+        self.chips -= amount;
+        self.bet += amount;
+        self.committed += amount;
+    }
+}
+
+/// Chips and the players who can win them, the main pot first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pot {
+    pub amount: u64,
+    pub eligible: Vec<usize>,
+}
+
+/// Chips won from a pot; `hand` is `None` when everyone else folded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payout {
+    pub pot: usize,
+    pub player: usize,
+    pub amount: u64,
+    pub hand: Option<HandRank>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Holdem {
+    config: HoldemConfig,
+    seats: Vec<Seat>,
+    deck: Deck<Card>,
+    board: Vec<Card>,
+    button: usize,
+    street: Street,
+    to_act: Option<usize>, // Seat index
+    current_bet: u64,
+    min_raise: u64, // The last raise, a new one must be at least as big
+    payouts: Vec<Payout>,
+}
+
+impl Holdem {
+    /// Starts a hand: `players` are (player id, chips) in seat order and
+    /// `button` the index of the dealer among them; the deck is dealt as it is
+    pub fn new(config: HoldemConfig, players: &[(usize, u64)], button: usize, mut deck: Deck<Card>) -> Result<Self, HoldemError> {
+        if players.len() < MIN_PLAYERS || players.iter().any(|(_, chips)| *chips == 0) {
+            return Err(HoldemError::NotEnoughPlayers);
+        }
+        if players.len() > MAX_PLAYERS {
+            return Err(HoldemError::TooManyPlayers);
+        }
+        let count = players.len();
+        let button = button % count;
+        let needed = count * HOLE_CARDS + BURNED_CARDS + BOARD_CARDS;
+        let different: HashSet<&Card> = deck.cards().iter().collect();
+        if deck.len() < needed || different.len() != deck.len() {
+            return Err(HoldemError::BadDeck { needed });
+        }
+
+        // One card at a time to everyone, starting left of the button
+        let mut hands = deck.deal(count, HOLE_CARDS).map_err(|_| HoldemError::BadDeck { needed })?;
+        hands.rotate_right(button + 1);
+        let seats = players
+            .iter()
+            .zip(hands)
+            .map(|((player, chips), hole)| Seat {
+                player: *player,
+                chips: *chips,
+                bet: 0,
+                committed: 0,
+                folded: false,
+                hole,
+                needs_to_act: true,
+                may_raise: true,
+            })
+            .collect();
+
+        let mut holdem = Holdem {
+            config,
+            seats,
+            deck,
+            board: Vec::new(),
+            button,
+            street: Street::PreFlop,
+            to_act: None,
+            current_bet: config.big_blind,
+            min_raise: config.big_blind,
+            payouts: Vec::new(),
+        };
+
+        // Heads up the button is the small blind
+        let small_blind = if count == 2 { button } else { (button + 1) % count };
+        let big_blind = (small_blind + 1) % count;
+        holdem.seats[small_blind].put(config.small_blind);
+        holdem.seats[big_blind].put(config.big_blind);
+        holdem.to_act = holdem.next_to_act(big_blind);
+        if holdem.to_act.is_none() {
+            holdem.next_street(); // The blinds put everyone all in
+        }
+        Ok(holdem)
+    }
+
+    pub fn new_shuffled(config: HoldemConfig, players: &[(usize, u64)], button: usize, seed: u64) -> Result<Self, HoldemError> {
+        let mut deck = Deck::standard();
+        deck.shuffle_with_seed(seed);
+        Self::new(config, players, button, deck)
+    }
+
+    /// The action of `player`, who must be the one to act
+    pub fn act(&mut self, player: usize, action: Action) -> Result<(), HoldemError> {
+        if self.street == Street::Showdown {
+            return Err(HoldemError::HandOver);
+        }
+        let index = self.seat_index(player).ok_or(HoldemError::UnknownPlayer)?;
+        if self.to_act != Some(index) {
+            return Err(HoldemError::NotYourTurn);
+        }
+
+        let seat = &self.seats[index];
+        let to_call = self.current_bet.saturating_sub(seat.bet);
+        let all_in_to = seat.bet + seat.chips;
+        let may_raise = seat.may_raise;
+        match action {
+            Action::Fold => self.seats[index].folded = true,
+            Action::Check if to_call > 0 => return Err(HoldemError::CannotCheck),
+            Action::Check => {}
+            Action::Call => self.seats[index].put(to_call),
+            Action::AllIn if all_in_to <= self.current_bet || !may_raise => self.seats[index].put(to_call),
+            Action::AllIn => self.raise(index, all_in_to),
+            Action::Raise(_) if !may_raise => return Err(HoldemError::CannotRaise),
+            Action::Raise(to) => {
+                let min = self.min_raise_to();
+                if to > all_in_to {
+                    return Err(HoldemError::NotEnoughChips);
+                }
+                // Going all in for less is allowed, it just doesn't count as a full raise
+                if to < min && to != all_in_to || to <= self.current_bet {
+                    return Err(HoldemError::RaiseTooSmall { min });
+                }
+                self.raise(index, to);
+            }
+        }
+        self.seats[index].needs_to_act = false;
+
+        if self.seats.iter().filter(|seat| !seat.folded).count() == 1 {
+            self.settle();
+            return Ok(());
+        }
+        self.to_act = self.next_to_act(index);
+        if self.to_act.is_none() {
+            self.next_street();
+        }
+        Ok(())
+    }
+
+    /// The pots as they stand, built from what everyone put in
+    pub fn pots(&self) -> Vec<Pot> {
+        let mut levels: Vec<u64> = self.seats.iter().map(|seat| seat.committed).filter(|committed| *committed > 0).collect();
+        levels.sort_unstable();
+        levels.dedup();
+
+        let mut pots: Vec<Pot> = Vec::new();
+        let mut previous = 0;
+        for level in levels {
+            let amount = self.seats.iter().map(|seat| seat.committed.min(level) - seat.committed.min(previous)).sum();
+            let eligible: Vec<usize> = self
+                .seats
+                .iter()
+                .filter(|seat| !seat.folded && seat.committed >= level)
+                .map(|seat| seat.player)
+                .collect();
+            previous = level;
+            match pots.last_mut() {
+                // Levels only folded players reached, or with the same players, join the pot before
+                Some(last) if eligible.is_empty() || last.eligible == eligible => last.amount += amount,
+                _ => pots.push(Pot { amount, eligible }),
+            }
+        }
+        pots
+    }
+
+    pub fn pot_total(&self) -> u64 {
+        self.seats.iter().map(|seat| seat.committed).sum()
+    }
+
+    /// The smallest total a raise can go to on this street
+    pub fn min_raise_to(&self) -> u64 {
+        self.current_bet + self.min_raise
+    }
+
+    pub fn to_call(&self, player: usize) -> Option<u64> {
+        let seat = &self.seats[self.seat_index(player)?];
+        Some(self.current_bet.saturating_sub(seat.bet).min(seat.chips))
+    }
+
+    /// The player whose turn it is, `None` once the hand is over
+    pub fn to_act(&self) -> Option<usize> {
+        self.to_act.map(|index| self.seats[index].player)
+    }
+
+    pub fn hole_cards(&self, player: usize) -> Option<&[Card]> {
+        self.seat_index(player).map(|index| self.seats[index].hole.as_slice())
+    }
+
+    pub fn seats(&self) -> &[Seat] {
+        &self.seats
+    }
+
+    pub fn board(&self) -> &[Card] {
+        &self.board
+    }
+
+    pub fn street(&self) -> Street {
+        self.street
+    }
+
+    pub fn current_bet(&self) -> u64 {
+        self.current_bet
+    }
+
+    /// The seat index of the dealer
+    pub fn button(&self) -> usize {
+        self.button
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.street == Street::Showdown
+    }
+
+    /// Who won what, empty until the hand is over
+    pub fn payouts(&self) -> &[Payout] {
+        &self.payouts
+    }
+
+    fn seat_index(&self, player: usize) -> Option<usize> {
+        self.seats.iter().position(|seat| seat.player == player)
+    }
+
+    fn raise(&mut self, index: usize, to: u64) {
+        // A short all in is only a bet to call for who already acted, it doesn't reopen the betting
+        let full = to - self.current_bet >= self.min_raise;
+        if full {
+            self.min_raise = to - self.current_bet;
+        }
+        self.current_bet = to;
+        let seat = &mut self.seats[index];
+        seat.put(to - seat.bet);
+        for seat in self.seats.iter_mut() {
+            seat.may_raise = full || (seat.may_raise && seat.needs_to_act);
+            seat.needs_to_act = true; // Everyone else gets to answer
+        }
+    }
+
+    // The first seat after `from` still to act on this street
+    fn next_to_act(&self, from: usize) -> Option<usize> {
+        let count = self.seats.len();
+        (1..=count).map(|offset| (from + offset) % count).find(|index| {
+            let seat = &self.seats[*index];
+            seat.can_bet() && seat.needs_to_act
+        })
+    }
+
+    // Deals the next street, all of them if nobody can bet anymore, and settles after the river
+    fn next_street(&mut self) {
+        loop {
+            for seat in self.seats.iter_mut() {
+                seat.bet = 0;
+                seat.needs_to_act = true;
+                seat.may_raise = true;
+            }
+            self.current_bet = 0;
+            self.min_raise = self.config.big_blind;
+
+            let cards = match self.street {
+                Street::PreFlop => 3,
+                Street::Flop | Street::Turn => 1,
+                Street::River | Street::Showdown => {
+                    self.settle();
+                    return;
+                }
+            };
+            self.deck.burn();
+            self.board.extend((0..cards).filter_map(|_| self.deck.draw()));
+            self.street = match self.street {
+                Street::PreFlop => Street::Flop,
+                Street::Flop => Street::Turn,
+                _ => Street::River,
+            };
+
+            if self.seats.iter().filter(|seat| seat.can_bet()).count() >= 2 {
+                self.to_act = self.next_to_act(self.button);
+                return;
+            }
+        }
+    }
+
+    // Pays every pot and ends the hand
+    fn settle(&mut self) {
+        self.street = Street::Showdown;
+        self.to_act = None;
+        let live = self.seats.iter().filter(|seat| !seat.folded).count();
+        let hands: Vec<Option<HandRank>> = self
+            .seats
+            .iter()
+            .map(|seat| {
+                let cards: Vec<Card> = seat.hole.iter().chain(&self.board).copied().collect();
+                if live > 1 && !seat.folded { evaluate(&cards).ok().map(|hand| hand.rank) } else { None }
+            })
+            .collect();
+
+        // The odd chips go to the first winners left of the button
+        let count = self.seats.len();
+        let order: Vec<usize> = (1..=count).map(|offset| (self.button + offset) % count).collect();
+
+        for (pot_index, pot) in self.pots().into_iter().enumerate() {
+            let contenders: Vec<usize> = order.iter().copied().filter(|index| pot.eligible.contains(&self.seats[*index].player)).collect();
+            // Without hands to compare (everyone else folded) the pot is shared by who is left in it
+            let ranked: Vec<(usize, HandRank)> =
+                contenders.iter().filter_map(|index| hands[*index].clone().map(|rank| (*index, rank))).collect();
+            let best: Vec<usize> = if ranked.is_empty() {
+                contenders
+            } else {
+                let ranks: Vec<HandRank> = ranked.iter().map(|(_, rank)| rank.clone()).collect();
+                winners(&ranks).into_iter().map(|place| ranked[place].0).collect()
+            };
+            for (index, amount) in split_pot(pot.amount, &best) {
+                self.seats[index].chips += amount;
+                let player = self.seats[index].player;
+                self.payouts.push(Payout { pot: pot_index, player, amount, hand: hands[index].clone() });
+            }
+        }
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A deck that deals these cards in this order
+    fn rigged_deck(names: &str) -> Deck<Card> {
+        let mut cards: Vec<Card> = names.split_whitespace().map(|name| name.parse().unwrap()).collect();
+        cards.reverse();
+        Deck::from_cards(cards)
+    }
+
+    fn chips(holdem: &Holdem) -> Vec<u64> {
+        holdem.seats().iter().map(|seat| seat.chips).collect()
+    }
+
+    #[test]
+    fn test_blinds_and_betting_rounds() {
+        let mut holdem = Holdem::new_shuffled(HoldemConfig::default(), &[(10, 500), (20, 500), (30, 500)], 0, 7).unwrap();
+        assert_eq!(chips(&holdem), vec![500, 495, 490]);
+        assert!(holdem.seats().iter().all(|seat| holdem.hole_cards(seat.player).unwrap().len() == HOLE_CARDS));
+
+        // Left of the big blind acts first, the big blind closes the round
+        assert_eq!(holdem.to_act(), Some(10));
+        holdem.act(10, Action::Call).unwrap();
+        holdem.act(20, Action::Call).unwrap();
+        assert_eq!(holdem.street(), Street::PreFlop);
+        holdem.act(30, Action::Check).unwrap();
+
+        assert_eq!(holdem.street(), Street::Flop);
+        assert_eq!(holdem.board().len(), 3);
+        assert_eq!(holdem.pot_total(), 30);
+        assert_eq!(holdem.current_bet(), 0);
+        assert_eq!(holdem.to_act(), Some(20)); // Left of the button after the flop
+    }
+
+    #[test]
+    fn test_heads_up_order() {
+        let mut holdem = Holdem::new_shuffled(HoldemConfig::default(), &[(1, 100), (2, 100)], 0, 3).unwrap();
+        assert_eq!(chips(&holdem), vec![95, 90]); // The button posts the small blind...
+        assert_eq!(holdem.to_act(), Some(1)); // ...and acts first before the flop
+        holdem.act(1, Action::Call).unwrap();
+        holdem.act(2, Action::Check).unwrap();
+        assert_eq!(holdem.to_act(), Some(2)); // But last after it
+    }
+
+    #[test]
+    fn test_illegal_actions() {
+        let mut holdem = Holdem::new_shuffled(HoldemConfig::default(), &[(1, 100), (2, 100), (3, 100)], 0, 5).unwrap();
+        assert_eq!(holdem.act(2, Action::Call), Err(HoldemError::NotYourTurn));
+        assert_eq!(holdem.act(9, Action::Call), Err(HoldemError::UnknownPlayer));
+        assert_eq!(holdem.act(1, Action::Check), Err(HoldemError::CannotCheck));
+        assert_eq!(holdem.act(1, Action::Raise(15)), Err(HoldemError::RaiseTooSmall { min: 20 }));
+        assert_eq!(holdem.act(1, Action::Raise(101)), Err(HoldemError::NotEnoughChips));
+
+        holdem.act(1, Action::Raise(30)).unwrap();
+        assert_eq!(holdem.min_raise_to(), 50); // Raised by 20, the next raise too
+        assert_eq!(holdem.act(2, Action::Raise(45)), Err(HoldemError::RaiseTooSmall { min: 50 }));
+        assert_eq!(holdem.to_call(2), Some(25));
+
+        holdem.act(2, Action::Fold).unwrap();
+        holdem.act(3, Action::Fold).unwrap();
+        assert!(holdem.is_over());
+        assert_eq!(holdem.payouts(), &[Payout { pot: 0, player: 1, amount: 45, hand: None }]);
+        assert_eq!(chips(&holdem), vec![115, 95, 90]);
+        assert_eq!(holdem.act(1, Action::Check), Err(HoldemError::HandOver));
+
+        assert_eq!(Holdem::new_shuffled(HoldemConfig::default(), &[(1, 100)], 0, 5).err(), Some(HoldemError::NotEnoughPlayers));
+        assert_eq!(Holdem::new_shuffled(HoldemConfig::default(), &[(1, 100), (2, 0)], 0, 5).err(), Some(HoldemError::NotEnoughPlayers));
+    }
+
+    #[test]
+    fn test_short_all_in_does_not_reopen_betting() {
+        let mut holdem = Holdem::new_shuffled(HoldemConfig::default(), &[(1, 100), (2, 100), (3, 25)], 0, 4).unwrap();
+        holdem.act(1, Action::Raise(20)).unwrap();
+        holdem.act(2, Action::Call).unwrap();
+        holdem.act(3, Action::AllIn).unwrap(); // To 25, less than a full raise to 30
+
+        // Both already acted: they can call the 5 more, not raise again
+        assert_eq!(holdem.to_call(1), Some(5));
+        assert_eq!(holdem.act(1, Action::Raise(50)), Err(HoldemError::CannotRaise));
+        holdem.act(1, Action::AllIn).unwrap();
+        assert_eq!(holdem.street(), Street::PreFlop);
+        holdem.act(2, Action::Call).unwrap();
+        assert_eq!(holdem.street(), Street::Flop);
+        assert_eq!(chips(&holdem), vec![75, 75, 0]);
+    }
+
+    #[test]
+    fn test_bad_deck_is_refused() {
+        let players = [(1, 100), (2, 100)];
+        let short = rigged_deck("2_of_clubs 3_of_clubs 4_of_clubs");
+        assert_eq!(Holdem::new(HoldemConfig::default(), &players, 0, short).err(), Some(HoldemError::BadDeck { needed: 12 }));
+
+        let mut cards: Vec<Card> = Card::all().collect();
+        cards[0] = cards[1];
+        let doubled = Deck::from_cards(cards);
+        assert_eq!(Holdem::new(HoldemConfig::default(), &players, 0, doubled).err(), Some(HoldemError::BadDeck { needed: 12 }));
+    }
+
+    #[test]
+    fn test_side_pots_at_showdown() {
+        // Dealt left of the button first: seat 1, 2, 0, then burn and board
+        let deck = rigged_deck(
+            "king_of_clubs queen_of_clubs ace_of_clubs king_of_hearts 3_of_hearts ace_of_hearts \
+             5_of_spades 2_of_clubs 7_of_diamonds 9_of_spades 6_of_spades jack_of_hearts 8_of_spades 4_of_diamonds",
+        );
+        let mut holdem = Holdem::new(HoldemConfig::default(), &[(1, 50), (2, 100), (3, 200)], 0, deck).unwrap();
+        holdem.act(1, Action::AllIn).unwrap();
+        holdem.act(2, Action::AllIn).unwrap();
+        holdem.act(3, Action::AllIn).unwrap();
+
+        // Nobody can bet anymore, so the board is dealt out
+        assert!(holdem.is_over());
+        assert_eq!(holdem.board().len(), 5);
+        let pots = holdem.pots();
+        assert_eq!(pots, vec![
+            Pot { amount: 150, eligible: vec![1, 2, 3] },
+            Pot { amount: 100, eligible: vec![2, 3] },
+            Pot { amount: 100, eligible: vec![3] },
+        ]);
+
+        // Aces win the main pot, kings the side pot and the last 100 go back to who bet them
+        let won: Vec<(usize, usize, u64)> = holdem.payouts().iter().map(|payout| (payout.pot, payout.player, payout.amount)).collect();
+        assert_eq!(won, vec![(0, 1, 150), (1, 2, 100), (2, 3, 100)]);
+        assert_eq!(chips(&holdem), vec![150, 100, 100]);
+    }
+
+    #[test]
+    fn test_split_pot_odd_chip() {
+        // Everyone plays the straight on the board
+        let deck = rigged_deck(
+            "2_of_clubs 2_of_diamonds 4_of_clubs 3_of_hearts 3_of_spades 5_of_hearts \
+             6_of_clubs 10_of_spades jack_of_hearts queen_of_clubs 7_of_clubs king_of_diamonds 8_of_clubs ace_of_spades",
+        );
+        let mut holdem = Holdem::new(HoldemConfig::default(), &[(1, 100), (2, 100), (3, 100)], 0, deck).unwrap();
+        holdem.act(1, Action::Call).unwrap();
+        holdem.act(2, Action::Fold).unwrap(); // Leaving the small blind behind
+        holdem.act(3, Action::Check).unwrap();
+        for _ in 0..3 {
+            holdem.act(3, Action::Check).unwrap();
+            holdem.act(1, Action::Check).unwrap();
+        }
+        assert!(holdem.is_over());
+        // 25 split in two, the odd chip to the first winner left of the button
+        assert_eq!(chips(&holdem), vec![102, 95, 103]);
+
+        let mut holdem = Holdem::new_shuffled(HoldemConfig::default(), &[(1, 100), (2, 100)], 1, 9).unwrap();
+        holdem.act(2, Action::Fold).unwrap();
+        assert_eq!(chips(&holdem), vec![105, 95]);
+    }
+}
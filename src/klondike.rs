@@ -0,0 +1,399 @@
+// Regole del solitario Klondike, senza Bevy: la scena in solitaire.rs le disegna soltanto.
+//
+// Draw-one Klondike: seven tableau columns dealt 1 to 7 cards with only the
+// last one face up, four foundations built up by suit from the ace, and the
+// stock turned one card at a time onto the waste (recycled when empty).
+// Every legal move is remembered so it can be undone.
+
+use std::fmt;
+
+use crate::cards::{Card, Deck, Rank};
+
+
+// ====== CONSTANTS ======
+
+pub const TABLEAU_COLUMNS: usize = 7;
+pub const FOUNDATIONS: usize = 4;
+
+
+// ====== STRUCTS ======
+
+/// Where cards can be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pile {
+    Stock,
+    Waste,
+    Foundation(usize),
+    Tableau(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableauCard {
+    pub card: Card,
+    pub face_up: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveError {
+    NoSuchPile,
+    /// Fewer cards there than asked, or some of them face down
+    NotMovable,
+    /// Only single cards go to and come from the waste and the foundations
+    OneCardOnly,
+    /// The card doesn't go on that pile
+    Illegal,
+    NothingToDraw,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            MoveError::NoSuchPile => "There is no such pile",
+            MoveError::NotMovable => "Those cards can't be moved",
+            MoveError::OneCardOnly => "Only one card at a time there",
+            MoveError::Illegal => "The card doesn't go there",
+            MoveError::NothingToDraw => "Stock and waste are both empty",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+// Everything on the table, saved before every move for the undo
+#[derive(Debug, Clone, PartialEq)]
+struct Layout {
+    stock: Vec<Card>, // Face down, the top is the end
+    waste: Vec<Card>,
+    foundations: [Vec<Card>; FOUNDATIONS],
+    tableau: [Vec<TableauCard>; TABLEAU_COLUMNS],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Klondike {
+    layout: Layout,
+    history: Vec<Layout>,
+}
+
+impl Klondike {
+    /// Deals the deck as it is, the top card first
+    pub fn new(mut deck: Deck<Card>) -> Self {
+        let mut tableau: [Vec<TableauCard>; TABLEAU_COLUMNS] = Default::default();
+        for row in 0..TABLEAU_COLUMNS {
+            for (column, cards) in tableau.iter_mut().enumerate().skip(row) {
+                if let Some(card) = deck.draw() {
+                    cards.push(TableauCard { card, face_up: column == row });
+                }
+            }
+        }
+        let mut stock = Vec::new();
+        while let Some(card) = deck.draw() {
+            stock.insert(0, card);
+        }
+        let layout = Layout { stock, waste: Vec::new(), foundations: Default::default(), tableau };
+        Klondike { layout, history: Vec::new() }
+    }
+
+    pub fn new_shuffled(seed: u64) -> Self {
+        let mut deck = Deck::standard();
+        deck.shuffle_with_seed(seed);
+        Self::new(deck)
+    }
+
+    /// Turns the top of the stock onto the waste, or the waste back into the stock once empty
+    pub fn draw(&mut self) -> Result<(), MoveError> {
+        let layout = &self.layout;
+        if layout.stock.is_empty() && layout.waste.is_empty() {
+            return Err(MoveError::NothingToDraw);
+        }
+        self.history.push(self.layout.clone());
+        let layout = &mut self.layout;
+        match layout.stock.pop() {
+            Some(card) => layout.waste.push(card),
+            None => {
+                layout.stock = layout.waste.drain(..).rev().collect();
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the top `count` cards of `from` can go on `to`
+    pub fn can_move(&self, from: Pile, count: usize, to: Pile) -> Result<(), MoveError> {
+        let moving = self.movable(from, count)?;
+        let first = moving[0];
+        match to {
+            _ if to == from => Err(MoveError::Illegal),
+            Pile::Stock | Pile::Waste => Err(MoveError::Illegal),
+            Pile::Foundation(index) => {
+                let foundation = self.layout.foundations.get(index).ok_or(MoveError::NoSuchPile)?;
+                if count != 1 {
+                    return Err(MoveError::OneCardOnly);
+                }
+                let fits = match foundation.last() {
+                    None => first.rank == Rank::Ace,
+                    Some(top) => top.suit == first.suit && next_rank(top.rank) == Some(first.rank),
+                };
+                if fits { Ok(()) } else { Err(MoveError::Illegal) }
+            }
+            Pile::Tableau(index) => {
+                let column = self.layout.tableau.get(index).ok_or(MoveError::NoSuchPile)?;
+                let fits = match column.last() {
+                    None => first.rank == Rank::King,
+                    Some(top) => {
+                        top.face_up
+                            && top.card.suit.is_red() != first.suit.is_red()
+                            && next_rank(first.rank) == Some(top.card.rank)
+                    }
+                };
+                if fits { Ok(()) } else { Err(MoveError::Illegal) }
+            }
+        }
+    }
+
+    /// Moves the top `count` cards of `from` on `to`, turning the card left uncovered
+    pub fn move_cards(&mut self, from: Pile, count: usize, to: Pile) -> Result<(), MoveError> {
+        self.can_move(from, count, to)?;
+        self.history.push(self.layout.clone());
+
+        let layout = &mut self.layout;
+        let moving: Vec<Card> = match from {
+            Pile::Waste => layout.waste.pop().into_iter().collect(),
+            Pile::Foundation(index) => layout.foundations[index].pop().into_iter().collect(),
+            Pile::Tableau(index) => {
+                let column = &mut layout.tableau[index];
+                let moving = column.split_off(column.len() - count).into_iter().map(|c| c.card).collect();
+                if let Some(top) = column.last_mut() {
+                    top.face_up = true;
+                }
+                moving
+            }
+            Pile::Stock => unreachable!("can_move never allows it"),
+        };
+        match to {
+            Pile::Foundation(index) => layout.foundations[index].extend(moving),
+            Pile::Tableau(index) => {
+                layout.tableau[index].extend(moving.into_iter().map(|card| TableauCard { card, face_up: true }))
+            }
+            Pile::Stock | Pile::Waste => unreachable!("can_move never allows it"),
+        }
+        Ok(())
+    }
+
+    /// Where the top card of `from` can go on a foundation, if anywhere
+    pub fn foundation_for(&self, from: Pile) -> Option<Pile> {
+        (0..FOUNDATIONS).map(Pile::Foundation).find(|to| self.can_move(from, 1, *to).is_ok())
+    }
+
+    /// Moves to the foundations every card that can go there (the waste and
+    /// the tableau tops, again and again), returns how many were moved
+    pub fn auto_move(&mut self) -> usize {
+        let sources: Vec<Pile> = std::iter::once(Pile::Waste).chain((0..TABLEAU_COLUMNS).map(Pile::Tableau)).collect();
+        let mut moved = 0;
+        while let Some((from, to)) = sources.iter().find_map(|from| self.foundation_for(*from).map(|to| (*from, to))) {
+            if self.move_cards(from, 1, to).is_err() {
+                break;
+            }
+            moved += 1;
+        }
+        moved
+    }
+
+    /// Back to before the last move, false if there is nothing to undo
+    pub fn undo(&mut self) -> bool {
+        match self.history.pop() {
+            Some(layout) => {
+                self.layout = layout;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_won(&self) -> bool {
+        self.layout.foundations.iter().all(|foundation| foundation.len() == Rank::ALL.len())
+    }
+
+    pub fn moves(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn stock(&self) -> &[Card] {
+        &self.layout.stock
+    }
+
+    pub fn waste(&self) -> &[Card] {
+        &self.layout.waste
+    }
+
+    pub fn foundation(&self, index: usize) -> &[Card] {
+        &self.layout.foundations[index]
+    }
+
+    pub fn column(&self, index: usize) -> &[TableauCard] {
+        &self.layout.tableau[index]
+    }
+
+    // The top `count` cards of a pile, if they are face up and can be picked up together
+    fn movable(&self, from: Pile, count: usize) -> Result<Vec<Card>, MoveError> {
+        let layout = &self.layout;
+        let cards: Vec<TableauCard> = match from {
+            Pile::Stock => return Err(MoveError::NotMovable),
+            Pile::Waste => layout.waste.iter().map(|card| TableauCard { card: *card, face_up: true }).collect(),
+            Pile::Foundation(index) => {
+                let foundation = layout.foundations.get(index).ok_or(MoveError::NoSuchPile)?;
+                foundation.iter().map(|card| TableauCard { card: *card, face_up: true }).collect()
+            }
+            Pile::Tableau(index) => layout.tableau.get(index).ok_or(MoveError::NoSuchPile)?.clone(),
+        };
+        if !matches!(from, Pile::Tableau(_)) && count != 1 {
+            return Err(MoveError::OneCardOnly);
+        }
+        if count == 0 || count > cards.len() {
+            return Err(MoveError::NotMovable);
+        }
+        let moving = &cards[cards.len() - count..];
+        if moving.iter().any(|card| !card.face_up) {
+            return Err(MoveError::NotMovable);
+        }
+        Ok(moving.iter().map(|card| card.card).collect())
+    }
+}
+
+fn next_rank(rank: Rank) -> Option<Rank> {
+    let index = Rank::ALL.iter().position(|r| *r == rank)?;
+    // The ace comes first on the foundations
+    match rank {
+        Rank::Ace => Some(Rank::Two),
+        Rank::King => None,
+        _ => Rank::ALL.get(index + 1).copied(),
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cards::Suit;
+
+    fn card(name: &str) -> Card {
+        name.parse().unwrap()
+    }
+
+    fn up(name: &str) -> TableauCard {
+        TableauCard { card: card(name), face_up: true }
+    }
+
+    // A game with only the given piles, everything else empty
+    fn game(tableau: Vec<Vec<TableauCard>>, waste: &[&str], foundations: Vec<Vec<Card>>) -> Klondike {
+        let mut layout = Layout {
+            stock: Vec::new(),
+            waste: waste.iter().map(|name| card(name)).collect(),
+            foundations: Default::default(),
+            tableau: Default::default(),
+        };
+        for (index, column) in tableau.into_iter().enumerate() {
+            layout.tableau[index] = column;
+        }
+        for (index, foundation) in foundations.into_iter().enumerate() {
+            layout.foundations[index] = foundation;
+        }
+        Klondike { layout, history: Vec::new() }
+    }
+
+    #[test]
+    fn test_deal() {
+        let game = Klondike::new_shuffled(3);
+        for index in 0..TABLEAU_COLUMNS {
+            let column = game.column(index);
+            assert_eq!(column.len(), index + 1);
+            assert!(column[..index].iter().all(|card| !card.face_up));
+            assert!(column[index].face_up);
+        }
+        assert_eq!(game.stock().len(), 52 - 28);
+        assert!(game.waste().is_empty());
+        assert_eq!(game, Klondike::new_shuffled(3));
+    }
+
+    #[test]
+    fn test_draw_and_recycle() {
+        let mut game = Klondike::new_shuffled(5);
+        let stock: Vec<Card> = game.stock().to_vec();
+        for _ in 0..stock.len() {
+            game.draw().unwrap();
+        }
+        assert!(game.stock().is_empty());
+        assert_eq!(game.waste().last(), stock.first());
+
+        game.draw().unwrap(); // Back to the stock, in the same order
+        assert_eq!(game.stock(), stock);
+        assert!(game.waste().is_empty());
+    }
+
+    #[test]
+    fn test_tableau_rules() {
+        let mut game = game(
+            vec![
+                vec![TableauCard { card: card("2_of_clubs"), face_up: false }, up("9_of_hearts"), up("8_of_spades")],
+                vec![up("10_of_spades")],
+                vec![up("10_of_diamonds")],
+                vec![],
+            ],
+            &["queen_of_hearts"],
+            vec![],
+        );
+        // Same color, a face down card, a queen on an empty column
+        assert_eq!(game.can_move(Pile::Tableau(0), 2, Pile::Tableau(2)), Err(MoveError::Illegal));
+        assert_eq!(game.can_move(Pile::Tableau(0), 3, Pile::Tableau(1)), Err(MoveError::NotMovable));
+        assert_eq!(game.can_move(Pile::Waste, 1, Pile::Tableau(3)), Err(MoveError::Illegal));
+
+        game.move_cards(Pile::Tableau(0), 2, Pile::Tableau(1)).unwrap();
+        assert_eq!(game.column(1).len(), 3);
+        assert_eq!(game.column(0), [up("2_of_clubs")]); // Turned face up
+        assert_eq!(game.can_move(Pile::Waste, 2, Pile::Tableau(3)), Err(MoveError::OneCardOnly));
+
+        assert!(game.undo());
+        assert_eq!(game.column(0).len(), 3);
+        assert!(!game.column(0)[0].face_up);
+        assert!(!game.undo());
+    }
+
+    #[test]
+    fn test_foundations_and_auto_move() {
+        let mut game = game(
+            vec![vec![up("3_of_hearts"), up("2_of_hearts")], vec![up("ace_of_hearts")], vec![up("ace_of_spades")]],
+            &["3_of_spades", "2_of_spades"],
+            vec![],
+        );
+        assert_eq!(game.can_move(Pile::Tableau(0), 1, Pile::Foundation(0)), Err(MoveError::Illegal));
+        assert_eq!(game.foundation_for(Pile::Tableau(1)), Some(Pile::Foundation(0)));
+
+        assert_eq!(game.auto_move(), 6);
+        let suits: Vec<Suit> = (0..FOUNDATIONS).filter_map(|index| game.foundation(index).last()).map(|c| c.suit).collect();
+        assert_eq!(suits.len(), 2);
+        assert!((0..FOUNDATIONS).all(|index| game.foundation(index).len() != 1));
+        assert!(game.waste().is_empty());
+        assert!(!game.is_won());
+    }
+
+    #[test]
+    fn test_win() {
+        let foundations: Vec<Vec<Card>> = Suit::ALL
+            .into_iter()
+            .map(|suit| {
+                let mut cards: Vec<Card> = Rank::ALL.into_iter().map(|rank| Card::new(rank, suit)).collect();
+                cards.rotate_right(1); // The ace first
+                cards
+            })
+            .collect();
+        let mut game = game(vec![], &[], foundations);
+        assert!(game.is_won());
+        assert_eq!(game.draw(), Err(MoveError::NothingToDraw));
+
+        game.move_cards(Pile::Foundation(0), 1, Pile::Tableau(0)).unwrap(); // A king on an empty column
+        assert!(!game.is_won());
+    }
+}
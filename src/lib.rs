@@ -10,17 +10,39 @@ mod constants;
 mod ui;
 mod player;
 mod particles;
-mod cards;
+pub mod cards;
+mod card_animation;
+pub mod card_assets;
+mod card_svg;
+mod picking;
+mod game_rng;
 mod app_state;
 mod tilemaps;
 mod buttons;
 mod pendulum;
 mod scene5;
 mod scene1;
+pub mod klondike;
+pub mod poker;
+pub mod holdem;
+mod solitaire;
+pub mod blackjack;
+mod blackjack_scene;
 
 pub mod server;
+mod async_server;
+pub mod udp;
 pub mod client;
+pub mod protocol;
+pub mod auth;
+pub mod rooms;
+pub mod table;
+pub mod simulation;
+pub mod recording;
 mod networking;
+mod prediction;
+mod chat;
+mod card_table;
 mod collisions;
 mod filling_circle_timer;
 
@@ -50,17 +72,26 @@ use particles::*;
 use player::*;
 use ui::*;
 use cards::*;
+use card_animation::*;
+use card_assets::*;
+use picking::*;
+use game_rng::*;
 use app_state::*;
 use tilemaps::*;
 use buttons::*;
 use pendulum::*;
 use scene5::*;
 use scene1::*;
+use solitaire::*;
+use blackjack_scene::*;
 use collisions::*;
 use filling_circle_timer::*;
 
 use client::*;
 use networking::*;
+use prediction::*;
+use chat::*;
+use card_table::*;
 
 
 // SYSTEM SETS
@@ -103,6 +134,15 @@ pub fn run_game() {
                 TilemapPlugin
             ))
         .add_plugins(WorldInspectorPlugin::new())
+        .add_plugins(NetworkClientPlugin::from_args().with_playback_from_env())
+        .add_plugins(PredictionPlugin)
+        .add_plugins(ChatPlugin)
+        .add_plugins(CardTablePlugin)
+        .add_plugins(SolitairePlugin)
+        .add_plugins(BlackjackPlugin::default())
+        .add_plugins(CardAnimationPlugin)
+        .add_plugins(CardAssetsPlugin)
+        .add_plugins(PickingPlugin)
 
         // RESOURCES - must be initialized after the Default Plugins (else weird crashes happen)
         .insert_resource(WinitSettings {
@@ -112,7 +152,8 @@ pub fn run_game() {
             )),
         })
         // .insert_resource(WinitSettings::desktop_app())
-        .insert_resource(CardHandles { cards_map: HashMap::new() } )
+        .insert_resource(GameRng::from_env())
+        .insert_resource(CardHandles::new(if cfg!(target_arch = "wasm32") { CardArt::Png } else { CardArt::Svg }))
         .insert_resource(SceneStack::new(AppState::Scene3))  // TODO: Start with Scene 1
         .insert_resource(Maps::new())
         .insert_state(AppState::Scene3) // TODO: Match above state
@@ -198,6 +239,7 @@ pub fn run_game() {
             (
                 handle_scene_switch, // one time event, oneshot system
                 (fps_text_update_system, 
+                ping_text_update_system,
                 gravity_text_update_system,
                 update_bloom_settings,).in_set(Scene1Set),
                 (button_system, execute_animations, spawn_slimes_system, update_slime_position).in_set(Scene3Set),
@@ -268,13 +310,10 @@ fn world_setup(mut commands: Commands,) {
 fn assets_setup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    card_handles: ResMut<CardHandles>
 ) {
     let icon_handle: Handle<Image> = asset_server.load("icon.png");
     let stars_handle: Handle<Image> = asset_server.load("particles/star.png");
 
-    load_cards_pngs(asset_server, card_handles);
-
     // Store the material handle as a resource
     commands.insert_resource(ParticleMaterialHandle(stars_handle));
 }
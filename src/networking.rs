@@ -1,114 +1,704 @@
 use bevy::prelude::*;
 use bevy::prelude::Timer;
 use serde::{Serialize, Deserialize};
+use native_tls::TlsConnector;
 use std::net::TcpStream;
-use std::io::{self, Write, Read};
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::client::*;
+use crate::protocol::{is_timeout, write_json, ClientMessage, FrameDecoder, FrameError, ServerMessage, SharedTransport};
+use crate::recording::{client_messages, load_recording};
+use crate::server::{DEFAULT_ADDRESS, DEFAULT_PORT};
+use crate::udp::UdpClient;
 
 
-#[derive(Serialize, Deserialize)]
-struct Message {
-    command: String,
-    data: serde_json::Value, // This allows for flexible data fields
+// ====== CONSTANTS ======
+
+pub const PLAYBACK_ENV: &str = "IVAN_GAME_PLAYBACK";
+pub const SERVER_ADDRESS_ENV: &str = "IVAN_GAME_SERVER";
+
+const UDP_POLL_INTERVAL: Duration = Duration::from_millis(5); // Also how late a queued message can leave
+const TCP_READ_TIMEOUT: Duration = Duration::from_secs(1); // Longest the reader holds the TLS lock over the writer
+
+
+// ====== STRUCTS ======
+
+/// A message received from the server, readable with an `EventReader`
+#[derive(Debug, Clone, Event)]
+pub struct ServerMessageEvent(pub ServerMessage);
+
+/// A message to send to the server, write it with an `EventWriter`
+#[derive(Debug, Clone, Event)]
+pub struct ClientMessageEvent(pub ClientMessage);
+
+/// Sent on every change of `ClientState`, e.g. to show a reconnect overlay
+#[derive(Debug, Clone, Copy, Event)]
+pub struct ConnectionStateChanged {
+    pub state: ClientState,
+    pub reconnect_attempt: u32, // 0 while not reconnecting
+    pub max_attempts: u32,
 }
 
-// Send JSON
-fn send_json_message(stream: &mut TcpStream, message: &Message) -> io::Result<()> {
-    let json = serde_json::to_string(message)?;
-    stream.write_all(json.as_bytes())?;
-    Ok(())
+/// Opens the connection on a background thread so the Bevy schedule never blocks on the socket
+pub struct NetworkClientPlugin {
+    pub address: String,
+    pub login: Option<(String, String)>, // Username and password sent on every new connection
+    pub playback: Option<Vec<(Duration, ServerMessage)>>, // Plays these back instead of connecting
+    pub udp: bool, // Talk to the server's UDP port instead of TCP
+    pub tls: Option<ClientTls>, // None for plain TCP
+}
+
+impl NetworkClientPlugin {
+    pub fn new(address: &str) -> Self {
+        Self { address: address.to_string(), login: None, playback: None, udp: false, tls: None }
+    }
+
+    /// Connects to the address given with `--server <address>` on the command
+    /// line, or in SERVER_ADDRESS_ENV, or else to the server's default one.
+    /// With `--tls <domain>` the connection is TLS, checked against the system roots
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let plugin = Self::new(&server_address(&args, std::env::var(SERVER_ADDRESS_ENV).ok()));
+        let Some(domain) = args.windows(2).find(|pair| pair[0] == "--tls").map(|pair| pair[1].clone()) else {
+            return plugin;
+        };
+        match TlsConnector::new() {
+            Ok(connector) => plugin.with_tls(&domain, connector),
+            Err(e) => {
+                eprintln!("Could not set up TLS, connecting without it: {}", e);
+                plugin
+            }
+        }
+    }
+
+    /// TLS over TCP, `domain` must match the name in the server certificate
+    pub fn with_tls(mut self, domain: &str, connector: TlsConnector) -> Self {
+        self.tls = Some(ClientTls { domain: domain.to_string(), connector });
+        self
+    }
+
+    /// Over UDP snapshots and inputs may be lost instead of holding up everything else,
+    /// see `Channel`; the address must be the server's UDP one (`--udp-port`)
+    pub fn with_udp(mut self) -> Self {
+        self.udp = true;
+        self
+    }
+
+    /// Never connects: the game receives these messages, each at its time since the start
+    pub fn with_playback(mut self, messages: Vec<(Duration, ServerMessage)>) -> Self {
+        self.playback = Some(messages);
+        self
+    }
+
+    /// With PLAYBACK_ENV set to `<recording>[:<client id>]`, plays back what that
+    /// client (0 if not given) received in a session recorded by the server
+    pub fn with_playback_from_env(self) -> Self {
+        let Ok(spec) = std::env::var(PLAYBACK_ENV) else {
+            return self;
+        };
+        let (path, client_id) = match spec.rsplit_once(':').map(|(path, id)| (path, id.parse::<usize>())) {
+            Some((path, Ok(client_id))) => (path, client_id),
+            _ => (spec.as_str(), 0),
+        };
+        match load_recording(path) {
+            Ok(entries) => {
+                println!("Playing back the session of client {} from {}", client_id, path);
+                self.with_playback(client_messages(&entries, client_id))
+            }
+            Err(e) => {
+                eprintln!("Could not load the recording {}: {}", path, e);
+                self
+            }
+        }
+    }
+
+    pub fn with_login(mut self, username: &str, password: &str) -> Self {
+        self.login = Some((username.to_string(), password.to_string()));
+        self
+    }
 }
 
-// Receive JSON
-fn receive_json_message(stream: &mut TcpStream) -> io::Result<Message> {
-    let mut buffer = [0; 512];
-    let size = stream.read(&mut buffer)?;
-    let message: Message = serde_json::from_slice(&buffer[..size])?;
-    Ok(message)
+impl Plugin for NetworkClientPlugin {
+    fn build(&self, app: &mut App) {
+        if let Some(messages) = &self.playback {
+            // Nobody reads the ClientMessageEvents: what the player does goes nowhere
+            app.insert_resource(SessionPlayback { messages: messages.clone(), next: 0, elapsed: Duration::ZERO })
+                .add_event::<ServerMessageEvent>()
+                .add_event::<ClientMessageEvent>()
+                .add_event::<ConnectionStateChanged>()
+                .add_systems(PreUpdate, play_back_session);
+            return;
+        }
+
+        let mut client_resource = ClientResource::new(&self.address);
+        client_resource.login = self.login.clone();
+        client_resource.udp = self.udp;
+        client_resource.client.tls = self.tls.clone();
+
+        app.insert_resource(client_resource)
+            .add_event::<ServerMessageEvent>()
+            .add_event::<ClientMessageEvent>()
+            .add_event::<ConnectionStateChanged>()
+            .add_systems(PreUpdate, handle_networking)
+            .add_systems(PostUpdate, send_outbound_messages)
+            .add_systems(Last, cleanup_client.run_if(on_event::<AppExit>()));
+
+        // No threads nor raw sockets on the web build
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(Startup, connect_client);
+    }
+}
+
+// What the background threads report back to the Bevy world.
+// Every event carries the attempt it belongs to, so a late answer from an
+// abandoned attempt (e.g. one that already timed out) is ignored.
+#[derive(Debug)]
+enum WorkerEvent {
+    Connected { attempt: u64, stream: Option<TcpStream> }, // None over UDP, the worker keeps the socket
+    Message { attempt: u64, message: ServerMessage },
+    Disconnected { attempt: u64, reason: String },
 }
 
+// A recorded session being played back, see recording.rs
+#[derive(Debug, Resource)]
+struct SessionPlayback {
+    messages: Vec<(Duration, ServerMessage)>,
+    next: usize,
+    elapsed: Duration,
+}
 
-// This resource has not been initialized yet
 #[derive(Debug, Resource)]
 pub struct ClientResource {
     pub client: Client,
     pub connection_timer: Timer, // Timer for connection attempts
+    pub login: Option<(String, String)>,
+    pub udp: bool,
+    heartbeat_timer: Timer, // Sends a ping every HEARTBEAT_INTERVAL while connected
+    reconnect_timer: Option<Timer>, // Running while waiting for the next reconnect attempt
+    reconnect_attempts: u32, // Failed attempts since the last successful connection
+    reported_state: ClientState, // Last state sent as ConnectionStateChanged
+    attempt: u64,
+    worker_sender: Sender<WorkerEvent>,
+    worker_receiver: Mutex<Receiver<WorkerEvent>>, // Mutex because Receiver is not Sync
+    outbound: Option<Sender<ClientMessage>>,
 }
 
 impl ClientResource {
-    pub fn new() -> Self {
+    pub fn new(address: &str) -> Self {
+        let (worker_sender, worker_receiver) = mpsc::channel();
         Self {
-            client: Client::new(),
+            client: Client::with_address(address),
             connection_timer: Timer::from_seconds(10.0, TimerMode::Once), // 10-second timeout
+            login: None,
+            udp: false,
+            heartbeat_timer: Timer::new(HEARTBEAT_INTERVAL, TimerMode::Repeating),
+            reconnect_timer: None,
+            reconnect_attempts: 0,
+            reported_state: ClientState::Disconnected,
+            attempt: 0,
+            worker_sender,
+            worker_receiver: Mutex::new(worker_receiver),
+            outbound: None,
+        }
+    }
+
+    /// Starts a new connection attempt, the result arrives in `handle_networking`
+    pub fn connect(&mut self) {
+        let Some(address) = self.client.address.clone() else {
+            eprintln!("No server address to connect to");
+            return;
+        };
+
+        self.attempt += 1;
+        self.reconnect_timer = None;
+        self.client.state = ClientState::Connecting;
+        self.connection_timer.reset();
+        let handshake = self.handshake();
+        self.outbound = Some(if self.udp {
+            spawn_udp_worker(address.clone(), self.attempt, handshake, self.worker_sender.clone())
+        } else {
+            let tls = self.client.tls.clone();
+            spawn_connection_worker(address.clone(), tls, self.attempt, handshake, self.worker_sender.clone())
+        });
+        println!("Connecting to the server at {}...", address);
+    }
+
+    // First message of a new connection: resume the old session if there is one
+    fn handshake(&self) -> Option<ClientMessage> {
+        if let Some(session_token) = &self.client.session_token {
+            return Some(ClientMessage::Resume { session_token: session_token.clone() });
+        }
+        self.login_message()
+    }
+
+    fn login_message(&self) -> Option<ClientMessage> {
+        self.login.as_ref().map(|(username, password)| ClientMessage::Login {
+            username: username.clone(),
+            password: password.clone(),
+        })
+    }
+
+    // Keeps the session token up to date with the server answers
+    fn track_session(&mut self, message: &ServerMessage) {
+        match message {
+            ServerMessage::LoginAccepted { session_token, .. } => {
+                self.client.session_token = Some(session_token.clone());
+            }
+            ServerMessage::LoginRejected { reason } => {
+                eprintln!("Login rejected: {}", reason);
+                // An expired session falls back to the password, if we have one
+                if self.client.session_token.take().is_some() {
+                    if let (Some(login), Some(sender)) = (self.login_message(), &self.outbound) {
+                        let _ = sender.send(login);
+                    }
+                }
+            }
+            ServerMessage::LoggedOut => self.client.session_token = None,
+            ServerMessage::Disconnected { reason } => {
+                eprintln!("Disconnected by the server: {}", reason);
+            }
+            _ => {}
+        }
+    }
+
+    /// Closes the connection on purpose, no reconnection will be attempted
+    pub fn disconnect(&mut self) {
+        self.attempt += 1; // Whatever the old threads still report is now stale
+        self.reconnect_timer = None;
+        self.reconnect_attempts = 0;
+        self.outbound = None; // Closing the channel stops the writer thread
+        self.client.disconnect(); // Shutting down the socket stops the reader thread
+    }
+
+    /// Round-trip time to the server, e.g. to show the ping in the UI
+    pub fn rtt(&self) -> Option<Duration> {
+        self.client.rtt()
+    }
+
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnect_timer.is_some()
+    }
+
+    // The connection failed or dropped: schedule the next attempt following
+    // the client's ReconnectPolicy, or give up once out of attempts
+    fn connection_lost(&mut self) {
+        let attempts = self.reconnect_attempts;
+        self.disconnect();
+
+        let policy = &self.client.reconnect_policy;
+        if attempts >= policy.max_attempts {
+            eprintln!("Giving up after {} reconnection attempts", attempts);
+            return;
+        }
+
+        let delay = policy.delay(attempts);
+        println!("Reconnecting in {:.1}s (attempt {} of {})", delay.as_secs_f32(), attempts + 1, policy.max_attempts);
+        self.reconnect_attempts = attempts + 1;
+        self.reconnect_timer = Some(Timer::new(delay, TimerMode::Once));
+    }
+
+    fn drain_worker_events(&self) -> Vec<WorkerEvent> {
+        match self.worker_receiver.lock() {
+            Ok(receiver) => receiver.try_iter().collect(),
+            Err(_) => Vec::new(),
         }
     }
 }
 
 
-fn handle_networking(
-    mut client_resource: ResMut<ClientResource>,
-    mut commands: Commands,
-    time: Res<Time>,
-) {
-    // Check the state of the client
-    match client_resource.client.state {
-        ClientState::Connected => {
-            // Handle message sending
-            let input = get_user_input(); // Your function to get user input
-            if let Err(e) = client_resource.client.send_message(&input) {
-                eprintln!("Failed to send message: {}", e);
+// ====== METHODS ======
+
+/// The server to connect to: `--server <address>` in `args`, else `env`, else the default
+pub fn server_address(args: &[String], env: Option<String>) -> String {
+    let flag = args.windows(2).find(|pair| pair[0] == "--server").map(|pair| pair[1].clone());
+    flag.or(env).unwrap_or_else(|| format!("{}:{}", DEFAULT_ADDRESS, DEFAULT_PORT))
+}
+
+// Send JSON (one length-prefixed frame per message)
+fn send_json_message(stream: &mut SharedTransport, message: &ClientMessage) -> io::Result<()> {
+    write_json(stream, message)?;
+    Ok(())
+}
+
+// Receive JSON (blocks until a whole frame has arrived)
+// Reads until a whole frame is in; a timed out read only means nothing arrived
+// yet, the decoder keeps any half frame for the next one
+fn receive_json_message(stream: &mut SharedTransport, decoder: &mut FrameDecoder) -> io::Result<ServerMessage> {
+    let mut buffer = [0u8; 4096];
+    loop {
+        if let Some(payload) = decoder.next_frame()? {
+            return Ok(serde_json::from_slice(&payload).map_err(FrameError::from)?);
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) => return Err(FrameError::ConnectionClosed.into()),
+            Ok(bytes_read) => decoder.extend(&buffer[..bytes_read]),
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Connects on a new thread (over TLS with `tls`), sends the handshake, then
+// keeps one thread reading and one writing. Returns the sending half of the
+// outbound queue; messages queued before the connection is up are sent as soon as it is.
+fn spawn_connection_worker(
+    address: String,
+    tls: Option<ClientTls>,
+    attempt: u64,
+    handshake: Option<ClientMessage>,
+    events: Sender<WorkerEvent>,
+) -> Sender<ClientMessage> {
+    let (outbound_sender, outbound_receiver) = mpsc::channel::<ClientMessage>();
+
+    thread::spawn(move || {
+        // The game only keeps the bare socket, to close it on disconnect
+        let streams = open_transport(&address, tls.as_ref()).and_then(|transport| {
+            let reader = SharedTransport::new(transport)?;
+            reader.set_read_timeout(Some(TCP_READ_TIMEOUT))?;
+            let stream = reader.socket()?;
+            let writer = reader.clone();
+            Ok((stream, reader, writer))
+        });
+        let (stream, mut reader, mut writer) = match streams {
+            Ok(streams) => streams,
+            Err(e) => {
+                let _ = events.send(WorkerEvent::Disconnected { attempt, reason: e.to_string() });
+                return;
             }
+        };
+        if events.send(WorkerEvent::Connected { attempt, stream: Some(stream) }).is_err() {
+            return;
+        }
 
-            // Handle receiving messages
-            match client_resource.client.receive_message() {
-                Ok(response) => {
-                    println!("Received: {}", response);
-                    // You can add logic here to update game state based on the response
+        // Reader thread
+        let reader_events = events.clone();
+        let mut decoder = FrameDecoder::new();
+        thread::spawn(move || loop {
+            match receive_json_message(&mut reader, &mut decoder) {
+                Ok(message) => {
+                    if reader_events.send(WorkerEvent::Message { attempt, message }).is_err() {
+                        break;
+                    }
                 }
                 Err(e) => {
-                    eprintln!("Failed to receive message: {}", e);
+                    let _ = reader_events.send(WorkerEvent::Disconnected { attempt, reason: e.to_string() });
+                    break;
                 }
             }
+        });
+
+        // Writer loop, ends when the ClientResource drops its Sender
+        for message in handshake.into_iter().chain(outbound_receiver) {
+            if let Err(e) = send_json_message(&mut writer, &message) {
+                let _ = events.send(WorkerEvent::Disconnected { attempt, reason: e.to_string() });
+                break;
+            }
         }
-        ClientState::Disconnected => {
-            // Handle reconnection logic
-            println!("Client disconnected. Attempting to reconnect...");
-            let reconnect_attempt = client_resource.client.reconnect();
-            match reconnect_attempt {
-                Ok(_) => {
-                    println!("Reconnected successfully.");
-                    client_resource.client.state = ClientState::Connecting;
+    });
+
+    outbound_sender
+}
+
+// Same job as spawn_connection_worker over UDP: a single thread sends what is
+// queued and polls the socket, which also sends the resends and the acks
+fn spawn_udp_worker(
+    address: String,
+    attempt: u64,
+    handshake: Option<ClientMessage>,
+    events: Sender<WorkerEvent>,
+) -> Sender<ClientMessage> {
+    let (outbound_sender, outbound_receiver) = mpsc::channel::<ClientMessage>();
+
+    thread::spawn(move || {
+        let mut client = match UdpClient::connect(&address) {
+            Ok(client) => client,
+            Err(e) => {
+                let _ = events.send(WorkerEvent::Disconnected { attempt, reason: e.to_string() });
+                return;
+            }
+        };
+        if events.send(WorkerEvent::Connected { attempt, stream: None }).is_err() {
+            return;
+        }
+
+        let mut outbound = handshake.into_iter().collect::<Vec<_>>();
+        loop {
+            // Ends when the ClientResource drops its Sender
+            loop {
+                match outbound_receiver.try_recv() {
+                    Ok(message) => outbound.push(message),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+            }
+            let received = outbound
+                .drain(..)
+                .try_for_each(|message| client.send(&message))
+                .and_then(|_| client.receive(UDP_POLL_INTERVAL));
+            match received {
+                Ok(messages) => {
+                    for message in messages {
+                        let message = match message {
+                            Ok(message) => message,
+                            Err(e) => {
+                                eprintln!("{}, ignoring it", e);
+                                continue;
+                            }
+                        };
+                        if events.send(WorkerEvent::Message { attempt, message }).is_err() {
+                            return;
+                        }
+                    }
                 }
                 Err(e) => {
-                    eprintln!("Reconnection failed: {}", e);
-                    // You can add retry logic or cooldowns if needed.
+                    let _ = events.send(WorkerEvent::Disconnected { attempt, reason: e.to_string() });
+                    return;
+                }
+            }
+        }
+    });
+
+    outbound_sender
+}
+
+pub fn connect_client(mut client_resource: ResMut<ClientResource>) {
+    client_resource.connect();
+}
+
+pub fn handle_networking(
+    mut client_resource: ResMut<ClientResource>,
+    mut inbound: EventWriter<ServerMessageEvent>,
+    mut state_changes: EventWriter<ConnectionStateChanged>,
+    time: Res<Time>,
+) {
+    for event in client_resource.drain_worker_events() {
+        match event {
+            WorkerEvent::Connected { attempt, stream } => {
+                if attempt != client_resource.attempt {
+                    if let Some(stream) = stream {
+                        let _ = stream.shutdown(std::net::Shutdown::Both);
+                    }
+                    continue;
+                }
+                match stream {
+                    Some(stream) => client_resource.client.set_stream(stream),
+                    None => client_resource.client.set_connected(),
                 }
+                client_resource.reconnect_attempts = 0;
+                client_resource.heartbeat_timer.reset();
+                println!("Connected to the server.");
+            }
+            WorkerEvent::Message { attempt, message } => {
+                if attempt == client_resource.attempt {
+                    client_resource.client.heartbeat.on_message(&message, Instant::now());
+                    client_resource.track_session(&message);
+                    inbound.send(ServerMessageEvent(message));
+                }
+            }
+            WorkerEvent::Disconnected { attempt, reason } => {
+                if attempt != client_resource.attempt {
+                    continue;
+                }
+                eprintln!("Connection lost: {}", reason);
+                client_resource.connection_lost();
+            }
+        }
+    }
+
+    // Check the state of the client
+    match client_resource.client.state {
+        ClientState::Connected => {
+            // The socket of a server that died without closing it stays open forever
+            if client_resource.client.heartbeat.is_timed_out(Instant::now(), HEARTBEAT_TIMEOUT) {
+                eprintln!("The server stopped answering.");
+                client_resource.connection_lost();
+            } else if client_resource.heartbeat_timer.tick(time.delta()).just_finished() {
+                let ping = client_resource.client.heartbeat.ping(Instant::now());
+                if let Some(sender) = &client_resource.outbound {
+                    let _ = sender.send(ping);
+                }
+            }
+        }
+        ClientState::Disconnected => {
+            // Waiting for the next reconnection attempt, if any
+            let retry = match client_resource.reconnect_timer.as_mut() {
+                Some(timer) => timer.tick(time.delta()).finished(),
+                None => false,
+            };
+            if retry {
+                client_resource.connect();
             }
         }
         ClientState::Connecting => {
-            // Show loading screen or connection message
-            println!("Connecting to the server...");
             client_resource.connection_timer.tick(time.delta());
 
             if client_resource.connection_timer.finished() {
                 eprintln!("Connection attempt timed out.");
-                client_resource.client.state = ClientState::Disconnected;
-            } else if client_resource.client.is_connected() {
-                client_resource.client.state = ClientState::Connected;
-                println!("Connected to the server.");
+                client_resource.connection_lost();
             }
         }
     }
+
+    if client_resource.client.state != client_resource.reported_state {
+        client_resource.reported_state = client_resource.client.state;
+        state_changes.send(ConnectionStateChanged {
+            state: client_resource.client.state,
+            reconnect_attempt: client_resource.reconnect_attempts,
+            max_attempts: client_resource.client.reconnect_policy.max_attempts,
+        });
+    }
 }
 
-// Placeholder function to get user input; replace with actual input handling
-fn get_user_input() -> String {
-    // Implement your input handling logic here
-    "example input".to_string()
+pub fn send_outbound_messages(
+    client_resource: Res<ClientResource>,
+    mut outbound: EventReader<ClientMessageEvent>,
+) {
+    for ClientMessageEvent(message) in outbound.read() {
+        match &client_resource.outbound {
+            Some(sender) => {
+                if sender.send(message.clone()).is_err() {
+                    eprintln!("Failed to send message: connection closed");
+                }
+            }
+            None => eprintln!("Dropping message, not connected: {:?}", message),
+        }
+    }
 }
 
 fn cleanup_client(mut client_resource: ResMut<ClientResource>) {
-    client_resource.client.disconnect();
+    client_resource.disconnect();
 }
 
+// Every message whose time has come, in the recorded order
+fn play_back_session(
+    mut playback: ResMut<SessionPlayback>,
+    mut inbound: EventWriter<ServerMessageEvent>,
+    time: Res<Time>,
+) {
+    playback.elapsed += time.delta();
+    while let Some((at, message)) = playback.messages.get(playback.next) {
+        if *at > playback.elapsed {
+            break;
+        }
+        inbound.send(ServerMessageEvent(message.clone()));
+        playback.next += 1;
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::test_credentials;
+    use crate::protocol::Transport;
+    use crate::server::{start_server, LogLevel, ServerConfig};
+
+    #[test]
+    fn test_read_timeouts_keep_the_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let payload = serde_json::to_vec(&ServerMessage::Pong { nonce: 7 }).unwrap();
+            let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+            frame.extend(payload);
+            // Idle, then half a frame, then idle again before the rest
+            thread::sleep(Duration::from_millis(150));
+            io::Write::write_all(&mut stream, &frame[..3]).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            io::Write::write_all(&mut stream, &frame[3..]).unwrap();
+        });
+
+        let mut reader = SharedTransport::new(Transport::Plain(TcpStream::connect(address).unwrap())).unwrap();
+        reader.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let message = receive_json_message(&mut reader, &mut FrameDecoder::new()).unwrap();
+        assert_eq!(message, ServerMessage::Pong { nonce: 7 });
+
+        server.join().unwrap();
+        let closed = receive_json_message(&mut reader, &mut FrameDecoder::new()).unwrap_err();
+        assert_eq!(closed.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    // Runs the app until the server sends a message matching `wanted`
+    fn wait_for(app: &mut App, wanted: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            app.update();
+            let received: Vec<ServerMessageEvent> = app.world_mut().resource_mut::<Events<ServerMessageEvent>>().drain().collect();
+            if let Some(ServerMessageEvent(message)) = received.into_iter().find(|ServerMessageEvent(message)| wanted(message)) {
+                return message;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("The server never answered");
+    }
+
+    #[test]
+    fn test_server_address() {
+        let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(server_address(&args(&[]), None), "127.0.0.1:8080");
+        assert_eq!(server_address(&args(&[]), Some("10.0.0.2:9000".to_string())), "10.0.0.2:9000");
+        assert_eq!(server_address(&args(&["--server", "example.com:7000"]), Some("10.0.0.2:9000".to_string())), "example.com:7000");
+    }
+
+    // The plugin against a real server on the loopback: login, a message each way, then the server goes away
+    #[test]
+    fn test_loopback_round_trip() {
+        let config = ServerConfig { port: 0, log_level: LogLevel::Error, ..ServerConfig::default() };
+        let handle = start_server(&config, test_credentials(), None).unwrap();
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugins(NetworkClientPlugin::new(&handle.local_addr().to_string()).with_login("player1", "securepassword"));
+
+        let accepted = wait_for(&mut app, |message| matches!(message, ServerMessage::LoginAccepted { .. }));
+        let ServerMessage::LoginAccepted { client_id, .. } = accepted else { unreachable!() };
+        assert_eq!(app.world().resource::<ClientResource>().client.state, ClientState::Connected);
+
+        app.world_mut().send_event(ClientMessageEvent(ClientMessage::Join { name: "player1".to_string() }));
+        let joined = wait_for(&mut app, |message| matches!(message, ServerMessage::Joined { .. }));
+        assert_eq!(joined, ServerMessage::Joined { client_id, name: "player1".to_string() });
+
+        handle.shutdown();
+        wait_for(&mut app, |message| matches!(message, ServerMessage::Disconnected { .. }));
+        handle.wait();
+    }
+
+    // Same over TLS, with a self-signed certificate only this client trusts
+    #[test]
+    fn test_loopback_round_trip_over_tls() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = certified.cert.pem();
+        let identity = native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), certified.key_pair.serialize_pem().as_bytes()).unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+
+        let config = ServerConfig { port: 0, log_level: LogLevel::Error, ..ServerConfig::default() };
+        let handle = start_server(&config, test_credentials(), Some(acceptor)).unwrap();
+
+        let root = native_tls::Certificate::from_pem(cert_pem.as_bytes()).unwrap();
+        let connector = TlsConnector::builder().add_root_certificate(root).build().unwrap();
+        let plugin = NetworkClientPlugin::new(&handle.local_addr().to_string())
+            .with_login("player1", "securepassword")
+            .with_tls("localhost", connector);
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins).add_plugins(plugin);
+
+        wait_for(&mut app, |message| matches!(message, ServerMessage::LoginAccepted { .. }));
+        // Both ways at once on the one TLS stream: the pings go out while the reader waits
+        app.world_mut().send_event(ClientMessageEvent(ClientMessage::Join { name: "player1".to_string() }));
+        wait_for(&mut app, |message| matches!(message, ServerMessage::Joined { .. }));
+        wait_for(&mut app, |message| matches!(message, ServerMessage::StateSnapshot(_)));
+
+        handle.shutdown();
+        wait_for(&mut app, |message| matches!(message, ServerMessage::Disconnected { .. }));
+        handle.wait();
+    }
+}
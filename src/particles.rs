@@ -1,6 +1,8 @@
 use bevy::prelude::*;
 
-use crate::{get_mouse_position, Scene1Entity};
+use rand::Rng;
+
+use crate::{get_mouse_position, GameRng, RngStream, Scene1Entity};
 
 /*
 Key Components of a Particle System
@@ -117,8 +119,10 @@ pub fn emitter_system_scene1(
     time: Res<Time>,
     mut commands: Commands,
     particle_material: Res<ParticleMaterialHandle>,
-    mut query: Query<(&mut ParticleEmitter, &Transform, &Scene1Entity)>
+    mut query: Query<(&mut ParticleEmitter, &Transform, &Scene1Entity)>,
+    mut game_rng: ResMut<GameRng>,
 ) {
+    let rng = game_rng.stream(RngStream::Particles);
     for (mut emitter, transform, _) in query.iter_mut() {
         emitter.time_since_last_spawn += time.delta_seconds();
 
@@ -129,12 +133,12 @@ pub fn emitter_system_scene1(
             commands.spawn((
                 Particle {
                     velocity: Vec3::new(
-                        rand::random::<f32>() * 2.0 - 1.0,
-                        rand::random::<f32>() * 2.0 - 1.0,
+                        rng.gen::<f32>() * 2.0 - 1.0,
+                        rng.gen::<f32>() * 2.0 - 1.0,
                         0.0,
                     ),
                     lifetime: 4.0,
-                    size: rand::random::<f32>() * 5.0 + 1.0, // Random initial size between 5 and 15
+                    size: rng.gen::<f32>() * 5.0 + 1.0, // Random initial size between 5 and 15
                 },
                 SpriteBundle {
                     sprite: Sprite {
@@ -0,0 +1,330 @@
+// Selezione degli sprite 2D col mouse: evidenziare, cliccare, trascinare e lasciare sui bersagli.
+//
+// Put `Pickable` on a sprite to get hover highlight and `PickEvent::Clicked`,
+// add `Draggable` to move it with the left button (raised above everything
+// while dragged) and `DropTarget` on whatever it can be dropped on. On release
+// a `PickEvent::Dropped` names the target under the cursor; games check the
+// move in a system between `PickingSet::Input` and `PickingSet::Resolve` and
+// send `RejectDrop` to refuse it. Rejected drops, and drops on nothing, go
+// back where they came from with a `CardTween`. Hit tests use the sprite's
+// full transform, so scaled and rotated (fanned) cards work too; dragged
+// sprites should have no parent.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::app_utils::get_mouse_position;
+use crate::card_animation::CardTween;
+
+
+// ====== CONSTANTS ======
+
+pub const DRAG_Z: f32 = 500.;
+const DRAG_THRESHOLD: f32 = 4.; // Pixels the mouse must move before a press becomes a drag
+const SNAP_BACK_DURATION: Duration = Duration::from_millis(200);
+const HOVER_TINT: Color = Color::srgb(1., 1., 0.75);
+
+
+// ====== STRUCTS ======
+
+/// Can be hovered and clicked; `size` overrides the sprite's own for hit tests
+#[derive(Debug, Clone, Copy, Default, Component)]
+pub struct Pickable {
+    pub size: Option<Vec2>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Component)]
+pub struct Draggable;
+
+/// Something draggables can be dropped on, `size` as in `Pickable`
+#[derive(Debug, Clone, Copy, Default, Component)]
+pub struct DropTarget {
+    pub size: Option<Vec2>,
+}
+
+/// On the pickable under the cursor, with the color to give back on leave
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Hovered {
+    previous_color: Color,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Event)]
+pub enum PickEvent {
+    Clicked { entity: Entity, button: MouseButton },
+    DragStarted { entity: Entity },
+    /// `target` is the topmost drop target under the cursor, if any
+    Dropped { entity: Entity, target: Option<Entity> },
+}
+
+/// Sent in answer to a `PickEvent::Dropped` to send the sprite back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Event)]
+pub struct RejectDrop(pub Entity);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemSet)]
+pub enum PickingSet {
+    Input,
+    Resolve,
+}
+
+// The left button went down on this entity
+#[derive(Debug, Clone, Copy)]
+struct Press {
+    entity: Entity,
+    cursor: Vec2,
+    grab: Vec2, // From the cursor to the sprite center
+    start: Transform,
+    dragging: bool,
+}
+
+#[derive(Debug, Default, Resource)]
+struct PickingState {
+    press: Option<Press>,
+    drops: Vec<(Entity, Transform, bool)>, // Where it came from, whether it landed on a target
+}
+
+// What the hit tests need of a pickable or a drop target
+type Shape<'a, T> = (Entity, &'a T, &'a GlobalTransform, Option<&'a Sprite>, Option<&'a Handle<Image>>);
+type HoverShape<'a> = (Entity, &'a Pickable, &'a GlobalTransform, Option<&'a mut Sprite>, Option<&'a Handle<Image>>, Option<&'a Hovered>);
+
+pub struct PickingPlugin;
+
+impl Plugin for PickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PickingState>()
+            .add_event::<PickEvent>()
+            .add_event::<RejectDrop>()
+            .configure_sets(Update, PickingSet::Input.before(PickingSet::Resolve))
+            .add_systems(Update, (hover_pickables, press_and_drag).chain().in_set(PickingSet::Input))
+            .add_systems(Update, resolve_drops.in_set(PickingSet::Resolve));
+    }
+}
+
+
+// ====== METHODS ======
+
+/// Whether `point` falls on a sprite of `size` (centered) placed by `transform`
+pub fn hit_test(point: Vec2, transform: &GlobalTransform, size: Vec2) -> bool {
+    let local = transform.affine().inverse().transform_point3(point.extend(transform.translation().z));
+    local.x.abs() <= size.x / 2. && local.y.abs() <= size.y / 2.
+}
+
+/// The topmost (highest z) of the candidates hit by `point`
+pub fn topmost(point: Vec2, candidates: impl Iterator<Item = (Entity, GlobalTransform, Vec2)>) -> Option<Entity> {
+    candidates
+        .filter(|(_, transform, size)| hit_test(point, transform, *size))
+        .max_by(|(_, a, _), (_, b, _)| a.translation().z.total_cmp(&b.translation().z))
+        .map(|(entity, _, _)| entity)
+}
+
+fn sprite_size(size: Option<Vec2>, sprite: Option<&Sprite>, texture: Option<&Handle<Image>>, images: &Assets<Image>) -> Vec2 {
+    size.or_else(|| sprite.and_then(|sprite| sprite.custom_size))
+        .or_else(|| texture.and_then(|texture| images.get(texture)).map(Image::size_f32))
+        .unwrap_or(Vec2::ZERO)
+}
+
+fn hover_pickables(
+    mut commands: Commands,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    window: Query<&Window>,
+    images: Res<Assets<Image>>,
+    state: Res<PickingState>,
+    mut pickables: Query<HoverShape>,
+) {
+    let cursor = get_mouse_position(camera_query, window);
+    // While dragging the dragged sprite stays lit
+    let under = match state.press {
+        Some(Press { entity, dragging: true, .. }) => Some(entity),
+        _ => cursor.and_then(|cursor| {
+            let candidates = pickables.iter().map(|(entity, pickable, transform, sprite, texture, _)| {
+                (entity, *transform, sprite_size(pickable.size, sprite, texture, &images))
+            });
+            topmost(cursor, candidates)
+        }),
+    };
+
+    for (entity, _, _, sprite, _, hovered) in &mut pickables {
+        let Some(mut sprite) = sprite else {
+            continue;
+        };
+        match (hovered, Some(entity) == under) {
+            (Some(hover), false) => {
+                sprite.color = hover.previous_color;
+                commands.entity(entity).remove::<Hovered>();
+            }
+            (None, true) => {
+                commands.entity(entity).insert(Hovered { previous_color: sprite.color });
+                sprite.color = HOVER_TINT;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn press_and_drag(
+    buttons: Res<ButtonInput<MouseButton>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    window: Query<&Window>,
+    images: Res<Assets<Image>>,
+    mut state: ResMut<PickingState>,
+    mut events: EventWriter<PickEvent>,
+    pickables: Query<Shape<Pickable>>,
+    targets: Query<Shape<DropTarget>>,
+    mut draggables: Query<&mut Transform, With<Draggable>>,
+) {
+    // None outside the window, where a release still has to end the press
+    let cursor = get_mouse_position(camera_query, window);
+    let under = |cursor: Vec2| {
+        let candidates = pickables.iter().map(|(entity, pickable, transform, sprite, texture)| {
+            (entity, *transform, sprite_size(pickable.size, sprite, texture, &images))
+        });
+        topmost(cursor, candidates)
+    };
+
+    if let Some(cursor) = cursor {
+        // Right and middle clicks never drag
+        for button in [MouseButton::Right, MouseButton::Middle] {
+            if buttons.just_pressed(button) {
+                if let Some(entity) = under(cursor) {
+                    events.send(PickEvent::Clicked { entity, button });
+                }
+            }
+        }
+
+        if buttons.just_pressed(MouseButton::Left) {
+            state.press = under(cursor).map(|entity| {
+                let start = draggables.get(entity).copied().unwrap_or_default();
+                Press { entity, cursor, grab: start.translation.truncate() - cursor, start, dragging: false }
+            });
+        }
+    }
+    let Some(mut press) = state.press else {
+        return;
+    };
+
+    if buttons.pressed(MouseButton::Left) {
+        let (Some(cursor), Ok(mut transform)) = (cursor, draggables.get_mut(press.entity)) else {
+            return; // Only clickable, or waiting for the cursor to come back
+        };
+        if !press.dragging && cursor.distance(press.cursor) >= DRAG_THRESHOLD {
+            press.dragging = true;
+            events.send(PickEvent::DragStarted { entity: press.entity });
+        }
+        if press.dragging {
+            transform.translation = (cursor + press.grab).extend(DRAG_Z);
+        }
+        state.press = Some(press);
+        return;
+    }
+
+    // Released; outside the window nothing is clicked and a drag lands on nothing, so it goes back
+    state.press = None;
+    if !press.dragging {
+        if cursor.is_some() {
+            events.send(PickEvent::Clicked { entity: press.entity, button: MouseButton::Left });
+        }
+        return;
+    }
+    let target = cursor.and_then(|cursor| {
+        let candidates = targets
+            .iter()
+            .filter(|(entity, ..)| *entity != press.entity)
+            .map(|(entity, target, transform, sprite, texture)| (entity, *transform, sprite_size(target.size, sprite, texture, &images)));
+        topmost(cursor, candidates)
+    });
+    events.send(PickEvent::Dropped { entity: press.entity, target });
+    state.drops.push((press.entity, press.start, target.is_some()));
+}
+
+// Sends back what was dropped on nothing or refused, leaves the rest where it was dropped
+fn resolve_drops(
+    mut commands: Commands,
+    mut state: ResMut<PickingState>,
+    mut rejected: EventReader<RejectDrop>,
+    mut transforms: Query<&mut Transform>,
+) {
+    let rejected: Vec<Entity> = rejected.read().map(|RejectDrop(entity)| *entity).collect();
+    for (entity, start, on_target) in state.drops.drain(..) {
+        let Ok(mut transform) = transforms.get_mut(entity) else {
+            continue; // Despawned by whoever took the drop
+        };
+        if on_target && !rejected.contains(&entity) {
+            transform.translation.z = start.translation.z;
+            continue;
+        }
+        commands.entity(entity).insert(CardTween::new(start, SNAP_BACK_DURATION));
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_test_follows_the_transform() {
+        let size = Vec2::new(100., 140.);
+        let plain = GlobalTransform::from_xyz(50., 0., 0.);
+        assert!(hit_test(Vec2::new(99., 69.), &plain, size));
+        assert!(!hit_test(Vec2::new(101., 0.), &plain, size));
+
+        // Half as big
+        let scaled = GlobalTransform::from(Transform::from_scale(Vec3::splat(0.5)));
+        assert!(hit_test(Vec2::new(24., 0.), &scaled, size));
+        assert!(!hit_test(Vec2::new(26., 0.), &scaled, size));
+
+        // Turned a quarter: tall becomes wide
+        let turned = GlobalTransform::from(Transform::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2)));
+        assert!(hit_test(Vec2::new(65., 0.), &turned, size));
+        assert!(!hit_test(Vec2::new(0., 65.), &turned, size));
+    }
+
+    #[test]
+    fn test_topmost_wins() {
+        let size = Vec2::splat(100.);
+        let below = Entity::from_raw(1);
+        let above = Entity::from_raw(2);
+        let candidates = || {
+            [(below, GlobalTransform::from_xyz(0., 0., 1.), size), (above, GlobalTransform::from_xyz(40., 0., 2.), size)].into_iter()
+        };
+        assert_eq!(topmost(Vec2::new(20., 0.), candidates()), Some(above));
+        assert_eq!(topmost(Vec2::new(-20., 0.), candidates()), Some(below));
+        assert_eq!(topmost(Vec2::new(0., 80.), candidates()), None);
+    }
+
+    #[test]
+    fn test_drag_released_outside_the_window_goes_back() {
+        let mut app = App::new();
+        app.init_resource::<PickingState>()
+            .init_resource::<Assets<Image>>()
+            .init_resource::<ButtonInput<MouseButton>>()
+            .add_event::<PickEvent>()
+            .add_event::<RejectDrop>()
+            .add_systems(Update, (press_and_drag, resolve_drops).chain());
+        app.world_mut().spawn(Window::default()); // No cursor: it is outside
+        app.world_mut().spawn(Camera2dBundle::default());
+
+        let start = Transform::from_xyz(10., 20., 1.);
+        let card = app.world_mut().spawn((Transform::from_xyz(300., 0., DRAG_Z), Draggable)).id();
+        let press = Press { entity: card, cursor: Vec2::ZERO, grab: Vec2::ZERO, start, dragging: true };
+        app.world_mut().resource_mut::<PickingState>().press = Some(press);
+
+        // Still held: the card waits
+        app.world_mut().resource_mut::<ButtonInput<MouseButton>>().press(MouseButton::Left);
+        app.update();
+        assert!(app.world().resource::<PickingState>().press.is_some());
+
+        app.world_mut().resource_mut::<ButtonInput<MouseButton>>().release(MouseButton::Left);
+        app.update();
+        assert!(app.world().resource::<PickingState>().press.is_none());
+        let events = app.world().resource::<Events<PickEvent>>();
+        let sent: Vec<PickEvent> = events.get_reader().read(events).copied().collect();
+        assert_eq!(sent, vec![PickEvent::Dropped { entity: card, target: None }]);
+        assert!(app.world().get::<CardTween>(card).is_some()); // On its way back
+    }
+}
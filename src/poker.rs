@@ -0,0 +1,260 @@
+// Valutazione delle mani di poker sul modello di cards.rs, senza Bevy.
+//
+// Any hand of 5 to 7 cards is ranked by its best five: a `HandRank` is the
+// category followed by the ranks that break ties inside it, so plain `Ord`
+// compares two hands. `winners` and `split_pot` settle who gets the chips.
+
+use std::cmp::Reverse;
+use std::fmt;
+
+use crate::cards::{Card, Rank};
+
+
+// ====== CONSTANTS ======
+
+pub const HAND_CARDS: usize = 5;
+pub const MAX_CARDS: usize = 7; // Two hole cards and the whole board in Hold'em
+
+
+
+// ====== STRUCTS ======
+
+/// From the weakest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HandCategory {
+    HighCard,
+    Pair,
+    TwoPair,
+    ThreeOfAKind,
+    Straight,
+    Flush,
+    FullHouse,
+    FourOfAKind,
+    StraightFlush,
+}
+
+impl fmt::Display for HandCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HandCategory::HighCard => "high card",
+            HandCategory::Pair => "pair",
+            HandCategory::TwoPair => "two pair",
+            HandCategory::ThreeOfAKind => "three of a kind",
+            HandCategory::Straight => "straight",
+            HandCategory::Flush => "flush",
+            HandCategory::FullHouse => "full house",
+            HandCategory::FourOfAKind => "four of a kind",
+            HandCategory::StraightFlush => "straight flush",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a hand is worth: the category, then the ranks deciding between two hands
+/// of that category (e.g. the pair, then the kickers from the highest)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HandRank {
+    pub category: HandCategory,
+    pub ranks: Vec<Rank>,
+}
+
+impl fmt::Display for HandRank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ranks.first() {
+            Some(high) => write!(f, "{}, {} high", self.category, high),
+            None => write!(f, "{}", self.category),
+        }
+    }
+}
+
+/// The best five cards found in a hand and their rank
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hand {
+    pub rank: HandRank,
+    pub cards: [Card; HAND_CARDS],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandError {
+    WrongCardCount(usize),
+    DuplicateCard(Card),
+}
+
+impl fmt::Display for HandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandError::WrongCardCount(count) => {
+                write!(f, "A hand has {} to {} cards, not {}", HAND_CARDS, MAX_CARDS, count)
+            }
+            HandError::DuplicateCard(card) => write!(f, "{} is in the hand twice", card),
+        }
+    }
+}
+
+impl std::error::Error for HandError {}
+
+
+// ====== METHODS ======
+
+/// Ranks a hand of 5 to 7 cards by its best five
+pub fn evaluate(cards: &[Card]) -> Result<Hand, HandError> {
+    if !(HAND_CARDS..=MAX_CARDS).contains(&cards.len()) {
+        return Err(HandError::WrongCardCount(cards.len()));
+    }
+    for (index, card) in cards.iter().enumerate() {
+        if cards[..index].contains(card) {
+            return Err(HandError::DuplicateCard(*card));
+        }
+    }
+
+    // Every way of picking 5 cards, at most 21 of them with 7
+    let mut best: Option<Hand> = None;
+    for picked in 0u32..(1 << cards.len()) {
+        if picked.count_ones() as usize != HAND_CARDS {
+            continue;
+        }
+        let mut five = cards.iter().enumerate().filter(|(index, _)| picked & (1 << index) != 0).map(|(_, card)| *card);
+        let five: [Card; HAND_CARDS] = std::array::from_fn(|_| five.next().expect("5 bits are set"));
+        let rank = rank_five(&five);
+        if best.as_ref().is_none_or(|best| rank > best.rank) {
+            best = Some(Hand { rank, cards: five });
+        }
+    }
+    Ok(best.expect("There are at least 5 cards"))
+}
+
+/// Indices of the best hands, more than one when they tie
+pub fn winners(hands: &[HandRank]) -> Vec<usize> {
+    let Some(best) = hands.iter().max() else {
+        return Vec::new();
+    };
+    hands.iter().enumerate().filter(|(_, hand)| *hand == best).map(|(index, _)| index).collect()
+}
+
+/// Splits `amount` between the winners in equal parts, the odd chips going one
+/// each to the first ones (callers order them starting left of the button)
+pub fn split_pot(amount: u64, winners: &[usize]) -> Vec<(usize, u64)> {
+    if winners.is_empty() {
+        return Vec::new();
+    }
+    let share = amount / winners.len() as u64;
+    let odd_chips = (amount % winners.len() as u64) as usize;
+    winners.iter().enumerate().map(|(place, winner)| (*winner, share + u64::from(place < odd_chips))).collect()
+}
+
+fn rank_five(cards: &[Card; HAND_CARDS]) -> HandRank {
+    // Ranks grouped by how many times they appear, the biggest groups first
+    let mut groups: Vec<(usize, Rank)> = Vec::new();
+    for card in cards {
+        match groups.iter_mut().find(|(_, rank)| *rank == card.rank) {
+            Some((count, _)) => *count += 1,
+            None => groups.push((1, card.rank)),
+        }
+    }
+    groups.sort_by_key(|group| Reverse(*group));
+    let ranks: Vec<Rank> = groups.iter().map(|(_, rank)| *rank).collect();
+
+    let flush = cards.iter().all(|card| card.suit == cards[0].suit);
+    let straight_high = match ranks.as_slice() {
+        [Rank::Ace, Rank::Five, Rank::Four, Rank::Three, Rank::Two] => Some(Rank::Five), // The wheel, the ace is low
+        [high, .., low] if ranks.len() == HAND_CARDS && high.value() - low.value() == 4 => Some(*high),
+        _ => None,
+    };
+
+    let category = match (straight_high, flush, groups[0].0, groups.get(1).map(|group| group.0)) {
+        (Some(_), true, _, _) => HandCategory::StraightFlush,
+        (_, _, 4, _) => HandCategory::FourOfAKind,
+        (_, _, 3, Some(2)) => HandCategory::FullHouse,
+        (_, true, _, _) => HandCategory::Flush,
+        (Some(_), _, _, _) => HandCategory::Straight,
+        (_, _, 3, _) => HandCategory::ThreeOfAKind,
+        (_, _, 2, Some(2)) => HandCategory::TwoPair,
+        (_, _, 2, _) => HandCategory::Pair,
+        _ => HandCategory::HighCard,
+    };
+    match (category, straight_high) {
+        (HandCategory::StraightFlush | HandCategory::Straight, Some(high)) => HandRank { category, ranks: vec![high] },
+        _ => HandRank { category, ranks },
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cards(names: &str) -> Vec<Card> {
+        names.split_whitespace().map(|name| name.parse().unwrap()).collect()
+    }
+
+    fn rank(names: &str) -> HandRank {
+        evaluate(&cards(names)).unwrap().rank
+    }
+
+    #[test]
+    fn test_categories() {
+        let hands = [
+            ("2_of_clubs 7_of_hearts 9_of_spades jack_of_clubs king_of_diamonds", HandCategory::HighCard),
+            ("2_of_clubs 2_of_hearts 9_of_spades jack_of_clubs king_of_diamonds", HandCategory::Pair),
+            ("2_of_clubs 2_of_hearts 9_of_spades 9_of_clubs king_of_diamonds", HandCategory::TwoPair),
+            ("9_of_hearts 2_of_hearts 9_of_spades 9_of_clubs king_of_diamonds", HandCategory::ThreeOfAKind),
+            ("10_of_hearts jack_of_clubs queen_of_spades king_of_clubs ace_of_diamonds", HandCategory::Straight),
+            ("2_of_clubs 7_of_clubs 9_of_clubs jack_of_clubs king_of_clubs", HandCategory::Flush),
+            ("9_of_hearts 2_of_hearts 9_of_spades 9_of_clubs 2_of_diamonds", HandCategory::FullHouse),
+            ("9_of_hearts 9_of_diamonds 9_of_spades 9_of_clubs 2_of_diamonds", HandCategory::FourOfAKind),
+            ("5_of_spades 6_of_spades 7_of_spades 8_of_spades 9_of_spades", HandCategory::StraightFlush),
+        ];
+        for (names, category) in hands {
+            assert_eq!(rank(names).category, category, "{}", names);
+        }
+        assert!(hands.windows(2).all(|pair| rank(pair[0].0) < rank(pair[1].0)));
+    }
+
+    #[test]
+    fn test_the_wheel_is_the_lowest_straight() {
+        let wheel = rank("ace_of_clubs 2_of_hearts 3_of_spades 4_of_clubs 5_of_diamonds");
+        assert_eq!(wheel, HandRank { category: HandCategory::Straight, ranks: vec![Rank::Five] });
+        assert!(wheel < rank("2_of_hearts 3_of_spades 4_of_clubs 5_of_diamonds 6_of_clubs"));
+        assert_eq!(rank("queen_of_clubs king_of_hearts ace_of_spades 2_of_clubs 3_of_diamonds").category, HandCategory::HighCard);
+    }
+
+    #[test]
+    fn test_kickers_and_best_of_seven() {
+        let aces_king = rank("ace_of_clubs ace_of_hearts king_of_spades 4_of_clubs 3_of_diamonds");
+        let aces_queen = rank("ace_of_spades ace_of_diamonds queen_of_spades 4_of_hearts 3_of_clubs");
+        assert!(aces_king > aces_queen);
+        assert_eq!(aces_king.ranks, vec![Rank::Ace, Rank::King, Rank::Four, Rank::Three]);
+
+        // A flush hidden among seven cards, the lowest club left out
+        let hand = evaluate(&cards("2_of_clubs 5_of_clubs 9_of_clubs jack_of_clubs king_of_clubs king_of_hearts 4_of_clubs")).unwrap();
+        assert_eq!(hand.rank.category, HandCategory::Flush);
+        assert!(!hand.cards.contains(&"2_of_clubs".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_winners_and_split_pot() {
+        let board = "2_of_clubs 7_of_hearts 9_of_spades jack_of_clubs king_of_diamonds";
+        let hands = [
+            rank(&format!("{} 3_of_hearts 4_of_hearts", board)), // Both play the board
+            rank(&format!("{} 3_of_spades 4_of_spades", board)),
+            rank(&format!("{} 2_of_hearts 4_of_clubs", board)),
+        ];
+        assert_eq!(winners(&hands), vec![2]);
+        assert_eq!(winners(&hands[..2]), vec![0, 1]);
+        assert_eq!(winners(&[]), Vec::<usize>::new());
+
+        assert_eq!(split_pot(100, &[4, 1, 7]), vec![(4, 34), (1, 33), (7, 33)]);
+        assert_eq!(split_pot(90, &[2]), vec![(2, 90)]);
+    }
+
+    #[test]
+    fn test_bad_hands() {
+        assert_eq!(evaluate(&cards("2_of_clubs 7_of_hearts")), Err(HandError::WrongCardCount(2)));
+        let twice = cards("2_of_clubs 7_of_hearts 9_of_spades jack_of_clubs 7_of_hearts");
+        assert_eq!(evaluate(&twice), Err(HandError::DuplicateCard(twice[1])));
+    }
+}
@@ -0,0 +1,370 @@
+// Predizione lato client e interpolazione degli snapshot del server.
+//
+// Remote entities are drawn a couple of ticks in the past, interpolating
+// between the two snapshots around that moment. The own player can't wait for
+// the server: its inputs are simulated right away on a local copy of the
+// server world, and re-simulated from the authoritative state every time a
+// snapshot acknowledges some of them.
+//
+// Any entity with a `NetworkId` is moved by these systems; the one matching the
+// client id received with `LoginAccepted` is the predicted local player.
+
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+use crate::networking::{ClientMessageEvent, ServerMessageEvent};
+use crate::protocol::{ClientMessage, EntitySnapshot, PlayerInput, ServerMessage, StateSnapshot};
+use crate::simulation::{ServerWorld, TICK_DURATION, TICK_RATE};
+
+
+// ====== CONSTANTS ======
+
+const SNAPSHOT_BUFFER_SIZE: usize = 32;
+const INTERPOLATION_DELAY_TICKS: f64 = 2.; // How far in the past remote entities are drawn
+const MAX_CLOCK_DRIFT_TICKS: f64 = 10.; // Past this the render clock jumps instead of catching up
+
+
+// ====== STRUCTS ======
+
+/// Id of the entity on the server, the same one found in the snapshots
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkId(pub u64);
+
+/// Direction the local player wants to move in, set it from the game's input systems
+#[derive(Resource, Debug, Default)]
+pub struct LocalInput(pub Vec2);
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SnapshotBuffer>()
+            .init_resource::<LocalInput>()
+            .init_resource::<Prediction>()
+            .add_systems(
+                Update,
+                (receive_snapshots, predict_local_player, interpolate_remote_entities).chain(),
+            );
+    }
+}
+
+/// Last snapshots received, ordered by tick, plus the tick being drawn right now
+#[derive(Resource, Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<StateSnapshot>,
+    render_tick: Option<f64>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, snapshot: StateSnapshot) {
+        // Late or duplicated snapshots go in their place, or nowhere if already known
+        let index = self.snapshots.partition_point(|s| s.tick < snapshot.tick);
+        if self.snapshots.get(index).is_some_and(|s| s.tick == snapshot.tick) {
+            return;
+        }
+        self.snapshots.insert(index, snapshot);
+
+        while self.snapshots.len() > SNAPSHOT_BUFFER_SIZE {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn latest_tick(&self) -> Option<u64> {
+        self.snapshots.back().map(|s| s.tick)
+    }
+
+    pub fn render_tick(&self) -> Option<f64> {
+        self.render_tick
+    }
+
+    /// Moves the render clock forward, keeping it a few ticks behind the newest snapshot
+    pub fn advance(&mut self, delta_seconds: f64) {
+        let Some(latest) = self.latest_tick() else {
+            return;
+        };
+        let latest = latest as f64;
+        let target = latest - INTERPOLATION_DELAY_TICKS;
+
+        let render_tick = match self.render_tick {
+            Some(tick) if (tick - target).abs() <= MAX_CLOCK_DRIFT_TICKS => tick + delta_seconds * TICK_RATE as f64,
+            _ => target,
+        };
+        self.render_tick = Some(render_tick.min(latest)); // Never extrapolate
+    }
+
+    /// Position of an entity at `tick`, interpolated between the snapshots around it
+    pub fn sample(&self, id: u64, tick: f64) -> Option<Vec2> {
+        let position = |snapshot: &StateSnapshot| {
+            snapshot.entities.iter().find(|e| e.id == id).map(|e| Vec2::from(e.position))
+        };
+
+        let after = self.snapshots.partition_point(|s| (s.tick as f64) < tick);
+        let before = after.checked_sub(1).and_then(|i| self.snapshots.get(i));
+        let after = self.snapshots.get(after);
+
+        match (before, after) {
+            (Some(a), Some(b)) => match (position(a), position(b)) {
+                (Some(from), Some(to)) => {
+                    let t = (tick - a.tick as f64) / (b.tick - a.tick) as f64;
+                    Some(from.lerp(to, t as f32))
+                }
+                (from, to) => to.or(from),
+            },
+            (Some(only), None) | (None, Some(only)) => position(only),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Local copy of the simulation running the inputs the server hasn't confirmed yet
+pub struct Predictor {
+    pub client_id: usize,
+    world: ServerWorld,
+    next_sequence: u64,
+    pending: VecDeque<PlayerInput>, // Sent but not yet acknowledged
+}
+
+impl Predictor {
+    pub fn new(client_id: usize) -> Self {
+        let mut world = ServerWorld::new();
+        world.add_player(client_id);
+        Predictor { client_id, world, next_sequence: 1, pending: VecDeque::new() }
+    }
+
+    pub fn next_input(&mut self, direction: Vec2) -> PlayerInput {
+        let input = PlayerInput { sequence: self.next_sequence, direction: direction.to_array() };
+        self.next_sequence += 1;
+        input
+    }
+
+    /// Simulates one tick with the input, before the server does
+    pub fn apply(&mut self, input: PlayerInput) {
+        self.world.apply_input(self.client_id, input);
+        self.world.step();
+        self.pending.push_back(input);
+    }
+
+    /// Restarts from the server's state and replays the inputs it hasn't seen yet
+    pub fn reconcile(&mut self, authoritative: &EntitySnapshot) {
+        self.pending.retain(|input| input.sequence > authoritative.last_input);
+        self.world.set_player_state(
+            self.client_id,
+            Vec2::from(authoritative.position),
+            Vec2::from(authoritative.velocity),
+        );
+        for input in &self.pending {
+            self.world.apply_input(self.client_id, *input);
+            self.world.step();
+        }
+    }
+
+    pub fn position(&self) -> Option<Vec2> {
+        self.world.player_position(self.client_id)
+    }
+
+    pub fn pending_inputs(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[derive(Resource)]
+pub struct Prediction {
+    pub predictor: Option<Predictor>, // Created once the server tells us our client id
+    input_timer: Timer, // Inputs are sent at the server's tick rate
+}
+
+impl Default for Prediction {
+    fn default() -> Self {
+        Prediction { predictor: None, input_timer: Timer::new(TICK_DURATION, TimerMode::Repeating) }
+    }
+}
+
+impl Prediction {
+    pub fn local_id(&self) -> Option<u64> {
+        self.predictor.as_ref().map(|p| p.client_id as u64)
+    }
+}
+
+
+// ====== METHODS ======
+
+pub fn receive_snapshots(
+    mut inbound: EventReader<ServerMessageEvent>,
+    mut buffer: ResMut<SnapshotBuffer>,
+    mut prediction: ResMut<Prediction>,
+) {
+    for ServerMessageEvent(message) in inbound.read() {
+        match message {
+            ServerMessage::LoginAccepted { client_id, .. } if prediction.local_id() != Some(*client_id as u64) => {
+                prediction.predictor = Some(Predictor::new(*client_id));
+            }
+            ServerMessage::StateSnapshot(snapshot) => {
+                if let Some(predictor) = prediction.predictor.as_mut() {
+                    let own = snapshot.entities.iter().find(|e| e.id == predictor.client_id as u64);
+                    if let Some(own) = own {
+                        predictor.reconcile(own);
+                    }
+                }
+                buffer.push(snapshot.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn predict_local_player(
+    time: Res<Time>,
+    local_input: Res<LocalInput>,
+    mut prediction: ResMut<Prediction>,
+    mut outbound: EventWriter<ClientMessageEvent>,
+    mut query: Query<(&NetworkId, &mut Transform)>,
+) {
+    let prediction = &mut *prediction;
+    let Some(predictor) = prediction.predictor.as_mut() else {
+        return;
+    };
+
+    prediction.input_timer.tick(time.delta());
+    for _ in 0..prediction.input_timer.times_finished_this_tick() {
+        let input = predictor.next_input(local_input.0);
+        outbound.send(ClientMessageEvent(ClientMessage::Input(input)));
+        predictor.apply(input);
+    }
+
+    let Some(position) = predictor.position() else {
+        return;
+    };
+    for (network_id, mut transform) in &mut query {
+        if network_id.0 == predictor.client_id as u64 {
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+        }
+    }
+}
+
+pub fn interpolate_remote_entities(
+    time: Res<Time>,
+    prediction: Res<Prediction>,
+    mut buffer: ResMut<SnapshotBuffer>,
+    mut query: Query<(&NetworkId, &mut Transform)>,
+) {
+    buffer.advance(time.delta_seconds_f64());
+    let Some(render_tick) = buffer.render_tick() else {
+        return;
+    };
+
+    let local_id = prediction.local_id();
+    for (network_id, mut transform) in &mut query {
+        if Some(network_id.0) == local_id {
+            continue;
+        }
+        if let Some(position) = buffer.sample(network_id.0, render_tick) {
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+        }
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(tick: u64, id: u64, x: f32) -> StateSnapshot {
+        StateSnapshot {
+            tick,
+            entities: vec![EntitySnapshot { id, position: [x, 0.], velocity: [0., 0.], last_input: 0 }],
+        }
+    }
+
+    #[test]
+    fn test_buffer_keeps_snapshots_ordered() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(2, 0, 20.));
+        buffer.push(snapshot(1, 0, 10.));
+        buffer.push(snapshot(2, 0, 99.)); // Duplicate, ignored
+
+        assert_eq!(buffer.latest_tick(), Some(2));
+        assert_eq!(buffer.sample(0, 2.), Some(Vec2::new(20., 0.)));
+
+        for tick in 3..100 {
+            buffer.push(snapshot(tick, 0, 0.));
+        }
+        assert_eq!(buffer.snapshots.len(), SNAPSHOT_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_interpolation_between_snapshots() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(10, 3, 0.));
+        buffer.push(snapshot(12, 3, 100.));
+
+        assert_eq!(buffer.sample(3, 11.), Some(Vec2::new(50., 0.)));
+        assert_eq!(buffer.sample(3, 10.5), Some(Vec2::new(25., 0.)));
+        // Outside the buffer the closest snapshot is used
+        assert_eq!(buffer.sample(3, 5.), Some(Vec2::new(0., 0.)));
+        assert_eq!(buffer.sample(3, 50.), Some(Vec2::new(100., 0.)));
+        assert_eq!(buffer.sample(4, 11.), None);
+    }
+
+    #[test]
+    fn test_render_clock_stays_behind_latest_snapshot() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.advance(0.1);
+        assert_eq!(buffer.render_tick(), None);
+
+        buffer.push(snapshot(100, 0, 0.));
+        buffer.advance(0.);
+        assert_eq!(buffer.render_tick(), Some(100. - INTERPOLATION_DELAY_TICKS));
+
+        // Never past the newest snapshot
+        buffer.advance(1.);
+        assert_eq!(buffer.render_tick(), Some(100.));
+
+        // Too far behind: jump
+        buffer.push(snapshot(500, 0, 0.));
+        buffer.advance(0.);
+        assert_eq!(buffer.render_tick(), Some(500. - INTERPOLATION_DELAY_TICKS));
+    }
+
+    #[test]
+    fn test_prediction_matches_server() {
+        let mut server = ServerWorld::new();
+        server.add_player(0);
+        let mut predictor = Predictor::new(0);
+
+        for _ in 0..5 {
+            let input = predictor.next_input(Vec2::X);
+            predictor.apply(input);
+            server.apply_input(0, input);
+            server.step();
+        }
+        assert_eq!(predictor.position(), server.player_position(0));
+
+        let snapshot = server.snapshot();
+        predictor.reconcile(&snapshot.entities[0]);
+        assert_eq!(predictor.pending_inputs(), 0);
+        assert_eq!(predictor.position(), server.player_position(0));
+    }
+
+    #[test]
+    fn test_reconcile_replays_unacknowledged_inputs() {
+        let mut predictor = Predictor::new(0);
+        for _ in 0..4 {
+            let input = predictor.next_input(Vec2::X);
+            predictor.apply(input);
+        }
+
+        // The server has seen the first two inputs but disagrees on where we are
+        let authoritative = EntitySnapshot { id: 0, position: [-200., 0.], velocity: [0., 0.], last_input: 2 };
+        predictor.reconcile(&authoritative);
+
+        assert_eq!(predictor.pending_inputs(), 2);
+        let position = predictor.position().unwrap();
+        assert!(position.x > -200. && position.x < 0.);
+    }
+}
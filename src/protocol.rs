@@ -0,0 +1,723 @@
+// Protocollo di comunicazione condiviso da server, client e networking.
+//
+// Every message on the wire is a frame: a 4-byte big-endian length
+// followed by exactly that many bytes of payload (usually JSON).
+// This way messages bigger than a single `read` and messages that arrive
+// coalesced in the same TCP segment are both handled correctly.
+//
+// The JSON payloads are the `ClientMessage` / `ServerMessage` enums below,
+// tagged by a `"type"` field, e.g. `{"type":"chat","text":"hi"}`.
+// Over UDP (see udp.rs) the same payloads travel without the length prefix,
+// each on the `Channel` its kind asks for.
+
+use native_tls::TlsStream;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::cards::Card;
+
+
+// ====== CONSTANTS ======
+
+/// Size in bytes of the length prefix in front of every frame
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Biggest payload accepted on either side of the connection (64 KiB)
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+
+// ====== STRUCTS ======
+
+/// Everything a client can ask the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Must be the first message on a new connection
+    Login { username: String, password: String },
+    /// Alternative to Login, with the token of a previous session
+    Resume { session_token: String },
+    Join { name: String },
+    Leave,
+    /// Ends the session but keeps the connection, to log in again as someone else
+    Logout,
+    Chat { text: String },
+    /// Private message to the player logged in with that username
+    Whisper { to: String, text: String },
+    Input(PlayerInput),
+    Ping { nonce: u64 },
+    Status,
+    CreateRoom { name: String, max_players: usize },
+    ListRooms,
+    JoinRoom { room_id: usize },
+    LeaveRoom,
+    /// Only the room owner can start the match
+    StartMatch,
+    /// Draw, play or discard at the card table of the current room
+    TableAction(TableAction),
+}
+
+/// Everything the server can send to a client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    LoginAccepted { client_id: usize, session_token: String },
+    LoginRejected { reason: String },
+    /// Answer to Logout: the session token is no longer valid
+    LoggedOut,
+    Joined { client_id: usize, name: String },
+    Left { client_id: usize },
+    /// `room` is the channel it was sent on, `None` for the lobby
+    Chat {
+        from: usize,
+        name: String,
+        text: String,
+        #[serde(default)]
+        room: Option<usize>,
+    },
+    /// Delivered to the recipient and echoed back to the sender
+    Whisper { from: usize, name: String, to: String, text: String },
+    StateSnapshot(StateSnapshot),
+    Pong { nonce: u64 },
+    Status { message: String },
+    RoomList { rooms: Vec<RoomInfo> },
+    /// Sent to everyone in the room whenever its players, owner or state change
+    RoomUpdate(RoomInfo),
+    /// Sent to the player who left (or was removed from) the room
+    RoomLeft { room_id: usize },
+    MatchStarted { room_id: usize },
+    /// Every card was played or discarded (or everyone left): the room is open again
+    MatchEnded { room_id: usize },
+    /// The own hand, only ever sent to its owner
+    Hand { cards: Vec<Card> },
+    /// Public state of the table: what everyone can see
+    TableUpdate(TableInfo),
+    /// Sent to the whole room, a drawn card stays secret
+    TableAction { player: usize, action: TableAction },
+    /// The server is closing this connection (kicked, server full, shutdown...)
+    Disconnected { reason: String },
+    /// The last message from this client was rejected
+    Error { message: String },
+}
+
+/// One frame of player input, numbered so the server can acknowledge it
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub sequence: u64,
+    pub direction: [f32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub tick: u64,
+    pub entities: Vec<EntitySnapshot>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    pub id: u64,
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    /// Sequence of the last input of this entity's owner already simulated
+    #[serde(default)]
+    pub last_input: u64,
+}
+
+/// Public view of a room, as shown in the lobby
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub id: usize,
+    pub name: String,
+    pub owner: usize,
+    pub players: Vec<usize>,
+    pub max_players: usize,
+    pub in_match: bool,
+}
+
+/// What a player can do at the card table, cards travel by asset name (e.g. `7_of_clubs`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TableAction {
+    Draw,
+    Play { card: Card },
+    Discard { card: Card },
+}
+
+/// Public view of a card table, without the cards in the hands
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct TableInfo {
+    pub room_id: usize,
+    pub deck_size: usize,
+    pub hands: Vec<HandInfo>, // In seating order
+    pub played: Vec<PlayedCard>, // Face up in the middle, oldest first
+    pub discard_top: Option<Card>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandInfo {
+    pub player: usize,
+    pub cards: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayedCard {
+    pub player: usize,
+    pub card: Card,
+}
+
+/// How a message travels over UDP; on TCP everything is reliable and ordered anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// May be lost, and an older one arriving after a newer one is dropped
+    Unreliable,
+    /// Resent until acknowledged, delivered exactly once and in the order sent
+    ReliableOrdered,
+}
+
+impl ClientMessage {
+    /// Inputs are sent every frame and pings are only useful when fresh, the rest must arrive
+    pub fn channel(&self) -> Channel {
+        match self {
+            ClientMessage::Input(_) | ClientMessage::Ping { .. } => Channel::Unreliable,
+            _ => Channel::ReliableOrdered,
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Every snapshot replaces the previous one, losing one is cheaper than waiting for it
+    pub fn channel(&self) -> Channel {
+        match self {
+            ServerMessage::StateSnapshot(_) | ServerMessage::Pong { .. } => Channel::Unreliable,
+            _ => Channel::ReliableOrdered,
+        }
+    }
+}
+
+/// A connection to a peer, either plain TCP or TLS on top of TCP
+#[derive(Debug)]
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Transport {
+    /// The socket below the (optional) TLS layer
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Transport::Plain(stream) => stream,
+            Transport::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Transport::Tls(_))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        if let Transport::Tls(stream) = self {
+            let _ = stream.shutdown(); // Best effort close_notify
+        }
+        self.tcp().shutdown(Shutdown::Both)
+    }
+}
+
+impl From<TcpStream> for Transport {
+    fn from(stream: TcpStream) -> Self {
+        Transport::Plain(stream)
+    }
+}
+
+impl From<TlsStream<TcpStream>> for Transport {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        Transport::Tls(stream)
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A Transport used by a reader and a writer thread at once. A TLS stream
+/// can't be split in two halves, so the reader waits for data on the bare
+/// socket and only takes the lock to read what already arrived; meanwhile
+/// the writer is free to send. Every clone is a handle on the same connection.
+#[derive(Debug, Clone)]
+pub struct SharedTransport {
+    socket: Arc<TcpStream>, // The socket below the TLS layer, to wait on and to shut down
+    transport: Arc<Mutex<Transport>>,
+}
+
+impl SharedTransport {
+    pub fn new(transport: Transport) -> io::Result<Self> {
+        let socket = transport.tcp().try_clone()?;
+        Ok(SharedTransport { socket: Arc::new(socket), transport: Arc::new(Mutex::new(transport)) })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.lock().is_tls()
+    }
+
+    /// Also bounds how long a read can hold the lock, e.g. on half a TLS record
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// A clone of the socket, closing it ends the connection for every handle
+    pub fn socket(&self) -> io::Result<TcpStream> {
+        self.socket.try_clone()
+    }
+
+    /// Wakes up both threads: blocked reads return, the next write fails
+    pub fn shutdown(&self) -> io::Result<()> {
+        // The close_notify is best effort, never wait for a thread busy on the stream
+        if let Ok(mut transport) = self.transport.try_lock() {
+            return transport.shutdown();
+        }
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Transport> {
+        self.transport.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Bytes the TLS layer already decrypted: the socket has nothing left to wait for
+    fn has_buffered_data(&self) -> bool {
+        match &*self.lock() {
+            Transport::Plain(_) => false,
+            Transport::Tls(stream) => stream.buffered_read_size().is_ok_and(|size| size > 0),
+        }
+    }
+}
+
+impl Read for SharedTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.has_buffered_data() && self.socket.peek(&mut [0u8])? == 0 {
+            return Ok(0);
+        }
+        self.lock().read(buf)
+    }
+}
+
+impl Write for SharedTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    /// One whole message under the lock
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut transport = self.lock();
+        transport.write_all(buf)?;
+        transport.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+/// Rebuilds frames from bytes that arrive in arbitrary chunks, for readers
+/// that can't block until a frame is complete (read timeouts, non-blocking sockets)
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder { buffer: Vec::new() }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, `None` until enough bytes have arrived
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..FRAME_HEADER_SIZE]);
+        let size = u32::from_be_bytes(header) as usize;
+        if size > MAX_FRAME_SIZE {
+            return Err(FrameError::Oversized { size, max: MAX_FRAME_SIZE });
+        }
+
+        if self.buffer.len() < FRAME_HEADER_SIZE + size {
+            return Ok(None);
+        }
+        let frame = self.buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + size].to_vec();
+        self.buffer.drain(..FRAME_HEADER_SIZE + size);
+        Ok(Some(frame))
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The underlying stream failed
+    Io(io::Error),
+    /// The peer closed the connection cleanly between two frames
+    ConnectionClosed,
+    /// The frame is bigger than `MAX_FRAME_SIZE`
+    Oversized { size: usize, max: usize },
+    /// The stream ended in the middle of a frame
+    Truncated { expected: usize, received: usize },
+    /// The payload is not valid JSON for the requested type
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "I/O error: {}", e),
+            FrameError::ConnectionClosed => write!(f, "Connection closed by peer"),
+            FrameError::Oversized { size, max } => {
+                write!(f, "Frame of {} bytes exceeds the maximum of {} bytes", size, max)
+            }
+            FrameError::Truncated { expected, received } => {
+                write!(f, "Truncated frame: expected {} bytes, received {}", expected, received)
+            }
+            FrameError::Json(e) => write!(f, "Invalid JSON payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            FrameError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FrameError {
+    fn from(e: serde_json::Error) -> Self {
+        FrameError::Json(e)
+    }
+}
+
+// Lets the functions returning io::Result use `?` on framing calls
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e,
+            FrameError::ConnectionClosed => io::Error::new(io::ErrorKind::ConnectionAborted, e),
+            FrameError::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            FrameError::Oversized { .. } | FrameError::Json(_) => {
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            }
+        }
+    }
+}
+
+
+// ====== METHODS ======
+
+/// Writes a single length-prefixed frame
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), FrameError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::Oversized { size: payload.len(), max: MAX_FRAME_SIZE });
+    }
+
+    let header = (payload.len() as u32).to_be_bytes();
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a single length-prefixed frame, blocking until it is complete
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>, FrameError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let received = read_until_full(reader, &mut header)?;
+    if received == 0 {
+        return Err(FrameError::ConnectionClosed);
+    }
+    if received < FRAME_HEADER_SIZE {
+        return Err(FrameError::Truncated { expected: FRAME_HEADER_SIZE, received });
+    }
+
+    let size = u32::from_be_bytes(header) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(FrameError::Oversized { size, max: MAX_FRAME_SIZE });
+    }
+
+    let mut payload = vec![0u8; size];
+    let received = read_until_full(reader, &mut payload)?;
+    if received < size {
+        return Err(FrameError::Truncated { expected: size, received });
+    }
+
+    Ok(payload)
+}
+
+/// Serializes `message` as JSON and sends it as one frame
+pub fn write_json<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<(), FrameError> {
+    let json = serde_json::to_vec(message)?;
+    write_frame(writer, &json)
+}
+
+/// Header and JSON payload of `message` in one buffer, for writers that aren't `Write` (e.g. async)
+pub fn encode_json<T: Serialize>(message: &T) -> Result<Vec<u8>, FrameError> {
+    let json = serde_json::to_vec(message)?;
+    if json.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::Oversized { size: json.len(), max: MAX_FRAME_SIZE });
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + json.len());
+    frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&json);
+    Ok(frame)
+}
+
+/// Reads one frame and deserializes its JSON payload
+pub fn read_json<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T, FrameError> {
+    let payload = read_frame(reader)?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// True for the errors a read with a timeout returns when nothing arrived
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Like `read_exact`, but reports how many bytes were read before EOF
+// instead of failing, so the caller can tell a clean close from a truncation
+fn read_until_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestMessage {
+        command: String,
+        data: Vec<u32>,
+    }
+
+    // Returns both ends of a connected local socket
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_round_trip_over_socket() {
+        let (mut client, server) = socket_pair();
+
+        let message = TestMessage { command: "status".to_string(), data: vec![1, 2, 3] };
+        write_json(&mut client, &message).unwrap();
+
+        let mut reader = BufReader::new(server);
+        let received: TestMessage = read_json(&mut reader).unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[test]
+    fn test_message_bigger_than_old_buffer() {
+        let (mut client, server) = socket_pair();
+
+        // Way over the 512 bytes the old fixed buffer could hold
+        let message = TestMessage { command: "big".to_string(), data: (0..5000).collect() };
+        let sender = thread::spawn(move || write_json(&mut client, &message).map(|_| message));
+
+        let mut reader = BufReader::new(server);
+        let received: TestMessage = read_json(&mut reader).unwrap();
+        assert_eq!(received, sender.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_coalesced_messages_are_split() {
+        let (mut client, server) = socket_pair();
+
+        // Both frames go out in a single write
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"first").unwrap();
+        write_frame(&mut bytes, b"second").unwrap();
+        client.write_all(&bytes).unwrap();
+
+        let mut reader = BufReader::new(server);
+        assert_eq!(read_frame(&mut reader).unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_clean_close_between_frames() {
+        let (client, server) = socket_pair();
+        drop(client);
+
+        let mut reader = BufReader::new(server);
+        assert!(matches!(read_frame(&mut reader), Err(FrameError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        let payload = vec![0u8; MAX_FRAME_SIZE + 1];
+        let mut bytes = Vec::new();
+        assert!(matches!(write_frame(&mut bytes, &payload), Err(FrameError::Oversized { .. })));
+        assert!(bytes.is_empty());
+
+        // A malicious header must be rejected before allocating the payload
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        let mut reader = Cursor::new(header.to_vec());
+        assert!(matches!(
+            read_frame(&mut reader),
+            Err(FrameError::Oversized { size, max }) if size == MAX_FRAME_SIZE + 1 && max == MAX_FRAME_SIZE
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"hello world").unwrap();
+        bytes.truncate(bytes.len() - 3);
+
+        let mut reader = Cursor::new(bytes);
+        assert!(matches!(
+            read_frame(&mut reader),
+            Err(FrameError::Truncated { expected: 11, received: 8 })
+        ));
+
+        let mut reader = Cursor::new(vec![0u8, 0]);
+        assert!(matches!(
+            read_frame(&mut reader),
+            Err(FrameError::Truncated { expected: FRAME_HEADER_SIZE, received: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_decoder_handles_partial_frames() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"first").unwrap();
+        write_frame(&mut bytes, b"second").unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        // Feed one byte at a time, the worst possible fragmentation
+        for byte in bytes {
+            decoder.extend(&[byte]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn test_decoder_rejects_oversized_header() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        assert!(matches!(decoder.next_frame(), Err(FrameError::Oversized { .. })));
+    }
+
+    #[test]
+    fn test_messages_are_tagged() {
+        let json = serde_json::to_string(&ClientMessage::Chat { text: "hi".to_string() }).unwrap();
+        assert_eq!(json, r#"{"type":"chat","text":"hi"}"#);
+
+        let message: ClientMessage = serde_json::from_str(r#"{"type":"leave"}"#).unwrap();
+        assert_eq!(message, ClientMessage::Leave);
+
+        let input = ClientMessage::Input(PlayerInput { sequence: 7, direction: [1., 0.] });
+        let json = serde_json::to_string(&input).unwrap();
+        assert_eq!(serde_json::from_str::<ClientMessage>(&json).unwrap(), input);
+    }
+
+    #[test]
+    fn test_table_action_is_flattened() {
+        let play = ClientMessage::TableAction(TableAction::Play { card: "ace_of_spades".parse().unwrap() });
+        let json = serde_json::to_string(&play).unwrap();
+        assert_eq!(json, r#"{"type":"table_action","action":"play","card":"ace_of_spades"}"#);
+        assert_eq!(serde_json::from_str::<ClientMessage>(&json).unwrap(), play);
+
+        let draw: ClientMessage = serde_json::from_str(r#"{"type":"table_action","action":"draw"}"#).unwrap();
+        assert_eq!(draw, ClientMessage::TableAction(TableAction::Draw));
+
+        let joker = r#"{"type":"table_action","action":"play","card":"red_joker"}"#;
+        assert!(serde_json::from_str::<ClientMessage>(joker).is_err());
+    }
+
+    #[test]
+    fn test_unknown_command_is_an_error() {
+        let result = serde_json::from_str::<ClientMessage>(r#"{"type":"fly","speed":3}"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_encoded_frame_matches_write_json() {
+        let message = ClientMessage::Chat { text: "ciao".to_string() };
+        let mut written = Vec::new();
+        write_json(&mut written, &message).unwrap();
+        assert_eq!(encode_json(&message).unwrap(), written);
+    }
+
+    #[test]
+    fn test_invalid_json_payload() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"not json").unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let result: Result<TestMessage, _> = read_json(&mut reader);
+        assert!(matches!(result, Err(FrameError::Json(_))));
+    }
+}
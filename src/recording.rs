@@ -0,0 +1,536 @@
+// Registrazione delle sessioni di rete e replay offline, per il debug del multiplayer.
+//
+// With recording on (`server --record <file>`) the server writes one JSON line
+// per event, in the order they happened under the state lock: clients coming
+// and going, every message in and out, the simulation ticks, the admin
+// actions and first of all the seed of its random numbers. Passwords are never
+// written. `Replay` feeds the same events, at the recorded times, to a fresh
+// ServerState and checks the server answers exactly as it did the first time.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self as std_mpsc, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::auth::{CredentialStore, UserRecord};
+use crate::protocol::{ClientMessage, ServerMessage};
+use crate::server::{
+    finish_login, handle_payload, process_message, server_log, simulation_tick, LogLevel, OutgoingMessage,
+    PendingLogin, ServerState, CLIENT_QUEUE_SIZE,
+};
+use crate::simulation::ServerWorld;
+
+
+// ====== CONSTANTS ======
+
+/// Written in place of the passwords
+pub const REDACTED: &str = "<redacted>";
+
+
+// ====== STRUCTS ======
+
+/// Something that happened on the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    /// Always the first line, the seed makes the decks come out the same on replay
+    Started { seed: u64 },
+    Connected { client_id: usize },
+    Inbound { client_id: usize, message: ClientMessage },
+    /// The answer of the password check of the client's last Login
+    LoginChecked { client_id: usize, accepted: bool },
+    /// A payload that wasn't a valid message, as (lossy) text
+    InvalidInbound { client_id: usize, payload: String },
+    Outbound { client_id: usize, message: ServerMessage },
+    /// The same message queued for several clients (a snapshot, a room update...), written once
+    Broadcast { client_ids: Vec<usize>, message: ServerMessage },
+    /// Disconnected by the server: kick, idle timeout, shutdown...
+    Dropped { client_id: usize, reason: String },
+    /// Disconnected because its queue was full
+    Lagging { client_id: usize },
+    Disconnected { client_id: usize },
+    AdminBroadcast { message: String },
+    Tick,
+}
+
+impl RecordedEvent {
+    /// An inbound message, without the password of a Login
+    pub fn inbound(client_id: usize, message: &ClientMessage) -> Self {
+        let message = match message {
+            ClientMessage::Login { username, .. } => {
+                ClientMessage::Login { username: username.clone(), password: REDACTED.to_string() }
+            }
+            message => message.clone(),
+        };
+        RecordedEvent::Inbound { client_id, message }
+    }
+}
+
+/// One line of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordEntry {
+    pub at_ms: u64, // Since the recording started
+    #[serde(flatten)]
+    pub event: RecordedEvent,
+}
+
+/// Writes the events of a session as JSON lines. The server records under its
+/// state lock, so the events only go through a channel there: a thread of the
+/// Recorder serializes them and does the disk writes.
+pub struct Recorder {
+    start: Instant,
+    sender: Option<Sender<RecordEntry>>, // Taken on drop, which ends the writer thread
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        let (sender, receiver) = std_mpsc::channel();
+        let writer = thread::spawn(move || write_entries(writer, receiver));
+        Recorder { start: Instant::now(), sender: Some(sender), writer: Some(writer) }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn record(&self, now: Instant, event: RecordedEvent) {
+        let entry = RecordEntry { at_ms: now.saturating_duration_since(self.start).as_millis() as u64, event };
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(entry); // The writer thread only stops on drop
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").field("start", &self.start).finish_non_exhaustive()
+    }
+}
+
+impl Drop for Recorder {
+    // Everything recorded so far is on disk once the Recorder is gone
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The first line is not a `started` event
+    NotARecording,
+    /// The replayed server gave a new client another id than the recorded one
+    ClientIdMismatch { recorded: usize, replayed: usize },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::NotARecording => write!(f, "Not a recording: the first event must be 'started'"),
+            ReplayError::ClientIdMismatch { recorded, replayed } => {
+                write!(f, "Client {} was recorded, but the replay gave it id {}", recorded, replayed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// How a replay went
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    pub events: usize,
+    pub clients: usize, // Still connected at the end
+    pub mismatches: Vec<String>, // Answers of the replayed server that differ from the recording
+}
+
+// Passwords are not in the recording: a login succeeds if its login_checked event says so
+struct ReplayCredentials;
+
+impl CredentialStore for ReplayCredentials {
+    fn user(&self, _username: &str) -> Option<UserRecord> {
+        None
+    }
+}
+
+/// A recorded session being fed back to a fresh server, one event at a time
+pub struct Replay {
+    entries: Vec<RecordEntry>,
+    next: usize,
+    state: ServerState,
+    world: ServerWorld,
+    start: Instant,
+    outputs: HashMap<usize, Receiver<OutgoingMessage>>, // What the replayed server sent to each client
+    logins: HashMap<usize, PendingLogin>, // Waiting for their login_checked event
+    tokens: HashMap<String, String>, // Recorded session token -> the one issued by the replay
+    mismatches: Vec<String>,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<RecordEntry>) -> Result<Self, ReplayError> {
+        let Some(RecordedEvent::Started { seed }) = entries.first().map(|entry| &entry.event) else {
+            return Err(ReplayError::NotARecording);
+        };
+        let state = ServerState::for_replay(Box::new(ReplayCredentials), *seed);
+
+        Ok(Replay {
+            entries,
+            next: 1,
+            state,
+            world: ServerWorld::new(),
+            start: Instant::now(),
+            outputs: HashMap::new(),
+            logins: HashMap::new(),
+            tokens: HashMap::new(),
+            mismatches: Vec::new(),
+        })
+    }
+
+    /// Replays the next event, returns false once at the end of the recording
+    pub fn step(&mut self) -> Result<bool, ReplayError> {
+        let Some(entry) = self.entries.get(self.next).cloned() else {
+            return Ok(false);
+        };
+        let index = self.next;
+        self.next += 1;
+        self.state.set_clock(self.start + Duration::from_millis(entry.at_ms));
+
+        match entry.event {
+            RecordedEvent::Started { .. } => {}
+            RecordedEvent::Connected { client_id } => {
+                let (sender, receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+                let replayed = self.state.add_client(sender);
+                if replayed != client_id {
+                    return Err(ReplayError::ClientIdMismatch { recorded: client_id, replayed });
+                }
+                self.outputs.insert(client_id, receiver);
+            }
+            RecordedEvent::Inbound { client_id, message } => {
+                let message = self.prepare_inbound(message);
+                if let Some(login) = process_message(client_id, message, &mut self.state) {
+                    self.logins.insert(client_id, login);
+                }
+            }
+            RecordedEvent::LoginChecked { client_id, accepted } => {
+                if let Some(login) = self.logins.remove(&client_id) {
+                    finish_login(&mut self.state, login, accepted);
+                }
+            }
+            RecordedEvent::InvalidInbound { client_id, payload } => {
+                handle_payload(client_id, payload.as_bytes(), &mut self.state);
+            }
+            RecordedEvent::Outbound { client_id, message } => self.check_outbound(index, client_id, message),
+            RecordedEvent::Broadcast { client_ids, message } => {
+                for client_id in client_ids {
+                    self.check_outbound(index, client_id, message.clone());
+                }
+            }
+            RecordedEvent::Dropped { client_id, reason } => {
+                self.state.disconnect_client(client_id, &reason);
+            }
+            RecordedEvent::Lagging { client_id } => self.state.drop_lagging_client(client_id),
+            RecordedEvent::Disconnected { client_id } => self.state.remove_client(client_id),
+            RecordedEvent::AdminBroadcast { message } => self.state.broadcast_status(&message),
+            RecordedEvent::Tick => simulation_tick(&mut self.world, &mut self.state),
+        }
+        Ok(true)
+    }
+
+    /// Replays the whole recording
+    pub fn run(&mut self) -> Result<ReplayReport, ReplayError> {
+        while self.step()? {}
+        Ok(ReplayReport {
+            events: self.entries.len(),
+            clients: self.state.client_ids().len(),
+            mismatches: self.mismatches.clone(),
+        })
+    }
+
+    pub(crate) fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    // What the recording can't hold as is: the random session tokens
+    fn prepare_inbound(&self, message: ClientMessage) -> ClientMessage {
+        match message {
+            ClientMessage::Resume { session_token } => {
+                let session_token = self.tokens.get(&session_token).cloned().unwrap_or(session_token);
+                ClientMessage::Resume { session_token }
+            }
+            message => message,
+        }
+    }
+
+    fn check_outbound(&mut self, index: usize, client_id: usize, recorded: ServerMessage) {
+        let Some(output) = self.outputs.get_mut(&client_id) else {
+            self.mismatches.push(format!("event {}: message for unknown client {}", index + 1, client_id));
+            return;
+        };
+
+        loop {
+            let Ok(actual) = output.try_recv() else {
+                self.mismatches.push(format!("event {}: client {} never got {:?}", index + 1, client_id, recorded));
+                return;
+            };
+            let actual = actual.message().clone();
+            // A snapshot the recorded server skipped because the client was slow
+            if matches!(actual, ServerMessage::StateSnapshot(_)) && !matches!(recorded, ServerMessage::StateSnapshot(_)) {
+                continue;
+            }
+
+            match (&recorded, &actual) {
+                (
+                    ServerMessage::LoginAccepted { client_id: recorded_id, session_token: recorded_token },
+                    ServerMessage::LoginAccepted { client_id: replayed_id, session_token },
+                ) if recorded_id == replayed_id => {
+                    self.tokens.insert(recorded_token.clone(), session_token.clone());
+                }
+                _ if recorded == actual => {}
+                _ => self.mismatches.push(format!(
+                    "event {}: client {} expected {:?}, got {:?}",
+                    index + 1, client_id, recorded, actual
+                )),
+            }
+            return;
+        }
+    }
+}
+
+
+// ====== METHODS ======
+
+// The writer thread of a Recorder, until the Recorder is dropped. Only the first write error is logged
+fn write_entries(mut writer: impl Write, entries: std_mpsc::Receiver<RecordEntry>) {
+    let mut failed = false;
+    for entry in entries {
+        // Flushed regularly, so a crash or a kill loses at most one tick
+        let flush = matches!(entry.event, RecordedEvent::Tick | RecordedEvent::Disconnected { .. });
+        let mut result = serde_json::to_writer(&mut writer, &entry)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"));
+        if flush {
+            result = result.and_then(|_| writer.flush());
+        }
+        if let Err(e) = result {
+            if !failed {
+                server_log!(LogLevel::Error, "Could not write the recording: {}", e);
+            }
+            failed = true;
+        }
+    }
+    let _ = writer.flush();
+}
+
+/// Reads a recording, one JSON event per line
+pub fn load_recording(path: impl AsRef<Path>) -> io::Result<Vec<RecordEntry>> {
+    parse_recording(&fs::read_to_string(path)?)
+}
+
+pub fn parse_recording(text: &str) -> io::Result<Vec<RecordEntry>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e))
+            })
+        })
+        .collect()
+}
+
+/// What one client received, with the time since the start of the recording,
+/// e.g. to play it back into the game's event stream
+pub fn client_messages(entries: &[RecordEntry], client_id: usize) -> Vec<(Duration, ServerMessage)> {
+    entries
+        .iter()
+        .filter_map(|entry| match &entry.event {
+            RecordedEvent::Outbound { client_id: to, message } if *to == client_id => {
+                Some((Duration::from_millis(entry.at_ms), message.clone()))
+            }
+            RecordedEvent::Broadcast { client_ids, message } if client_ids.contains(&client_id) => {
+                Some((Duration::from_millis(entry.at_ms), message.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::test_credentials;
+    use crate::protocol::{read_json, write_json, TableAction};
+    use crate::server::{start_server, ServerConfig};
+    use std::net::TcpStream;
+
+    // A handwritten session in the recording format: two players, a room and a match,
+    // then one of them comes back with its session token
+    const CAPTURED_SESSION: &str = r#"
+{"at_ms":0,"event":"started","seed":42}
+{"at_ms":3,"event":"connected","client_id":0}
+{"at_ms":5,"event":"inbound","client_id":0,"message":{"type":"login","username":"ivan","password":"<redacted>"}}
+{"at_ms":6,"event":"login_checked","client_id":0,"accepted":true}
+{"at_ms":6,"event":"outbound","client_id":0,"message":{"type":"login_accepted","client_id":0,"session_token":"aa"}}
+{"at_ms":9,"event":"connected","client_id":1}
+{"at_ms":10,"event":"inbound","client_id":1,"message":{"type":"login","username":"farid","password":"<redacted>"}}
+{"at_ms":11,"event":"login_checked","client_id":1,"accepted":false}
+{"at_ms":11,"event":"outbound","client_id":1,"message":{"type":"login_rejected","reason":"Invalid username or password"}}
+{"at_ms":15,"event":"inbound","client_id":1,"message":{"type":"login","username":"farid","password":"<redacted>"}}
+{"at_ms":16,"event":"login_checked","client_id":1,"accepted":true}
+{"at_ms":16,"event":"outbound","client_id":1,"message":{"type":"login_accepted","client_id":1,"session_token":"bb"}}
+{"at_ms":20,"event":"inbound","client_id":0,"message":{"type":"create_room","name":"table","max_players":4}}
+{"at_ms":25,"event":"inbound","client_id":1,"message":{"type":"join_room","room_id":0}}
+{"at_ms":30,"event":"inbound","client_id":0,"message":{"type":"start_match"}}
+{"at_ms":31,"event":"tick"}
+{"at_ms":40,"event":"disconnected","client_id":1}
+{"at_ms":45,"event":"connected","client_id":2}
+{"at_ms":46,"event":"inbound","client_id":2,"message":{"type":"resume","session_token":"bb"}}
+{"at_ms":47,"event":"outbound","client_id":2,"message":{"type":"login_accepted","client_id":2,"session_token":"bb"}}
+{"at_ms":50,"event":"inbound","client_id":2,"message":{"type":"join","name":"farid the second"}}
+"#;
+
+    #[test]
+    fn test_entries_are_flat_json_lines() {
+        let entry = RecordEntry { at_ms: 7, event: RecordedEvent::Disconnected { client_id: 3 } };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"at_ms":7,"event":"disconnected","client_id":3}"#);
+        assert_eq!(parse_recording(&format!("\n{}\n", json)).unwrap(), vec![entry]);
+        assert!(parse_recording("{\"at_ms\":1}").is_err());
+    }
+
+    #[test]
+    fn test_passwords_are_not_recorded() {
+        let login = ClientMessage::Login { username: "ivan".to_string(), password: "secret".to_string() };
+        let RecordedEvent::Inbound { message, .. } = RecordedEvent::inbound(0, &login) else { unreachable!() };
+        assert_eq!(message, ClientMessage::Login { username: "ivan".to_string(), password: REDACTED.to_string() });
+    }
+
+    #[test]
+    fn test_replay_captured_session() {
+        let entries = parse_recording(CAPTURED_SESSION).unwrap();
+        let received = client_messages(&entries, 1);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].0, Duration::from_millis(16));
+
+        let mut replay = Replay::new(entries).unwrap();
+        let report = replay.run().unwrap();
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        assert_eq!(report.clients, 2);
+
+        let state = replay.state();
+        assert_eq!(state.client_ids(), vec![0, 2]);
+        assert!(state.is_authenticated(2)); // Resumed with the token of the replayed login
+        assert_eq!(state.client_name(2), "farid the second");
+
+        // Client 1 left: the room and the table only have the owner
+        let room = state.rooms().room(0).unwrap();
+        assert_eq!(room.players, vec![0]);
+        assert!(room.in_match);
+        let table = state.table(0).unwrap().info(0);
+        assert_eq!(table.hands.len(), 1);
+        assert_eq!(table.deck_size, 52 - 5);
+    }
+
+    #[test]
+    fn test_replay_reports_differences() {
+        let session = CAPTURED_SESSION.replace(r#""reason":"Invalid username or password""#, r#""reason":"Nope""#);
+        let mut replay = Replay::new(parse_recording(&session).unwrap()).unwrap();
+        let report = replay.run().unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert!(report.mismatches[0].starts_with("event 9:"), "{}", report.mismatches[0]);
+
+        let without_start = CAPTURED_SESSION.replace(r#""event":"started","seed":42"#, r#""event":"tick""#);
+        assert_eq!(Replay::new(parse_recording(&without_start).unwrap()).err(), Some(ReplayError::NotARecording));
+    }
+
+    // Record a real session over TCP, then replay it: same answers, same final state
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("ivan_game_session_{}.jsonl", std::process::id()));
+        let config = ServerConfig {
+            port: 0,
+            log_level: LogLevel::Error,
+            record_path: Some(path.clone()),
+            ..ServerConfig::default()
+        };
+        let handle = start_server(&config, test_credentials(), None).unwrap();
+
+        let mut players = Vec::new();
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+            let login = ClientMessage::Login { username: "player1".to_string(), password: "securepassword".to_string() };
+            write_json(&mut stream, &login).unwrap();
+            players.push(stream);
+        }
+        let mut read_until = |stream: &mut TcpStream, done: fn(&ServerMessage) -> bool| loop {
+            let message: ServerMessage = read_json(stream).unwrap();
+            if done(&message) {
+                return message;
+            }
+        };
+        for stream in &mut players {
+            read_until(stream, |m| matches!(m, ServerMessage::LoginAccepted { .. }));
+        }
+
+        write_json(&mut players[0], &ClientMessage::CreateRoom { name: "table".to_string(), max_players: 2 }).unwrap();
+        read_until(&mut players[0], |m| matches!(m, ServerMessage::RoomUpdate(_)));
+        write_json(&mut players[1], &ClientMessage::JoinRoom { room_id: 0 }).unwrap();
+        read_until(&mut players[1], |m| matches!(m, ServerMessage::RoomUpdate(_)));
+        write_json(&mut players[0], &ClientMessage::StartMatch).unwrap();
+        let ServerMessage::Hand { cards } = read_until(&mut players[0], |m| matches!(m, ServerMessage::Hand { .. })) else {
+            unreachable!()
+        };
+        let play = ClientMessage::TableAction(TableAction::Play { card: cards[2] });
+        write_json(&mut players[0], &play).unwrap();
+        read_until(&mut players[1], |m| matches!(m, ServerMessage::TableAction { .. }));
+        write_json(&mut players[1], &ClientMessage::Chat { text: "nice".to_string() }).unwrap();
+        read_until(&mut players[0], |m| matches!(m, ServerMessage::Chat { .. }));
+
+        handle.shutdown();
+        handle.wait();
+        drop(players);
+
+        let entries = load_recording(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert!(entries.iter().any(|entry| entry.event == RecordedEvent::Tick));
+        // No snapshot is written per client: each is one Broadcast naming both clients
+        let snapshots = entries.iter().filter(|entry| match &entry.event {
+            RecordedEvent::Outbound { message, .. } => matches!(message, ServerMessage::StateSnapshot(_)),
+            _ => false,
+        });
+        assert_eq!(snapshots.count(), 0);
+        assert!(entries.iter().any(|entry| matches!(
+            &entry.event,
+            RecordedEvent::Broadcast { client_ids, message: ServerMessage::StateSnapshot(_) } if client_ids.len() == 2
+        )));
+
+        let mut replay = Replay::new(entries.clone()).unwrap();
+        let report = replay.run().unwrap();
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        assert_eq!(report.clients, 0); // The shutdown disconnected everyone
+        assert!(replay.state().rooms().list().is_empty());
+
+        // Up to the shutdown, the table is as the players left it
+        let shutdown = entries.iter().position(|entry| matches!(entry.event, RecordedEvent::Dropped { .. })).unwrap();
+        let mut replay = Replay::new(entries[..shutdown].to_vec()).unwrap();
+        assert_eq!(replay.run().unwrap().clients, 2);
+        let table = replay.state().table(0).unwrap().info(0);
+        assert_eq!(table.played.len(), 1);
+        assert_eq!(table.played[0].card, cards[2]);
+    }
+}
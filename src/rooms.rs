@@ -0,0 +1,294 @@
+// Stanze del server: ogni stanza e' un tavolo / una partita separata.
+//
+// Pure bookkeeping, no sockets: the server asks the RoomManager what changed
+// and takes care of notifying the players.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::protocol::RoomInfo;
+
+
+// ====== CONSTANTS ======
+
+pub const DEFAULT_MAX_PLAYERS: usize = 4;
+pub const MAX_ROOM_PLAYERS: usize = 16;
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Room {
+    pub id: usize,
+    pub name: String,
+    pub owner: usize, // The only player who can start the match
+    pub players: Vec<usize>, // In joining order
+    pub max_players: usize,
+    pub in_match: bool,
+}
+
+impl Room {
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= self.max_players
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner,
+            players: self.players.clone(),
+            max_players: self.max_players,
+            in_match: self.in_match,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomError {
+    NotFound,
+    Full,
+    AlreadyInRoom,
+    NotInRoom,
+    NotOwner,
+    MatchInProgress,
+    InvalidMaxPlayers,
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RoomError::NotFound => "Room not found",
+            RoomError::Full => "Room is full",
+            RoomError::AlreadyInRoom => "Already in a room, leave it first",
+            RoomError::NotInRoom => "Not in a room",
+            RoomError::NotOwner => "Only the room owner can do that",
+            RoomError::MatchInProgress => "The match already started",
+            RoomError::InvalidMaxPlayers => "Max players must be between 1 and 16",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+#[derive(Debug, Default)]
+pub struct RoomManager {
+    rooms: HashMap<usize, Room>,
+    player_rooms: HashMap<usize, usize>, // Player (client id) -> room id
+    next_room_id: usize,
+}
+
+impl RoomManager {
+    pub fn new() -> Self {
+        RoomManager::default()
+    }
+
+    /// Creates a room owned by `owner`, who joins it right away
+    pub fn create_room(&mut self, owner: usize, name: &str, max_players: usize) -> Result<&Room, RoomError> {
+        if self.player_rooms.contains_key(&owner) {
+            return Err(RoomError::AlreadyInRoom);
+        }
+        if max_players == 0 || max_players > MAX_ROOM_PLAYERS {
+            return Err(RoomError::InvalidMaxPlayers);
+        }
+
+        let room_id = self.next_room_id;
+        self.next_room_id += 1;
+        self.rooms.insert(room_id, Room {
+            id: room_id,
+            name: name.to_string(),
+            owner,
+            players: vec![owner],
+            max_players,
+            in_match: false,
+        });
+        self.player_rooms.insert(owner, room_id);
+        Ok(&self.rooms[&room_id])
+    }
+
+    pub fn join_room(&mut self, player: usize, room_id: usize) -> Result<&Room, RoomError> {
+        if self.player_rooms.contains_key(&player) {
+            return Err(RoomError::AlreadyInRoom);
+        }
+        let room = self.rooms.get_mut(&room_id).ok_or(RoomError::NotFound)?;
+        if room.in_match {
+            return Err(RoomError::MatchInProgress);
+        }
+        if room.is_full() {
+            return Err(RoomError::Full);
+        }
+
+        room.players.push(player);
+        self.player_rooms.insert(player, room_id);
+        Ok(room)
+    }
+
+    /// Removes the player from its room. Returns the room id and the room as it
+    /// is now, or `None` if it was the last player and the room was closed.
+    /// If the owner leaves, the player who joined first after them takes over.
+    pub fn leave_room(&mut self, player: usize) -> Result<(usize, Option<&Room>), RoomError> {
+        let room_id = self.player_rooms.remove(&player).ok_or(RoomError::NotInRoom)?;
+        let room = self.rooms.get_mut(&room_id).ok_or(RoomError::NotFound)?;
+
+        room.players.retain(|p| *p != player);
+        if room.players.is_empty() {
+            self.rooms.remove(&room_id);
+            return Ok((room_id, None));
+        }
+        if room.owner == player {
+            room.owner = room.players[0];
+        }
+        Ok((room_id, self.rooms.get(&room_id)))
+    }
+
+    pub fn start_match(&mut self, player: usize) -> Result<&Room, RoomError> {
+        let room_id = *self.player_rooms.get(&player).ok_or(RoomError::NotInRoom)?;
+        let room = self.rooms.get_mut(&room_id).ok_or(RoomError::NotFound)?;
+        if room.owner != player {
+            return Err(RoomError::NotOwner);
+        }
+        if room.in_match {
+            return Err(RoomError::MatchInProgress);
+        }
+
+        room.in_match = true;
+        Ok(room)
+    }
+
+    /// Opens the room again to new players and to the next match
+    pub fn end_match(&mut self, room_id: usize) -> Option<&Room> {
+        let room = self.rooms.get_mut(&room_id)?;
+        room.in_match = false;
+        Some(room)
+    }
+
+    pub fn room(&self, room_id: usize) -> Option<&Room> {
+        self.rooms.get(&room_id)
+    }
+
+    pub fn room_of(&self, player: usize) -> Option<&Room> {
+        self.player_rooms.get(&player).and_then(|room_id| self.rooms.get(room_id))
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self.rooms.values().map(Room::info).collect();
+        rooms.sort_by_key(|room| room.id);
+        rooms
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_join() {
+        let mut rooms = RoomManager::new();
+        let room_id = rooms.create_room(0, "table", 2).unwrap().id;
+
+        let room = rooms.join_room(1, room_id).unwrap();
+        assert_eq!(room.players, vec![0, 1]);
+        assert_eq!(room.owner, 0);
+        assert_eq!(rooms.room_of(1).unwrap().id, room_id);
+    }
+
+    #[test]
+    fn test_room_is_full() {
+        let mut rooms = RoomManager::new();
+        let room_id = rooms.create_room(0, "duel", 2).unwrap().id;
+        rooms.join_room(1, room_id).unwrap();
+
+        assert_eq!(rooms.join_room(2, room_id).unwrap_err(), RoomError::Full);
+        assert!(rooms.room_of(2).is_none());
+    }
+
+    #[test]
+    fn test_one_room_at_a_time() {
+        let mut rooms = RoomManager::new();
+        let first = rooms.create_room(0, "first", 4).unwrap().id;
+        let second = rooms.create_room(1, "second", 4).unwrap().id;
+
+        assert_eq!(rooms.join_room(0, second).unwrap_err(), RoomError::AlreadyInRoom);
+        assert_eq!(rooms.create_room(0, "third", 4).unwrap_err(), RoomError::AlreadyInRoom);
+        assert_eq!(rooms.join_room(2, 99).unwrap_err(), RoomError::NotFound);
+        assert_eq!(rooms.room_of(0).unwrap().id, first);
+    }
+
+    #[test]
+    fn test_invalid_max_players() {
+        let mut rooms = RoomManager::new();
+        assert_eq!(rooms.create_room(0, "empty", 0).unwrap_err(), RoomError::InvalidMaxPlayers);
+        assert_eq!(
+            rooms.create_room(0, "huge", MAX_ROOM_PLAYERS + 1).unwrap_err(),
+            RoomError::InvalidMaxPlayers
+        );
+    }
+
+    #[test]
+    fn test_owner_leaving_passes_ownership() {
+        let mut rooms = RoomManager::new();
+        let room_id = rooms.create_room(0, "table", 4).unwrap().id;
+        rooms.join_room(1, room_id).unwrap();
+        rooms.join_room(2, room_id).unwrap();
+
+        let (left_room, room) = rooms.leave_room(0).unwrap();
+        assert_eq!(left_room, room_id);
+        let room = room.unwrap();
+        assert_eq!(room.owner, 1);
+        assert_eq!(room.players, vec![1, 2]);
+        assert_eq!(rooms.leave_room(0).unwrap_err(), RoomError::NotInRoom);
+    }
+
+    #[test]
+    fn test_last_player_closes_room() {
+        let mut rooms = RoomManager::new();
+        let room_id = rooms.create_room(0, "table", 4).unwrap().id;
+
+        let (_, room) = rooms.leave_room(0).unwrap();
+        assert!(room.is_none());
+        assert!(rooms.room(room_id).is_none());
+        assert!(rooms.list().is_empty());
+    }
+
+    #[test]
+    fn test_only_owner_starts_match() {
+        let mut rooms = RoomManager::new();
+        let room_id = rooms.create_room(0, "table", 4).unwrap().id;
+        rooms.join_room(1, room_id).unwrap();
+
+        assert_eq!(rooms.start_match(1).unwrap_err(), RoomError::NotOwner);
+        assert!(rooms.start_match(0).unwrap().in_match);
+        assert_eq!(rooms.start_match(0).unwrap_err(), RoomError::MatchInProgress);
+        assert_eq!(rooms.join_room(2, room_id).unwrap_err(), RoomError::MatchInProgress);
+    }
+
+    #[test]
+    fn test_end_match_reopens_room() {
+        let mut rooms = RoomManager::new();
+        let room_id = rooms.create_room(0, "table", 4).unwrap().id;
+        rooms.start_match(0).unwrap();
+
+        assert!(!rooms.end_match(room_id).unwrap().in_match);
+        rooms.join_room(1, room_id).unwrap();
+        assert!(rooms.start_match(0).unwrap().in_match); // Rematch
+        assert!(rooms.end_match(99).is_none());
+    }
+
+    #[test]
+    fn test_list_rooms() {
+        let mut rooms = RoomManager::new();
+        rooms.create_room(5, "b", 4).unwrap();
+        rooms.create_room(6, "a", 2).unwrap();
+
+        let list = rooms.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].name, "b");
+        assert_eq!(list[1].max_players, 2);
+    }
+}
@@ -12,6 +12,7 @@ use crate::BALL_DIAMETER;
 use crate::Scene1Entity;
 use crate::PURPLE;
 use crate::BALL_STARTING_POSITION;
+use crate::{GameRng, RngStream};
 
 
 // STRUCTS
@@ -24,7 +25,7 @@ pub struct Ball;
 pub struct TempBall;
 
 #[derive(Component, Deref, DerefMut, Debug, PartialEq)]
-pub struct Velocity(Vec2);
+pub struct Velocity(pub Vec2);
 
 #[derive(Debug, Resource)]
 pub struct Gravity {
@@ -320,10 +321,11 @@ pub fn add_ball_random_pos(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     window: Query<&Window>,
+    mut game_rng: ResMut<GameRng>,
 ) {
     if keyboard_input.pressed(KeyCode::KeyA) {
-        // Create rng generator
-        let mut rng = rand::thread_rng();
+        // The stream of the balls
+        let rng = game_rng.stream(RngStream::Balls);
         // println!("Integer: {}", rng.gen_range(0..10));
         // println!("Float: {}", rng.gen_range(0.0..10.0));
 
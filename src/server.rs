@@ -2,108 +2,1200 @@
 // delle connessioni TCP e il loop principale del server, qui.
 
 // src/server.rs
-use std::net::{TcpListener, TcpStream};
+use native_tls::{Identity, TlsAcceptor};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
 use std::thread;
-use std::io::{BufReader, BufRead, Write};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufReader, BufRead, Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::async_server;
+use crate::auth::{generate_session_token, CredentialStore, UserRecord};
+use crate::chat::{validate_chat_text, RateLimiter};
+use crate::recording::{RecordedEvent, Recorder};
+use crate::protocol::{
+    encode_json, is_timeout, read_frame, read_json, write_frame, write_json, ClientMessage, FrameDecoder,
+    FrameError, PlayerInput, ServerMessage, SharedTransport, TableAction, Transport,
+};
+use crate::rooms::{RoomError, RoomManager};
+use crate::table::{CardTable, TableError};
+use crate::udp;
+use crate::simulation::{ServerWorld, TICK_DURATION};
+
+
+// Longest a read holds the stream of a client, e.g. waiting for the rest of a TLS record
+const CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+// How often the accept loop checks whether the server is shutting down
+pub(crate) const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// A peer that doesn't finish the TLS handshake in time is dropped
+pub(crate) const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Messages waiting to be written to one client; a client that lets it fill up is dropped
+pub(crate) const CLIENT_QUEUE_SIZE: usize = 256;
+// A client that sends nothing (not even a ping) for this long is disconnected
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// How long the token of a disconnected client can still be used to Resume
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_MAX_CLIENTS: usize = 64;
+
+
+// ====== LOGGING ======
+
+/// How much the server prints, set with `--log-level`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level '{}', use error, warn, info or debug", s)),
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+// Errors and warnings go to stderr, everything else to stdout
+macro_rules! server_log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::server::log_enabled($level) {
+            if $level <= $crate::server::LogLevel::Warn {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+pub(crate) use server_log;
+
+
+// ====== STRUCTS ======
+
+/// How connections are served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerMode {
+    /// One OS thread per client
+    Threads,
+    /// Tasks on a tokio runtime, for many clients
+    Async,
+}
+
+impl FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "threads" => Ok(ServerMode::Threads),
+            "async" => Ok(ServerMode::Async),
+            _ => Err(format!("Unknown server mode '{}', use threads or async", s)),
+        }
+    }
+}
+
+/// Everything the server binary can set from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub max_clients: usize,
+    pub log_level: LogLevel,
+    pub mode: ServerMode,
+    pub idle_timeout: Duration,
+    pub session_timeout: Duration,
+    pub record_path: Option<PathBuf>, // Where to record the session, see recording.rs
+    pub udp_port: Option<u16>, // Also serve UDP clients on this port, see udp.rs
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            port: DEFAULT_PORT,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            log_level: LogLevel::Info,
+            mode: ServerMode::Threads,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            record_path: None,
+            udp_port: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+}
+
+/// A message waiting in the queue of one or more clients. A broadcast is
+/// encoded once, the first writer to need the bytes shares them with the others.
+#[derive(Debug, Clone)]
+pub(crate) struct OutgoingMessage(Arc<SharedMessage>);
+
+#[derive(Debug)]
+struct SharedMessage {
+    message: ServerMessage,
+    frame: OnceLock<Option<Vec<u8>>>,
+}
+
+impl OutgoingMessage {
+    fn new(message: ServerMessage) -> Self {
+        OutgoingMessage(Arc::new(SharedMessage { message, frame: OnceLock::new() }))
+    }
+
+    pub(crate) fn message(&self) -> &ServerMessage {
+        &self.0.message
+    }
+
+    /// Header and payload ready for the socket, `None` if the message can't be encoded
+    pub(crate) fn frame(&self) -> Option<&[u8]> {
+        self.0.frame.get_or_init(|| encode_json(&self.0.message).ok()).as_deref()
+    }
+}
+
+// The outgoing queue of one client, drained by its own thread or task
+struct ClientQueue {
+    sender: Sender<OutgoingMessage>,
+    lagging: AtomicBool, // The queue was full: the client can't keep up and will be dropped
+    last_seen: Instant, // Last time a message arrived from the client
+}
+
+// A session token; it only starts to expire once no client uses it
+struct Session {
+    username: String,
+    expires: Option<Instant>,
+}
+
+/// A login waiting for its password check. PBKDF2 is slow on purpose, so it
+/// runs without the state lock and `finish_login` takes the answer back
+pub(crate) struct PendingLogin {
+    client_id: usize,
+    username: String,
+    password: String,
+    user: Option<UserRecord>, // None for an unknown username
+}
+
+impl PendingLogin {
+    pub(crate) fn verify(&self) -> bool {
+        self.user.as_ref().is_some_and(|user| user.verify(&self.password))
+    }
+}
 
 // Struttura per mantenere lo stato del server
-struct ServerState {
-    clients: HashMap<usize, TcpStream>, // Mappa degli ID client a stream TCP
+pub(crate) struct ServerState {
+    clients: HashMap<usize, ClientQueue>, // Mappa degli ID client alla loro coda di messaggi in uscita
+    names: HashMap<usize, String>, // Nomi scelti con il messaggio Join
+    pending_inputs: VecDeque<(usize, PlayerInput)>, // Input ricevuti e non ancora simulati
     next_client_id: usize,
+    credentials: Box<dyn CredentialStore>,
+    authenticated: HashMap<usize, String>, // Client che hanno fatto login -> username
+    sessions: HashMap<String, Session>, // Token di sessione -> username e scadenza
+    session_tokens: HashMap<usize, String>, // Client -> the token of its session
+    logging_in: HashSet<usize>, // Clients whose password is being checked
+    rooms: RoomManager,
+    tables: HashMap<usize, CardTable>, // Room id -> its card table, once the match started
+    chat_limits: HashMap<usize, RateLimiter>, // Created with the first chat message of a client
+    max_clients: usize,
+    idle_timeout: Duration,
+    session_timeout: Duration,
+    rng: StdRng, // Shuffles the decks, seeded so a recorded session can be replayed
+    recorder: Option<Recorder>,
+    clock: Option<Instant>, // Replaces the real time during a replay
+    pub(crate) shutting_down: bool, // Once set, no new client is accepted and every loop ends
 }
 
 impl ServerState {
-    fn new() -> Self {
+    pub(crate) fn new(credentials: Box<dyn CredentialStore>) -> Self {
         ServerState {
             clients: HashMap::new(),
+            names: HashMap::new(),
+            pending_inputs: VecDeque::new(),
             next_client_id: 0,
+            credentials,
+            authenticated: HashMap::new(),
+            sessions: HashMap::new(),
+            session_tokens: HashMap::new(),
+            logging_in: HashSet::new(),
+            rooms: RoomManager::new(),
+            tables: HashMap::new(),
+            chat_limits: HashMap::new(),
+            max_clients: DEFAULT_MAX_CLIENTS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            rng: StdRng::from_entropy(),
+            recorder: None,
+            clock: None,
+            shutting_down: false,
+        }
+    }
+
+    /// Records everything from now on, starting with the seed of a fresh random generator
+    pub(crate) fn start_recording(&mut self, recorder: Recorder, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.recorder = Some(recorder);
+        self.record(|| RecordedEvent::Started { seed });
+    }
+
+    /// Empty state to replay a recording into: same seed, and clients are only
+    /// dropped when the recording says so
+    pub(crate) fn for_replay(credentials: Box<dyn CredentialStore>, seed: u64) -> Self {
+        let mut state = ServerState::new(credentials);
+        state.rng = StdRng::seed_from_u64(seed);
+        state.idle_timeout = Duration::MAX;
+        state
+    }
+
+    pub(crate) fn set_clock(&mut self, now: Instant) {
+        self.clock = Some(now);
+    }
+
+    // The real time, or the time of the recorded event being replayed
+    fn now(&self) -> Instant {
+        self.clock.unwrap_or_else(Instant::now)
+    }
+
+    // The event is only built when recording, the outbound ones clone whole snapshots
+    fn record(&self, event: impl FnOnce() -> RecordedEvent) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(self.now(), event());
         }
     }
 
-    fn add_client(&mut self, stream: TcpStream) -> usize {
+    pub(crate) fn add_client(&mut self, sender: Sender<OutgoingMessage>) -> usize {
         let client_id = self.next_client_id;
-        self.clients.insert(client_id, stream);
+        self.clients.insert(client_id, ClientQueue { sender, lagging: AtomicBool::new(false), last_seen: self.now() });
         self.next_client_id += 1;
+        self.record(|| RecordedEvent::Connected { client_id });
         client_id
     }
 
-    fn remove_client(&mut self, client_id: usize) {
-        self.clients.remove(&client_id);
+    /// Adds the client unless the server is full or shutting down, in which case
+    /// it returns the reason to tell the peer before closing the connection
+    pub(crate) fn register_client(&mut self, sender: Sender<OutgoingMessage>) -> Result<usize, &'static str> {
+        if self.shutting_down {
+            return Err("Server is shutting down");
+        }
+        if self.clients.len() >= self.max_clients {
+            return Err("Server is full");
+        }
+        Ok(self.add_client(sender))
+    }
+
+    pub(crate) fn remove_client(&mut self, client_id: usize) {
+        if self.clients.remove(&client_id).is_some() {
+            self.record(|| RecordedEvent::Disconnected { client_id });
+        }
+        self.logging_in.remove(&client_id);
+        self.release_session(client_id); // The session token stays valid for Resume, for a while
+        self.forget_player(client_id);
+    }
+
+    // Everything about the player behind a client, the connection itself aside
+    fn forget_player(&mut self, client_id: usize) {
+        self.names.remove(&client_id);
+        self.chat_limits.remove(&client_id);
+        self.authenticated.remove(&client_id);
+
+        // Whoever is still in the room sees the player (and maybe the owner) change
+        if let Ok((room_id, room)) = self.rooms.leave_room(client_id) {
+            if let Some(room) = room {
+                let update = ServerMessage::RoomUpdate(room.info());
+                send_to_room(self, room_id, &update);
+            }
+            self.leave_table(client_id, room_id);
+        }
+    }
+
+    // The hand of a player leaving the room goes back in the deck
+    fn leave_table(&mut self, client_id: usize, room_id: usize) {
+        let Some(table) = self.tables.get_mut(&room_id) else {
+            return;
+        };
+        table.remove_player(client_id);
+        if table.is_empty() {
+            self.end_match(room_id);
+            return;
+        }
+        let update = ServerMessage::TableUpdate(table.info(room_id));
+        send_to_room(self, room_id, &update);
+    }
+
+    // The table goes away and the room can be joined and started again
+    fn end_match(&mut self, room_id: usize) {
+        self.tables.remove(&room_id);
+        let Some(room) = self.rooms.end_match(room_id).map(|room| room.info()) else {
+            return;
+        };
+        server_log!(LogLevel::Info, "Match ended in room {}", room_id);
+        send_to_room(self, room_id, &ServerMessage::MatchEnded { room_id });
+        send_to_room(self, room_id, &ServerMessage::RoomUpdate(room));
+    }
+
+    pub(crate) fn has_client(&self, client_id: usize) -> bool {
+        self.clients.contains_key(&client_id)
+    }
+
+    /// Tells the client why and closes its connection once the message is sent
+    pub(crate) fn disconnect_client(&mut self, client_id: usize, reason: &str) -> bool {
+        if !self.clients.contains_key(&client_id) {
+            return false;
+        }
+        self.record(|| RecordedEvent::Dropped { client_id, reason: reason.to_string() });
+        let was_authenticated = self.is_authenticated(client_id);
+        send_to_client(self, client_id, &ServerMessage::Disconnected { reason: reason.to_string() });
+        self.remove_client(client_id); // Dropping its queue closes the connection
+        if was_authenticated {
+            send_update_to_clients(self, &ServerMessage::Left { client_id });
+        }
+        true
+    }
+
+    // A slow client only ever hurts itself: once its queue is full it is disconnected
+    fn drop_lagging_clients(&mut self) {
+        let lagging: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|(_, queue)| queue.lagging.load(Ordering::Relaxed))
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in lagging {
+            server_log!(LogLevel::Warn, "Dropping client {}: too slow to keep up", client_id);
+            self.drop_lagging_client(client_id);
+        }
+    }
+
+    pub(crate) fn drop_lagging_client(&mut self, client_id: usize) {
+        self.record(|| RecordedEvent::Lagging { client_id });
+        self.remove_client(client_id);
+        send_update_to_clients(self, &ServerMessage::Left { client_id });
+    }
+
+    /// A status message from the admin to every logged in client
+    pub(crate) fn broadcast_status(&self, message: &str) {
+        self.record(|| RecordedEvent::AdminBroadcast { message: message.to_string() });
+        send_update_to_clients(self, &ServerMessage::Status { message: message.to_string() });
+    }
+
+    // Any message counts as a sign of life, the client pings when it has nothing to say
+    fn touch_client(&mut self, client_id: usize) {
+        let now = self.now();
+        if let Some(queue) = self.clients.get_mut(&client_id) {
+            queue.last_seen = now;
+        }
+    }
+
+    // Dead peers (crashed, unplugged...) never close their socket, the idle timeout does it for them
+    fn drop_idle_clients(&mut self, now: Instant) {
+        let idle: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|(_, queue)| now.saturating_duration_since(queue.last_seen) > self.idle_timeout)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in idle {
+            server_log!(LogLevel::Info, "Dropping client {}: idle for more than {:?}", client_id, self.idle_timeout);
+            self.disconnect_client(client_id, "Idle timeout");
+        }
+    }
+
+    pub(crate) fn is_authenticated(&self, client_id: usize) -> bool {
+        self.authenticated.contains_key(&client_id)
+    }
+
+    fn start_session(&mut self, client_id: usize, username: String, session_token: String) {
+        let session = Session { username: username.clone(), expires: None };
+        self.sessions.insert(session_token.clone(), session);
+        self.session_tokens.insert(client_id, session_token.clone());
+        self.authenticated.insert(client_id, username.clone());
+        self.names.insert(client_id, username);
+        send_to_client(self, client_id, &ServerMessage::LoginAccepted { client_id, session_token });
+    }
+
+    // The client is gone: its token can resume the session until the timeout
+    fn release_session(&mut self, client_id: usize) {
+        let Some(token) = self.session_tokens.remove(&client_id) else {
+            return;
+        };
+        let in_use = self.session_tokens.values().any(|other| *other == token);
+        let expires = self.now() + self.session_timeout;
+        if let Some(session) = self.sessions.get_mut(&token).filter(|_| !in_use) {
+            session.expires = Some(expires);
+        }
+    }
+
+    // Leave or Logout: the token can't be used anymore
+    fn end_session(&mut self, client_id: usize) {
+        if let Some(token) = self.session_tokens.remove(&client_id) {
+            self.sessions.remove(&token);
+        }
+    }
+
+    fn session_user(&self, session_token: &str) -> Option<String> {
+        let now = self.now();
+        self.sessions
+            .get(session_token)
+            .filter(|session| session.expires.is_none_or(|expires| now < expires))
+            .map(|session| session.username.clone())
+    }
+
+    fn drop_expired_sessions(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| session.expires.is_none_or(|expires| now < expires));
+    }
+
+    // Clients logged in as that user. The login username, not the name picked
+    // with Join: anybody can Join with someone else's name
+    fn clients_of_user(&self, username: &str) -> Vec<usize> {
+        let mut clients: Vec<usize> = self
+            .authenticated
+            .iter()
+            .filter(|(_, user)| user.as_str() == username)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        clients.sort();
+        clients
+    }
+
+    /// Ids of the connected clients, in connection order
+    pub(crate) fn client_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.clients.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    pub(crate) fn rooms(&self) -> &RoomManager {
+        &self.rooms
+    }
+
+    pub(crate) fn table(&self, room_id: usize) -> Option<&CardTable> {
+        self.tables.get(&room_id)
+    }
+
+    pub(crate) fn client_name(&self, client_id: usize) -> String {
+        self.names
+            .get(&client_id)
+            .cloned()
+            .unwrap_or_else(|| format!("client_{}", client_id))
+    }
+}
+
+/// A command typed in the server console
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    List,
+    Kick(usize),
+    Broadcast(String),
+    Shutdown,
+    Help,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        match (command, argument) {
+            ("list", "") => Ok(AdminCommand::List),
+            ("kick", id) => id
+                .parse()
+                .map(AdminCommand::Kick)
+                .map_err(|_| "Usage: kick <client id>".to_string()),
+            ("broadcast", "") => Err("Usage: broadcast <message>".to_string()),
+            ("broadcast", message) => Ok(AdminCommand::Broadcast(message.to_string())),
+            ("shutdown", "") => Ok(AdminCommand::Shutdown),
+            ("help", "") => Ok(AdminCommand::Help),
+            _ => Err(format!("Unknown command '{}', type help for the list", line)),
+        }
+    }
+}
+
+/// A running server: the admin console drives it, `wait` blocks until it stopped
+pub struct ServerHandle {
+    state: Arc<Mutex<ServerState>>,
+    threads: Vec<JoinHandle<()>>,
+    local_addr: SocketAddr,
+    udp_addr: Option<SocketAddr>,
+}
+
+impl ServerHandle {
+    /// Where the server actually listens, useful when started on port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Where the UDP clients are served, if they are
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    /// Runs an admin command and returns what to print in the console
+    pub fn execute(&self, command: &AdminCommand) -> String {
+        match command {
+            AdminCommand::List => {
+                let state = lock_state(&self.state);
+                let ids = state.client_ids();
+                let mut lines = vec![format!("{} client(s) connected", ids.len())];
+                for client_id in ids {
+                    let username = state.authenticated.get(&client_id).map_or("-", String::as_str);
+                    let room = match state.rooms.room_of(client_id) {
+                        Some(room) => format!("room {}", room.id),
+                        None => "lobby".to_string(),
+                    };
+                    lines.push(format!("  {}  {}  (user {}, {})", client_id, state.client_name(client_id), username, room));
+                }
+                lines.join("\n")
+            }
+            AdminCommand::Kick(client_id) => {
+                if lock_state(&self.state).disconnect_client(*client_id, "Kicked by the server admin") {
+                    format!("Kicked client {}", client_id)
+                } else {
+                    format!("No client with id {}", client_id)
+                }
+            }
+            AdminCommand::Broadcast(message) => {
+                let state = lock_state(&self.state);
+                state.broadcast_status(message);
+                format!("Sent to {} client(s)", state.authenticated.len())
+            }
+            AdminCommand::Shutdown => {
+                self.shutdown();
+                "Shutting down...".to_string()
+            }
+            AdminCommand::Help => {
+                "Commands: list, kick <id>, broadcast <message>, shutdown, help".to_string()
+            }
+        }
+    }
+
+    /// Stops accepting clients, tells every connected client and closes it
+    pub fn shutdown(&self) {
+        let mut state = lock_state(&self.state);
+        state.shutting_down = true;
+        let client_ids: Vec<usize> = state.clients.keys().copied().collect();
+        for client_id in client_ids {
+            state.disconnect_client(client_id, "Server is shutting down");
+        }
+    }
+
+    /// Blocks until the accept loop, the simulation and every client thread ended
+    pub fn wait(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+        server_log!(LogLevel::Info, "Server stopped");
+    }
+}
+
+/// Binds the listener and starts accepting clients and simulating on background threads
+pub fn start_server(config: &ServerConfig, credentials: Box<dyn CredentialStore>, tls: Option<TlsAcceptor>) -> io::Result<ServerHandle> {
+    set_log_level(config.log_level);
+
+    let mut state = ServerState::new(credentials);
+    state.max_clients = config.max_clients;
+    state.idle_timeout = config.idle_timeout;
+    state.session_timeout = config.session_timeout;
+    if let Some(path) = &config.record_path {
+        state.start_recording(Recorder::create(path)?, rand::random());
+        server_log!(LogLevel::Info, "Recording the session to {}", path.display());
+    }
+    let state = Arc::new(Mutex::new(state)); // Wrap in Arc and Mutex
+
+    let address = config.bind_address();
+    let listener = TcpListener::bind(&address)?;
+    let local_addr = listener.local_addr()?;
+    let mode = if tls.is_some() { "TLS" } else { "plain TCP" };
+    server_log!(
+        LogLevel::Info,
+        "Server listening on {} ({}, {:?} mode, max {} clients)",
+        local_addr, mode, config.mode, config.max_clients
+    );
+
+    let udp_socket = match config.udp_port {
+        Some(port) => Some(UdpSocket::bind(format!("{}:{}", config.address, port))?),
+        None => None,
+    };
+    let udp_addr = udp_socket.as_ref().map(UdpSocket::local_addr).transpose()?;
+    if let Some(udp_addr) = udp_addr {
+        server_log!(LogLevel::Info, "Serving UDP clients on {}", udp_addr);
+    }
+
+    let simulation_state = Arc::clone(&state);
+    let simulation = thread::spawn(move || run_simulation(simulation_state));
+    let accept_state = Arc::clone(&state);
+    let accept = match config.mode {
+        ServerMode::Threads => thread::spawn(move || accept_connections(listener, accept_state, tls)),
+        ServerMode::Async => thread::spawn(move || async_server::run(listener, accept_state, tls)),
+    };
+    let mut threads = vec![accept, simulation];
+    if let Some(socket) = udp_socket {
+        let udp_state = Arc::clone(&state);
+        threads.push(thread::spawn(move || udp::run(socket, udp_state)));
+    }
+
+    Ok(ServerHandle { state, threads, local_addr, udp_addr })
+}
+
+// A thread that panicked while holding the lock doesn't take the whole server down
+pub(crate) fn lock_state(state: &Mutex<ServerState>) -> MutexGuard<'_, ServerState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Fixed rate game loop, the server world is the only source of truth
+fn run_simulation(state: Arc<Mutex<ServerState>>) {
+    let mut world = ServerWorld::new();
+    let mut next_tick = Instant::now();
+
+    loop {
+        next_tick += TICK_DURATION;
+        {
+            let mut state_lock = lock_state(&state);
+            if state_lock.shutting_down {
+                return;
+            }
+            simulation_tick(&mut world, &mut state_lock);
+        }
+
+        // If a tick took too long, start counting again instead of running a burst of ticks
+        let now = Instant::now();
+        if next_tick > now {
+            thread::sleep(next_tick - now);
+        } else {
+            next_tick = now;
+        }
+    }
+}
+
+// One tick: players in sync with the logged in clients, queued inputs, step, snapshot,
+// then the clients that lag behind or went silent are dropped
+pub(crate) fn simulation_tick(world: &mut ServerWorld, state: &mut ServerState) {
+    state.record(|| RecordedEvent::Tick);
+    let players: Vec<usize> = state.authenticated.keys().copied().collect();
+    world.sync_players(&players);
+
+    while let Some((client_id, input)) = state.pending_inputs.pop_front() {
+        world.apply_input(client_id, input);
     }
+    world.step();
+
+    send_update_to_clients(state, &ServerMessage::StateSnapshot(world.snapshot()));
+    state.drop_lagging_clients();
+    state.drop_idle_clients(state.now());
+    state.drop_expired_sessions(state.now());
+}
+
+/// Builds a TLS acceptor from a PEM certificate (chain) and a PEM PKCS#8 private key
+pub fn load_tls_acceptor(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> io::Result<TlsAcceptor> {
+    let cert = fs::read(cert_path)?;
+    let key = fs::read(key_path)?;
+    let identity = Identity::from_pkcs8(&cert, &key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    TlsAcceptor::new(identity).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
 }
 
-pub fn start_server(address: &str) {
-    let state = Arc::new(Mutex::new(ServerState::new())); // Wrap in Arc and Mutex
-    let listener = TcpListener::bind(address).expect("Could not bind to address");
-    println!("Server listening on {}", address);
-    
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
+// Non-blocking accept, so the loop notices the shutdown. Returns once every
+// client thread it started has ended.
+fn accept_connections(listener: TcpListener, state: Arc<Mutex<ServerState>>, tls: Option<TlsAcceptor>) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        server_log!(LogLevel::Error, "Could not make the listener non-blocking: {}", e);
+        return;
+    }
+    let mut client_threads: Vec<JoinHandle<()>> = Vec::new();
+
+    while !lock_state(&state).shutting_down {
+        match listener.accept() {
+            Ok((stream, _)) => {
                 let state_clone = Arc::clone(&state); // Clone the Arc for the new thread
-                thread::spawn(move || {
-                    handle_client(stream, state_clone);
-                });
+                let tls = tls.clone();
+                client_threads.retain(|thread| !thread.is_finished());
+                client_threads.push(thread::spawn(move || {
+                    // The accepted socket must block, the client thread sets its own timeouts
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        server_log!(LogLevel::Warn, "Could not set up the connection: {}", e);
+                        return;
+                    }
+                    // The TLS handshake runs on the client thread, a slow peer can't block accept
+                    let transport = match tls {
+                        Some(acceptor) => {
+                            let _ = stream.set_read_timeout(Some(TLS_HANDSHAKE_TIMEOUT));
+                            match acceptor.accept(stream) {
+                                Ok(tls_stream) => Transport::Tls(tls_stream),
+                                Err(e) => {
+                                    server_log!(LogLevel::Warn, "TLS handshake failed: {}", e);
+                                    return;
+                                }
+                            }
+                        }
+                        None => Transport::Plain(stream),
+                    };
+                    handle_client(transport, state_clone);
+                }));
             }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+                server_log!(LogLevel::Warn, "Error accepting connection: {}", e);
             }
         }
     }
+
+    for thread in client_threads {
+        let _ = thread.join();
+    }
 }
 
-fn handle_client(stream: TcpStream, state: Arc<Mutex<ServerState>>) {
-    let client_id = {
-        let mut state_lock = state.lock().expect("Failed to lock mutex");
-        state_lock.add_client(stream.try_clone().expect("Could not clone stream"))
+// Every client gets two threads: this one blocks reading the socket, a writer
+// blocks on the client's queue. Neither polls, so an idle client costs nothing.
+// TLS streams can't be split, SharedTransport lets both threads use one.
+fn handle_client(stream: impl Into<Transport>, state: Arc<Mutex<ServerState>>) {
+    let mut stream = stream.into();
+    let (sender, outgoing) = mpsc::channel(CLIENT_QUEUE_SIZE);
+    let registered = lock_state(&state).register_client(sender);
+    let client_id = match registered {
+        Ok(client_id) => client_id,
+        Err(reason) => {
+            server_log!(LogLevel::Info, "Refused a connection: {}", reason);
+            let _ = write_json(&mut stream, &ServerMessage::Disconnected { reason: reason.to_string() });
+            let _ = stream.shutdown();
+            return;
+        }
     };
-    println!("Client connected: {}", client_id);
-    
-    let mut reader = BufReader::new(stream.try_clone().expect("Could not clone stream"));
-    
-    loop {
-        let mut buffer = String::new();
-        let bytes_read = reader.read_line(&mut buffer).expect("Error reading from stream");
-        
-        if bytes_read == 0 {
-            println!("Client disconnected: {}", client_id);
-            {
-                let mut state_lock = state.lock().expect("Failed to lock mutex");
-                state_lock.remove_client(client_id);
+    server_log!(LogLevel::Info, "Client connected: {}", client_id);
+
+    let mut reader = match SharedTransport::new(stream) {
+        Ok(reader) => reader,
+        Err(e) => {
+            server_log!(LogLevel::Warn, "Could not set up the connection of client {}: {}", client_id, e);
+            lock_state(&state).remove_client(client_id);
+            return;
+        }
+    };
+    if let Err(e) = reader.set_read_timeout(Some(CLIENT_READ_TIMEOUT)) {
+        server_log!(LogLevel::Warn, "Could not set read timeout for client {}: {}", client_id, e);
+    }
+    let writer = reader.clone();
+    let writer_thread = thread::spawn(move || write_queue(client_id, writer, outgoing));
+
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0u8; 4096];
+    'connection: loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => {
+                server_log!(LogLevel::Info, "Client disconnected: {}", client_id);
+                break;
             }
+            Ok(bytes_read) => decoder.extend(&buffer[..bytes_read]),
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => {
+                server_log!(LogLevel::Warn, "Dropping client {}: {}", client_id, e);
+                break;
+            }
+        }
+
+        loop {
+            let payload = match decoder.next_frame() {
+                Ok(Some(payload)) => payload,
+                Ok(None) => break,
+                Err(e) => {
+                    server_log!(LogLevel::Warn, "Dropping client {}: {}", client_id, e);
+                    break 'connection;
+                }
+            };
+
+            let login = handle_payload(client_id, &payload, &mut lock_state(&state));
+            if let Some(login) = login {
+                check_login(&state, login);
+            }
+        }
+    }
+
+    // Closes the queue, the writer sends what is left and shuts the connection down
+    lock_state(&state).remove_client(client_id);
+    let _ = writer_thread.join();
+}
+
+// Writes what other threads queued for the client. The queue closes once the
+// client is removed from the state (Leave, kick, shutdown...): everything
+// queued before is sent, then the connection is shut down, which also wakes
+// up the reader.
+fn write_queue(client_id: usize, mut stream: SharedTransport, mut outgoing: mpsc::Receiver<OutgoingMessage>) {
+    while let Some(message) = outgoing.blocking_recv() {
+        let Some(frame) = message.frame() else {
+            server_log!(LogLevel::Warn, "Could not encode message for client {}", client_id);
+            continue;
+        };
+        if let Err(e) = stream.write_all(frame) {
+            server_log!(LogLevel::Warn, "Failed to send message to client {}: {}", client_id, e);
             break;
         }
+    }
+    let _ = stream.shutdown();
+}
 
-        println!("Received from {}: {}", client_id, buffer.trim());
-        {
-            let mut state_lock = state.lock().expect("Failed to lock mutex");
-            process_message(&buffer, &mut state_lock);
+/// Parses one frame received from a client and acts on it. A Login comes
+/// back to the caller, to check the password once the lock is released
+pub(crate) fn handle_payload(client_id: usize, payload: &[u8], state: &mut ServerState) -> Option<PendingLogin> {
+    state.touch_client(client_id);
+    match serde_json::from_slice::<ClientMessage>(payload) {
+        Ok(message) => {
+            server_log!(LogLevel::Debug, "Received from {}: {:?}", client_id, message);
+            state.record(|| RecordedEvent::inbound(client_id, &message));
+            process_message(client_id, message, state)
+        }
+        Err(e) => {
+            state.record(|| RecordedEvent::InvalidInbound {
+                client_id,
+                payload: String::from_utf8_lossy(payload).to_string(),
+            });
+            // Unknown or malformed commands are reported back to the sender only
+            let error = ServerMessage::Error { message: format!("Invalid message: {}", e) };
+            send_to_client(state, client_id, &error);
+            None
         }
     }
 }
 
-fn process_message(message: &str, state: &mut ServerState) {
+pub(crate) fn process_message(client_id: usize, message: ClientMessage, state: &mut ServerState) -> Option<PendingLogin> {
+    // Finche' il client non ha fatto login accettiamo solo Login e Resume
+    if !state.is_authenticated(client_id) {
+        return authenticate(client_id, message, state);
+    }
+
     // Logica per gestire diversi tipi di messaggi
-    match message.trim() {
-        "status" => send_update_to_clients(state, "Server is running\n"),
-        _ => println!("Unknown message: {}", message),
+    match message {
+        ClientMessage::Login { .. } | ClientMessage::Resume { .. } => {
+            let error = ServerMessage::Error { message: "Already logged in".to_string() };
+            send_to_client(state, client_id, &error);
+        }
+        ClientMessage::Join { name } => {
+            state.names.insert(client_id, name.clone());
+            send_update_to_clients(state, &ServerMessage::Joined { client_id, name });
+        }
+        ClientMessage::Leave => {
+            state.end_session(client_id);
+            state.remove_client(client_id); // Dropping its queue closes the connection
+            send_update_to_clients(state, &ServerMessage::Left { client_id });
+        }
+        ClientMessage::Logout => {
+            state.end_session(client_id);
+            state.forget_player(client_id);
+            send_to_client(state, client_id, &ServerMessage::LoggedOut);
+            send_update_to_clients(state, &ServerMessage::Left { client_id });
+        }
+        ClientMessage::Chat { text } => {
+            let text = check_chat(client_id, &text, state)?;
+            let name = state.client_name(client_id);
+            let room = state.rooms.room_of(client_id).map(|room| room.id);
+            let chat = ServerMessage::Chat { from: client_id, name, text, room };
+            // Chat stays inside the room, players in the lobby talk among themselves
+            match room {
+                Some(room_id) => send_to_room(state, room_id, &chat),
+                None => send_to_lobby(state, &chat),
+            }
+        }
+        ClientMessage::Whisper { to, text } => {
+            let text = check_chat(client_id, &text, state)?;
+            let recipients = state.clients_of_user(&to);
+            if recipients.is_empty() {
+                let error = ServerMessage::Error { message: format!("No player named {}", to) };
+                send_to_client(state, client_id, &error);
+                return None;
+            }
+            let name = state.client_name(client_id);
+            let whisper = ServerMessage::Whisper { from: client_id, name, to, text };
+            for recipient in &recipients {
+                send_to_client(state, *recipient, &whisper);
+            }
+            if !recipients.contains(&client_id) {
+                send_to_client(state, client_id, &whisper);
+            }
+        }
+        ClientMessage::Input(input) => state.pending_inputs.push_back((client_id, input)),
+        ClientMessage::Ping { nonce } => send_to_client(state, client_id, &ServerMessage::Pong { nonce }),
+        ClientMessage::Status => {
+            let message = "Server is running".to_string();
+            send_update_to_clients(state, &ServerMessage::Status { message });
+        }
+        ClientMessage::CreateRoom { name, max_players } => {
+            let result = state.rooms.create_room(client_id, &name, max_players).map(|room| room.info());
+            match result {
+                Ok(room) => {
+                    server_log!(LogLevel::Info, "Client {} created room {} ({})", client_id, room.id, room.name);
+                    send_to_client(state, client_id, &ServerMessage::RoomUpdate(room));
+                }
+                Err(e) => send_room_error(state, client_id, e),
+            }
+        }
+        ClientMessage::ListRooms => {
+            let rooms = state.rooms.list();
+            send_to_client(state, client_id, &ServerMessage::RoomList { rooms });
+        }
+        ClientMessage::JoinRoom { room_id } => {
+            let result = state.rooms.join_room(client_id, room_id).map(|room| room.info());
+            match result {
+                Ok(room) => send_to_room(state, room_id, &ServerMessage::RoomUpdate(room)),
+                Err(e) => send_room_error(state, client_id, e),
+            }
+        }
+        ClientMessage::LeaveRoom => {
+            let result = state.rooms.leave_room(client_id).map(|(room_id, room)| (room_id, room.map(|r| r.info())));
+            match result {
+                Ok((room_id, room)) => {
+                    send_to_client(state, client_id, &ServerMessage::RoomLeft { room_id });
+                    if let Some(room) = room {
+                        send_to_room(state, room_id, &ServerMessage::RoomUpdate(room));
+                    }
+                    state.leave_table(client_id, room_id);
+                }
+                Err(e) => send_room_error(state, client_id, e),
+            }
+        }
+        ClientMessage::StartMatch => {
+            let result = state.rooms.start_match(client_id).map(|room| room.info());
+            match result {
+                Ok(room) => {
+                    server_log!(LogLevel::Info, "Match started in room {}", room.id);
+                    send_to_room(state, room.id, &ServerMessage::MatchStarted { room_id: room.id });
+                    start_table(state, room.id, &room.players);
+                    send_to_room(state, room.id, &ServerMessage::RoomUpdate(room));
+                }
+                Err(e) => send_room_error(state, client_id, e),
+            }
+        }
+        ClientMessage::TableAction(action) => table_action(client_id, action, state),
+    }
+    None
+}
+
+// Deals a fresh deck: everyone gets their own hand, the room the public view
+fn start_table(state: &mut ServerState, room_id: usize, players: &[usize]) {
+    let table = CardTable::new(players, &mut state.rng);
+    for player in players {
+        let cards = table.hand(*player).unwrap_or_default().to_vec();
+        send_to_client(state, *player, &ServerMessage::Hand { cards });
+    }
+    send_to_room(state, room_id, &ServerMessage::TableUpdate(table.info(room_id)));
+    state.tables.insert(room_id, table);
+}
+
+fn table_action(client_id: usize, action: TableAction, state: &mut ServerState) {
+    let room_id = state.rooms.room_of(client_id).map(|room| room.id);
+    let Some((room_id, table)) = room_id.and_then(|id| state.tables.get_mut(&id).map(|table| (id, table))) else {
+        send_table_error(state, client_id, TableError::NotAtTable);
+        return;
+    };
+
+    let result = match &action {
+        TableAction::Draw => table.draw(client_id).map(|_| ()),
+        TableAction::Play { card } => table.play(client_id, *card),
+        TableAction::Discard { card } => table.discard(client_id, *card),
+    };
+    if let Err(e) = result {
+        send_table_error(state, client_id, e);
+        return;
+    }
+    let cards = table.hand(client_id).unwrap_or_default().to_vec();
+    let info = table.info(room_id);
+    let finished = table.is_finished();
+
+    // The drawn card is only in the new hand, the others just see that a card was drawn
+    send_to_client(state, client_id, &ServerMessage::Hand { cards });
+    send_to_room(state, room_id, &ServerMessage::TableAction { player: client_id, action });
+    send_to_room(state, room_id, &ServerMessage::TableUpdate(info));
+    if finished {
+        state.end_match(room_id);
     }
 }
 
-fn send_update_to_clients(state: &ServerState, message: &str) {
-    for (client_id, mut stream) in &state.clients {
-        if let Err(e) = stream.write_all(message.as_bytes()) {
-            eprintln!("Failed to send message to client {}: {}", client_id, e);
+fn send_table_error(state: &ServerState, client_id: usize, error: TableError) {
+    send_to_client(state, client_id, &ServerMessage::Error { message: error.to_string() });
+}
+
+// Length and rate limits of chat and whispers. Returns the trimmed text, or
+// None after telling the sender why the message was dropped
+fn check_chat(client_id: usize, text: &str, state: &mut ServerState) -> Option<String> {
+    let text = match validate_chat_text(text) {
+        Ok(text) => text.to_string(),
+        Err(e) => {
+            send_to_client(state, client_id, &ServerMessage::Error { message: e.to_string() });
+            return None;
         }
+    };
+
+    let now = state.now();
+    let limiter = state.chat_limits.entry(client_id).or_insert_with(|| RateLimiter::chat(now));
+    if !limiter.try_acquire(now) {
+        let error = ServerMessage::Error { message: "Slow down, too many messages".to_string() };
+        send_to_client(state, client_id, &error);
+        return None;
     }
+    Some(text)
 }
 
-fn authenticate(username: &str, password: &str) -> bool {
-    // Replace with your actual authentication logic
-    username == "player1" && password == "securepassword"
+fn send_room_error(state: &ServerState, client_id: usize, error: RoomError) {
+    send_to_client(state, client_id, &ServerMessage::Error { message: error.to_string() });
+}
+
+// Queues a message for one client, its own thread or task does the socket write.
+// Never blocks: a full queue marks the client as lagging instead.
+fn send_to_client(state: &ServerState, client_id: usize, message: &ServerMessage) {
+    let Some(queue) = state.clients.get(&client_id) else {
+        return;
+    };
+    if queue_message(client_id, queue, &OutgoingMessage::new(message.clone())) {
+        state.record(|| RecordedEvent::Outbound { client_id, message: message.clone() });
+    }
+}
+
+// The same message to several clients: encoded once, recorded once
+fn broadcast(state: &ServerState, client_ids: impl IntoIterator<Item = usize>, message: &ServerMessage) {
+    let outgoing = OutgoingMessage::new(message.clone());
+    let mut queued: Vec<usize> = client_ids
+        .into_iter()
+        .filter(|client_id| {
+            let queue = state.clients.get(client_id);
+            queue.is_some_and(|queue| queue_message(*client_id, queue, &outgoing))
+        })
+        .collect();
+    if !queued.is_empty() {
+        queued.sort();
+        state.record(|| RecordedEvent::Broadcast { client_ids: queued, message: message.clone() });
+    }
+}
+
+// Broadcast to every client that completed the login
+fn send_update_to_clients(state: &ServerState, message: &ServerMessage) {
+    let client_ids = state.clients.keys().copied().filter(|client_id| state.is_authenticated(*client_id));
+    broadcast(state, client_ids, message);
+}
+
+// True if the message was queued, only those are recorded
+fn queue_message(client_id: usize, queue: &ClientQueue, message: &OutgoingMessage) -> bool {
+    // Snapshots may only take half of the queue: a client that reads them slowly
+    // skips a few (the next one replaces them anyway) but still gets everything else
+    let is_snapshot = matches!(message.message(), ServerMessage::StateSnapshot(_));
+    if is_snapshot && queue.sender.capacity() <= CLIENT_QUEUE_SIZE / 2 {
+        return false;
+    }
+
+    match queue.sender.try_send(message.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            queue.lagging.store(true, Ordering::Relaxed);
+            false
+        }
+        Err(TrySendError::Closed(_)) => {
+            server_log!(LogLevel::Warn, "Failed to send message to client {}: connection closed", client_id);
+            false
+        }
+    }
+}
+
+// Broadcast to the players of one room
+fn send_to_room(state: &ServerState, room_id: usize, message: &ServerMessage) {
+    if let Some(room) = state.rooms.room(room_id) {
+        broadcast(state, room.players.iter().copied(), message);
+    }
+}
+
+// Broadcast to the logged in clients that are not in any room
+fn send_to_lobby(state: &ServerState, message: &ServerMessage) {
+    let client_ids = state
+        .clients
+        .keys()
+        .copied()
+        .filter(|client_id| state.is_authenticated(*client_id) && state.rooms.room_of(*client_id).is_none());
+    broadcast(state, client_ids, message);
+}
+
+// Login handshake, the only thing an unauthenticated client can do
+fn authenticate(client_id: usize, message: ClientMessage, state: &mut ServerState) -> Option<PendingLogin> {
+    match message {
+        ClientMessage::Login { username, password } => {
+            // One at a time, so a client can't queue up password checks
+            if !state.logging_in.insert(client_id) {
+                let error = ServerMessage::Error { message: "Login already in progress".to_string() };
+                send_to_client(state, client_id, &error);
+                return None;
+            }
+            let user = state.credentials.user(&username);
+            return Some(PendingLogin { client_id, username, password, user });
+        }
+        ClientMessage::Resume { session_token } => match state.session_user(&session_token) {
+            Some(username) => {
+                server_log!(LogLevel::Info, "Client {} resumed the session of {}", client_id, username);
+                state.start_session(client_id, username, session_token);
+            }
+            None => {
+                let reason = "Unknown or expired session".to_string();
+                send_to_client(state, client_id, &ServerMessage::LoginRejected { reason });
+            }
+        },
+        _ => {
+            let error = ServerMessage::Error { message: "Not authenticated: log in first".to_string() };
+            send_to_client(state, client_id, &error);
+        }
+    }
+    None
+}
+
+/// Checks the password without holding the state lock, then finishes the login
+pub(crate) fn check_login(state: &Mutex<ServerState>, login: PendingLogin) {
+    let accepted = login.verify();
+    finish_login(&mut lock_state(state), login, accepted);
+}
+
+/// Answers a login once its password was checked
+pub(crate) fn finish_login(state: &mut ServerState, login: PendingLogin, accepted: bool) {
+    let client_id = login.client_id;
+    // The client went away while its password was being checked
+    if !state.logging_in.remove(&client_id) {
+        return;
+    }
+    state.record(|| RecordedEvent::LoginChecked { client_id, accepted });
+    if accepted {
+        server_log!(LogLevel::Info, "Client {} logged in as {}", client_id, login.username);
+        state.start_session(client_id, login.username, generate_session_token());
+    } else {
+        let reason = "Invalid username or password".to_string();
+        send_to_client(state, client_id, &ServerMessage::LoginRejected { reason });
+    }
 }
 
 
@@ -118,47 +1210,54 @@ mod tests {
     use std::io::{Write, Read};
     use std::thread;
     use std::time::Duration;
+    use crate::auth::test_credentials;
+    use crate::client::Client;
+    use crate::chat::{CHAT_BURST, MAX_CHAT_LENGTH};
+    use crate::protocol::HandInfo;
+    use crate::table::HAND_SIZE;
+    use crate::protocol::StateSnapshot;
+
+    // Server state with a single user: player1 / securepassword
+    fn test_state() -> ServerState {
+        ServerState::new(test_credentials())
+    }
+
+    // Logs in as player1 and returns the session token
+    fn login(client_stream: &mut TcpStream) -> String {
+        let login = ClientMessage::Login { username: "player1".to_string(), password: "securepassword".to_string() };
+        write_json(client_stream, &login).unwrap();
+        match read_json(client_stream).unwrap() {
+            ServerMessage::LoginAccepted { session_token, .. } => session_token,
+            other => panic!("Login failed: {:?}", other),
+        }
+    }
 
     fn start_test_server(address: &str, state: Arc<Mutex<ServerState>>) {
         let listener = TcpListener::bind(address).expect("Could not bind to address");
-        thread::spawn(move || {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let state_clone = Arc::clone(&state);
-                        thread::spawn(move || {
-                            handle_client(stream, state_clone);
-                        });
-                    }
-                    Err(e) => {
-                        eprintln!("Error accepting connection: {}", e);
-                    }
-                }
-            }
-        });
+        thread::spawn(move || accept_connections(listener, state, None));
     }
 
     #[test]
     fn test_add_client() {
-        let mut state = ServerState::new();
-        let mock_stream = TcpStream::connect("127.0.0.1:0").unwrap(); // Simulates a client
-        let client_id = state.add_client(mock_stream);
+        let mut state = test_state();
+        let (sender, _receiver) = mpsc::channel(CLIENT_QUEUE_SIZE); // Simulates a client
+        let client_id = state.add_client(sender);
         assert_eq!(state.clients.len(), 1);
         assert!(state.clients.contains_key(&client_id));
     }
 
     #[test]
     fn test_remove_client() {
-        let mut state = ServerState::new();
-        let mock_stream = TcpStream::connect("127.0.0.1:0").unwrap(); // Simulates a client
-        let client_id = state.add_client(mock_stream);
+        let mut state = test_state();
+        let (sender, _receiver) = mpsc::channel(CLIENT_QUEUE_SIZE); // Simulates a client
+        let client_id = state.add_client(sender);
         state.remove_client(client_id);
         assert_eq!(state.clients.len(), 0);
     }
 
     #[test]
     fn test_send_update_to_clients() {
-        let state = Arc::new(Mutex::new(ServerState::new()));
+        let state = Arc::new(Mutex::new(test_state()));
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap();
 
@@ -181,27 +1280,36 @@ mod tests {
         });
 
         let mut client_stream = TcpStream::connect(addr).unwrap();
-        state.lock().unwrap().add_client(client_stream.try_clone().unwrap());
+
+        // Allow some time for the server to register the client
+        thread::sleep(Duration::from_millis(100));
+        {
+            // Broadcasts only reach logged in clients
+            let mut state_lock = state.lock().unwrap();
+            let client_ids: Vec<usize> = state_lock.clients.keys().copied().collect();
+            for client_id in client_ids {
+                state_lock.authenticated.insert(client_id, "player1".to_string());
+            }
+        }
 
         // Simulate sending an update
-        let message = "Hello, clients!";
-        send_update_to_clients(&state.lock().unwrap(), message);
+        let message = ServerMessage::Status { message: "Hello, clients!".to_string() };
+        send_update_to_clients(&state.lock().unwrap(), &message);
 
         // Wait for a bit for the message to be processed
         thread::sleep(Duration::from_millis(100));
 
         // Verify that the message was sent (mocking the client)
-        let mut buffer = String::new();
-        let bytes_read = client_stream.read_to_string(&mut buffer).unwrap();
-        assert!(bytes_read > 0);
-        assert!(buffer.contains(message));
+        let received: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert_eq!(received, message);
     }
 
     #[test]
     fn test_handle_client() {
-        let state = Arc::new(Mutex::new(ServerState::new()));
+        let state = Arc::new(Mutex::new(test_state()));
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap();
+        drop(listener); // Free the port for the test server
 
         // Start the test server
         start_test_server(&addr.to_string(), Arc::clone(&state));
@@ -211,20 +1319,578 @@ mod tests {
 
         // Connect a client
         let mut client_stream = TcpStream::connect(addr).unwrap();
-        let message = "status\n";
+        login(&mut client_stream);
 
         // Send a message to the server
-        client_stream.write_all(message.as_bytes()).unwrap();
+        write_json(&mut client_stream, &ClientMessage::Status).unwrap();
 
         // Wait a bit for the server to process
         thread::sleep(Duration::from_millis(100));
 
         // Read response from the server
-        let mut response = String::new();
-        client_stream.read_to_string(&mut response).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
 
         // Check the response from the server
-        assert!(response.contains("Server is running")); // Adjust according to your server's expected response
+        assert_eq!(response, ServerMessage::Status { message: "Server is running".to_string() });
     }
-}
 
+    // Starts a test server on a free port and connects one client to it
+    fn connect_to_test_server() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        start_test_server(&addr.to_string(), Arc::new(Mutex::new(test_state())));
+        thread::sleep(Duration::from_millis(100));
+
+        TcpStream::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn test_unknown_command_returns_error() {
+        let mut client_stream = connect_to_test_server();
+
+        write_frame(&mut client_stream, br#"{"type":"fly"}"#).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert!(matches!(response, ServerMessage::Error { .. }));
+
+        // The connection stays usable after a protocol error
+        login(&mut client_stream);
+        write_json(&mut client_stream, &ClientMessage::Ping { nonce: 42 }).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert_eq!(response, ServerMessage::Pong { nonce: 42 });
+    }
+
+    #[test]
+    fn test_join_and_chat() {
+        let mut client_stream = connect_to_test_server();
+        login(&mut client_stream);
+
+        write_json(&mut client_stream, &ClientMessage::Join { name: "ivan".to_string() }).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert_eq!(response, ServerMessage::Joined { client_id: 0, name: "ivan".to_string() });
+
+        write_json(&mut client_stream, &ClientMessage::Chat { text: "ciao".to_string() }).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert_eq!(
+            response,
+            ServerMessage::Chat { from: 0, name: "ivan".to_string(), text: "ciao".to_string(), room: None }
+        );
+    }
+
+    #[test]
+    fn test_commands_rejected_before_login() {
+        let mut client_stream = connect_to_test_server();
+
+        write_json(&mut client_stream, &ClientMessage::Status).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert!(matches!(response, ServerMessage::Error { .. }));
+
+        write_json(&mut client_stream, &ClientMessage::Chat { text: "hi".to_string() }).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert!(matches!(response, ServerMessage::Error { .. }));
+    }
+
+    #[test]
+    fn test_wrong_password_rejected() {
+        let mut client_stream = connect_to_test_server();
+
+        let login = ClientMessage::Login { username: "player1".to_string(), password: "guess".to_string() };
+        write_json(&mut client_stream, &login).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert!(matches!(response, ServerMessage::LoginRejected { .. }));
+
+        // Still not logged in
+        write_json(&mut client_stream, &ClientMessage::Ping { nonce: 1 }).unwrap();
+        let response: ServerMessage = read_json(&mut client_stream).unwrap();
+        assert!(matches!(response, ServerMessage::Error { .. }));
+    }
+
+    #[test]
+    fn test_resume_session() {
+        let state = Arc::new(Mutex::new(test_state()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        start_test_server(&addr.to_string(), Arc::clone(&state));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut first_stream = TcpStream::connect(addr).unwrap();
+        let session_token = login(&mut first_stream);
+        drop(first_stream);
+
+        let mut second_stream = TcpStream::connect(addr).unwrap();
+        write_json(&mut second_stream, &ClientMessage::Resume { session_token: session_token.clone() }).unwrap();
+        let response: ServerMessage = read_json(&mut second_stream).unwrap();
+        assert!(matches!(response, ServerMessage::LoginAccepted { session_token: token, .. } if token == session_token));
+
+        write_json(&mut second_stream, &ClientMessage::Resume { session_token: "bogus".to_string() }).unwrap();
+        let response: ServerMessage = read_json(&mut second_stream).unwrap();
+        assert!(matches!(response, ServerMessage::Error { .. })); // Already logged in
+    }
+
+    #[test]
+    fn test_tls_connection() {
+        // Self-signed certificate generated on the fly
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = certified.cert.pem();
+        let dir = std::env::temp_dir();
+        let suffix = generate_session_token();
+        let cert_path = dir.join(format!("ivan_game_cert_{}.pem", suffix));
+        let key_path = dir.join(format!("ivan_game_key_{}.pem", suffix));
+        fs::write(&cert_path, &cert_pem).unwrap();
+        fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        let acceptor = load_tls_acceptor(&cert_path, &key_path).unwrap();
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(test_state()));
+        thread::spawn(move || accept_connections(listener, state, Some(acceptor)));
+
+        // The client only trusts our self-signed certificate
+        let root = native_tls::Certificate::from_pem(cert_pem.as_bytes()).unwrap();
+        let connector = native_tls::TlsConnector::builder().add_root_certificate(root).build().unwrap();
+        let mut client = Client::connect_with_tls_connector(&addr.to_string(), "localhost", connector).unwrap();
+        assert!(client.stream.as_ref().unwrap().is_tls());
+
+        client.authenticate("player1", "securepassword").unwrap();
+        client.send(&ClientMessage::Ping { nonce: 9 }).unwrap();
+        assert_eq!(client.receive().unwrap(), ServerMessage::Pong { nonce: 9 });
+
+        // A client that doesn't trust the certificate is refused
+        assert!(Client::connect_with_tls(&addr.to_string(), "localhost").is_err());
+    }
+
+    fn next_message(receiver: &mut mpsc::Receiver<OutgoingMessage>) -> ServerMessage {
+        receiver.try_recv().unwrap().message().clone()
+    }
+
+    // Logged in client without a socket, its messages end up in the Receiver
+    fn add_test_client(state: &mut ServerState, username: &str) -> (usize, mpsc::Receiver<OutgoingMessage>) {
+        let (sender, mut receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+        let client_id = state.add_client(sender);
+        state.start_session(client_id, username.to_string(), generate_session_token());
+        receiver.try_recv().unwrap(); // LoginAccepted
+        (client_id, receiver)
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut state = test_state();
+        let (owner, mut owner_rx) = add_test_client(&mut state, "owner");
+        let (guest, mut guest_rx) = add_test_client(&mut state, "guest");
+        let (lobby, mut lobby_rx) = add_test_client(&mut state, "lobby");
+
+        process_message(owner, ClientMessage::CreateRoom { name: "table".to_string(), max_players: 2 }, &mut state);
+        let room_id = match next_message(&mut owner_rx) {
+            ServerMessage::RoomUpdate(room) => room.id,
+            other => panic!("Expected RoomUpdate, got {:?}", other),
+        };
+
+        process_message(guest, ClientMessage::ListRooms, &mut state);
+        assert!(matches!(next_message(&mut guest_rx), ServerMessage::RoomList { rooms } if rooms.len() == 1));
+
+        process_message(guest, ClientMessage::JoinRoom { room_id }, &mut state);
+        for receiver in [&mut owner_rx, &mut guest_rx] {
+            assert!(matches!(next_message(receiver), ServerMessage::RoomUpdate(room) if room.players == vec![owner, guest]));
+        }
+
+        // Room is full now
+        process_message(lobby, ClientMessage::JoinRoom { room_id }, &mut state);
+        assert!(matches!(next_message(&mut lobby_rx), ServerMessage::Error { .. }));
+
+        // Chat in the room doesn't reach the lobby
+        process_message(guest, ClientMessage::Chat { text: "ciao".to_string() }, &mut state);
+        assert!(matches!(next_message(&mut owner_rx), ServerMessage::Chat { from, .. } if from == guest));
+        assert!(guest_rx.try_recv().is_ok());
+        assert!(lobby_rx.try_recv().is_err());
+
+        // Only the owner starts the match
+        process_message(guest, ClientMessage::StartMatch, &mut state);
+        assert!(matches!(next_message(&mut guest_rx), ServerMessage::Error { .. }));
+        process_message(owner, ClientMessage::StartMatch, &mut state);
+        assert_eq!(next_message(&mut guest_rx), ServerMessage::MatchStarted { room_id });
+
+        // The owner disconnects, the guest takes over the room
+        while owner_rx.try_recv().is_ok() {}
+        while guest_rx.try_recv().is_ok() {}
+        state.remove_client(owner);
+        assert!(matches!(next_message(&mut guest_rx), ServerMessage::RoomUpdate(room) if room.owner == guest));
+        // The match started: the owner's hand went back in the deck
+        assert!(matches!(next_message(&mut guest_rx), ServerMessage::TableUpdate(table) if table.hands.len() == 1));
+
+        process_message(guest, ClientMessage::LeaveRoom, &mut state);
+        assert_eq!(next_message(&mut guest_rx), ServerMessage::RoomLeft { room_id });
+        assert!(state.rooms.list().is_empty());
+    }
+
+    #[test]
+    fn test_card_table() {
+        let mut state = test_state();
+        let (owner, mut owner_rx) = add_test_client(&mut state, "owner");
+        let (guest, mut guest_rx) = add_test_client(&mut state, "guest");
+        let room_id = state.rooms.create_room(owner, "poker", 4).unwrap().id;
+        state.rooms.join_room(guest, room_id).unwrap();
+
+        process_message(owner, ClientMessage::StartMatch, &mut state);
+        assert_eq!(next_message(&mut owner_rx), ServerMessage::MatchStarted { room_id });
+        let ServerMessage::Hand { cards: hand } = next_message(&mut owner_rx) else { panic!("no hand") };
+        assert_eq!(hand.len(), HAND_SIZE);
+        assert_eq!(next_message(&mut guest_rx), ServerMessage::MatchStarted { room_id });
+        let ServerMessage::Hand { cards: guest_hand } = next_message(&mut guest_rx) else { panic!("no hand") };
+        assert!(guest_hand.iter().all(|card| !hand.contains(card)));
+        while guest_rx.try_recv().is_ok() {}
+
+        // Public actions reach the room, the drawn card only its owner
+        let play = TableAction::Play { card: hand[0] };
+        process_message(owner, ClientMessage::TableAction(play.clone()), &mut state);
+        assert_eq!(next_message(&mut guest_rx), ServerMessage::TableAction { player: owner, action: play });
+        process_message(owner, ClientMessage::TableAction(TableAction::Draw), &mut state);
+        while let Ok(message) = guest_rx.try_recv() {
+            assert!(!matches!(message.message(), ServerMessage::Hand { .. }));
+        }
+        while owner_rx.try_recv().is_ok() {}
+
+        let stolen = TableAction::Discard { card: guest_hand[0] };
+        process_message(owner, ClientMessage::TableAction(stolen), &mut state);
+        assert!(matches!(next_message(&mut owner_rx), ServerMessage::Error { .. }));
+
+        process_message(guest, ClientMessage::LeaveRoom, &mut state);
+        while owner_rx.try_recv().is_ok_and(|m| !matches!(m.message(), ServerMessage::TableUpdate(_))) {}
+        let info = state.tables[&room_id].info(room_id);
+        assert_eq!(info.hands, vec![HandInfo { player: owner, cards: HAND_SIZE }]);
+        assert_eq!(info.deck_size, 52 - 2 * HAND_SIZE - 1 + HAND_SIZE);
+    }
+
+    #[test]
+    fn test_finished_match_reopens_room() {
+        let mut state = test_state();
+        let (owner, mut owner_rx) = add_test_client(&mut state, "owner");
+        let room_id = state.rooms.create_room(owner, "solo", 2).unwrap().id;
+        process_message(owner, ClientMessage::StartMatch, &mut state);
+        state.tables.insert(room_id, CardTable::with_deck(&[owner], vec!["ace_of_spades".parse().unwrap()]));
+        while owner_rx.try_recv().is_ok() {}
+
+        let play = TableAction::Play { card: "ace_of_spades".parse().unwrap() };
+        process_message(owner, ClientMessage::TableAction(play), &mut state);
+        while owner_rx.try_recv().is_ok_and(|m| !matches!(m.message(), ServerMessage::MatchEnded { .. })) {}
+        assert!(matches!(next_message(&mut owner_rx), ServerMessage::RoomUpdate(room) if !room.in_match));
+        assert!(state.table(room_id).is_none());
+
+        // Someone new can sit down, and the owner can deal again
+        let (guest, _guest_rx) = add_test_client(&mut state, "guest");
+        process_message(guest, ClientMessage::JoinRoom { room_id }, &mut state);
+        process_message(owner, ClientMessage::StartMatch, &mut state);
+        assert_eq!(state.table(room_id).unwrap().players(), &[owner, guest]);
+    }
+
+    #[test]
+    fn test_simulation_tick() {
+        let mut state = test_state();
+        let mut world = ServerWorld::new();
+        let (client_id, mut receiver) = add_test_client(&mut state, "player1");
+
+        let input = PlayerInput { sequence: 1, direction: [1., 0.] };
+        process_message(client_id, ClientMessage::Input(input), &mut state);
+        simulation_tick(&mut world, &mut state);
+        assert!(state.pending_inputs.is_empty());
+
+        match next_message(&mut receiver) {
+            ServerMessage::StateSnapshot(snapshot) => {
+                assert_eq!(snapshot.tick, 1);
+                assert_eq!(snapshot.entities.len(), 1);
+                assert!(snapshot.entities[0].position[0] > 0.); // Moved by the input
+            }
+            other => panic!("Expected a snapshot, got {:?}", other),
+        }
+
+        // The ball goes away with its client
+        state.remove_client(client_id);
+        simulation_tick(&mut world, &mut state);
+        assert!(world.snapshot().entities.is_empty());
+    }
+
+    fn test_config(max_clients: usize) -> ServerConfig {
+        ServerConfig { port: 0, max_clients, log_level: LogLevel::Error, ..ServerConfig::default() }
+    }
+
+    // Skips the snapshots the simulation keeps sending
+    fn read_non_snapshot(stream: &mut TcpStream) -> ServerMessage {
+        loop {
+            match read_json(stream).unwrap() {
+                ServerMessage::StateSnapshot(_) => continue,
+                message => return message,
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_admin_commands() {
+        assert_eq!("list".parse(), Ok(AdminCommand::List));
+        assert_eq!(" kick 3 ".parse(), Ok(AdminCommand::Kick(3)));
+        assert_eq!("broadcast hello  there".parse(), Ok(AdminCommand::Broadcast("hello  there".to_string())));
+        assert_eq!("shutdown".parse(), Ok(AdminCommand::Shutdown));
+        assert!("kick".parse::<AdminCommand>().is_err());
+        assert!("kick me".parse::<AdminCommand>().is_err());
+        assert!("broadcast".parse::<AdminCommand>().is_err());
+        assert!("reboot".parse::<AdminCommand>().is_err());
+        assert_eq!("DEBUG".parse(), Ok(LogLevel::Debug));
+        assert!("loud".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn test_admin_kick_and_broadcast() {
+        let handle = start_server(&test_config(8), test_credentials(), None).unwrap();
+        let mut first = TcpStream::connect(handle.local_addr()).unwrap();
+        let mut second = TcpStream::connect(handle.local_addr()).unwrap();
+        login(&mut first);
+        login(&mut second);
+
+        let output = handle.execute(&AdminCommand::List);
+        assert!(output.starts_with("2 client(s)"), "{}", output);
+
+        handle.execute(&AdminCommand::Broadcast("maintenance soon".to_string()));
+        let expected = ServerMessage::Status { message: "maintenance soon".to_string() };
+        assert_eq!(read_non_snapshot(&mut first), expected);
+        assert_eq!(read_non_snapshot(&mut second), expected);
+
+        assert_eq!(handle.execute(&AdminCommand::Kick(0)), "Kicked client 0");
+        assert!(matches!(read_non_snapshot(&mut first), ServerMessage::Disconnected { .. }));
+        assert_eq!(read_non_snapshot(&mut second), ServerMessage::Left { client_id: 0 });
+        assert_eq!(handle.execute(&AdminCommand::Kick(0)), "No client with id 0");
+
+        handle.shutdown();
+        handle.wait();
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let handle = start_server(&test_config(8), test_credentials(), None).unwrap();
+        let addr = handle.local_addr();
+        let mut client_stream = TcpStream::connect(addr).unwrap();
+        login(&mut client_stream);
+
+        handle.execute(&AdminCommand::Shutdown);
+        let reason = match read_non_snapshot(&mut client_stream) {
+            ServerMessage::Disconnected { reason } => reason,
+            other => panic!("Expected Disconnected, got {:?}", other),
+        };
+        assert_eq!(reason, "Server is shutting down");
+        let closed: Result<ServerMessage, FrameError> = read_json(&mut client_stream);
+        assert!(matches!(closed, Err(FrameError::ConnectionClosed)));
+
+        handle.wait(); // Returns once every thread is done
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_server_full() {
+        let handle = start_server(&test_config(1), test_credentials(), None).unwrap();
+        let mut first = TcpStream::connect(handle.local_addr()).unwrap();
+        login(&mut first);
+
+        let mut second = TcpStream::connect(handle.local_addr()).unwrap();
+        let response: ServerMessage = read_json(&mut second).unwrap();
+        assert_eq!(response, ServerMessage::Disconnected { reason: "Server is full".to_string() });
+
+        handle.shutdown();
+        handle.wait();
+    }
+
+    #[test]
+    fn test_snapshots_never_fill_the_queue() {
+        let mut state = test_state();
+        let (client_id, mut receiver) = add_test_client(&mut state, "player1");
+
+        for tick in 0..CLIENT_QUEUE_SIZE as u64 {
+            let snapshot = StateSnapshot { tick, entities: Vec::new() };
+            send_update_to_clients(&state, &ServerMessage::StateSnapshot(snapshot));
+        }
+        send_update_to_clients(&state, &ServerMessage::Status { message: "still here".to_string() });
+        state.drop_lagging_clients();
+        assert!(state.has_client(client_id));
+
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message.message().clone());
+        }
+        assert_eq!(messages.len(), CLIENT_QUEUE_SIZE / 2 + 1);
+        assert_eq!(messages.last().unwrap(), &ServerMessage::Status { message: "still here".to_string() });
+    }
+
+    #[test]
+    fn test_broadcast_is_encoded_once() {
+        let message = OutgoingMessage::new(ServerMessage::Pong { nonce: 1 });
+        let copy = message.clone();
+        let first = message.frame().unwrap().as_ptr();
+        assert_eq!(copy.frame().unwrap().as_ptr(), first);
+    }
+
+    #[test]
+    fn test_slow_client_is_dropped() {
+        let mut state = test_state();
+        let (slow, _slow_rx) = add_test_client(&mut state, "slow"); // Never reads
+        let (fast, mut fast_rx) = add_test_client(&mut state, "fast");
+
+        for i in 0..CLIENT_QUEUE_SIZE + 1 {
+            send_update_to_clients(&state, &ServerMessage::Status { message: i.to_string() });
+            while fast_rx.try_recv().is_ok() {}
+        }
+        state.drop_lagging_clients();
+
+        assert!(!state.clients.contains_key(&slow));
+        assert!(state.clients.contains_key(&fast));
+        assert_eq!(next_message(&mut fast_rx), ServerMessage::Left { client_id: slow });
+    }
+
+    #[test]
+    fn test_whisper() {
+        let mut state = test_state();
+        let (ivan, mut ivan_rx) = add_test_client(&mut state, "ivan");
+        let (farid, mut farid_rx) = add_test_client(&mut state, "farid");
+        let (other, mut other_rx) = add_test_client(&mut state, "other");
+
+        // Taking farid's name with Join doesn't get farid's whispers
+        process_message(other, ClientMessage::Join { name: "farid".to_string() }, &mut state);
+        for receiver in [&mut ivan_rx, &mut farid_rx, &mut other_rx] {
+            while receiver.try_recv().is_ok() {}
+        }
+
+        let whisper = ClientMessage::Whisper { to: "farid".to_string(), text: " psst ".to_string() };
+        process_message(ivan, whisper, &mut state);
+        let expected = ServerMessage::Whisper {
+            from: ivan,
+            name: "ivan".to_string(),
+            to: "farid".to_string(),
+            text: "psst".to_string(),
+        };
+        assert_eq!(next_message(&mut farid_rx), expected);
+        assert_eq!(next_message(&mut ivan_rx), expected); // Echo
+        assert!(other_rx.try_recv().is_err());
+
+        process_message(farid, ClientMessage::Whisper { to: "nobody".to_string(), text: "hi".to_string() }, &mut state);
+        assert!(matches!(next_message(&mut farid_rx), ServerMessage::Error { .. }));
+    }
+
+    #[test]
+    fn test_chat_limits() {
+        let mut state = test_state();
+        let (client_id, mut receiver) = add_test_client(&mut state, "player1");
+
+        let too_long = "a".repeat(MAX_CHAT_LENGTH + 1);
+        process_message(client_id, ClientMessage::Chat { text: too_long }, &mut state);
+        assert!(matches!(next_message(&mut receiver), ServerMessage::Error { .. }));
+
+        // The burst goes through, then the limiter kicks in
+        for _ in 0..CHAT_BURST as usize {
+            process_message(client_id, ClientMessage::Chat { text: "spam".to_string() }, &mut state);
+            assert!(matches!(next_message(&mut receiver), ServerMessage::Chat { .. }));
+        }
+        process_message(client_id, ClientMessage::Chat { text: "spam".to_string() }, &mut state);
+        assert_eq!(
+            next_message(&mut receiver),
+            ServerMessage::Error { message: "Slow down, too many messages".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_idle_client_is_dropped() {
+        let mut state = test_state();
+        let (idle, mut idle_rx) = add_test_client(&mut state, "idle");
+        let (active, mut active_rx) = add_test_client(&mut state, "active");
+
+        let later = Instant::now() + DEFAULT_IDLE_TIMEOUT;
+        state.clients.get_mut(&active).unwrap().last_seen = later; // Pinged just in time
+        state.drop_idle_clients(later + Duration::from_secs(1));
+
+        assert!(!state.has_client(idle));
+        assert!(state.has_client(active));
+        assert_eq!(next_message(&mut idle_rx), ServerMessage::Disconnected { reason: "Idle timeout".to_string() });
+        assert_eq!(next_message(&mut active_rx), ServerMessage::Left { client_id: idle });
+    }
+
+    #[test]
+    fn test_password_is_checked_outside_the_lock() {
+        let mut state = test_state();
+        let (sender, mut receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+        let client_id = state.add_client(sender);
+
+        let login = ClientMessage::Login { username: "player1".to_string(), password: "securepassword".to_string() };
+        let pending = process_message(client_id, login.clone(), &mut state).unwrap();
+        assert!(!state.is_authenticated(client_id));
+        assert!(process_message(client_id, login, &mut state).is_none());
+        assert!(matches!(next_message(&mut receiver), ServerMessage::Error { .. }));
+
+        let accepted = pending.verify();
+        finish_login(&mut state, pending, accepted);
+        assert!(matches!(next_message(&mut receiver), ServerMessage::LoginAccepted { .. }));
+        assert!(state.is_authenticated(client_id));
+
+        // A client gone before its check ended gets nothing
+        let (sender, _receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+        let gone = state.add_client(sender);
+        let wrong = ClientMessage::Login { username: "player1".to_string(), password: "nope".to_string() };
+        let pending = process_message(gone, wrong, &mut state).unwrap();
+        assert!(!pending.verify());
+        state.remove_client(gone);
+        finish_login(&mut state, pending, false);
+        assert!(!state.logging_in.contains(&gone));
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let mut state = test_state();
+        let start = Instant::now();
+        state.set_clock(start);
+        let (client_id, _receiver) = add_test_client(&mut state, "player1");
+        let token = state.session_tokens[&client_id].clone();
+
+        // Never expires while in use, then only for the session timeout
+        state.drop_expired_sessions(start + DEFAULT_SESSION_TIMEOUT * 2);
+        assert_eq!(state.session_user(&token).as_deref(), Some("player1"));
+        state.remove_client(client_id);
+        state.set_clock(start + DEFAULT_SESSION_TIMEOUT - Duration::from_secs(1));
+        assert_eq!(state.session_user(&token).as_deref(), Some("player1"));
+        state.set_clock(start + DEFAULT_SESSION_TIMEOUT);
+        assert_eq!(state.session_user(&token), None);
+        state.drop_expired_sessions(start + DEFAULT_SESSION_TIMEOUT);
+        assert!(state.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_leave_and_logout_end_the_session() {
+        let mut state = test_state();
+        let (leaving, _leaving_rx) = add_test_client(&mut state, "leaving");
+        let (logging_out, mut logging_out_rx) = add_test_client(&mut state, "logging_out");
+        let leaving_token = state.session_tokens[&leaving].clone();
+        let logging_out_token = state.session_tokens[&logging_out].clone();
+
+        process_message(leaving, ClientMessage::Leave, &mut state);
+        assert!(!state.has_client(leaving));
+        assert_eq!(state.session_user(&leaving_token), None);
+
+        process_message(logging_out, ClientMessage::Logout, &mut state);
+        assert_eq!(next_message(&mut logging_out_rx), ServerMessage::Left { client_id: leaving });
+        assert_eq!(next_message(&mut logging_out_rx), ServerMessage::LoggedOut);
+        assert!(state.has_client(logging_out)); // Still connected, can log in again
+        assert!(!state.is_authenticated(logging_out));
+        assert_eq!(state.session_user(&logging_out_token), None);
+        assert!(state.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_ping_keeps_client_alive() {
+        let mut state = test_state();
+        let (client_id, mut receiver) = add_test_client(&mut state, "player1");
+        state.clients.get_mut(&client_id).unwrap().last_seen -= DEFAULT_IDLE_TIMEOUT;
+
+        handle_payload(client_id, br#"{"type":"ping","nonce":3}"#, &mut state);
+        assert_eq!(next_message(&mut receiver), ServerMessage::Pong { nonce: 3 });
+
+        state.drop_idle_clients(Instant::now());
+        assert!(state.has_client(client_id));
+    }
+}
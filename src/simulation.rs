@@ -0,0 +1,230 @@
+// Mondo di gioco lato server, senza rendering.
+//
+// The server owns the real state of the game: it runs the same ball/gravity
+// systems as scene1 on a headless Bevy `World` at a fixed rate, applies the
+// inputs queued by the clients and hands back a snapshot after every tick.
+
+use bevy::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::constants::{BALL_SPEED, BALL_STARTING_POSITION, INITIAL_BALL_DIRECTION};
+use crate::protocol::{EntitySnapshot, PlayerInput, StateSnapshot};
+use crate::scene1::{apply_gravity, apply_velocity, window_walls, Ball, Gravity, Velocity};
+
+
+// ====== CONSTANTS ======
+
+pub const TICK_RATE: u32 = 30; // Simulation ticks per second
+pub const TICK_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TICK_RATE as u64);
+
+/// Velocity added by one input with a full-length direction
+pub const INPUT_ACCELERATION: f32 = 40.;
+
+
+// ====== STRUCTS ======
+
+/// The ball controlled by a connected client
+#[derive(Component, Debug)]
+pub struct PlayerBall {
+    pub client_id: usize,
+    pub last_input: u64, // Acknowledged to the client in the snapshots
+}
+
+pub struct ServerWorld {
+    world: World,
+    schedule: Schedule,
+    tick: u64,
+    players: HashMap<usize, Entity>, // Client id -> its ball
+}
+
+impl ServerWorld {
+    pub fn new() -> Self {
+        let mut world = World::new();
+        world.insert_resource(Gravity { x: 0., y: -1. });
+        world.insert_resource(Time::<()>::default());
+        // Nothing is ever opened, the walls just need the size of a default window
+        world.spawn(Window::default());
+
+        // Same order as scene1 in FixedUpdate
+        let mut schedule = Schedule::default();
+        schedule.add_systems((window_walls, apply_gravity, apply_velocity).chain());
+
+        ServerWorld { world, schedule, tick: 0, players: HashMap::new() }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn add_player(&mut self, client_id: usize) {
+        if self.players.contains_key(&client_id) {
+            return;
+        }
+        let ball = self.world.spawn((
+            Ball,
+            PlayerBall { client_id, last_input: 0 },
+            Velocity(INITIAL_BALL_DIRECTION.normalize() * BALL_SPEED),
+            Transform::from_translation(BALL_STARTING_POSITION),
+        )).id();
+        self.players.insert(client_id, ball);
+    }
+
+    pub fn remove_player(&mut self, client_id: usize) {
+        if let Some(ball) = self.players.remove(&client_id) {
+            self.world.despawn(ball);
+        }
+    }
+
+    /// Spawns a ball for every new client and despawns those of clients that left
+    pub fn sync_players(&mut self, connected: &[usize]) {
+        let gone: Vec<usize> = self.players.keys().filter(|id| !connected.contains(id)).copied().collect();
+        for client_id in gone {
+            self.remove_player(client_id);
+        }
+        for client_id in connected {
+            self.add_player(*client_id);
+        }
+    }
+
+    pub fn apply_input(&mut self, client_id: usize, input: PlayerInput) {
+        let Some(ball) = self.players.get(&client_id) else {
+            return;
+        };
+        // Longer directions would let a modified client move faster
+        let direction = Vec2::from(input.direction).clamp_length_max(1.);
+        let mut ball = self.world.entity_mut(*ball);
+        if let Some(mut velocity) = ball.get_mut::<Velocity>() {
+            velocity.0 += direction * INPUT_ACCELERATION;
+        }
+        if let Some(mut player) = ball.get_mut::<PlayerBall>() {
+            player.last_input = player.last_input.max(input.sequence);
+        }
+    }
+
+    /// Overwrites position and velocity of a player's ball, e.g. with the
+    /// authoritative state when the client re-runs its own prediction
+    pub fn set_player_state(&mut self, client_id: usize, position: Vec2, velocity: Vec2) {
+        let Some(ball) = self.players.get(&client_id) else {
+            return;
+        };
+        let mut ball = self.world.entity_mut(*ball);
+        if let Some(mut transform) = ball.get_mut::<Transform>() {
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+        }
+        if let Some(mut current) = ball.get_mut::<Velocity>() {
+            current.0 = velocity;
+        }
+    }
+
+    pub fn player_position(&self, client_id: usize) -> Option<Vec2> {
+        let ball = self.players.get(&client_id)?;
+        self.world.get::<Transform>(*ball).map(|transform| transform.translation.truncate())
+    }
+
+    /// Advances the world by one tick
+    pub fn step(&mut self) {
+        self.world.resource_mut::<Time>().advance_by(TICK_DURATION);
+        self.schedule.run(&mut self.world);
+        self.tick += 1;
+    }
+
+    pub fn snapshot(&mut self) -> StateSnapshot {
+        let mut query = self.world.query::<(&PlayerBall, &Transform, &Velocity)>();
+        let mut entities: Vec<EntitySnapshot> = query
+            .iter(&self.world)
+            .map(|(player, transform, velocity)| EntitySnapshot {
+                id: player.client_id as u64,
+                position: transform.translation.truncate().to_array(),
+                velocity: velocity.0.to_array(),
+                last_input: player.last_input,
+            })
+            .collect();
+        entities.sort_by_key(|entity| entity.id);
+
+        StateSnapshot { tick: self.tick, entities }
+    }
+}
+
+impl Default for ServerWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(world: &mut ServerWorld, client_id: u64) -> Vec2 {
+        let snapshot = world.snapshot();
+        let entity = snapshot.entities.iter().find(|e| e.id == client_id).unwrap();
+        Vec2::from(entity.position)
+    }
+
+    #[test]
+    fn test_gravity_pulls_the_ball_down() {
+        let mut world = ServerWorld::new();
+        world.add_player(0);
+
+        let start = position(&mut world, 0);
+        world.step();
+        world.step();
+        assert!(position(&mut world, 0).y < start.y);
+        assert_eq!(world.snapshot().tick, 2);
+    }
+
+    #[test]
+    fn test_ball_stays_inside_the_walls() {
+        let mut world = ServerWorld::new();
+        world.add_player(0);
+
+        for _ in 0..TICK_RATE * 5 {
+            world.step();
+        }
+        let lower_y = -Window::default().resolution.height() / 2.;
+        assert!(position(&mut world, 0).y >= lower_y);
+    }
+
+    #[test]
+    fn test_input_moves_only_its_player() {
+        let mut world = ServerWorld::new();
+        world.sync_players(&[0, 1]);
+
+        world.apply_input(0, PlayerInput { sequence: 1, direction: [1., 0.] });
+        world.apply_input(7, PlayerInput { sequence: 1, direction: [1., 0.] }); // Unknown client
+        world.step();
+
+        assert!(position(&mut world, 0).x > 0.);
+        assert_eq!(position(&mut world, 1).x, 0.);
+        assert_eq!(world.snapshot().entities[0].last_input, 1);
+    }
+
+    #[test]
+    fn test_input_direction_is_clamped() {
+        let mut world = ServerWorld::new();
+        world.sync_players(&[0, 1]);
+
+        world.apply_input(0, PlayerInput { sequence: 1, direction: [1., 0.] });
+        world.apply_input(1, PlayerInput { sequence: 1, direction: [100., 0.] });
+        world.step();
+
+        assert_eq!(position(&mut world, 0).x, position(&mut world, 1).x);
+    }
+
+    #[test]
+    fn test_sync_players() {
+        let mut world = ServerWorld::new();
+        world.sync_players(&[0, 1, 2]);
+        world.sync_players(&[2, 3]);
+
+        let ids: Vec<u64> = world.snapshot().entities.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+}
@@ -0,0 +1,372 @@
+// Scena del solitario: disegna la partita di klondike.rs e la gioca con il mouse.
+//
+// Left click on the stock draws, drag face up cards onto another pile to move
+// them (an illegal drop puts them back), right click sends a card to the
+// foundations. Keys: Z undo, A auto-move everything possible, F2 new game.
+
+use bevy::prelude::*;
+use rand::Rng;
+
+use crate::app_state::AppState;
+use crate::app_utils::get_mouse_position;
+use crate::cards::{card_image, CardHandles, CARD_BACK};
+use crate::game_rng::{GameRng, RngStream};
+use crate::klondike::{Klondike, Pile, FOUNDATIONS, TABLEAU_COLUMNS};
+
+
+// ====== CONSTANTS ======
+
+// Every card sprite is drawn at this size whatever the art, so the clicks land where the cards are
+const CARD_SIZE: Vec2 = Vec2::new(100., 145.);
+const COLUMN_SPACING: f32 = 120.;
+const TOP_ROW_Y: f32 = 230.; // Stock, waste and foundations
+const TABLEAU_Y: f32 = 40.;
+const FACE_DOWN_OFFSET: f32 = 12.; // How much of a covered card shows in a column
+const FACE_UP_OFFSET: f32 = 28.;
+const CARD_Z: f32 = 1.;
+const DRAG_Z: f32 = 50.; // Above every other card
+const EMPTY_PILE_COLOR: Color = Color::srgba(1., 1., 1., 0.25);
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Component)]
+pub struct SolitaireEntity;
+
+/// The card at `index` (from the bottom) of `pile`
+#[derive(Debug, Clone, Copy, Component)]
+struct SolitaireCard {
+    pile: Pile,
+    index: usize,
+}
+
+#[derive(Debug, Component)]
+struct WinText;
+
+#[derive(Debug, Resource)]
+pub struct Solitaire {
+    pub game: Klondike,
+}
+
+// Cards being dragged, with where they were and where the cursor grabbed them
+#[derive(Debug)]
+struct Drag {
+    from: Pile,
+    count: usize,
+    grab: Vec2,
+    cards: Vec<(Entity, Vec3)>,
+}
+
+#[derive(Debug, Default, Resource)]
+struct SolitaireDrag(Option<Drag>);
+
+pub struct SolitairePlugin;
+
+impl Plugin for SolitairePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SolitaireDrag>()
+            .add_systems(OnEnter(AppState::Solitaire), setup_solitaire)
+            .add_systems(OnExit(AppState::Solitaire), cleanup_solitaire)
+            .add_systems(
+                Update,
+                (solitaire_keyboard_input, solitaire_mouse_input, draw_solitaire, draw_empty_piles, update_win_text)
+                    .chain()
+                    .run_if(in_state(AppState::Solitaire)),
+            );
+    }
+}
+
+
+// ====== METHODS ======
+
+fn setup_solitaire(mut commands: Commands, mut rng: ResMut<GameRng>) {
+    commands.insert_resource(Solitaire { game: Klondike::new_shuffled(rng.stream(RngStream::Solitaire).gen()) });
+    commands.spawn((
+        TextBundle::from_section(
+            "You won!\nPress F2 for a new game",
+            TextStyle { font_size: 48., color: Color::srgb(1., 0.85, 0.2), ..default() },
+        )
+        .with_text_justify(JustifyText::Center)
+        .with_style(Style { position_type: PositionType::Absolute, top: Val::Percent(40.), left: Val::Percent(35.), ..default() }),
+        Visibility::Hidden,
+        WinText,
+        SolitaireEntity,
+    ));
+}
+
+fn cleanup_solitaire(
+    mut commands: Commands,
+    query: Query<Entity, With<SolitaireEntity>>,
+    mut drag: ResMut<SolitaireDrag>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<Solitaire>();
+    drag.0 = None;
+}
+
+fn solitaire_keyboard_input(keys: Res<ButtonInput<KeyCode>>, mut solitaire: ResMut<Solitaire>, mut rng: ResMut<GameRng>) {
+    if keys.just_pressed(KeyCode::KeyZ) {
+        solitaire.game.undo();
+    }
+    if keys.just_pressed(KeyCode::KeyA) {
+        solitaire.game.auto_move();
+    }
+    if keys.just_pressed(KeyCode::F2) {
+        solitaire.game = Klondike::new_shuffled(rng.stream(RngStream::Solitaire).gen());
+    }
+}
+
+fn solitaire_mouse_input(
+    buttons: Res<ButtonInput<MouseButton>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    window: Query<&Window>,
+    mut solitaire: ResMut<Solitaire>,
+    mut drag: ResMut<SolitaireDrag>,
+    mut cards: Query<(Entity, &SolitaireCard, &mut Transform)>,
+) {
+    let Some(cursor) = get_mouse_position(camera_query, window) else {
+        // Released outside the window: nowhere to drop, the cards go back
+        if !buttons.pressed(MouseButton::Left) {
+            if let Some(drag) = drag.0.take() {
+                return_cards(drag, &mut cards);
+            }
+        }
+        return;
+    };
+
+    if buttons.just_pressed(MouseButton::Right) {
+        if let Some((from, _)) = card_at(&solitaire.game, cursor) {
+            if let Some(to) = solitaire.game.foundation_for(from) {
+                let _ = solitaire.game.move_cards(from, 1, to);
+            }
+        }
+    }
+
+    if buttons.just_pressed(MouseButton::Left) {
+        match card_at(&solitaire.game, cursor) {
+            Some((Pile::Stock, _)) => {
+                let _ = solitaire.game.draw();
+            }
+            None if stock_contains(cursor) => {
+                let _ = solitaire.game.draw(); // The empty stock turns the waste over
+            }
+            Some((from, index)) => {
+                let count = pile_len(&solitaire.game, from) - index;
+                if face_up(&solitaire.game, from, index) {
+                    let picked = cards.iter().filter(|(_, card, _)| card.pile == from && card.index >= index);
+                    let picked = picked.map(|(entity, _, transform)| (entity, transform.translation)).collect();
+                    drag.0 = Some(Drag { from, count, grab: cursor, cards: picked });
+                }
+            }
+            None => {}
+        }
+    }
+
+    let Some(current) = &drag.0 else {
+        return;
+    };
+    if buttons.pressed(MouseButton::Left) {
+        let moved = cursor - current.grab;
+        for (position, (entity, start)) in current.cards.iter().enumerate() {
+            if let Ok((_, _, mut transform)) = cards.get_mut(*entity) {
+                transform.translation = (start.truncate() + moved).extend(DRAG_Z + position as f32 * 0.01);
+            }
+        }
+        return;
+    }
+
+    // Dropped: a legal move redraws the table, anything else puts the cards back
+    let Some(drag) = drag.0.take() else {
+        return;
+    };
+    let moved = pile_at(cursor).is_some_and(|to| solitaire.game.move_cards(drag.from, drag.count, to).is_ok());
+    if !moved {
+        return_cards(drag, &mut cards);
+    }
+}
+
+fn return_cards(drag: Drag, cards: &mut Query<(Entity, &SolitaireCard, &mut Transform)>) {
+    for (entity, start) in drag.cards {
+        if let Ok((_, _, mut transform)) = cards.get_mut(entity) {
+            transform.translation = start;
+        }
+    }
+}
+
+// Lays every card out again after each move
+fn draw_solitaire(
+    mut commands: Commands,
+    solitaire: Res<Solitaire>,
+    card_handles: Res<CardHandles>,
+    asset_server: Res<AssetServer>,
+    cards: Query<Entity, With<SolitaireCard>>,
+) {
+    if !solitaire.is_changed() {
+        return;
+    }
+    for entity in &cards {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let game = &solitaire.game;
+    for pile in all_piles() {
+        for index in 0..pile_len(game, pile) {
+            let name = match pile {
+                Pile::Stock => CARD_BACK.to_string(),
+                Pile::Waste => game.waste()[index].to_string(),
+                Pile::Foundation(foundation) => game.foundation(foundation)[index].to_string(),
+                Pile::Tableau(column) => {
+                    let card = game.column(column)[index];
+                    if card.face_up { card.card.to_string() } else { CARD_BACK.to_string() }
+                }
+            };
+            let position = card_position(game, pile, index).extend(CARD_Z + index as f32 * 0.01);
+            commands.spawn((
+                SpriteBundle {
+                    sprite: Sprite { custom_size: Some(CARD_SIZE), ..default() },
+                    texture: card_image(&name, &card_handles, &asset_server),
+                    transform: Transform::from_translation(position),
+                    ..default()
+                },
+                SolitaireCard { pile, index },
+                SolitaireEntity,
+            ));
+        }
+    }
+}
+
+fn draw_empty_piles(mut gizmos: Gizmos, solitaire: Res<Solitaire>) {
+    for pile in all_piles() {
+        if pile_len(&solitaire.game, pile) == 0 {
+            gizmos.rect_2d(pile_position(pile), 0., CARD_SIZE, EMPTY_PILE_COLOR);
+        }
+    }
+}
+
+fn update_win_text(solitaire: Res<Solitaire>, mut text: Query<&mut Visibility, With<WinText>>) {
+    if !solitaire.is_changed() {
+        return;
+    }
+    for mut visibility in &mut text {
+        *visibility = if solitaire.game.is_won() { Visibility::Visible } else { Visibility::Hidden };
+    }
+}
+
+fn all_piles() -> impl Iterator<Item = Pile> {
+    [Pile::Stock, Pile::Waste]
+        .into_iter()
+        .chain((0..FOUNDATIONS).map(Pile::Foundation))
+        .chain((0..TABLEAU_COLUMNS).map(Pile::Tableau))
+}
+
+fn pile_len(game: &Klondike, pile: Pile) -> usize {
+    match pile {
+        Pile::Stock => game.stock().len(),
+        Pile::Waste => game.waste().len(),
+        Pile::Foundation(index) => game.foundation(index).len(),
+        Pile::Tableau(index) => game.column(index).len(),
+    }
+}
+
+fn face_up(game: &Klondike, pile: Pile, index: usize) -> bool {
+    match pile {
+        Pile::Stock => false,
+        Pile::Tableau(column) => game.column(column)[index].face_up,
+        _ => true,
+    }
+}
+
+fn column_x(column: usize) -> f32 {
+    (column as f32 - (TABLEAU_COLUMNS as f32 - 1.) / 2.) * COLUMN_SPACING
+}
+
+// Where the first card of a pile goes: stock and waste top left, foundations top right
+fn pile_position(pile: Pile) -> Vec2 {
+    match pile {
+        Pile::Stock => Vec2::new(column_x(0), TOP_ROW_Y),
+        Pile::Waste => Vec2::new(column_x(1), TOP_ROW_Y),
+        Pile::Foundation(index) => Vec2::new(column_x(TABLEAU_COLUMNS - FOUNDATIONS + index), TOP_ROW_Y),
+        Pile::Tableau(index) => Vec2::new(column_x(index), TABLEAU_Y),
+    }
+}
+
+// The tableau columns fan down, the other piles are stacked
+fn card_position(game: &Klondike, pile: Pile, index: usize) -> Vec2 {
+    let Pile::Tableau(column) = pile else {
+        return pile_position(pile);
+    };
+    let offset: f32 = game.column(column)[..index]
+        .iter()
+        .map(|card| if card.face_up { FACE_UP_OFFSET } else { FACE_DOWN_OFFSET })
+        .sum();
+    pile_position(pile) - Vec2::new(0., offset)
+}
+
+fn contains(center: Vec2, point: Vec2) -> bool {
+    (point - center).abs().cmple(CARD_SIZE / 2.).all()
+}
+
+fn stock_contains(point: Vec2) -> bool {
+    contains(pile_position(Pile::Stock), point)
+}
+
+/// The topmost card under the point, as its pile and index from the bottom
+fn card_at(game: &Klondike, point: Vec2) -> Option<(Pile, usize)> {
+    all_piles().find_map(|pile| {
+        let len = pile_len(game, pile);
+        // Only the top of a stacked pile can be picked, any visible card of a column
+        let first = if matches!(pile, Pile::Tableau(_)) { 0 } else { len.saturating_sub(1) };
+        (first..len).rev().find(|index| contains(card_position(game, pile, *index), point)).map(|index| (pile, index))
+    })
+}
+
+/// The pile a card dropped at the point would go on, by column
+fn pile_at(point: Vec2) -> Option<Pile> {
+    let column = ((point.x / COLUMN_SPACING) + (TABLEAU_COLUMNS as f32 - 1.) / 2.).round();
+    if column < 0. || column >= TABLEAU_COLUMNS as f32 || (point.x - column_x(column as usize)).abs() > CARD_SIZE.x / 2. {
+        return None;
+    }
+    let column = column as usize;
+    if point.y < TOP_ROW_Y - CARD_SIZE.y / 2. {
+        return Some(Pile::Tableau(column));
+    }
+    match column {
+        0 => Some(Pile::Stock),
+        1 => Some(Pile::Waste),
+        column if column >= TABLEAU_COLUMNS - FOUNDATIONS => Some(Pile::Foundation(column - (TABLEAU_COLUMNS - FOUNDATIONS))),
+        _ => None,
+    }
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_card_at_picks_the_topmost() {
+        let game = Klondike::new_shuffled(11);
+        assert_eq!(card_at(&game, pile_position(Pile::Stock)), Some((Pile::Stock, game.stock().len() - 1)));
+
+        // The last card of the last column covers the bottom of the one before it
+        let last = card_position(&game, Pile::Tableau(6), 6);
+        assert_eq!(card_at(&game, last), Some((Pile::Tableau(6), 6)));
+        let covered = card_position(&game, Pile::Tableau(6), 5) + Vec2::new(0., (CARD_SIZE.y - FACE_DOWN_OFFSET) / 2.);
+        assert_eq!(card_at(&game, covered), Some((Pile::Tableau(6), 5)));
+        assert_eq!(card_at(&game, Vec2::new(0., TOP_ROW_Y)), None); // Nothing between waste and foundations
+    }
+
+    #[test]
+    fn test_pile_at() {
+        assert_eq!(pile_at(pile_position(Pile::Foundation(3))), Some(Pile::Foundation(3)));
+        assert_eq!(pile_at(pile_position(Pile::Waste)), Some(Pile::Waste));
+        assert_eq!(pile_at(pile_position(Pile::Tableau(2)) - Vec2::new(0., 200.)), Some(Pile::Tableau(2)));
+        assert_eq!(pile_at(Vec2::new(column_x(0) - COLUMN_SPACING, 0.)), None);
+    }
+}
@@ -0,0 +1,219 @@
+// Tavolo da carte del server: un mazzo, le mani dei giocatori e il centro del tavolo.
+//
+// Pure bookkeeping like the RoomManager: the server owns the deck and the
+// hands, decides what is legal and tells every player only what they may see
+// (their own hand in full, the others' as a number of cards).
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cards::{Card, Deck};
+use crate::protocol::{HandInfo, PlayedCard, TableInfo};
+
+
+// ====== CONSTANTS ======
+
+pub const HAND_SIZE: usize = 5;
+
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableError {
+    NotAtTable,
+    EmptyDeck,
+    CardNotInHand,
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            TableError::NotAtTable => "Not sitting at a card table",
+            TableError::EmptyDeck => "The deck is empty",
+            TableError::CardNotInHand => "That card is not in your hand",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for TableError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardTable {
+    deck: Vec<Card>, // The top of the deck is the end of the Vec
+    players: Vec<usize>, // Seating order
+    hands: HashMap<usize, Vec<Card>>,
+    played: Vec<PlayedCard>,
+    discard: Vec<Card>,
+}
+
+impl CardTable {
+    /// Shuffles a standard 52 card deck and deals `HAND_SIZE` cards to every player
+    pub fn new(players: &[usize], rng: &mut impl Rng) -> Self {
+        let mut deck = standard_deck();
+        deck.shuffle(rng);
+        Self::with_deck(players, deck)
+    }
+
+    /// Deals from the given deck as it is, the last card first
+    pub fn with_deck(players: &[usize], deck: Vec<Card>) -> Self {
+        let mut table = CardTable {
+            deck,
+            players: players.to_vec(),
+            hands: players.iter().map(|player| (*player, Vec::new())).collect(),
+            played: Vec::new(),
+            discard: Vec::new(),
+        };
+        // One card at a time around the table, as a dealer would
+        for _ in 0..HAND_SIZE {
+            for player in players {
+                if let Some(card) = table.deck.pop() {
+                    table.hands.entry(*player).or_default().push(card);
+                }
+            }
+        }
+        table
+    }
+
+    pub fn hand(&self, player: usize) -> Option<&[Card]> {
+        self.hands.get(&player).map(Vec::as_slice)
+    }
+
+    /// Moves the top card of the deck to the hand and returns it
+    pub fn draw(&mut self, player: usize) -> Result<Card, TableError> {
+        let hand = self.hands.get_mut(&player).ok_or(TableError::NotAtTable)?;
+        let card = self.deck.pop().ok_or(TableError::EmptyDeck)?;
+        hand.push(card);
+        Ok(card)
+    }
+
+    /// Puts a card from the hand face up in the middle of the table
+    pub fn play(&mut self, player: usize, card: Card) -> Result<(), TableError> {
+        let card = self.take_from_hand(player, card)?;
+        self.played.push(PlayedCard { player, card });
+        Ok(())
+    }
+
+    pub fn discard(&mut self, player: usize, card: Card) -> Result<(), TableError> {
+        let card = self.take_from_hand(player, card)?;
+        self.discard.push(card);
+        Ok(())
+    }
+
+    /// A player leaving the table puts their hand back under the deck
+    pub fn remove_player(&mut self, player: usize) {
+        if let Some(hand) = self.hands.remove(&player) {
+            self.deck.splice(0..0, hand);
+        }
+        self.players.retain(|p| *p != player);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    /// Nothing left to draw and nothing left in any hand
+    pub fn is_finished(&self) -> bool {
+        self.deck.is_empty() && self.hands.values().all(Vec::is_empty)
+    }
+
+    pub fn players(&self) -> &[usize] {
+        &self.players
+    }
+
+    pub fn info(&self, room_id: usize) -> TableInfo {
+        TableInfo {
+            room_id,
+            deck_size: self.deck.len(),
+            hands: self
+                .players
+                .iter()
+                .map(|player| HandInfo { player: *player, cards: self.hands.get(player).map_or(0, Vec::len) })
+                .collect(),
+            played: self.played.clone(),
+            discard_top: self.discard.last().copied(),
+        }
+    }
+
+    fn take_from_hand(&mut self, player: usize, card: Card) -> Result<Card, TableError> {
+        let hand = self.hands.get_mut(&player).ok_or(TableError::NotAtTable)?;
+        let index = hand.iter().position(|c| *c == card).ok_or(TableError::CardNotInHand)?;
+        Ok(hand.remove(index))
+    }
+}
+
+/// The 52 cards, unshuffled
+pub fn standard_deck() -> Vec<Card> {
+    Deck::standard().cards().to_vec()
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(name: &str) -> Card {
+        name.parse().unwrap()
+    }
+
+    fn deck(cards: &[&str]) -> Vec<Card> {
+        cards.iter().map(|name| card(name)).collect()
+    }
+
+    #[test]
+    fn test_standard_deck() {
+        let deck = standard_deck();
+        assert_eq!(deck.len(), 52);
+        assert_eq!(deck.iter().collect::<std::collections::HashSet<_>>().len(), 52);
+    }
+
+    #[test]
+    fn test_deal_hides_the_hands() {
+        let table = CardTable::new(&[3, 7], &mut rand::thread_rng());
+        assert_eq!(table.hand(3).unwrap().len(), HAND_SIZE);
+        assert_eq!(table.hand(7).unwrap().len(), HAND_SIZE);
+
+        let info = table.info(1);
+        assert_eq!(info.deck_size, 52 - 2 * HAND_SIZE);
+        assert_eq!(info.hands, vec![HandInfo { player: 3, cards: HAND_SIZE }, HandInfo { player: 7, cards: HAND_SIZE }]);
+        assert!(info.played.is_empty());
+    }
+
+    #[test]
+    fn test_draw_play_discard() {
+        // A deck shorter than the hands: everything dealt, nothing left to draw
+        let mut table = CardTable::with_deck(&[0, 1], deck(&["2_of_clubs", "3_of_clubs", "4_of_clubs"]));
+        assert_eq!(table.hand(0).unwrap(), deck(&["4_of_clubs", "2_of_clubs"]));
+        assert_eq!(table.hand(1).unwrap(), deck(&["3_of_clubs"]));
+        assert_eq!(table.draw(0).unwrap_err(), TableError::EmptyDeck);
+
+        table.play(0, card("2_of_clubs")).unwrap();
+        table.discard(0, card("4_of_clubs")).unwrap();
+        assert_eq!(table.play(1, card("2_of_clubs")).unwrap_err(), TableError::CardNotInHand);
+        assert_eq!(table.play(9, card("3_of_clubs")).unwrap_err(), TableError::NotAtTable);
+
+        let info = table.info(0);
+        assert_eq!(info.played, vec![PlayedCard { player: 0, card: card("2_of_clubs") }]);
+        assert_eq!(info.discard_top, Some(card("4_of_clubs")));
+        assert!(table.hand(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_leaving_player_returns_the_hand() {
+        let mut table = CardTable::with_deck(&[0, 1], deck(&["2_of_clubs", "3_of_clubs", "4_of_clubs"]));
+        table.remove_player(1);
+
+        assert_eq!(table.players(), [0]);
+        assert_eq!(table.draw(0).unwrap(), card("3_of_clubs"));
+        assert!(table.hand(1).is_none());
+        table.remove_player(0);
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,988 @@
+// Trasporto UDP: numeri di sequenza, ack e due canali, affidabile e non.
+//
+// Every datagram carries a header with its own sequence number plus an ack of
+// the latest packet received from the peer and a bitfield for the 32 before it,
+// so both sides learn which of their packets arrived without extra messages.
+// After the header come one or more messages, each on a channel:
+//
+// - ReliableOrdered (chat, commands, table actions...): every message has an
+//   id, stays queued and is resent until a packet carrying it is acked; the
+//   receiver delivers them exactly once, in id order.
+// - Unreliable (snapshots, inputs, pings): sent once; one arriving in a packet
+//   older than the last one delivered is stale and dropped.
+//
+// Before any of that a client proves it receives at its address: it asks for
+// a challenge, the server answers with a cookie (a keyed hash of the address,
+// no state kept) and only a client echoing a valid cookie gets allocated.
+// Spoofed addresses can't fill the server, and every handshake packet has the
+// same size, so answering one never sends more bytes than it came with.
+//
+// The payloads are the same JSON messages as on TCP and the server logic is
+// shared: the UDP loop below only feeds `handle_payload` and drains the same
+// per-client queues as the TCP threads.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TryRecvError, Receiver};
+
+use crate::protocol::{Channel, ClientMessage, ServerMessage, FRAME_HEADER_SIZE};
+use crate::server::{check_login, handle_payload, lock_state, server_log, LogLevel, OutgoingMessage, ServerState, CLIENT_QUEUE_SIZE};
+
+
+// ====== CONSTANTS ======
+
+/// First bytes of every packet, anything else on the port is ignored
+pub const PROTOCOL_ID: u32 = 0x1BA7_0001;
+
+/// First bytes of the handshake packets, which all have HANDSHAKE_SIZE bytes
+pub const HANDSHAKE_ID: u32 = 0x1BA7_00FF;
+pub const HANDSHAKE_SIZE: usize = 32;
+
+/// A cookie is valid in the window it was made in and the next one
+pub const COOKIE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How long the client keeps asking for a challenge
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages are packed together up to this size, small enough to never be fragmented
+pub const MAX_PACKET_SIZE: usize = 1200;
+
+/// Biggest message accepted, it travels alone in a (fragmented) datagram
+pub const MAX_MESSAGE_SIZE: usize = 60 * 1024;
+
+/// An unacked reliable message is sent again after this long
+pub const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reliable messages waiting for an ack before the peer is considered gone
+pub const MAX_PENDING_RELIABLE: usize = 1024;
+
+const HEADER_SIZE: usize = 13; // Protocol id, sequence, flags, ack, ack bits
+const FLAG_HAS_ACK: u8 = 1; // Without it the ack fields mean nothing: the peer received no packet yet
+const ACK_BITS: u16 = 32;
+const CHANNEL_UNRELIABLE: u8 = 0;
+const CHANNEL_RELIABLE: u8 = 1;
+
+// How long a read waits before the loops flush resends and check for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// Time left to a removed client to ack what was queued before it
+const CLIENT_LINGER: Duration = Duration::from_secs(2);
+// An address stays closed this long, longer than any cookie it may still hold
+const TOMBSTONE_LIFETIME: Duration = Duration::from_secs(3 * COOKIE_WINDOW.as_secs());
+
+const HANDSHAKE_REQUEST: u8 = 0;
+const HANDSHAKE_CHALLENGE: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+
+
+// ====== STRUCTS ======
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    /// Shorter than its header or than a message it announces
+    Truncated,
+    /// Not one of our packets
+    WrongProtocol,
+    UnknownChannel(u8),
+    /// A message bigger than `MAX_MESSAGE_SIZE`
+    TooLarge(usize),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Truncated => write!(f, "Truncated packet"),
+            PacketError::WrongProtocol => write!(f, "Not a game packet"),
+            PacketError::UnknownChannel(channel) => write!(f, "Unknown channel {}", channel),
+            PacketError::TooLarge(size) => write!(f, "Message of {} bytes, the limit is {}", size, MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl From<PacketError> for io::Error {
+    fn from(e: PacketError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Something from the server that `UdpClient::receive` dropped, the rest of what arrived is kept
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// A packet that didn't parse, none of its messages are delivered
+    Packet(PacketError),
+    /// A delivered message that isn't a `ServerMessage`; it stays acked
+    Decode(serde_json::Error),
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiveError::Packet(e) => write!(f, "Bad packet from the server: {}", e),
+            ReceiveError::Decode(e) => write!(f, "Bad message from the server: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReceiveError {}
+
+#[derive(Debug)]
+struct PendingMessage {
+    id: u16,
+    payload: Vec<u8>,
+    last_sent: Option<Instant>, // None until it goes in a packet
+}
+
+/// Sequence numbers, acks and channels of one peer, without any socket:
+/// `send` queues, `packets` gives what to put on the wire, `receive` reads it
+#[derive(Debug, Default)]
+pub struct Connection {
+    local_sequence: u16, // Of the next packet sent
+    remote_sequence: Option<u16>, // Latest packet received
+    received_bits: u32, // Bit n set: packet remote_sequence - 1 - n was received
+    ack_pending: bool, // Received something the peer doesn't know about yet
+
+    next_reliable_id: u16,
+    unacked: VecDeque<PendingMessage>, // Reliable messages sent and not acked, oldest first
+    in_flight: HashMap<u16, Vec<u16>>, // Packet sequence -> ids of the reliable messages in it
+    unreliable: Vec<Vec<u8>>,
+
+    expected_reliable_id: u16, // Next one to deliver
+    reorder_buffer: HashMap<u16, Vec<u8>>, // Arrived ahead of a missing one
+    latest_unreliable: Option<u16>, // Packet of the last unreliable messages delivered
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&mut self, channel: Channel, payload: Vec<u8>) -> Result<(), PacketError> {
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(PacketError::TooLarge(payload.len()));
+        }
+        match channel {
+            Channel::Unreliable => self.unreliable.push(payload),
+            Channel::ReliableOrdered => {
+                let id = self.next_reliable_id;
+                self.next_reliable_id = id.wrapping_add(1);
+                self.unacked.push_back(PendingMessage { id, payload, last_sent: None });
+            }
+        }
+        Ok(())
+    }
+
+    /// Packets to send now: the queued messages, the reliable ones due for a
+    /// resend, or just an ack if there is nothing else to say
+    pub fn packets(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut packet = new_packet();
+        let mut reliable_ids = Vec::new();
+
+        let due = self.unacked.iter_mut().filter(|message| {
+            message.last_sent.is_none_or(|sent| now.saturating_duration_since(sent) >= RESEND_INTERVAL)
+        });
+        let reliable = due.map(|message| {
+            message.last_sent = Some(now);
+            (Some(message.id), message.payload.as_slice())
+        });
+        let unreliable = self.unreliable.iter().map(|payload| (None, payload.as_slice()));
+
+        for (id, payload) in reliable.chain(unreliable).collect::<Vec<_>>() {
+            let size = 1 + if id.is_some() { 2 } else { 0 } + 2 + payload.len();
+            if packet.len() > HEADER_SIZE && packet.len() + size > MAX_PACKET_SIZE {
+                packets.push((std::mem::replace(&mut packet, new_packet()), std::mem::take(&mut reliable_ids)));
+            }
+            match id {
+                Some(id) => {
+                    packet.push(CHANNEL_RELIABLE);
+                    packet.extend_from_slice(&id.to_be_bytes());
+                    reliable_ids.push(id);
+                }
+                None => packet.push(CHANNEL_UNRELIABLE),
+            }
+            packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            packet.extend_from_slice(payload);
+        }
+        self.unreliable.clear();
+        if packet.len() > HEADER_SIZE || (packets.is_empty() && self.ack_pending) {
+            packets.push((packet, reliable_ids));
+        }
+
+        // Only now the sequence numbers, so the packets go out numbered in order
+        packets
+            .into_iter()
+            .map(|(mut packet, reliable_ids)| {
+                let sequence = self.local_sequence;
+                self.local_sequence = sequence.wrapping_add(1);
+                self.write_header(&mut packet, sequence);
+                // Too old to ever be acked: whatever it carried is still in unacked and will be resent
+                self.in_flight.remove(&sequence.wrapping_sub(ACK_BITS + 1));
+                if !reliable_ids.is_empty() {
+                    self.in_flight.insert(sequence, reliable_ids);
+                }
+                packet
+            })
+            .collect()
+    }
+
+    /// Reads a packet from the peer, returns the messages it delivers, in order
+    pub fn receive(&mut self, packet: &[u8]) -> Result<Vec<Vec<u8>>, PacketError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        if read_u32(packet, 0) != PROTOCOL_ID {
+            return Err(PacketError::WrongProtocol);
+        }
+        let sequence = read_u16(packet, 4);
+        let has_ack = packet[6] & FLAG_HAS_ACK != 0;
+        let ack = read_u16(packet, 7);
+        let ack_bits = read_u32(packet, 9);
+
+        // Parsed completely before touching the state, a broken packet changes nothing
+        let mut messages = Vec::new();
+        let mut offset = HEADER_SIZE;
+        while offset < packet.len() {
+            let channel = packet[offset];
+            let id = match channel {
+                CHANNEL_UNRELIABLE => None,
+                CHANNEL_RELIABLE if offset + 3 <= packet.len() => Some(read_u16(packet, offset + 1)),
+                CHANNEL_RELIABLE => return Err(PacketError::Truncated),
+                channel => return Err(PacketError::UnknownChannel(channel)),
+            };
+            offset += if id.is_some() { 3 } else { 1 };
+            if offset + 2 > packet.len() {
+                return Err(PacketError::Truncated);
+            }
+            let size = read_u16(packet, offset) as usize;
+            let payload = packet.get(offset + 2..offset + 2 + size).ok_or(PacketError::Truncated)?;
+            messages.push((id, payload.to_vec()));
+            offset += 2 + size;
+        }
+
+        self.mark_received(sequence);
+        // Packets with only an ack need no answer, or two idle peers would ack each other forever
+        self.ack_pending |= !messages.is_empty();
+        if has_ack {
+            self.process_acks(ack, ack_bits);
+        }
+
+        let fresh = self.latest_unreliable.is_none_or(|latest| sequence_greater(sequence, latest));
+        if fresh {
+            self.latest_unreliable = Some(sequence);
+        }
+        let mut delivered = Vec::new();
+        for (id, payload) in messages {
+            match id {
+                None if fresh => delivered.push(payload),
+                None => {}
+                // Already delivered, or so far ahead that it can't be a new one
+                Some(id) if id.wrapping_sub(self.expected_reliable_id) >= MAX_PENDING_RELIABLE as u16 => {}
+                Some(id) => {
+                    self.reorder_buffer.insert(id, payload);
+                }
+            }
+        }
+        while let Some(payload) = self.reorder_buffer.remove(&self.expected_reliable_id) {
+            delivered.push(payload);
+            self.expected_reliable_id = self.expected_reliable_id.wrapping_add(1);
+        }
+        Ok(delivered)
+    }
+
+    /// Reliable messages the peer hasn't acknowledged yet
+    pub fn pending_reliable(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// The peer stopped acking: it is gone or can't keep up
+    pub fn is_overloaded(&self) -> bool {
+        self.unacked.len() > MAX_PENDING_RELIABLE
+    }
+
+    fn write_header(&mut self, packet: &mut [u8], sequence: u16) {
+        packet[0..4].copy_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet[4..6].copy_from_slice(&sequence.to_be_bytes());
+        packet[6] = if self.remote_sequence.is_some() { FLAG_HAS_ACK } else { 0 };
+        packet[7..9].copy_from_slice(&self.remote_sequence.unwrap_or(0).to_be_bytes());
+        packet[9..13].copy_from_slice(&self.received_bits.to_be_bytes());
+        self.ack_pending = false;
+    }
+
+    fn mark_received(&mut self, sequence: u16) {
+        let Some(remote) = self.remote_sequence else {
+            self.remote_sequence = Some(sequence);
+            return;
+        };
+        if sequence_greater(sequence, remote) {
+            let shift = sequence.wrapping_sub(remote) as u32;
+            // The old latest becomes bit shift - 1
+            self.received_bits = self.received_bits.checked_shl(shift).unwrap_or(0);
+            if shift <= ACK_BITS as u32 {
+                self.received_bits |= 1 << (shift - 1);
+            }
+            self.remote_sequence = Some(sequence);
+        } else if sequence != remote {
+            let age = remote.wrapping_sub(sequence);
+            if age <= ACK_BITS {
+                self.received_bits |= 1 << (age - 1);
+            }
+        }
+    }
+
+    fn process_acks(&mut self, ack: u16, ack_bits: u32) {
+        let acked = std::iter::once(ack).chain(
+            (0..ACK_BITS).filter(|bit| ack_bits & (1 << bit) != 0).map(|bit| ack.wrapping_sub(bit + 1)),
+        );
+        for sequence in acked {
+            if let Some(ids) = self.in_flight.remove(&sequence) {
+                self.unacked.retain(|message| !ids.contains(&message.id));
+            }
+        }
+    }
+}
+
+/// A client talking to the server over UDP, with the same messages as on TCP
+#[derive(Debug)]
+pub struct UdpClient {
+    socket: UdpSocket,
+    connection: Connection,
+    cookie: Option<u64>, // Echoed with every flush until the server answers, the echo may get lost
+}
+
+impl UdpClient {
+    /// Binds a local port and gets a cookie from the server; the server makes
+    /// the client once the cookie comes back, with the first flush
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to"))?;
+        let local: SocketAddr = if address.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+
+        let mut client = UdpClient { socket, connection: Connection::new(), cookie: None };
+        client.cookie = Some(client.request_cookie()?);
+        client.flush()?;
+        Ok(client)
+    }
+
+    // Asks again every RESEND_INTERVAL, the request or the challenge may get lost
+    fn request_cookie(&mut self) -> io::Result<u64> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        self.socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+        while Instant::now() < deadline {
+            self.socket.send(&handshake_packet(HANDSHAKE_REQUEST, 0))?;
+            match self.socket.recv(&mut buffer) {
+                Ok(size) => {
+                    if let Some((HANDSHAKE_CHALLENGE, cookie)) = read_handshake(&buffer[..size]) {
+                        return Ok(cookie);
+                    }
+                }
+                Err(e) if is_would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "The server never answered the handshake"))
+    }
+
+    /// Sends on the channel the kind of message asks for
+    pub fn send(&mut self, message: &ClientMessage) -> io::Result<()> {
+        self.send_on(message.channel(), message)
+    }
+
+    pub fn send_on(&mut self, channel: Channel, message: &ClientMessage) -> io::Result<()> {
+        self.connection.send(channel, serde_json::to_vec(message)?)?;
+        self.flush()
+    }
+
+    /// Sends what is due: new messages, resends and acks
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(cookie) = self.cookie {
+            self.socket.send(&handshake_packet(HANDSHAKE_RESPONSE, cookie))?;
+        }
+        for packet in self.connection.packets(Instant::now()) {
+            self.socket.send(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for packets and returns the messages they delivered,
+    /// in order; what couldn't be read is an `Err` in its place, for the caller to log
+    pub fn receive(&mut self, timeout: Duration) -> io::Result<Vec<Result<ServerMessage, ReceiveError>>> {
+        let mut messages = Vec::new();
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE + MAX_PACKET_SIZE];
+        self.socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+
+        loop {
+            let size = match self.socket.recv(&mut buffer) {
+                Ok(size) => size,
+                Err(e) if is_would_block(&e) => break,
+                Err(e) => return Err(e),
+            };
+            let packet = &buffer[..size];
+            let result = match read_handshake(packet) {
+                Some(_) => Ok(Vec::new()), // A late challenge, the cookie is already known
+                None => self.connection.receive(packet),
+            };
+            match result {
+                Ok(payloads) => {
+                    // Anything from the server means it knows us
+                    if read_handshake(packet).is_none() {
+                        self.cookie = None;
+                    }
+                    for payload in payloads {
+                        messages.push(serde_json::from_slice(&payload).map_err(ReceiveError::Decode));
+                    }
+                }
+                Err(e) => messages.push(Err(ReceiveError::Packet(e))),
+            }
+            // Whatever else already arrived, without waiting again
+            self.socket.set_read_timeout(Some(Duration::from_millis(1)))?;
+        }
+
+        self.flush()?;
+        Ok(messages)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+// A client of the UDP loop, known by the address its packets come from
+struct Peer {
+    client_id: usize,
+    connection: Connection,
+    outgoing: Receiver<OutgoingMessage>,
+    closed_at: Option<Instant>, // Removed from the server state, only acks are still read
+}
+
+// The server end of the socket: its peers, the handshake and the closed addresses
+struct UdpServer {
+    socket: UdpSocket,
+    state: Arc<Mutex<ServerState>>,
+    peers: HashMap<SocketAddr, Peer>,
+    closed: HashMap<SocketAddr, Instant>, // Tombstones: a late packet must not bring a closed peer back
+    cookie_key: RandomState, // Random keys for every server run, cookies can't be forged
+    start: Instant,
+}
+
+impl UdpServer {
+    fn new(socket: UdpSocket, state: Arc<Mutex<ServerState>>) -> Self {
+        UdpServer {
+            socket,
+            state,
+            peers: HashMap::new(),
+            closed: HashMap::new(),
+            cookie_key: RandomState::new(),
+            start: Instant::now(),
+        }
+    }
+
+    // Waits up to one POLL_INTERVAL, handling every datagram that arrives meanwhile
+    fn receive_packets(&mut self, buffer: &mut [u8]) {
+        let deadline = Instant::now() + POLL_INTERVAL;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return;
+            }
+            if let Err(e) = self.socket.set_read_timeout(Some(timeout)) {
+                server_log!(LogLevel::Error, "Could not use the UDP socket: {}", e);
+                return;
+            }
+            match self.socket.recv_from(buffer) {
+                Ok((size, address)) => self.receive_packet(address, &buffer[..size], Instant::now()),
+                Err(e) if is_would_block(&e) => return,
+                // On some systems an ICMP "port unreachable" from a gone client shows up here
+                Err(e) => server_log!(LogLevel::Debug, "UDP receive error: {}", e),
+            }
+        }
+    }
+
+    fn receive_packet(&mut self, address: SocketAddr, packet: &[u8], now: Instant) {
+        if let Some((kind, cookie)) = read_handshake(packet) {
+            self.handshake(address, kind, cookie, now);
+            return;
+        }
+        // Strangers have to go through the handshake first
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        match peer.connection.receive(packet) {
+            Ok(payloads) if peer.closed_at.is_none() => {
+                let mut logins = Vec::new();
+                {
+                    let mut state = lock_state(&self.state);
+                    for payload in payloads {
+                        logins.extend(handle_payload(peer.client_id, &payload, &mut state));
+                    }
+                }
+                // The password hash runs on its own thread, the loop keeps serving the other peers
+                for login in logins {
+                    let state = Arc::clone(&self.state);
+                    thread::spawn(move || check_login(&state, login));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => server_log!(LogLevel::Debug, "Bad packet from client {}: {}", peer.client_id, e),
+        }
+    }
+
+    fn handshake(&mut self, address: SocketAddr, kind: u8, cookie: u64, now: Instant) {
+        match kind {
+            // Answered without keeping anything, with a packet as big as the request
+            HANDSHAKE_REQUEST => {
+                let cookie = self.cookie(address, self.cookie_window(now));
+                let _ = self.socket.send_to(&handshake_packet(HANDSHAKE_CHALLENGE, cookie), address);
+            }
+            HANDSHAKE_RESPONSE if !self.peers.contains_key(&address) && !self.closed.contains_key(&address) => {
+                if !self.is_valid_cookie(address, cookie, now) {
+                    return;
+                }
+                let (sender, outgoing) = mpsc::channel(CLIENT_QUEUE_SIZE);
+                let registered = lock_state(&self.state).register_client(sender);
+                match registered {
+                    Ok(client_id) => {
+                        server_log!(LogLevel::Info, "Client connected over UDP: {} ({})", client_id, address);
+                        let peer = Peer { client_id, connection: Connection::new(), outgoing, closed_at: None };
+                        self.peers.insert(address, peer);
+                    }
+                    Err(reason) => {
+                        server_log!(LogLevel::Info, "Refused a UDP client: {}", reason);
+                        refuse(&self.socket, address, reason);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn cookie_window(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs() / COOKIE_WINDOW.as_secs()
+    }
+
+    fn cookie(&self, address: SocketAddr, window: u64) -> u64 {
+        self.cookie_key.hash_one((address, window))
+    }
+
+    fn is_valid_cookie(&self, address: SocketAddr, cookie: u64, now: Instant) -> bool {
+        let window = self.cookie_window(now);
+        cookie == self.cookie(address, window) || (window > 0 && cookie == self.cookie(address, window - 1))
+    }
+
+    // Moves what the server queued for every client into its connection, then sends
+    // the packets that are due; clients removed from the state are forgotten once
+    // everything they were sent is acked, and their address is closed for a while
+    fn send_packets(&mut self, now: Instant) {
+        let mut overloaded = Vec::new();
+
+        for (address, peer) in self.peers.iter_mut() {
+            loop {
+                match peer.outgoing.try_recv() {
+                    Ok(message) => {
+                        let Some(frame) = message.frame() else {
+                            server_log!(LogLevel::Warn, "Could not encode message for client {}", peer.client_id);
+                            continue;
+                        };
+                        let payload = frame[FRAME_HEADER_SIZE..].to_vec();
+                        if let Err(e) = peer.connection.send(message.message().channel(), payload) {
+                            server_log!(LogLevel::Warn, "Could not send to client {}: {}", peer.client_id, e);
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        peer.closed_at.get_or_insert(now);
+                        break;
+                    }
+                }
+            }
+
+            for packet in peer.connection.packets(now) {
+                if let Err(e) = self.socket.send_to(&packet, address) {
+                    server_log!(LogLevel::Debug, "Failed to send a packet to client {}: {}", peer.client_id, e);
+                }
+            }
+            if peer.closed_at.is_none() && peer.connection.is_overloaded() {
+                overloaded.push(peer.client_id);
+            }
+        }
+
+        for client_id in overloaded {
+            server_log!(LogLevel::Warn, "Dropping client {}: too many unacknowledged messages", client_id);
+            lock_state(&self.state).drop_lagging_client(client_id);
+        }
+        let gone: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.closed_at.is_some_and(|closed_at| {
+                    peer.connection.pending_reliable() == 0 || now.duration_since(closed_at) >= CLIENT_LINGER
+                })
+            })
+            .map(|(address, _)| *address)
+            .collect();
+        for address in gone {
+            self.peers.remove(&address);
+            self.closed.insert(address, now);
+        }
+        self.closed.retain(|_, closed_at| now.duration_since(*closed_at) < TOMBSTONE_LIFETIME);
+    }
+}
+
+
+// ====== METHODS ======
+
+/// Serves the UDP socket until the server shuts down. Runs next to the TCP
+/// listener, on the same ServerState: the game can't tell the transports apart
+pub(crate) fn run(socket: UdpSocket, state: Arc<Mutex<ServerState>>) {
+    let mut server = UdpServer::new(socket, state);
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE + MAX_PACKET_SIZE];
+
+    loop {
+        let shutting_down = lock_state(&server.state).shutting_down;
+        server.receive_packets(&mut buffer);
+        // Once per poll tick, however many datagrams arrived
+        server.send_packets(Instant::now());
+        // One last round sends the Disconnected of the shutdown
+        if shutting_down {
+            return;
+        }
+    }
+}
+
+// A full server answers once, without keeping any state for the client
+fn refuse(socket: &UdpSocket, address: SocketAddr, reason: &str) {
+    let mut connection = Connection::new();
+    let message = ServerMessage::Disconnected { reason: reason.to_string() };
+    let Ok(payload) = serde_json::to_vec(&message) else {
+        return;
+    };
+    if connection.send(Channel::Unreliable, payload).is_ok() {
+        for packet in connection.packets(Instant::now()) {
+            let _ = socket.send_to(&packet, address);
+        }
+    }
+}
+
+// Handshake packets are padded to HANDSHAKE_SIZE, whatever they carry
+fn handshake_packet(kind: u8, cookie: u64) -> Vec<u8> {
+    let mut packet = vec![0u8; HANDSHAKE_SIZE];
+    packet[0..4].copy_from_slice(&HANDSHAKE_ID.to_be_bytes());
+    packet[4] = kind;
+    packet[5..13].copy_from_slice(&cookie.to_be_bytes());
+    packet
+}
+
+// Kind and cookie of a handshake packet, None for anything else
+fn read_handshake(packet: &[u8]) -> Option<(u8, u64)> {
+    if packet.len() != HANDSHAKE_SIZE || read_u32(packet, 0) != HANDSHAKE_ID {
+        return None;
+    }
+    let cookie = u64::from_be_bytes(packet[5..13].try_into().ok()?);
+    Some((packet[4], cookie))
+}
+
+// Room for the header, written once the packet gets its sequence number
+fn new_packet() -> Vec<u8> {
+    let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+    packet.resize(HEADER_SIZE, 0);
+    packet
+}
+
+// a is more recent than b, even across the wrap around
+fn sequence_greater(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+// Out of bounds reads as 0, the callers check the length where it matters
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    match bytes.get(offset..offset + 2) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    match bytes.get(offset..offset + 4) {
+        Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        None => 0,
+    }
+}
+
+fn is_would_block(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+
+
+// ================== TEST DOWN HERE ==================
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::test_credentials;
+    use crate::server::{start_server, ServerConfig};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn payloads(messages: &[&str]) -> Vec<Vec<u8>> {
+        messages.iter().map(|message| message.as_bytes().to_vec()).collect()
+    }
+
+    fn receive_all(connection: &mut Connection, packets: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        packets.iter().flat_map(|packet| connection.receive(packet).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_reliable_survives_loss_and_reordering() {
+        let mut sender = Connection::new();
+        let mut receiver = Connection::new();
+        let sent: Vec<String> = (0..200).map(|i| format!("message {} {}", i, "x".repeat(i % 50))).collect();
+        for message in &sent {
+            sender.send(Channel::ReliableOrdered, message.as_bytes().to_vec()).unwrap();
+        }
+
+        // A third of the packets (acks too) is lost and the rest arrive in reverse order
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut now = Instant::now();
+        let mut received = Vec::new();
+        for _ in 0..50 {
+            let mut packets = sender.packets(now);
+            packets.reverse();
+            for packet in packets.iter().filter(|_| rng.gen_bool(2. / 3.)) {
+                received.extend(receiver.receive(packet).unwrap());
+            }
+            for ack in receiver.packets(now).iter().filter(|_| rng.gen_bool(2. / 3.)) {
+                sender.receive(ack).unwrap();
+            }
+            now += RESEND_INTERVAL;
+        }
+
+        let received: Vec<String> = received.into_iter().map(|payload| String::from_utf8(payload).unwrap()).collect();
+        assert_eq!(received, sent);
+        assert_eq!(sender.pending_reliable(), 0);
+    }
+
+    #[test]
+    fn test_nothing_is_acked_before_receiving() {
+        let mut a = Connection::new();
+        let mut b = Connection::new();
+        let now = Instant::now();
+        a.send(Channel::ReliableOrdered, b"lost".to_vec()).unwrap();
+        a.packets(now); // Never arrives
+
+        b.send(Channel::ReliableOrdered, b"hello".to_vec()).unwrap();
+        for packet in b.packets(now) {
+            assert_eq!(a.receive(&packet).unwrap(), payloads(&["hello"]));
+        }
+        assert_eq!(a.pending_reliable(), 1);
+    }
+
+    #[test]
+    fn test_unreliable_drops_stale_messages() {
+        let mut sender = Connection::new();
+        let mut receiver = Connection::new();
+        let now = Instant::now();
+
+        sender.send(Channel::Unreliable, b"old".to_vec()).unwrap();
+        let old = sender.packets(now);
+        sender.send(Channel::Unreliable, b"new".to_vec()).unwrap();
+        sender.send(Channel::Unreliable, b"newer".to_vec()).unwrap();
+        let new = sender.packets(now);
+
+        assert_eq!(receive_all(&mut receiver, new.clone()), payloads(&["new", "newer"]));
+        assert!(receive_all(&mut receiver, old).is_empty());
+        assert!(receive_all(&mut receiver, new).is_empty()); // A duplicate is stale too
+        // Sent once: nothing to resend, only the ack of what arrived is owed
+        assert!(sender.packets(now + RESEND_INTERVAL).is_empty());
+        assert_eq!(receiver.packets(now).len(), 1);
+        assert!(receiver.packets(now).is_empty());
+    }
+
+    #[test]
+    fn test_big_messages_are_split_in_packets() {
+        let mut connection = Connection::new();
+        // Three small ones share a packet, the big one goes alone
+        for _ in 0..3 {
+            connection.send(Channel::ReliableOrdered, vec![b'a'; MAX_PACKET_SIZE / 4]).unwrap();
+        }
+        connection.send(Channel::ReliableOrdered, vec![b'b'; MAX_PACKET_SIZE * 4]).unwrap();
+        let packets = connection.packets(Instant::now());
+        assert_eq!(packets.len(), 2);
+        assert!(packets[0].len() <= MAX_PACKET_SIZE);
+
+        let too_big = vec![0; MAX_MESSAGE_SIZE + 1];
+        assert_eq!(connection.send(Channel::Unreliable, too_big), Err(PacketError::TooLarge(MAX_MESSAGE_SIZE + 1)));
+    }
+
+    #[test]
+    fn test_foreign_packets_are_rejected() {
+        let mut connection = Connection::new();
+        assert_eq!(connection.receive(b"GET / HTTP/1.1\r\n"), Err(PacketError::WrongProtocol));
+        assert_eq!(connection.receive(&[0; 4]), Err(PacketError::Truncated));
+
+        let mut sender = Connection::new();
+        sender.send(Channel::ReliableOrdered, b"hello".to_vec()).unwrap();
+        let mut packet = sender.packets(Instant::now()).remove(0);
+        packet.pop();
+        assert_eq!(connection.receive(&packet), Err(PacketError::Truncated));
+        packet[HEADER_SIZE] = 7;
+        assert_eq!(connection.receive(&packet), Err(PacketError::UnknownChannel(7)));
+    }
+
+    #[test]
+    fn test_sequence_wrap_around() {
+        assert!(sequence_greater(0, u16::MAX));
+        assert!(sequence_greater(5, 65_530));
+        assert!(!sequence_greater(65_530, 5));
+        assert!(!sequence_greater(9, 9));
+    }
+
+    fn udp_server() -> UdpServer {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        UdpServer::new(socket, Arc::new(Mutex::new(ServerState::new(test_credentials()))))
+    }
+
+    // A real socket, so the challenge can be read back
+    fn stranger() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let address = socket.local_addr().unwrap();
+        (socket, address)
+    }
+
+    #[test]
+    fn test_clients_need_a_cookie() {
+        let mut server = udp_server();
+        let (socket, address) = stranger();
+        let now = Instant::now();
+
+        // Data and wrong cookies from an unknown address leave no trace
+        let mut connection = Connection::new();
+        connection.send(Channel::ReliableOrdered, b"{}".to_vec()).unwrap();
+        for packet in connection.packets(now) {
+            server.receive_packet(address, &packet, now);
+        }
+        server.receive_packet(address, &handshake_packet(HANDSHAKE_RESPONSE, 42), now);
+        server.receive_packet(address, &handshake_packet(HANDSHAKE_REQUEST, 0), now);
+        assert!(server.peers.is_empty());
+
+        // The challenge is as big as the request
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let size = socket.recv(&mut buffer).unwrap();
+        assert_eq!(size, HANDSHAKE_SIZE);
+        let (kind, cookie) = read_handshake(&buffer[..size]).unwrap();
+        assert_eq!(kind, HANDSHAKE_CHALLENGE);
+
+        // Someone else's cookie is no good
+        let (_, other) = stranger();
+        server.receive_packet(other, &handshake_packet(HANDSHAKE_RESPONSE, cookie), now);
+        assert!(server.peers.is_empty());
+
+        server.receive_packet(address, &handshake_packet(HANDSHAKE_RESPONSE, cookie), now);
+        server.receive_packet(address, &handshake_packet(HANDSHAKE_RESPONSE, cookie), now);
+        assert_eq!(server.peers.len(), 1);
+
+        // Cookies expire after their window and the next one
+        let later = now + 2 * COOKIE_WINDOW;
+        assert!(server.is_valid_cookie(address, cookie, now + COOKIE_WINDOW));
+        assert!(!server.is_valid_cookie(address, cookie, later));
+    }
+
+    #[test]
+    fn test_closed_address_stays_closed() {
+        let mut server = udp_server();
+        let (_socket, address) = stranger();
+        let now = Instant::now();
+        let cookie = server.cookie(address, server.cookie_window(now));
+        server.receive_packet(address, &handshake_packet(HANDSHAKE_RESPONSE, cookie), now);
+        let client_id = server.peers[&address].client_id;
+
+        lock_state(&server.state).remove_client(client_id);
+        server.send_packets(now);
+        assert!(server.peers.is_empty());
+        assert!(server.closed.contains_key(&address));
+
+        // A late response or ack doesn't make a ghost client
+        server.receive_packet(address, &handshake_packet(HANDSHAKE_RESPONSE, cookie), now);
+        let mut connection = Connection::new();
+        connection.send(Channel::ReliableOrdered, b"{}".to_vec()).unwrap();
+        for packet in connection.packets(now) {
+            server.receive_packet(address, &packet, now);
+        }
+        assert!(server.peers.is_empty());
+
+        // Until the tombstone is gone
+        server.send_packets(now + TOMBSTONE_LIFETIME);
+        assert!(server.closed.is_empty());
+    }
+
+    // A message that doesn't decode is reported in its place, the ones around it still arrive
+    #[test]
+    fn test_bad_message_keeps_the_rest() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        server.connect(socket.local_addr().unwrap()).unwrap();
+        let mut client = UdpClient { socket, connection: Connection::new(), cookie: None };
+
+        let mut connection = Connection::new();
+        let pong = ServerMessage::Pong { nonce: 7 };
+        connection.send(Channel::ReliableOrdered, b"not json".to_vec()).unwrap();
+        connection.send(Channel::ReliableOrdered, serde_json::to_vec(&pong).unwrap()).unwrap();
+        server.send(b"GET / HTTP/1.1\r\n").unwrap();
+        for packet in connection.packets(Instant::now()) {
+            server.send(&packet).unwrap();
+        }
+
+        let received = client.receive(Duration::from_millis(200)).unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(matches!(received[0], Err(ReceiveError::Packet(PacketError::WrongProtocol))));
+        assert!(matches!(received[1], Err(ReceiveError::Decode(_))));
+        assert!(matches!(&received[2], Ok(ServerMessage::Pong { nonce: 7 })));
+    }
+
+    fn receive_until(client: &mut UdpClient, done: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(message) = client.receive(Duration::from_millis(20)).unwrap().into_iter().map(Result::unwrap).find(&done) {
+                return message;
+            }
+        }
+        panic!("Nothing arrived from the server");
+    }
+
+    #[test]
+    fn test_udp_server() {
+        let config = ServerConfig { port: 0, udp_port: Some(0), log_level: LogLevel::Error, ..ServerConfig::default() };
+        let handle = start_server(&config, test_credentials(), None).unwrap();
+        let mut client = UdpClient::connect(handle.udp_addr().unwrap()).unwrap();
+
+        let login = ClientMessage::Login { username: "player1".to_string(), password: "securepassword".to_string() };
+        client.send(&login).unwrap();
+        receive_until(&mut client, |m| matches!(m, ServerMessage::LoginAccepted { .. }));
+
+        // Both channels on the same connection: snapshots every tick, chat once
+        receive_until(&mut client, |m| matches!(m, ServerMessage::StateSnapshot(_)));
+        client.send(&ClientMessage::Chat { text: "over udp".to_string() }).unwrap();
+        let chat = receive_until(&mut client, |m| matches!(m, ServerMessage::Chat { .. }));
+        assert!(matches!(chat, ServerMessage::Chat { text, .. } if text == "over udp"));
+        assert!(handle.execute(&crate::server::AdminCommand::List).starts_with("1 client(s)"));
+
+        handle.shutdown();
+        receive_until(&mut client, |m| matches!(m, ServerMessage::Disconnected { .. }));
+        handle.wait();
+    }
+}
@@ -1,12 +1,16 @@
 use bevy::{color::palettes::{css::*, tailwind::*}, core_pipeline::bloom::{BloomCompositeMode, BloomSettings}, diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin}, prelude::*};
 
-use crate::{Gravity, Health, Scene1Entity};
+use crate::{ClientResource, Gravity, Health, Scene1Entity};
 
 // ====== STRUCTS ======
 // A unit struct to help identify the FPS UI component, since there may be many Text components
 #[derive(Component)]
 pub struct FpsText;
 
+// Round-trip time to the server, next to the FPS
+#[derive(Component)]
+pub struct PingText;
+
 #[derive(Component)]
 pub struct GravityText;
 
@@ -53,6 +57,26 @@ pub fn ui_setup_scene1(
         Scene1Entity
     ));
 
+    // Ping Text
+    commands.spawn((
+        TextBundle::from_section(
+            "Ping: -",
+            TextStyle {
+                font_size: 30.,
+                ..default()
+            },
+        )
+        .with_text_justify(JustifyText::Left)
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.),
+            right: Val::Px(230.),
+            ..default()
+        }),
+        PingText,
+        Scene1Entity
+    ));
+
     // Gravity Text
     commands.spawn((
         TextBundle::from_section(
@@ -100,6 +124,20 @@ pub fn fps_text_update_system(
     }
 }
 
+pub fn ping_text_update_system(
+    client: Option<Res<ClientResource>>,
+    mut query: Query<&mut Text, With<PingText>>,
+) {
+    // No measurement yet (or not connected at all): show a dash instead of a stale value
+    let rtt = client.filter(|client| client.client.is_connected()).and_then(|client| client.rtt());
+    for mut text in &mut query {
+        text.sections[0].value = match rtt {
+            Some(rtt) => format!("Ping: {} ms", rtt.as_millis()),
+            None => "Ping: -".to_string(),
+        };
+    }
+}
+
 pub fn gravity_text_update_system(
     mut query: Query<&mut Text, With<GravityText>>,
     gravity: Res<Gravity>,